@@ -388,7 +388,7 @@ pub const kAudioChannelBit_TopBackRight: u32 = (1 << 17);
 pub type AudioChannelFlags = u32;
 pub const kAudioChannelFlags_AllOff: u32 = 0;
 pub const kAudioChannelFlags_RectangularCoordinates: u32 = 1;
-pub const kAudioChannelFlags_SphericalCoordinates: u32 = 1;
+pub const kAudioChannelFlags_SphericalCoordinates: u32 = (1 << 1);
 pub const kAudioChannelFlags_Meters: u32 = (1 << 2);
 
 pub type AudioChannelCoordinateIndex = u32;
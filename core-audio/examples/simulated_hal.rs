@@ -0,0 +1,57 @@
+extern crate core_audio;
+
+use core_audio::{audio_object_iter, audio_system_object, ffi, set_backend, AudioDevice,
+                 AudioStream, ClassID, Result, SimulatedHal};
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+fn print<T: Debug>(name: &str, t: Result<T>) {
+    match t {
+        Ok(t) => println!("{} = {:?}", name, t),
+        Err(e) => println!("{} not found: {:?}", name, e.description()),
+    }
+}
+
+fn addr(selector: ffi::AudioObjectPropertySelector) -> ffi::AudioObjectPropertyAddress {
+    ffi::AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: ffi::kAudioObjectPropertyScopeGlobal,
+        mElement: ffi::kAudioObjectPropertyElementMaster,
+    }
+}
+
+fn main() {
+    let hal = Arc::new(SimulatedHal::new());
+    let aso = audio_system_object();
+
+    let device = hal.add_object(AudioDevice::CLASS_ID, &aso);
+    hal.add_object(AudioStream::CLASS_ID, &device);
+    hal.set_property(&device, &addr(ffi::kAudioDevicePropertyNominalSampleRate), &44100f64);
+    hal.set_property_settable(&device, &addr(ffi::kAudioDevicePropertyNominalSampleRate), true);
+    hal.set_property_array(
+        &device,
+        &addr(ffi::kAudioDevicePropertyAvailableNominalSampleRates),
+        &[
+            ffi::AudioValueRange { mMinimum: 44100., mMaximum: 44100. },
+            ffi::AudioValueRange { mMinimum: 48000., mMaximum: 48000. },
+        ],
+    );
+    hal.set_property(&aso, &addr(ffi::kAudioHardwarePropertyDefaultOutputDevice), &device);
+    set_backend(hal.clone());
+
+    print("devices", aso.devices());
+    print("default_output_device", aso.default_output_device());
+    for device in audio_object_iter(&aso.devices().unwrap()) {
+        if let Some(device) = device.downcast_ref::<AudioDevice>() {
+            let mut device = *device;
+            print("class", device.class());
+            print("owner", device.owner());
+            print("streams", device.streams());
+            print("available_nominal_sample_rates", device.available_nominal_sample_rates());
+            print("nominal_sample_rate", device.nominal_sample_rate());
+            print("set_nominal_sample_rate", device.set_nominal_sample_rate(&48000.));
+            print("nominal_sample_rate", device.nominal_sample_rate());
+        }
+    }
+}
@@ -0,0 +1,604 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Streaming reading and writing of AIFF and AIFF-C files.
+
+use {AudioBufferListRef, AudioFormat, AudioFormatFlags, AudioStreamBasicDescription,
+     AudioStreamBasicDescriptionRef, SampleKind};
+use audio_file::{buffer_frames, check_sample_rate, gather, scatter, skip, AudioFileError,
+                 AudioFileResult, ReadBytes};
+use ffi;
+use interleave::frame_count;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The timestamp of the only version of AIFF-C, for its `FVER` chunk.
+const AIFC_VERSION_1: u32 = 0xA280_5140;
+
+/// Converts an 80-bit IEEE 754 extended precision number, as AIFF
+/// stores sample rates, to the nearest `f64`.
+fn extended_to_f64(bytes: [u8; 10]) -> f64 {
+    let negative = bytes[0] & 0x80 != 0;
+    let exponent = i32::from(u16::from_be_bytes([bytes[0] & 0x7F, bytes[1]]));
+    let mut mantissa = [0; 8];
+    mantissa.copy_from_slice(&bytes[2..]);
+    let mantissa = u64::from_be_bytes(mantissa);
+    let value = if exponent == 0x7FFF {
+        if mantissa << 1 == 0 {
+            f64::INFINITY
+        } else {
+            f64::NAN
+        }
+    } else {
+        // The mantissa has an explicit integer bit, so it's the value
+        // scaled by 2^63. Scale in two steps so that neither overflows
+        // before the value does.
+        let exponent = exponent - 16383 - 63;
+        let half = exponent / 2;
+        mantissa as f64 * 2f64.powi(half) * 2f64.powi(exponent - half)
+    };
+    if negative {
+        -value
+    } else {
+        value
+    }
+}
+
+/// Converts `value` to an 80-bit IEEE 754 extended precision number.
+/// Every `f64` can be represented exactly.
+fn f64_to_extended(value: f64) -> [u8; 10] {
+    let bits = value.to_bits();
+    let negative = bits >> 63 != 0;
+    let exponent = ((bits >> 52) & 0x7FF) as i32;
+    let fraction = bits & ((1 << 52) - 1);
+    let (exponent, mantissa) = match exponent {
+        0 if fraction == 0 => (0, 0),
+        // Subnormal numbers become normal with the wider exponent.
+        0 => {
+            let shift = fraction.leading_zeros() as i32;
+            (-1011 - shift + 16383, fraction << shift)
+        }
+        0x7FF => (0x7FFF, (1 << 63) | (fraction << 11)),
+        _ => (exponent - 1023 + 16383, (1 << 63) | (fraction << 11)),
+    };
+    let mut bytes = [0; 10];
+    let exponent = (exponent as u16) | if negative { 0x8000 } else { 0 };
+    bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+    bytes[2..].copy_from_slice(&mantissa.to_be_bytes());
+    bytes
+}
+
+/// The contents of a `COMM` chunk.
+struct CommonChunk {
+    channels: u16,
+    frames: u32,
+    sample_size: u16,
+    sample_rate: f64,
+    compression: [u8; 4],
+}
+
+impl CommonChunk {
+    fn read<R: Read>(reader: &mut R, size: u32, aifc: bool) -> AudioFileResult<CommonChunk> {
+        let min_size = if aifc { 22 } else { 18 };
+        if size < min_size {
+            return Err(AudioFileError::Malformed("COMM chunk is too short"));
+        }
+        let chunk = CommonChunk {
+            channels: reader.u16_be()?,
+            frames: reader.u32_be()?,
+            sample_size: reader.u16_be()?,
+            sample_rate: extended_to_f64(reader.bytes_array()?),
+            compression: if aifc { reader.four_cc()? } else { *b"NONE" },
+        };
+        // The rest is the compression type's name.
+        skip(reader, u64::from(size - min_size) + u64::from(size & 1))?;
+        Ok(chunk)
+    }
+
+    fn to_format(&self) -> AudioFileResult<AudioStreamBasicDescription> {
+        let channels = u32::from(self.channels);
+        let rate = self.sample_rate;
+        if channels == 0 || !rate.is_finite() || rate <= 0. {
+            return Err(AudioFileError::Malformed("COMM chunk has no sample rate or channels"));
+        }
+        let bits = u32::from(self.sample_size);
+        let lpcm = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, rate, channels);
+        let format = match &self.compression {
+            // Integer samples are signed, even at 8 bits, and take whole
+            // bytes with the sample in the high bits.
+            b"NONE" | b"twos" | b"sowt" => {
+                if bits == 0 || bits > 32 {
+                    return Err(AudioFileError::Unsupported("AIFF sample size"));
+                }
+                lpcm.sample_kind(SampleKind::SignedInteger)
+                    .bits_per_channel(bits)
+                    .word_bits((bits + 7) / 8 * 8)
+                    .big_endian(&self.compression != b"sowt")
+                    .build()
+            }
+            // The sample size of floats and companded samples is the size
+            // they decode to, so it's ignored.
+            b"fl32" | b"FL32" => lpcm.sample_kind(SampleKind::Float)
+                .bits_per_channel(32)
+                .big_endian(true)
+                .build(),
+            b"fl64" | b"FL64" => lpcm.sample_kind(SampleKind::Float)
+                .bits_per_channel(64)
+                .big_endian(true)
+                .build(),
+            b"ulaw" | b"ULAW" => {
+                AudioStreamBasicDescription::builder(AudioFormat::ULaw, rate, channels).build()
+            }
+            b"alaw" | b"ALAW" => {
+                AudioStreamBasicDescription::builder(AudioFormat::ALaw, rate, channels).build()
+            }
+            _ => return Err(AudioFileError::Unsupported("AIFF-C compression type")),
+        };
+        Ok(format)
+    }
+}
+
+/// Reads the frames of an AIFF or AIFF-C file.
+///
+/// Big endian and `sowt` little endian integer samples, `fl32` and
+/// `fl64` floats, and `ulaw` and `alaw` samples can be read.
+pub struct AiffReader<R> {
+    reader: R,
+    format: AudioStreamBasicDescription,
+    data_start: u64,
+    frames: u64,
+    position: u64,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read + Seek> AiffReader<R> {
+    /// Reads the header of the file, leaving `reader` at the first
+    /// frame.
+    pub fn new(mut reader: R) -> AudioFileResult<AiffReader<R>> {
+        if &reader.four_cc()? != b"FORM" {
+            return Err(AudioFileError::Malformed("not an IFF file"));
+        }
+        let _form_size = reader.u32_be()?;
+        let aifc = match &reader.four_cc()? {
+            b"AIFF" => false,
+            b"AIFC" => true,
+            _ => return Err(AudioFileError::Malformed("not an AIFF file")),
+        };
+
+        // The sound data can come before the common chunk, so the whole
+        // file is read.
+        let mut comm = None;
+        let mut data = None;
+        loop {
+            let id = match reader.four_cc() {
+                Ok(id) => id,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            let size = reader.u32_be()?;
+            let next = reader.stream_position()? + u64::from(size) + u64::from(size & 1);
+            match &id {
+                b"COMM" => comm = Some(CommonChunk::read(&mut reader, size, aifc)?),
+                b"SSND" => {
+                    let offset = reader.u32_be()?;
+                    let _block_size = reader.u32_be()?;
+                    let data_size = u64::from(size)
+                        .checked_sub(8 + u64::from(offset))
+                        .ok_or(AudioFileError::Malformed("SSND chunk is too short"))?;
+                    let data_start = reader.stream_position()? + u64::from(offset);
+                    data = Some((data_start, data_size));
+                    reader.seek(SeekFrom::Start(next))?;
+                }
+                _ => skip(&mut reader, u64::from(size) + u64::from(size & 1))?,
+            }
+        }
+        let comm = comm.ok_or(AudioFileError::Malformed("no COMM chunk"))?;
+        let format = comm.to_format()?;
+
+        // A file without frames needn't have sound data.
+        let (data_start, data_size) = match data {
+            Some(data) => data,
+            None if comm.frames == 0 => (0, 0),
+            None => return Err(AudioFileError::Malformed("no SSND chunk")),
+        };
+        let available = reader.seek(SeekFrom::End(0))?.saturating_sub(data_start);
+        let data_frames = data_size.min(available) / u64::from(format.bytes_per_frame());
+        reader.seek(SeekFrom::Start(data_start))?;
+        Ok(AiffReader {
+            reader,
+            format,
+            data_start,
+            frames: u64::from(comm.frames).min(data_frames),
+            position: 0,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+impl<R> AiffReader<R> {
+    /// The format of the file's frames. It's always interleaved.
+    pub fn format(&self) -> &AudioStreamBasicDescriptionRef {
+        &self.format
+    }
+
+    /// The number of frames in the file.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// The index of the next frame to be read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R> fmt::Debug for AiffReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AiffReader")
+            .field("format", &self.format.to_string())
+            .field("frames", &self.frames)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl<R: Read + Seek> AiffReader<R> {
+    /// Moves to `frame`, or to the end of the file if it's past the
+    /// end.
+    pub fn seek(&mut self, frame: u64) -> AudioFileResult<()> {
+        let frame = frame.min(self.frames);
+        let frame_bytes = u64::from(self.format.bytes_per_frame());
+        self.reader
+            .seek(SeekFrom::Start(self.data_start + frame * frame_bytes))?;
+        self.position = frame;
+        Ok(())
+    }
+
+    /// Reads as many frames as fit in `abl`, and returns the number
+    /// read. The list can be interleaved or not, but must have a channel
+    /// for each of the file's channels, with samples of the file's
+    /// size. Returns 0 at the end of the file.
+    pub fn read(&mut self, abl: &mut AudioBufferListRef) -> AudioFileResult<usize> {
+        let channels = self.format.channels_per_frame() as usize;
+        let sample_bytes = self.format.sample_word_size() as usize;
+        let frames = buffer_frames(abl, channels, sample_bytes)?;
+        let frames = (frames as u64).min(self.frames - self.position) as usize;
+        self.buffer.resize(frames * channels * sample_bytes, 0);
+        self.reader.read_exact(&mut self.buffer)?;
+        scatter(&self.buffer, abl, sample_bytes);
+        self.position += frames as u64;
+        Ok(frames)
+    }
+}
+
+/// Writes the frames of an AIFF or AIFF-C file.
+///
+/// Big endian integer samples are written to an AIFF file, and other
+/// formats to an AIFF-C file. The header is written first with empty
+/// sizes, which `finalize` fills in. The file is finalized when the
+/// writer is dropped, ignoring errors, so call `finalize` or
+/// `into_inner` to see them.
+pub struct AiffWriter<W: Write + Seek> {
+    writer: Option<W>,
+    format: AudioStreamBasicDescription,
+    form_start: u64,
+    frames_at: u64,
+    data_start: u64,
+    data_size: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write + Seek> AiffWriter<W> {
+    /// Starts a file of frames in `format`.
+    pub fn new(
+        mut writer: W,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> AudioFileResult<AiffWriter<W>> {
+        check_sample_rate(format)?;
+        let channels = format.channels_per_frame();
+        if channels > u32::from(u16::MAX) {
+            return Err(AudioFileError::Unsupported("AIFF channel count"));
+        }
+        let (compression, name, sample_size): (&[u8; 4], &[u8], u32) = match format.format_id() {
+            AudioFormat::LinearPcm => {
+                if format.validate().is_err() {
+                    return Err(AudioFileError::Unsupported("inconsistent stream description"));
+                }
+                let flags = format.format_flags();
+                let raw_flags = unsafe { (*format.as_ptr()).mFormatFlags };
+                if raw_flags & ffi::kLinearPCMFormatFlagsSampleFractionMask != 0 {
+                    return Err(AudioFileError::Unsupported("fixed point AIFF samples"));
+                }
+                let big_endian = flags.contains(AudioFormatFlags::IS_BIG_ENDIAN);
+                let bits = format.bits_per_channel();
+                let word_bits = format.sample_word_size().saturating_mul(8);
+                if format.is_float() {
+                    match (big_endian, bits) {
+                        (true, 32) => (b"fl32", b"32-bit floating point", 32),
+                        (true, _) => (b"fl64", b"64-bit floating point", 64),
+                        (false, _) => {
+                            return Err(AudioFileError::Unsupported("little endian AIFF floats"))
+                        }
+                    }
+                } else {
+                    if !flags.contains(AudioFormatFlags::IS_SIGNED_INTEGER) {
+                        return Err(AudioFileError::Unsupported("unsigned AIFF samples"));
+                    }
+                    if word_bits != bits.saturating_add(7) / 8 * 8 {
+                        return Err(AudioFileError::Unsupported(
+                            "AIFF samples padded to more than a byte",
+                        ));
+                    }
+                    if bits < word_bits && !flags.contains(AudioFormatFlags::IS_ALIGNED_HIGH) {
+                        return Err(AudioFileError::Unsupported("low aligned AIFF samples"));
+                    }
+                    if big_endian {
+                        (b"NONE", b"not compressed", bits)
+                    } else {
+                        (b"sowt", b"little endian", bits)
+                    }
+                }
+            }
+            AudioFormat::ULaw => (b"ulaw", b"uLaw 2:1", 16),
+            AudioFormat::ALaw => (b"alaw", b"aLaw 2:1", 16),
+            _ => return Err(AudioFileError::Unsupported("AIFF can't hold the format")),
+        };
+        let aifc = compression != b"NONE";
+
+        let mut header = Vec::with_capacity(80);
+        header.extend_from_slice(b"FORM\0\0\0\0");
+        header.extend_from_slice(if aifc { b"AIFC" } else { b"AIFF" });
+        if aifc {
+            header.extend_from_slice(b"FVER");
+            header.extend_from_slice(&4u32.to_be_bytes());
+            header.extend_from_slice(&AIFC_VERSION_1.to_be_bytes());
+        }
+        // The compression name is a Pascal string, padded to an even
+        // length.
+        let name_size = (name.len() + 2) & !1;
+        let comm_size = if aifc { 22 + name_size } else { 18 };
+        header.extend_from_slice(b"COMM");
+        header.extend_from_slice(&(comm_size as u32).to_be_bytes());
+        header.extend_from_slice(&(channels as u16).to_be_bytes());
+        let frames_at = header.len() as u64;
+        header.extend_from_slice(&0u32.to_be_bytes());
+        header.extend_from_slice(&(sample_size as u16).to_be_bytes());
+        header.extend_from_slice(&f64_to_extended(format.sample_rate()));
+        if aifc {
+            header.extend_from_slice(compression);
+            header.push(name.len() as u8);
+            header.extend_from_slice(name);
+            if name.len() & 1 == 0 {
+                header.push(0);
+            }
+        }
+        header.extend_from_slice(b"SSND");
+        header.extend_from_slice(&8u32.to_be_bytes());
+        header.extend_from_slice(&[0; 8]);
+
+        let form_start = writer.stream_position()?;
+        writer.write_all(&header)?;
+        let file_format = format.to_owned();
+        unsafe {
+            let asbd = &mut *file_format.as_ptr();
+            asbd.mFormatFlags &= !AudioFormatFlags::IS_NON_INTERLEAVED.bits();
+            asbd.mBytesPerFrame = channels * format.sample_word_size();
+            asbd.mBytesPerPacket = asbd.mBytesPerFrame;
+        }
+        Ok(AiffWriter {
+            writer: Some(writer),
+            format: file_format,
+            form_start,
+            frames_at: form_start + frames_at,
+            data_start: form_start + header.len() as u64,
+            data_size: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// The format of the file's frames. It's the format the writer was
+    /// created with, but interleaved.
+    pub fn format(&self) -> &AudioStreamBasicDescriptionRef {
+        &self.format
+    }
+
+    /// The number of frames written so far.
+    pub fn frames(&self) -> u64 {
+        self.data_size / u64::from(self.format.bytes_per_frame())
+    }
+
+    /// Appends every frame in `abl`, and returns the number written. The
+    /// list can be interleaved or not, but must have a channel for each
+    /// of the file's channels, with samples of the file's size.
+    ///
+    /// AIFF files can't be larger than 4 GB, and nothing is written if
+    /// the frames would make the file larger.
+    pub fn write(&mut self, abl: &AudioBufferListRef) -> AudioFileResult<usize> {
+        let channels = self.format.channels_per_frame() as usize;
+        let sample_bytes = self.format.sample_word_size() as usize;
+        buffer_frames(abl, channels, sample_bytes)?;
+        let frames = frame_count(abl, sample_bytes);
+        let data_end = self.data_start + self.data_size + (frames * channels * sample_bytes) as u64;
+        if data_end + 1 - (self.form_start + 8) > u64::from(u32::MAX) {
+            return Err(AudioFileError::Unsupported("AIFF files larger than 4 GB"));
+        }
+        self.buffer.clear();
+        gather(abl, frames, sample_bytes, &mut self.buffer);
+        let writer = self.writer.as_mut().unwrap();
+        writer.seek(SeekFrom::Start(self.data_start + self.data_size))?;
+        writer.write_all(&self.buffer)?;
+        self.data_size += self.buffer.len() as u64;
+        Ok(frames)
+    }
+
+    /// Fills in the sizes in the header, and pads the data to an even
+    /// length. More frames can be written afterwards.
+    pub fn finalize(&mut self) -> AudioFileResult<()> {
+        let frames = self.frames() as u32;
+        let writer = self.writer.as_mut().unwrap();
+        let data_end = self.data_start + self.data_size;
+        writer.seek(SeekFrom::Start(data_end))?;
+        let pad = self.data_size & 1;
+        if pad != 0 {
+            writer.write_all(&[0])?;
+        }
+        let form_size = data_end + pad - (self.form_start + 8);
+        writer.seek(SeekFrom::Start(self.form_start + 4))?;
+        writer.write_all(&(form_size as u32).to_be_bytes())?;
+        writer.seek(SeekFrom::Start(self.frames_at))?;
+        writer.write_all(&frames.to_be_bytes())?;
+        writer.seek(SeekFrom::Start(self.data_start - 12))?;
+        writer.write_all(&((self.data_size + 8) as u32).to_be_bytes())?;
+        writer.seek(SeekFrom::Start(data_end))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Finalizes the file and returns the underlying writer.
+    pub fn into_inner(mut self) -> AudioFileResult<W> {
+        self.finalize()?;
+        Ok(self.writer.take().unwrap())
+    }
+}
+
+impl<W: Write + Seek> fmt::Debug for AiffWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AiffWriter")
+            .field("format", &self.format.to_string())
+            .field("frames", &self.frames())
+            .finish()
+    }
+}
+
+impl<W: Write + Seek> Drop for AiffWriter<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.finalize();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use AudioBufferList;
+
+    fn format(s: &str) -> AudioStreamBasicDescription {
+        s.parse().unwrap()
+    }
+
+    /// A buffer list of `format` holding `frames` of counting bytes.
+    fn frames(format: &AudioStreamBasicDescriptionRef, frames: usize) -> AudioBufferList {
+        let mut abl = AudioBufferList::allocate(format, frames);
+        for buffer in abl.iter_mut() {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+        }
+        abl
+    }
+
+    const RATES: &[(f64, [u8; 10])] = &[
+        (44100., [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]),
+        (48000., [0x40, 0x0E, 0xBB, 0x80, 0, 0, 0, 0, 0, 0]),
+        (22050.5, [0x40, 0x0D, 0xAC, 0x45, 0, 0, 0, 0, 0, 0]),
+    ];
+
+    #[test]
+    fn extended_sample_rates() {
+        for &(rate, bytes) in RATES {
+            assert_eq!(f64_to_extended(rate), bytes, "{}", rate);
+            assert_eq!(extended_to_f64(bytes), rate, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn extended_round_trips() {
+        for &value in &[
+            0.,
+            1.,
+            -1.,
+            0.1,
+            -22050.5,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::MIN_POSITIVE / 1024.,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            assert_eq!(extended_to_f64(f64_to_extended(value)), value, "{}", value);
+        }
+        assert!(extended_to_f64(f64_to_extended(f64::NAN)).is_nan());
+        // Values past the range of an f64 become infinite.
+        assert_eq!(extended_to_f64([0x7F, 0xFE, 0x80, 0, 0, 0, 0, 0, 0, 0]), f64::INFINITY);
+    }
+
+    fn round_trip(s: &str, compression: &[u8; 4]) {
+        let format = format(s);
+        let written = frames(&format, 5);
+        let mut writer = AiffWriter::new(Cursor::new(Vec::new()), &format).unwrap();
+        assert_eq!(writer.write(&written).unwrap(), 5);
+        let file = writer.into_inner().unwrap().into_inner();
+        let aifc = compression != b"NONE";
+        assert_eq!(&file[8..12], if aifc { b"AIFC" } else { b"AIFF" }, "{}", s);
+        let comm = file.windows(4).position(|w| w == b"COMM").unwrap();
+        assert_eq!(&file[comm + 16..comm + 26], &f64_to_extended(format.sample_rate()));
+        if aifc {
+            assert_eq!(&file[comm + 26..comm + 30], compression, "{}", s);
+        }
+
+        let mut reader = AiffReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.format().to_string(), format.to_string(), "{}", s);
+        assert_eq!(reader.frames(), 5);
+        let mut read = AudioBufferList::allocate(&format, 8);
+        assert_eq!(reader.read(&mut read).unwrap(), 5);
+        assert_eq!(&read[0][..written[0].len()], &written[0][..], "{}", s);
+        assert_eq!(reader.read(&mut read).unwrap(), 0);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip("s16be@44100x2", b"NONE");
+        round_trip("s8be@8000x1", b"NONE");
+        round_trip("s24be@48000x2", b"NONE");
+        round_trip("1 ch, 48000 Hz, Int20/24, big-endian, interleaved", b"NONE");
+        round_trip("s16le@44100x2", b"sowt");
+        round_trip("s32le@22050.5x1", b"sowt");
+        round_trip("f32be@48000x2", b"fl32");
+        round_trip("f64be@48000x1", b"fl64");
+        round_trip("2 ch, 8000 Hz, 'ulaw', 1 frames/packet, 2 bytes/packet", b"ulaw");
+        round_trip("1 ch, 8000 Hz, 'alaw', 1 frames/packet, 1 bytes/packet", b"alaw");
+    }
+
+    #[test]
+    fn seeking() {
+        let format = format("s16be@48000x1");
+        let mut writer = AiffWriter::new(Cursor::new(Vec::new()), &format).unwrap();
+        writer.write(&frames(&format, 8)).unwrap();
+        let file = writer.into_inner().unwrap().into_inner();
+        let mut reader = AiffReader::new(Cursor::new(file)).unwrap();
+        let mut read = AudioBufferList::allocate(&format, 2);
+        reader.seek(5).unwrap();
+        assert_eq!(reader.read(&mut read).unwrap(), 2);
+        assert_eq!(&read[0][..], &[10, 11, 12, 13][..]);
+        reader.seek(100).unwrap();
+        assert_eq!(reader.position(), 8);
+        assert_eq!(reader.read(&mut read).unwrap(), 0);
+    }
+
+    #[test]
+    fn writers_reject_what_aiff_cant_hold() {
+        let unsupported = |s: &str| AiffWriter::new(Cursor::new(Vec::new()), &format(s)).is_err();
+        assert!(unsupported("u8@48000x1"));
+        assert!(unsupported("f32le@48000x2"));
+        assert!(unsupported("s16be@0x2"));
+        assert!(unsupported("1 ch, 48000 Hz, Fixed8.24, big-endian"));
+        assert!(unsupported("1 ch, 48000 Hz, Int16/32, big-endian"));
+        assert!(unsupported("1 ch, 48000 Hz, Int20/24, aligned low, big-endian"));
+        assert!(unsupported("2 ch, 48000 Hz, 'aac ', 1024 frames/packet"));
+    }
+}
@@ -0,0 +1,581 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Ambisonic channel orderings and normalizations, conversion between
+//! them, and decoding to speakers.
+
+use {AudioChannelDescription, AudioChannelLabel, AudioChannelLayoutRef, AudioChannelLayoutTag,
+     MixMatrix};
+use ffi;
+use std::{error, f64, fmt, result};
+
+/// Why an ambisonic format or decoder couldn't be created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmbisonicError {
+    /// Furse-Malham ordering and normalization are only defined up to
+    /// third order.
+    UnsupportedOrder(u32),
+    /// The layout tag isn't an ambisonic layout, or its channel count
+    /// isn't a full order.
+    NotAmbisonic(AudioChannelLayoutTag),
+    /// A speaker has no coordinates. The index is the speaker's channel.
+    MissingCoordinates(usize),
+    /// The layout has no speakers to decode to.
+    NoSpeakers,
+}
+
+impl fmt::Display for AmbisonicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AmbisonicError::UnsupportedOrder(order) => {
+                write!(f, "Furse-Malham is not defined for order {}", order)
+            }
+            AmbisonicError::MissingCoordinates(channel) => {
+                write!(f, "speaker {} has no coordinates", channel)
+            }
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+impl error::Error for AmbisonicError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AmbisonicError {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            AmbisonicError::UnsupportedOrder(_) => "Furse-Malham is only defined up to third order",
+            AmbisonicError::NotAmbisonic(_) => "not an ambisonic channel layout",
+            AmbisonicError::MissingCoordinates(_) => "a speaker has no coordinates",
+            AmbisonicError::NoSpeakers => "the layout has no speakers",
+        }
+    }
+}
+
+pub type AmbisonicResult<T> = result::Result<T, AmbisonicError>;
+
+/// The order of the spherical harmonic components in a stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmbisonicOrdering {
+    /// Furse-Malham: W X Y Z R S T U V K L M N O P Q.
+    FuMa,
+    /// Ambisonic Channel Number: component `l * (l + 1) + m`.
+    Acn,
+}
+
+/// The scaling of the spherical harmonic components in a stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmbisonicNormalization {
+    /// Furse-Malham's maxN weights, with W at -3 dB.
+    FuMa,
+    /// Schmidt semi-normalized, as in AmbiX.
+    Sn3d,
+    /// Fully normalized.
+    N3d,
+}
+
+/// The degree `l` and index `m` of each FuMa channel, in order.
+const FUMA_COMPONENTS: [(u32, i32); 16] = [
+    (0, 0),
+    (1, 1),
+    (1, -1),
+    (1, 0),
+    (2, 0),
+    (2, 1),
+    (2, -1),
+    (2, 2),
+    (2, -2),
+    (3, 0),
+    (3, 1),
+    (3, -1),
+    (3, 2),
+    (3, -2),
+    (3, 3),
+    (3, -3),
+];
+
+/// The gain of a component in `normalization` relative to SN3D.
+fn sn3d_to(normalization: AmbisonicNormalization, l: u32, m: i32) -> f64 {
+    match normalization {
+        AmbisonicNormalization::Sn3d => 1.,
+        AmbisonicNormalization::N3d => f64::from(2 * l + 1).sqrt(),
+        AmbisonicNormalization::FuMa => match (l, m.abs()) {
+            (0, _) => f64::consts::FRAC_1_SQRT_2,
+            (1, _) | (2, 0) | (3, 0) => 1.,
+            (2, _) => 2. / 3f64.sqrt(),
+            (3, 1) => (45. / 32f64).sqrt(),
+            (3, 2) => 3. / 5f64.sqrt(),
+            _ => (8. / 5f64).sqrt(),
+        },
+    }
+}
+
+/// The layout of an ambisonic stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AmbisonicFormat {
+    order: u32,
+    ordering: AmbisonicOrdering,
+    normalization: AmbisonicNormalization,
+}
+
+impl AmbisonicFormat {
+    /// Creates a full-sphere format of `order`. Furse-Malham ordering
+    /// or normalization limits the order to 3.
+    pub fn new(
+        order: u32,
+        ordering: AmbisonicOrdering,
+        normalization: AmbisonicNormalization,
+    ) -> AmbisonicResult<AmbisonicFormat> {
+        let fuma = ordering == AmbisonicOrdering::FuMa
+            || normalization == AmbisonicNormalization::FuMa;
+        if fuma && order > 3 {
+            return Err(AmbisonicError::UnsupportedOrder(order));
+        }
+        Ok(AmbisonicFormat {
+            order,
+            ordering,
+            normalization,
+        })
+    }
+
+    /// Traditional B-format with Furse-Malham ordering and weights.
+    pub fn fuma(order: u32) -> AmbisonicResult<AmbisonicFormat> {
+        AmbisonicFormat::new(order, AmbisonicOrdering::FuMa, AmbisonicNormalization::FuMa)
+    }
+
+    /// AmbiX: ACN ordering with SN3D normalization.
+    pub fn ambix(order: u32) -> AmbisonicFormat {
+        AmbisonicFormat {
+            order,
+            ordering: AmbisonicOrdering::Acn,
+            normalization: AmbisonicNormalization::Sn3d,
+        }
+    }
+
+    /// The format of a layout tagged `HOA_ACN_SN3D`, `HOA_ACN_N3D` or
+    /// `AMBISONIC_B_FORMAT`.
+    pub fn from_layout_tag(tag: AudioChannelLayoutTag) -> AmbisonicResult<AmbisonicFormat> {
+        let raw = ffi::AudioChannelLayoutTag::from(tag);
+        if raw == ffi::kAudioChannelLayoutTag_Ambisonic_B_Format {
+            return AmbisonicFormat::fuma(1);
+        }
+        let normalization = match raw & 0xFFFF_0000 {
+            ffi::kAudioChannelLayoutTag_HOA_ACN_SN3D => AmbisonicNormalization::Sn3d,
+            ffi::kAudioChannelLayoutTag_HOA_ACN_N3D => AmbisonicNormalization::N3d,
+            _ => return Err(AmbisonicError::NotAmbisonic(tag)),
+        };
+        let channels = tag.channels() as u32;
+        let order = (f64::from(channels).sqrt() as u32).saturating_sub(1);
+        if channels == 0 || (order + 1) * (order + 1) != channels {
+            return Err(AmbisonicError::NotAmbisonic(tag));
+        }
+        AmbisonicFormat::new(order, AmbisonicOrdering::Acn, normalization)
+    }
+
+    /// The layout tag for the format, if there is one. Only ACN with
+    /// SN3D or N3D, and first order Furse-Malham, have tags.
+    pub fn layout_tag(&self) -> Option<AudioChannelLayoutTag> {
+        let channels = self.channels() as u32;
+        let raw = match (self.ordering, self.normalization) {
+            (AmbisonicOrdering::Acn, AmbisonicNormalization::Sn3d) => {
+                ffi::kAudioChannelLayoutTag_HOA_ACN_SN3D | channels
+            }
+            (AmbisonicOrdering::Acn, AmbisonicNormalization::N3d) => {
+                ffi::kAudioChannelLayoutTag_HOA_ACN_N3D | channels
+            }
+            (AmbisonicOrdering::FuMa, AmbisonicNormalization::FuMa) if self.order == 1 => {
+                ffi::kAudioChannelLayoutTag_Ambisonic_B_Format
+            }
+            _ => return None,
+        };
+        Some(AudioChannelLayoutTag::from(raw))
+    }
+
+    pub fn order(&self) -> u32 {
+        self.order
+    }
+
+    pub fn ordering(&self) -> AmbisonicOrdering {
+        self.ordering
+    }
+
+    pub fn normalization(&self) -> AmbisonicNormalization {
+        self.normalization
+    }
+
+    /// The number of channels: `(order + 1)²`.
+    pub fn channels(&self) -> usize {
+        ((self.order + 1) * (self.order + 1)) as usize
+    }
+
+    /// The degree `l` and index `m` of the spherical harmonic in
+    /// `channel`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `channel` isn't in the format.
+    pub fn component(&self, channel: usize) -> (u32, i32) {
+        assert!(channel < self.channels(), "no ambisonic channel {}", channel);
+        match self.ordering {
+            AmbisonicOrdering::FuMa => FUMA_COMPONENTS[channel],
+            AmbisonicOrdering::Acn => {
+                let l = (channel as f64).sqrt() as u32;
+                (l, channel as i32 - (l * (l + 1)) as i32)
+            }
+        }
+    }
+
+    /// The channel holding the spherical harmonic of degree `l` and
+    /// index `m`, if the format has one.
+    pub fn channel(&self, l: u32, m: i32) -> Option<usize> {
+        if l > self.order || m.unsigned_abs() > l {
+            return None;
+        }
+        match self.ordering {
+            AmbisonicOrdering::FuMa => FUMA_COMPONENTS.iter().position(|&c| c == (l, m)),
+            AmbisonicOrdering::Acn => Some(((l * (l + 1)) as i32 + m) as usize),
+        }
+    }
+
+    /// A matrix converting a stream in this format to `to`.
+    ///
+    /// Components of an order `to` doesn't have are dropped, and
+    /// components this format doesn't have are silent.
+    pub fn conversion_matrix(&self, to: &AmbisonicFormat) -> MixMatrix {
+        let mut matrix = MixMatrix::zeroed(self.channels(), to.channels());
+        for src in 0..self.channels() {
+            let (l, m) = self.component(src);
+            if let Some(dst) = to.channel(l, m) {
+                let gain = sn3d_to(to.normalization, l, m) / sn3d_to(self.normalization, l, m);
+                matrix.set_coefficient(dst, src, gain as f32);
+            }
+        }
+        matrix
+    }
+
+    /// The gain of each channel for a plane wave from `azimuth` and
+    /// `elevation`, which are in radians, counterclockwise from the
+    /// front and up from the horizon.
+    pub fn encode(&self, azimuth: f64, elevation: f64) -> Vec<f64> {
+        let sn3d = spherical_harmonics(self.order, azimuth, elevation);
+        (0..self.channels())
+            .map(|c| {
+                let (l, m) = self.component(c);
+                sn3d[acn(l, m)] * sn3d_to(self.normalization, l, m)
+            })
+            .collect()
+    }
+
+    /// A matrix decoding this format to the speakers in `speakers`.
+    ///
+    /// Each speaker's position comes from the spherical or rectangular
+    /// coordinates in its description. Spherical azimuths are degrees
+    /// clockwise from the front, as Core Audio describes them, and
+    /// elevations are degrees up from the horizon. LFE channels are
+    /// left silent.
+    ///
+    /// The decoder samples the sound field at each speaker. It's
+    /// exact for layouts that sample the sphere evenly, such as the
+    /// platonic solids, and a reasonable start for others. Pass
+    /// `max_re` to weight the orders for the most energy in the
+    /// direction of the source, which sounds better at high orders.
+    pub fn decoder(&self, speakers: &AudioChannelLayoutRef, max_re: bool) -> AmbisonicResult<MixMatrix> {
+        let directions = speaker_directions(speakers)?;
+        let count = directions.iter().filter(|d| d.is_some()).count();
+        if count == 0 {
+            return Err(AmbisonicError::NoSpeakers);
+        }
+        let weights = (0..=self.order)
+            .map(|l| {
+                let weight = if max_re {
+                    let x = (137.9f64.to_radians() / (f64::from(self.order) + 1.51)).cos();
+                    legendre(l, x)
+                } else {
+                    1.
+                };
+                // The addition theorem for SN3D harmonics needs the
+                // `2l + 1` of each order to recover a plane wave.
+                weight * f64::from(2 * l + 1) / count as f64
+            })
+            .collect::<Vec<_>>();
+
+        let mut matrix = MixMatrix::zeroed(self.channels(), directions.len());
+        for (s, direction) in directions.iter().enumerate() {
+            let (azimuth, elevation) = match *direction {
+                Some(d) => d,
+                None => continue,
+            };
+            let sn3d = spherical_harmonics(self.order, azimuth, elevation);
+            for c in 0..self.channels() {
+                let (l, m) = self.component(c);
+                let gain = sn3d[acn(l, m)] * weights[l as usize] / sn3d_to(self.normalization, l, m);
+                matrix.set_coefficient(s, c, gain as f32);
+            }
+        }
+        Ok(matrix)
+    }
+}
+
+fn acn(l: u32, m: i32) -> usize {
+    ((l * (l + 1)) as i32 + m) as usize
+}
+
+/// The Legendre polynomial `P_l(x)`.
+fn legendre(l: u32, x: f64) -> f64 {
+    let (mut p0, mut p1) = (1., x);
+    if l == 0 {
+        return p0;
+    }
+    for n in 1..l {
+        let n = f64::from(n);
+        let p2 = ((2. * n + 1.) * x * p1 - n * p0) / (n + 1.);
+        p0 = p1;
+        p1 = p2;
+    }
+    p1
+}
+
+/// The real SN3D spherical harmonics up to `order` in ACN order, without
+/// the Condon-Shortley phase, for a direction counterclockwise from
+/// the front by `azimuth` and up by `elevation`.
+pub fn spherical_harmonics(order: u32, azimuth: f64, elevation: f64) -> Vec<f64> {
+    let n = order as usize;
+    let (x, c) = (elevation.sin(), elevation.cos());
+    // Associated Legendre functions P_l^m(sin(elevation)) for m <= l.
+    let mut p = vec![vec![0.; n + 1]; n + 1];
+    p[0][0] = 1.;
+    for m in 0..=n {
+        if m > 0 {
+            p[m][m] = p[m - 1][m - 1] * (2 * m - 1) as f64 * c;
+        }
+        if m < n {
+            p[m + 1][m] = x * (2 * m + 1) as f64 * p[m][m];
+        }
+        for l in m + 2..=n {
+            p[l][m] = ((2 * l - 1) as f64 * x * p[l - 1][m] - (l + m - 1) as f64 * p[l - 2][m])
+                / (l - m) as f64;
+        }
+    }
+
+    let mut sh = vec![0.; (n + 1) * (n + 1)];
+    for l in 0..=n {
+        for m in 0..=l {
+            // sqrt((2 - δm0) (l - m)! / (l + m)!)
+            let ratio = (l - m + 1..=l + m).fold(1., |r, k| r / k as f64);
+            let norm = (if m == 0 { 1. } else { 2. } * ratio).sqrt() * p[l][m];
+            let m_az = m as f64 * azimuth;
+            sh[l * (l + 1) + m] = norm * m_az.cos();
+            if m > 0 {
+                sh[l * (l + 1) - m] = norm * m_az.sin();
+            }
+        }
+    }
+    sh
+}
+
+/// The direction of each speaker as counterclockwise azimuth and
+/// elevation in radians, or `None` for LFE channels.
+fn speaker_directions(speakers: &AudioChannelLayoutRef) -> AmbisonicResult<Vec<Option<(f64, f64)>>> {
+    speakers
+        .iter()
+        .enumerate()
+        .map(|(i, d)| direction(i, d))
+        .collect()
+}
+
+fn direction(index: usize, d: &AudioChannelDescription) -> AmbisonicResult<Option<(f64, f64)>> {
+    if is_lfe(d.channel_label) {
+        return Ok(None);
+    }
+    coordinate_direction(d)
+        .map(Some)
+        .ok_or(AmbisonicError::MissingCoordinates(index))
+}
+
+pub(crate) fn is_lfe(label: AudioChannelLabel) -> bool {
+    let label = ffi::AudioChannelLabel::from(label);
+    label == ffi::kAudioChannelLabel_LFEScreen || label == ffi::kAudioChannelLabel_LFE2
+}
+
+/// The direction of a description's coordinates as counterclockwise
+/// azimuth and elevation in radians.
+pub(crate) fn coordinate_direction(d: &AudioChannelDescription) -> Option<(f64, f64)> {
+    if let Some(s) = d.spherical_coordinate() {
+        return Some((
+            -f64::from(s.azimuth).to_radians(),
+            f64::from(s.elevation).to_radians(),
+        ));
+    }
+    let r = d.rectangular_coordinate()?;
+    let (x, y, z) = (f64::from(r.back_front), -f64::from(r.left_right), f64::from(r.down_up));
+    Some((y.atan2(x), z.atan2(x.hypot(y))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioChannelLayout, AudioChannelSphericalCoordinates};
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    fn speakers(positions: &[(f32, f32)], lfe: bool) -> AudioChannelLayout {
+        let mut layout = AudioChannelLayout::with_len(positions.len() + lfe as usize);
+        layout.set_channel_layout_tag(AudioChannelLayoutTag::USE_CHANNEL_DESCRIPTIONS);
+        for (i, d) in layout.iter_mut().enumerate() {
+            *d = match positions.get(i) {
+                Some(&(azimuth, elevation)) => AudioChannelDescription::with_spherical_coordinate(
+                    AudioChannelLabel::from(ffi::kAudioChannelLabel_Discrete_0 + i as u32),
+                    AudioChannelSphericalCoordinates {
+                        azimuth,
+                        elevation,
+                        distance: 1.,
+                    },
+                ),
+                None => AudioChannelDescription::new(ffi::kAudioChannelLabel_LFEScreen.into()),
+            };
+        }
+        layout
+    }
+
+    #[test]
+    fn components_and_channels() {
+        for format in &[AmbisonicFormat::fuma(3).unwrap(), AmbisonicFormat::ambix(3)] {
+            assert_eq!(format.channels(), 16);
+            for c in 0..16 {
+                let (l, m) = format.component(c);
+                assert_eq!(format.channel(l, m), Some(c));
+            }
+            assert_eq!(format.channel(4, 0), None);
+            assert_eq!(format.channel(1, 2), None);
+        }
+        let fuma = AmbisonicFormat::fuma(1).unwrap();
+        assert_eq!(fuma.component(1), (1, 1));
+        assert_eq!(AmbisonicFormat::ambix(1).component(1), (1, -1));
+        assert_eq!(AmbisonicFormat::fuma(4), Err(AmbisonicError::UnsupportedOrder(4)));
+        assert!(AmbisonicFormat::new(5, AmbisonicOrdering::Acn, AmbisonicNormalization::N3d).is_ok());
+    }
+
+    #[test]
+    fn layout_tags() {
+        let tag = |t| AudioChannelLayoutTag::from(t);
+        let sn3d = AmbisonicFormat::from_layout_tag(tag(ffi::kAudioChannelLayoutTag_HOA_ACN_SN3D | 9))
+            .unwrap();
+        assert_eq!(sn3d, AmbisonicFormat::ambix(2));
+        assert_eq!(sn3d.layout_tag(), Some(tag(ffi::kAudioChannelLayoutTag_HOA_ACN_SN3D | 9)));
+        let n3d = AmbisonicFormat::from_layout_tag(tag(ffi::kAudioChannelLayoutTag_HOA_ACN_N3D | 4))
+            .unwrap();
+        assert_eq!(n3d.normalization(), AmbisonicNormalization::N3d);
+        let b = AmbisonicFormat::from_layout_tag(tag(ffi::kAudioChannelLayoutTag_Ambisonic_B_Format))
+            .unwrap();
+        assert_eq!(b, AmbisonicFormat::fuma(1).unwrap());
+        assert_eq!(b.layout_tag(), Some(tag(ffi::kAudioChannelLayoutTag_Ambisonic_B_Format)));
+        assert_eq!(AmbisonicFormat::fuma(2).unwrap().layout_tag(), None);
+
+        for &bad in &[
+            ffi::kAudioChannelLayoutTag_HOA_ACN_SN3D | 5,
+            ffi::kAudioChannelLayoutTag_HOA_ACN_SN3D,
+            ffi::kAudioChannelLayoutTag_Stereo,
+        ] {
+            assert_eq!(
+                AmbisonicFormat::from_layout_tag(tag(bad)),
+                Err(AmbisonicError::NotAmbisonic(tag(bad)))
+            );
+        }
+    }
+
+    #[test]
+    fn encoding_first_order() {
+        let ambix = AmbisonicFormat::ambix(1);
+        let encoded = |az: f64, el: f64| ambix.encode(az.to_radians(), el.to_radians());
+        let expect = |got: Vec<f64>, want: [f64; 4]| {
+            assert!(got.iter().zip(&want).all(|(a, b)| close(*a, *b)), "{:?}", got);
+        };
+        // W Y Z X
+        expect(encoded(0., 0.), [1., 0., 0., 1.]);
+        expect(encoded(90., 0.), [1., 1., 0., 0.]);
+        expect(encoded(0., 90.), [1., 0., 1., 0.]);
+
+        let n3d = AmbisonicFormat::new(1, AmbisonicOrdering::Acn, AmbisonicNormalization::N3d)
+            .unwrap();
+        expect(n3d.encode(0., 0.), [1., 0., 0., 3f64.sqrt()]);
+        let fuma = AmbisonicFormat::fuma(1).unwrap();
+        // W X Y Z
+        expect(fuma.encode(0., 0.), [f64::consts::FRAC_1_SQRT_2, 1., 0., 0.]);
+    }
+
+    #[test]
+    fn harmonics_are_unit_power_on_average() {
+        // The SN3D harmonics of order l sum in square to 1 in every
+        // direction.
+        let sh = spherical_harmonics(3, 0.7, -0.3);
+        for l in 0..4 {
+            let power: f64 = (l * l..(l + 1) * (l + 1)).map(|c| sh[c] * sh[c]).sum();
+            assert!(close(power, 1.), "order {}: {}", l, power);
+        }
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        let fuma = AmbisonicFormat::fuma(3).unwrap();
+        let ambix = AmbisonicFormat::ambix(3);
+        let there = fuma.conversion_matrix(&ambix);
+        let back = ambix.conversion_matrix(&fuma);
+        assert!(close(f64::from(there.coefficient(0, 0)), 2f64.sqrt()));
+        // FuMa X is ACN 3.
+        assert_eq!(there.coefficient(3, 1), 1.);
+        for i in 0..16 {
+            for j in 0..16 {
+                let product: f32 = (0..16).map(|k| back.coefficient(i, k) * there.coefficient(k, j)).sum();
+                let expected = if i == j { 1. } else { 0. };
+                assert!((product - expected).abs() < 1e-6, "({}, {}) = {}", i, j, product);
+            }
+        }
+
+        // Converting down an order drops the higher components.
+        let lower = AmbisonicFormat::ambix(1);
+        let matrix = ambix.conversion_matrix(&lower);
+        assert_eq!((matrix.src_channels(), matrix.dst_channels()), (16, 4));
+        assert!((4..16).all(|c| (0..4).all(|d| matrix.coefficient(d, c) == 0.)));
+    }
+
+    #[test]
+    fn decoding_to_an_octahedron() {
+        let layout = speakers(
+            &[(0., 0.), (90., 0.), (180., 0.), (-90., 0.), (0., 90.), (0., -90.)],
+            true,
+        );
+        let ambix = AmbisonicFormat::ambix(1);
+        let decoder = ambix.decoder(&layout, false).unwrap();
+        assert_eq!(decoder.dst_channels(), 7);
+        // A source straight ahead: Core Audio's azimuth is clockwise,
+        // the encoder's counterclockwise.
+        let source = ambix.encode(0., 0.);
+        let gains: Vec<f64> = (0..7)
+            .map(|s| (0..4).map(|c| f64::from(decoder.coefficient(s, c)) * source[c]).sum())
+            .collect();
+        let expected = [2. / 3., 1. / 6., -1. / 3., 1. / 6., 1. / 6., 1. / 6., 0.];
+        assert!(gains.iter().zip(&expected).all(|(a, b)| (a - b).abs() < 1e-5), "{:?}", gains);
+
+        let max_re = ambix.decoder(&layout, true).unwrap();
+        assert!(max_re.coefficient(2, 3).abs() < decoder.coefficient(2, 3).abs());
+    }
+
+    #[test]
+    fn decoder_errors() {
+        let ambix = AmbisonicFormat::ambix(1);
+        assert_eq!(
+            ambix.decoder(&speakers(&[], true), false),
+            Err(AmbisonicError::NoSpeakers)
+        );
+        let mut layout = speakers(&[(0., 0.), (90., 0.)], false);
+        layout[1] = AudioChannelDescription::new(ffi::kAudioChannelLabel_Left.into());
+        assert_eq!(
+            ambix.decoder(&layout, false),
+            Err(AmbisonicError::MissingCoordinates(1))
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use ::*;
-use std::{mem, ops, ptr, slice};
+use std::convert::TryFrom;
+use std::{alloc, fmt, mem, ops, ptr, slice};
 
 #[repr(C)]
 struct ABLHeap {
@@ -7,38 +8,151 @@ struct ABLHeap {
     pub buffers: [AudioBufferList; 0],
 }
 
-fn new_heap(len: usize) -> *mut ffi::AudioBufferList {
-    let n_bytes = mem::size_of::<ABLHeap>() + len * mem::size_of::<AudioBuffer>();
-    assert_eq!(n_bytes % mem::size_of::<u32>(), 0);
-    let n_u32 = n_bytes / mem::size_of::<u32>();
-    let mut vec = Vec::<u32>::with_capacity(n_u32);
-    let ptr = vec.as_mut_ptr() as *mut ABLHeap;
+/// Alignment of the allocation and of each buffer's data. The size of
+/// the allocation is stored in the first `HEAP_ALIGN` bytes, before the
+/// `AudioBufferList`.
+const HEAP_ALIGN: usize = 16;
+
+fn round_up(n: usize) -> usize {
+    (n + HEAP_ALIGN - 1) & !(HEAP_ALIGN - 1)
+}
+
+/// Allocates a list of `len` buffers followed by `data_bytes` bytes of
+/// zeroed storage for each buffer.
+///
+/// Panics if `data_bytes` doesn't fit in a buffer's `u32` size.
+fn new_heap(len: usize, channels: u32, data_bytes: usize) -> *mut ffi::AudioBufferList {
+    let data_byte_size = u32::try_from(data_bytes).expect("buffer size overflows u32");
+    let header_bytes = mem::size_of::<ABLHeap>() + len * mem::size_of::<AudioBuffer>();
+    let data_offset = round_up(HEAP_ALIGN + header_bytes);
+    let stride = round_up(data_bytes);
+    let n_bytes = data_offset + len * stride;
+    let layout = alloc::Layout::from_size_align(n_bytes, HEAP_ALIGN).unwrap();
     unsafe {
-        let mut header: ABLHeap = mem::zeroed();
-        header.number_buffers = len as _;
-        mem::forget(vec);
-        ptr::write(ptr, header);
+        let base = alloc::alloc_zeroed(layout);
+        if base.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        ptr::write(base as *mut usize, n_bytes);
+        let abl = base.add(HEAP_ALIGN) as *mut ffi::AudioBufferList;
+        (*abl).mNumberBuffers = len as _;
+        // An empty list has no room for the `mBuffers[0]` its type
+        // declares, so it must not be referenced.
+        if len == 0 {
+            return abl;
+        }
+        for (i, buffer) in raw_buffers_mut(abl).iter_mut().enumerate() {
+            buffer.mNumberChannels = channels;
+            if data_bytes > 0 {
+                buffer.mDataByteSize = data_byte_size;
+                buffer.mData = base.add(data_offset + i * stride) as *mut _;
+            }
+        }
+        abl
     }
-    ptr as _
 }
 
 unsafe fn delete_heap(ptr: *mut ffi::AudioBufferList) {
-    let len = (*ptr).mNumberBuffers as usize;
-    let n_bytes = mem::size_of::<ABLHeap>() + len * mem::size_of::<AudioBuffer>();
-    let n_u32 = n_bytes / mem::size_of::<u32>();
-    drop(Vec::<u32>::from_raw_parts(ptr as _, 0, n_u32))
+    let base = (ptr as *mut u8).sub(HEAP_ALIGN);
+    let n_bytes = ptr::read(base as *const usize);
+    alloc::dealloc(base, alloc::Layout::from_size_align_unchecked(n_bytes, HEAP_ALIGN));
+}
+
+/// Copies a list and the data of each of its buffers into a new
+/// allocation. Buffers without data stay without data.
+unsafe fn clone_heap(ptr: *mut ffi::AudioBufferList) -> *mut ffi::AudioBufferList {
+    let buffers = raw_buffers(ptr);
+    let data_bytes = buffers
+        .iter()
+        .map(|b| b.mDataByteSize as usize)
+        .max()
+        .unwrap_or(0);
+    let copy = new_heap(buffers.len(), 0, data_bytes);
+    for (buffer, copy) in buffers.iter().zip(raw_buffers_mut(copy)) {
+        copy.mNumberChannels = buffer.mNumberChannels;
+        copy.mDataByteSize = buffer.mDataByteSize;
+        if buffer.mData.is_null() {
+            copy.mData = ptr::null_mut();
+        } else {
+            ptr::copy_nonoverlapping(
+                buffer.mData as *const u8,
+                copy.mData as *mut u8,
+                buffer.mDataByteSize as usize,
+            );
+        }
+    }
+    copy
+}
+
+unsafe fn raw_buffers<'a>(ptr: *mut ffi::AudioBufferList) -> &'a [ffi::AudioBuffer] {
+    slice::from_raw_parts(
+        ptr::addr_of!((*ptr).mBuffers) as *const ffi::AudioBuffer,
+        (*ptr).mNumberBuffers as usize,
+    )
+}
+
+unsafe fn raw_buffers_mut<'a>(ptr: *mut ffi::AudioBufferList) -> &'a mut [ffi::AudioBuffer] {
+    slice::from_raw_parts_mut(
+        ptr::addr_of_mut!((*ptr).mBuffers) as *mut ffi::AudioBuffer,
+        (*ptr).mNumberBuffers as usize,
+    )
+}
+
+/// The data of `buffer`, which is empty if it has none.
+unsafe fn raw_data<'a>(buffer: &ffi::AudioBuffer) -> &'a [u8] {
+    if buffer.mData.is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(buffer.mData as *const u8, buffer.mDataByteSize as usize)
+    }
 }
 
 ffi_type_heap!{
     type CType = ffi::AudioBufferList;
     fn drop = delete_heap;
+    fn clone = clone_heap;
     pub struct AudioBufferList;
     pub struct AudioBufferListRef;
 }
 
 impl AudioBufferList {
     pub fn with_len(len: usize) -> Self {
-        AudioBufferList(new_heap(len))
+        AudioBufferList(new_heap(len, 0, 0))
+    }
+
+    /// Allocates a list with zeroed storage for `frames` frames of
+    /// audio in `format`.
+    ///
+    /// Interleaved formats get one buffer holding every channel and
+    /// non-interleaved formats get a buffer per channel. The storage of
+    /// each buffer is 16-byte aligned and freed with the list.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `format` has no fixed number of bytes per frame or
+    /// packet, or if a buffer would hold more than `u32::MAX` bytes.
+    pub fn allocate(format: &AudioStreamBasicDescriptionRef, frames: usize) -> Self {
+        let channels = format.channels_per_frame();
+        let bytes = if format.bytes_per_frame() > 0 {
+            frames.checked_mul(format.bytes_per_frame() as usize)
+        } else {
+            assert!(
+                format.bytes_per_packet() > 0 && format.frames_per_packet() > 0,
+                "format has no fixed frame or packet size"
+            );
+            let frames_per_packet = format.frames_per_packet() as usize;
+            let packets = frames / frames_per_packet + (frames % frames_per_packet != 0) as usize;
+            packets.checked_mul(format.bytes_per_packet() as usize)
+        };
+        let bytes = bytes.expect("buffer size overflows u32");
+        if format
+            .format_flags()
+            .contains(AudioFormatFlags::IS_NON_INTERLEAVED)
+        {
+            AudioBufferList(new_heap(channels as usize, 1, bytes))
+        } else {
+            AudioBufferList(new_heap(1, channels, bytes))
+        }
     }
 }
 
@@ -51,16 +165,46 @@ impl AudioBufferListRef {
     }
 }
 
+impl PartialEq for AudioBufferListRef {
+    /// Lists are equal when their buffers have the same number of
+    /// channels and the same data.
+    fn eq(&self, other: &AudioBufferListRef) -> bool {
+        let (a, b) = unsafe { (raw_buffers(self.as_ptr()), raw_buffers(other.as_ptr())) };
+        a.len() == b.len()
+            && a.iter().zip(b).all(|(a, b)| {
+                a.mNumberChannels == b.mNumberChannels && unsafe { raw_data(a) == raw_data(b) }
+            })
+    }
+}
+
+impl fmt::Debug for AudioBufferListRef {
+    /// Shows the channels and size of each buffer, but not their data.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        struct Buffer<'a>(&'a ffi::AudioBuffer);
+
+        impl<'a> fmt::Debug for Buffer<'a> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.debug_struct("AudioBuffer")
+                    .field("channels", &self.0.mNumberChannels)
+                    .field("bytes", &self.0.mDataByteSize)
+                    .finish()
+            }
+        }
+
+        let buffers = unsafe { raw_buffers(self.as_ptr()) };
+        f.debug_struct("AudioBufferList")
+            .field("buffers", &buffers.iter().map(Buffer).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
 impl ops::Deref for AudioBufferListRef {
     type Target = [AudioBuffer];
 
     fn deref(&self) -> &Self::Target {
         unsafe {
-            let abl = &(*self.as_ptr());
-            slice::from_raw_parts(
-                &abl.mBuffers[0] as *const _ as *const _,
-                abl.mNumberBuffers as _,
-            )
+            let buffers = raw_buffers(self.as_ptr());
+            slice::from_raw_parts(buffers.as_ptr() as *const AudioBuffer, buffers.len())
         }
     }
 }
@@ -68,11 +212,125 @@ impl ops::Deref for AudioBufferListRef {
 impl ops::DerefMut for AudioBufferListRef {
     fn deref_mut(&mut self) -> &mut [AudioBuffer] {
         unsafe {
-            let abl = &mut (*self.as_ptr());
-            slice::from_raw_parts_mut(
-                &mut abl.mBuffers[0] as *mut _ as *mut _,
-                abl.mNumberBuffers as _,
-            )
+            let buffers = raw_buffers_mut(self.as_ptr());
+            slice::from_raw_parts_mut(buffers.as_mut_ptr() as *mut AudioBuffer, buffers.len())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(s: &str) -> AudioStreamBasicDescription {
+        s.parse().unwrap()
+    }
+
+    fn data_ptr(buffer: &AudioBuffer) -> usize {
+        unsafe { (*buffer.as_ptr()).mData as usize }
+    }
+
+    #[test]
+    fn allocate_interleaved() {
+        let abl = AudioBufferList::allocate(&format("s16le@48000x2"), 5);
+        assert_eq!(abl.buffer_count(), 1);
+        assert_eq!(abl[0].num_channels(), 2);
+        assert_eq!(abl[0].len(), 20);
+        assert!(abl[0].iter().all(|&b| b == 0));
+        assert_eq!(data_ptr(&abl[0]) % HEAP_ALIGN, 0);
+    }
+
+    #[test]
+    fn allocate_non_interleaved() {
+        let fmt = AudioStreamBasicDescription::with_lpcm(48000., 3, 32, 32, true, false, true);
+        let mut abl = AudioBufferList::allocate(&fmt, 3);
+        assert_eq!(abl.buffer_count(), 3);
+        for buffer in abl.iter() {
+            assert_eq!(buffer.num_channels(), 1);
+            assert_eq!(buffer.len(), 12);
+            assert_eq!(data_ptr(buffer) % HEAP_ALIGN, 0);
+        }
+        // The buffers don't overlap.
+        abl[0].copy_from_slice(&[1; 12]);
+        abl[2].copy_from_slice(&[3; 12]);
+        assert_eq!(&abl[1][..], &[0; 12][..]);
+        assert_eq!(&abl[0][..], &[1; 12][..]);
+    }
+
+    #[test]
+    fn allocate_whole_packets() {
+        let fmt = format("1 ch, 44100 Hz, 'ima4', 64 frames/packet, 34 bytes/packet");
+        let abl = AudioBufferList::allocate(&fmt, 65);
+        assert_eq!(abl[0].len(), 68);
+        let abl = AudioBufferList::allocate(&fmt, 0);
+        assert_eq!(abl[0].len(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "no fixed frame or packet size")]
+    fn allocate_variable_packets_panics() {
+        AudioBufferList::allocate(&format("2 ch, 44100 Hz, 'aac ', 1024 frames/packet"), 1024);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    #[should_panic(expected = "overflows u32")]
+    fn allocate_past_u32_panics() {
+        AudioBufferList::allocate(&format("s16le@48000x2"), 1 << 30);
+    }
+
+    #[test]
+    fn clones_are_deep() {
+        let fmt = AudioStreamBasicDescription::with_lpcm(48000., 3, 16, 16, false, false, true);
+        let mut abl = AudioBufferList::allocate(&fmt, 4);
+        for (i, buffer) in abl.iter_mut().enumerate() {
+            for (j, b) in buffer.iter_mut().enumerate() {
+                *b = (i * 16 + j) as u8;
+            }
+        }
+        let mut copy = abl.clone();
+        assert_eq!(copy, abl);
+        assert_eq!(format!("{:?}", copy), format!("{:?}", abl));
+        for (buffer, copied) in abl.iter().zip(copy.iter()) {
+            assert_ne!(data_ptr(buffer), data_ptr(copied));
+            assert_eq!(data_ptr(copied) % HEAP_ALIGN, 0);
+        }
+
+        copy[2][7] = 0xff;
+        assert_ne!(copy, abl);
+        assert_eq!(abl[2][7], 39);
+        let owned: AudioBufferList = (*abl).to_owned();
+        assert_eq!(owned, abl);
+        assert_ne!(data_ptr(&owned[0]), data_ptr(&abl[0]));
+    }
+
+    #[test]
+    fn clones_keep_buffers_without_data() {
+        let abl = AudioBufferList::with_len(2);
+        let copy = abl.clone();
+        assert_eq!(copy, abl);
+        assert!(copy.iter().all(|b| data_ptr(b) == 0 && b.is_empty()));
+        assert_eq!(
+            format!("{:?}", copy),
+            "AudioBufferList { buffers: [AudioBuffer { channels: 0, bytes: 0 }, \
+             AudioBuffer { channels: 0, bytes: 0 }] }"
+        );
+    }
+
+    #[test]
+    fn empty_lists() {
+        let mut abl = AudioBufferList::with_len(0);
+        assert_eq!(abl.buffer_count(), 0);
+        assert!(abl.is_empty());
+        assert!(abl.iter_mut().next().is_none());
+        let copy = abl.clone();
+        assert_eq!(copy.buffer_count(), 0);
+    }
+
+    #[test]
+    fn buffers_without_data() {
+        let abl = AudioBufferList::with_len(2);
+        assert_eq!(abl.buffer_count(), 2);
+        assert!(abl.iter().all(|b| b.is_empty() && b.num_channels() == 0));
+    }
+}
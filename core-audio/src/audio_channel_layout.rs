@@ -1,6 +1,84 @@
-use {AudioChannelBitmap, AudioChannelDescription, AudioChannelLayoutTag};
+use {AudioChannelBitmap, AudioChannelDescription, AudioChannelLabel, AudioChannelLayoutTag};
 use ffi;
-use std::{mem, ops, ptr, slice};
+use std::{error, fmt, mem, ops, ptr, result, slice};
+
+/// The size of a layout in bytes without its channel descriptions.
+const LAYOUT_HEADER_SIZE: usize = 12;
+
+/// The size of a channel description in bytes.
+const DESCRIPTION_SIZE: usize = 20;
+
+/// Why bytes couldn't be read as an `AudioChannelLayout`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelLayoutError {
+    /// There are fewer bytes than the fixed part of a layout needs. The
+    /// value is the number of bytes.
+    Truncated(usize),
+    /// The number of channel descriptions doesn't match the number of
+    /// bytes.
+    DescriptionCount { descriptions: u32, bytes: usize },
+    /// The layout tag isn't one Core Audio defines.
+    UnknownTag(u32),
+    /// The channel bitmap has bits that aren't channels.
+    InvalidBitmap(u32),
+    /// A channel description's label isn't one Core Audio defines.
+    UnknownLabel { channel: usize, label: u32 },
+    /// A channel description has unknown flags, or flags for both kinds
+    /// of coordinates.
+    InvalidFlags { channel: usize, flags: u32 },
+    /// A channel description's coordinates aren't all finite. The value
+    /// is the channel.
+    InvalidCoordinates(usize),
+}
+
+impl fmt::Display for ChannelLayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChannelLayoutError::Truncated(bytes) => {
+                write!(f, "{} bytes is too short for a channel layout", bytes)
+            }
+            ChannelLayoutError::DescriptionCount {
+                descriptions,
+                bytes,
+            } => write!(
+                f,
+                "{} channel descriptions don't fit in {} bytes",
+                descriptions, bytes
+            ),
+            ChannelLayoutError::UnknownTag(tag) => write!(f, "unknown layout tag {:#x}", tag),
+            ChannelLayoutError::InvalidBitmap(bitmap) => {
+                write!(f, "invalid channel bitmap {:#x}", bitmap)
+            }
+            ChannelLayoutError::UnknownLabel { channel, label } => {
+                write!(f, "channel {} has unknown label {:#x}", channel, label)
+            }
+            ChannelLayoutError::InvalidFlags { channel, flags } => {
+                write!(f, "channel {} has invalid flags {:#x}", channel, flags)
+            }
+            ChannelLayoutError::InvalidCoordinates(channel) => {
+                write!(f, "channel {} has invalid coordinates", channel)
+            }
+        }
+    }
+}
+
+impl error::Error for ChannelLayoutError {
+    fn description(&self) -> &str {
+        match *self {
+            ChannelLayoutError::Truncated(_) => "channel layout is truncated",
+            ChannelLayoutError::DescriptionCount { .. } => {
+                "channel description count doesn't match the size"
+            }
+            ChannelLayoutError::UnknownTag(_) => "unknown layout tag",
+            ChannelLayoutError::InvalidBitmap(_) => "invalid channel bitmap",
+            ChannelLayoutError::UnknownLabel { .. } => "unknown channel label",
+            ChannelLayoutError::InvalidFlags { .. } => "invalid channel flags",
+            ChannelLayoutError::InvalidCoordinates(_) => "invalid channel coordinates",
+        }
+    }
+}
+
+pub type ChannelLayoutResult<T> = result::Result<T, ChannelLayoutError>;
 
 #[repr(C)]
 struct ACLHeap {
@@ -32,9 +110,24 @@ unsafe fn delete_heap(ptr: *mut ffi::AudioChannelLayout) {
     drop(Vec::<u32>::from_raw_parts(ptr as _, 0, n_u32))
 }
 
+/// Copies a layout into a new allocation sized for its descriptions.
+unsafe fn clone_heap(ptr: *mut ffi::AudioChannelLayout) -> *mut ffi::AudioChannelLayout {
+    let len = (*ptr).mNumberChannelDescriptions as usize;
+    let copy = new_heap(len);
+    (*copy).mChannelLayoutTag = (*ptr).mChannelLayoutTag;
+    (*copy).mChannelBitmap = (*ptr).mChannelBitmap;
+    ptr::copy_nonoverlapping(
+        ptr::addr_of!((*ptr).mChannelDescriptions) as *const ffi::AudioChannelDescription,
+        ptr::addr_of_mut!((*copy).mChannelDescriptions) as *mut ffi::AudioChannelDescription,
+        len,
+    );
+    copy
+}
+
 ffi_type_heap! {
     type CType = ffi::AudioChannelLayout;
     fn drop = delete_heap;
+    fn clone = clone_heap;
     pub struct AudioChannelLayout;
     pub struct AudioChannelLayoutRef;
 }
@@ -43,6 +136,120 @@ impl AudioChannelLayout {
     pub fn with_len(len: usize) -> Self {
         AudioChannelLayout(new_heap(len))
     }
+
+    /// A layout with a copy of each of `descriptions`.
+    pub fn with_descriptions(descriptions: &[AudioChannelDescription]) -> Self {
+        let mut layout = AudioChannelLayout::with_len(descriptions.len());
+        layout.set_channel_layout_tag(AudioChannelLayoutTag::USE_CHANNEL_DESCRIPTIONS);
+        layout.copy_from_slice(descriptions);
+        layout
+    }
+
+    /// Reads a layout in the byte order and layout of a CAF file's
+    /// `chan` chunk: the tag, the bitmap and the number of descriptions
+    /// as big endian 32-bit words, then each description's label, flags
+    /// and three coordinates.
+    ///
+    /// The bytes must hold exactly the number of descriptions they
+    /// claim, and the tag, bitmap, labels and flags must all be ones
+    /// Core Audio defines, so bytes from untrusted files can be passed
+    /// in as they are. Coordinates must be finite when the flags say
+    /// they're used.
+    pub fn from_bytes(bytes: &[u8]) -> ChannelLayoutResult<AudioChannelLayout> {
+        if bytes.len() < LAYOUT_HEADER_SIZE {
+            return Err(ChannelLayoutError::Truncated(bytes.len()));
+        }
+        let word = |at: usize| {
+            u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        let descriptions = word(8);
+        let size = (descriptions as usize)
+            .checked_mul(DESCRIPTION_SIZE)
+            .and_then(|size| size.checked_add(LAYOUT_HEADER_SIZE));
+        if size != Some(bytes.len()) {
+            return Err(ChannelLayoutError::DescriptionCount {
+                descriptions,
+                bytes: bytes.len(),
+            });
+        }
+        let tag = AudioChannelLayoutTag::from(word(0));
+        if !tag.is_defined() {
+            return Err(ChannelLayoutError::UnknownTag(word(0)));
+        }
+        let bitmap = AudioChannelBitmap::from_bits(word(4))
+            .ok_or(ChannelLayoutError::InvalidBitmap(word(4)))?;
+
+        let coordinates_flags = ffi::kAudioChannelFlags_RectangularCoordinates
+            | ffi::kAudioChannelFlags_SphericalCoordinates;
+        let known_flags = coordinates_flags | ffi::kAudioChannelFlags_Meters;
+        let mut layout = AudioChannelLayout::with_len(descriptions as usize);
+        layout.set_channel_layout_tag(tag);
+        layout.set_channel_bitmap(bitmap);
+        for (channel, description) in layout.iter_mut().enumerate() {
+            let at = LAYOUT_HEADER_SIZE + channel * DESCRIPTION_SIZE;
+            let label = word(at);
+            if !AudioChannelLabel::from(label).is_defined() {
+                return Err(ChannelLayoutError::UnknownLabel { channel, label });
+            }
+            let flags = word(at + 4);
+            if flags & !known_flags != 0 || flags & coordinates_flags == coordinates_flags {
+                return Err(ChannelLayoutError::InvalidFlags { channel, flags });
+            }
+            let coordinates = [
+                f32::from_bits(word(at + 8)),
+                f32::from_bits(word(at + 12)),
+                f32::from_bits(word(at + 16)),
+            ];
+            if flags & coordinates_flags != 0 && !coordinates.iter().all(|c| c.is_finite()) {
+                return Err(ChannelLayoutError::InvalidCoordinates(channel));
+            }
+            *description = AudioChannelDescription::from(ffi::AudioChannelDescription {
+                mChannelLabel: label,
+                mChannelFlags: flags,
+                mCoordinates: coordinates,
+            });
+        }
+        Ok(layout)
+    }
+}
+
+impl AudioChannelLayoutRef {
+    /// The layout as bytes that `AudioChannelLayout::from_bytes` reads.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(LAYOUT_HEADER_SIZE + self.len() * DESCRIPTION_SIZE);
+        let tag = ffi::AudioChannelLayoutTag::from(self.channel_layout_tag());
+        bytes.extend_from_slice(&tag.to_be_bytes());
+        bytes.extend_from_slice(&self.channel_bitmap().bits().to_be_bytes());
+        bytes.extend_from_slice(&(self.len() as u32).to_be_bytes());
+        for &description in self.iter() {
+            let description = ffi::AudioChannelDescription::from(description);
+            bytes.extend_from_slice(&description.mChannelLabel.to_be_bytes());
+            bytes.extend_from_slice(&description.mChannelFlags.to_be_bytes());
+            for c in &description.mCoordinates {
+                bytes.extend_from_slice(&c.to_bits().to_be_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+impl PartialEq for AudioChannelLayoutRef {
+    /// Layouts are equal when their tags, bitmaps and descriptions are.
+    fn eq(&self, other: &AudioChannelLayoutRef) -> bool {
+        let (a, b) = unsafe { (&*self.as_ptr(), &*other.as_ptr()) };
+        a.mChannelLayoutTag == b.mChannelLayoutTag && a.mChannelBitmap == b.mChannelBitmap
+            && self[..] == other[..]
+    }
+}
+
+impl fmt::Debug for AudioChannelLayoutRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AudioChannelLayout")
+            .field("tag", &self.channel_layout_tag())
+            .field("bitmap", &self.channel_bitmap())
+            .field("descriptions", &&self[..])
+            .finish()
+    }
 }
 
 impl ops::Deref for AudioChannelLayoutRef {
@@ -70,3 +277,261 @@ impl ops::DerefMut for AudioChannelLayoutRef {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioChannelRectangularCoordinates, AudioChannelSphericalCoordinates,
+         StandardChannelLayoutTag};
+
+    /// The bytes of a layout with `descriptions` of a label, flags and
+    /// coordinates.
+    fn layout_bytes(tag: u32, bitmap: u32, descriptions: &[(u32, u32, [f32; 3])]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&tag.to_be_bytes());
+        bytes.extend_from_slice(&bitmap.to_be_bytes());
+        bytes.extend_from_slice(&(descriptions.len() as u32).to_be_bytes());
+        for &(label, flags, coordinates) in descriptions {
+            bytes.extend_from_slice(&label.to_be_bytes());
+            bytes.extend_from_slice(&flags.to_be_bytes());
+            for c in &coordinates {
+                bytes.extend_from_slice(&c.to_bits().to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn layouts() -> Vec<AudioChannelLayout> {
+        vec![
+            AudioChannelLayout::with_tag(StandardChannelLayoutTag::STEREO),
+            AudioChannelLayout::with_tag(ffi::kAudioChannelLayoutTag_MPEG_5_1_A.into()),
+            AudioChannelLayout::with_tag(AudioChannelLayoutTag::discrete_in_order(3)),
+            AudioChannelLayout::with_tag(AudioChannelLayoutTag::unknown(7)),
+            AudioChannelLayout::with_tag(AudioChannelLayoutTag::from(
+                ffi::kAudioChannelLayoutTag_HOA_ACN_SN3D | 9,
+            )),
+            AudioChannelLayout::with_bitmap(
+                AudioChannelBitmap::LEFT | AudioChannelBitmap::LFE_SCREEN,
+            ),
+            AudioChannelLayout::with_descriptions(&[
+                AudioChannelDescription::new(ffi::kAudioChannelLabel_Left.into()),
+                AudioChannelDescription::with_rectangular_coordinate(
+                    ffi::kAudioChannelLabel_UseCoordinates.into(),
+                    AudioChannelRectangularCoordinates {
+                        left_right: 0.5,
+                        back_front: -1.,
+                        down_up: 0.25,
+                    },
+                ),
+                AudioChannelDescription::with_spherical_coordinate(
+                    ffi::kAudioChannelLabel_Discrete_65535.into(),
+                    AudioChannelSphericalCoordinates {
+                        azimuth: -30.,
+                        elevation: 10.,
+                        distance: 2.,
+                    },
+                ),
+                AudioChannelDescription::new(ffi::kAudioChannelLabel_HOA_ACN_65024.into()),
+                AudioChannelDescription::new(AudioChannelLabel::UNKNOWN),
+            ]),
+        ]
+    }
+
+    #[test]
+    fn clones_are_deep() {
+        let layout = layouts().pop().unwrap();
+        let mut copy = layout.clone();
+        assert_eq!(copy, layout);
+        assert_eq!(format!("{:?}", copy), format!("{:?}", layout));
+        assert_ne!(copy.as_ptr(), layout.as_ptr());
+
+        copy[1] = AudioChannelDescription::new(ffi::kAudioChannelLabel_Right.into());
+        assert_ne!(copy, layout);
+        assert_eq!(layout[1].rectangular_coordinate().unwrap().down_up, 0.25);
+        assert_eq!(layout, layouts().pop().unwrap());
+
+        let owned: AudioChannelLayout = (*layout).to_owned();
+        assert_eq!(owned, layout);
+        assert_ne!(owned.as_ptr(), layout.as_ptr());
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        for layout in layouts() {
+            let bytes = layout.to_bytes();
+            let read = AudioChannelLayout::from_bytes(&bytes).unwrap();
+            assert_eq!(read, layout);
+            assert_eq!(read.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn bytes_are_big_endian() {
+        let layout = AudioChannelLayout::with_labels(&[ffi::kAudioChannelLabel_Center.into()]);
+        assert_eq!(
+            layout.to_bytes(),
+            layout_bytes(0, 0, &[(ffi::kAudioChannelLabel_Center, 0, [0.; 3])])
+        );
+        assert_eq!(&layout.to_bytes()[8..16], &[0, 0, 0, 1, 0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn description_counts_must_match_the_size() {
+        assert_eq!(
+            AudioChannelLayout::from_bytes(&[0; 11]),
+            Err(ChannelLayoutError::Truncated(11))
+        );
+        let mut bytes = layout_bytes(0, 0, &[(ffi::kAudioChannelLabel_Left, 0, [0.; 3])]);
+        bytes.pop();
+        assert_eq!(
+            AudioChannelLayout::from_bytes(&bytes),
+            Err(ChannelLayoutError::DescriptionCount {
+                descriptions: 1,
+                bytes: 31,
+            })
+        );
+        let mut bytes = layout_bytes(0, 0, &[]);
+        bytes[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            AudioChannelLayout::from_bytes(&bytes),
+            Err(ChannelLayoutError::DescriptionCount {
+                descriptions: u32::MAX,
+                bytes: 12,
+            })
+        );
+    }
+
+    #[test]
+    fn tags_must_be_defined() {
+        for &tag in &[
+            ffi::kAudioChannelLayoutTag_UseChannelDescriptions,
+            ffi::kAudioChannelLayoutTag_UseChannelBitmap,
+            ffi::kAudioChannelLayoutTag_Stereo,
+            ffi::kAudioChannelLayoutTag_DiscreteInOrder | 0xFFFF,
+            ffi::kAudioChannelLayoutTag_HOA_ACN_N3D | 16,
+            ffi::kAudioChannelLayoutTag_Unknown | 2,
+        ] {
+            assert!(AudioChannelLayout::from_bytes(&layout_bytes(tag, 0, &[])).is_ok());
+        }
+        for &tag in &[
+            ffi::kAudioChannelLayoutTag_Stereo + 1,
+            ffi::kAudioChannelLayoutTag_UseChannelBitmap | 1,
+            1,
+            0x1234_0002,
+        ] {
+            assert_eq!(
+                AudioChannelLayout::from_bytes(&layout_bytes(tag, 0, &[])),
+                Err(ChannelLayoutError::UnknownTag(tag))
+            );
+        }
+    }
+
+    #[test]
+    fn bitmaps_must_be_channels() {
+        let bitmap = ffi::kAudioChannelBit_Left | 1 << 31;
+        assert_eq!(
+            AudioChannelLayout::from_bytes(&layout_bytes(
+                ffi::kAudioChannelLayoutTag_UseChannelBitmap,
+                bitmap,
+                &[]
+            )),
+            Err(ChannelLayoutError::InvalidBitmap(bitmap))
+        );
+    }
+
+    #[test]
+    fn labels_must_be_defined() {
+        for &label in &[19, 32, 46, 99, 101, 208, 303, 401, 0x2_FE01, 0x3_0000, 0xF000_0000] {
+            let descriptions = [(ffi::kAudioChannelLabel_Left, 0, [0.; 3]), (label, 0, [0.; 3])];
+            let bytes = layout_bytes(0, 0, &descriptions);
+            assert_eq!(
+                AudioChannelLayout::from_bytes(&bytes),
+                Err(ChannelLayoutError::UnknownLabel { channel: 1, label })
+            );
+        }
+    }
+
+    #[test]
+    fn flags_must_be_defined() {
+        let both = ffi::kAudioChannelFlags_RectangularCoordinates
+            | ffi::kAudioChannelFlags_SphericalCoordinates;
+        for &flags in &[both, 1 << 3, ffi::kAudioChannelFlags_Meters | 1 << 31] {
+            let bytes = layout_bytes(0, 0, &[(ffi::kAudioChannelLabel_Left, flags, [0.; 3])]);
+            assert_eq!(
+                AudioChannelLayout::from_bytes(&bytes),
+                Err(ChannelLayoutError::InvalidFlags { channel: 0, flags })
+            );
+        }
+    }
+
+    #[test]
+    fn used_coordinates_must_be_finite() {
+        let label = ffi::kAudioChannelLabel_UseCoordinates;
+        for &flags in &[
+            ffi::kAudioChannelFlags_RectangularCoordinates,
+            ffi::kAudioChannelFlags_SphericalCoordinates | ffi::kAudioChannelFlags_Meters,
+        ] {
+            for &bad in &[f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+                let bytes = layout_bytes(0, 0, &[(label, flags, [0., bad, 1.])]);
+                assert_eq!(
+                    AudioChannelLayout::from_bytes(&bytes),
+                    Err(ChannelLayoutError::InvalidCoordinates(0))
+                );
+            }
+        }
+        // Unused coordinates aren't looked at.
+        let bytes = layout_bytes(0, 0, &[(ffi::kAudioChannelLabel_Left, 0, [f32::NAN; 3])]);
+        assert!(AudioChannelLayout::from_bytes(&bytes).is_ok());
+    }
+
+    /// A xorshift generator, so the fuzz test is repeatable.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic_and_accepted_bytes_round_trip() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let valid = layouts().iter().map(|l| l.to_bytes()).collect::<Vec<_>>();
+        let mut accepted = 0;
+        for _ in 0..20_000 {
+            let mut bytes = valid[rng.below(valid.len())].clone();
+            match rng.below(4) {
+                // Random bytes of a random length.
+                0 => {
+                    let len = rng.below(80);
+                    bytes = (0..len).map(|_| rng.next() as u8).collect();
+                }
+                // Cut short or run on.
+                1 => {
+                    let len = rng.below(bytes.len() + 40);
+                    bytes.resize(len, rng.next() as u8);
+                }
+                // Flip a few bits.
+                _ => {
+                    for _ in 0..1 + rng.below(3) {
+                        let at = rng.below(bytes.len());
+                        bytes[at] ^= 1 << rng.below(8);
+                    }
+                }
+            }
+            if let Ok(layout) = AudioChannelLayout::from_bytes(&bytes) {
+                assert_eq!(layout.to_bytes(), bytes);
+                accepted += 1;
+            }
+        }
+        // Enough mutations are valid for the round trip to be tested.
+        assert!(accepted > 100, "only {} accepted", accepted);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! What the audio file readers and writers have in common: their error
+//! type, byte order helpers, and moving frames between file bytes and
+//! an `AudioBufferList`.
+
+use {AudioBufferListRef, AudioStreamBasicDescription, AudioStreamBasicDescriptionRef};
+use interleave::{channel_count, channels, frame_count};
+use std::{error, fmt, io, ptr, result, slice};
+
+/// Why an audio file couldn't be read or written.
+#[derive(Debug)]
+pub enum AudioFileError {
+    /// Reading or writing the underlying stream failed.
+    Io(io::Error),
+    /// The file isn't well formed. The message says what's wrong.
+    Malformed(&'static str),
+    /// The file or format is valid, but not supported. The message says
+    /// what isn't.
+    Unsupported(&'static str),
+    /// The buffer list doesn't have a channel for each of the file's
+    /// channels.
+    BufferLayout,
+    /// The packet descriptions don't fit the packet data or the file's
+    /// format.
+    PacketLayout,
+}
+
+impl fmt::Display for AudioFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AudioFileError::Io(ref e) => write!(f, "{}", e),
+            AudioFileError::Malformed(what) => write!(f, "malformed file: {}", what),
+            AudioFileError::Unsupported(what) => write!(f, "unsupported: {}", what),
+            AudioFileError::BufferLayout => f.write_str("buffer list doesn't match the file"),
+            AudioFileError::PacketLayout => f.write_str("packets don't match the file"),
+        }
+    }
+}
+
+impl error::Error for AudioFileError {
+    fn description(&self) -> &str {
+        match *self {
+            AudioFileError::Io(_) => "I/O error",
+            AudioFileError::Malformed(_) => "malformed file",
+            AudioFileError::Unsupported(_) => "unsupported file or format",
+            AudioFileError::BufferLayout => "buffer list doesn't match the file",
+            AudioFileError::PacketLayout => "packets don't match the file",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            AudioFileError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AudioFileError {
+    fn from(e: io::Error) -> Self {
+        AudioFileError::Io(e)
+    }
+}
+
+pub type AudioFileResult<T> = result::Result<T, AudioFileError>;
+
+/// Reads fixed size values from a file.
+pub(crate) trait ReadBytes: io::Read {
+    fn bytes_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn u16_le(&mut self) -> io::Result<u16> {
+        self.bytes_array().map(u16::from_le_bytes)
+    }
+
+    fn u32_le(&mut self) -> io::Result<u32> {
+        self.bytes_array().map(u32::from_le_bytes)
+    }
+
+    fn u64_le(&mut self) -> io::Result<u64> {
+        self.bytes_array().map(u64::from_le_bytes)
+    }
+
+    fn u16_be(&mut self) -> io::Result<u16> {
+        self.bytes_array().map(u16::from_be_bytes)
+    }
+
+    fn u32_be(&mut self) -> io::Result<u32> {
+        self.bytes_array().map(u32::from_be_bytes)
+    }
+
+    fn u64_be(&mut self) -> io::Result<u64> {
+        self.bytes_array().map(u64::from_be_bytes)
+    }
+
+    /// A chunk ID or other four char code.
+    fn four_cc(&mut self) -> io::Result<[u8; 4]> {
+        self.bytes_array()
+    }
+}
+
+impl<R: io::Read + ?Sized> ReadBytes for R {}
+
+/// Skips `count` bytes of a stream that may not be seekable.
+pub(crate) fn skip<R: io::Read>(reader: &mut R, count: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut io::Read::take(reader, count), &mut io::sink())?;
+    if skipped < count {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Reads the `size` bytes of a chunk. Memory is only allocated for
+/// bytes that are actually there, so a corrupt size can't exhaust it.
+pub(crate) fn read_chunk<R: io::Read>(reader: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    io::Read::read_to_end(&mut io::Read::take(reader, size), &mut bytes)?;
+    if (bytes.len() as u64) < size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+/// Fails if `format` leaves its sample rate unspecified, which no file
+/// header can record.
+pub(crate) fn check_sample_rate(format: &AudioStreamBasicDescriptionRef) -> AudioFileResult<()> {
+    if format.sample_rate() == AudioStreamBasicDescription::ANY_RATE {
+        return Err(AudioFileError::Unsupported("unspecified sample rate"));
+    }
+    Ok(())
+}
+
+/// The number of frames `abl` can hold, if it has `channels` channels
+/// of `sample_bytes` each.
+pub(crate) fn buffer_frames(
+    abl: &AudioBufferListRef,
+    channels: usize,
+    sample_bytes: usize,
+) -> AudioFileResult<usize> {
+    if channel_count(abl) != channels {
+        return Err(AudioFileError::BufferLayout);
+    }
+    Ok(frame_count(abl, sample_bytes))
+}
+
+/// Copies the interleaved frames in `bytes` into the channels of `abl`,
+/// which may be interleaved or not.
+pub(crate) fn scatter(bytes: &[u8], abl: &mut AudioBufferListRef, sample_bytes: usize) {
+    let channels = channels(abl, sample_bytes).collect::<Vec<_>>();
+    let frame_bytes = channels.len() * sample_bytes;
+    if frame_bytes == 0 {
+        return;
+    }
+    for (f, frame) in bytes.chunks_exact(frame_bytes).enumerate() {
+        for (channel, sample) in channels.iter().zip(frame.chunks_exact(sample_bytes)) {
+            if channel.data.is_null() {
+                continue;
+            }
+            unsafe {
+                let dst = channel.data.add(f * channel.stride * sample_bytes);
+                ptr::copy_nonoverlapping(sample.as_ptr(), dst, sample_bytes);
+            }
+        }
+    }
+}
+
+/// Appends `frames` interleaved frames from the channels of `abl` to
+/// `bytes`. Missing channel data is written as zeros.
+pub(crate) fn gather(
+    abl: &AudioBufferListRef,
+    frames: usize,
+    sample_bytes: usize,
+    bytes: &mut Vec<u8>,
+) {
+    let channels = channels(abl, sample_bytes).collect::<Vec<_>>();
+    bytes.reserve(frames * channels.len() * sample_bytes);
+    for f in 0..frames {
+        for channel in &channels {
+            if channel.data.is_null() {
+                bytes.resize(bytes.len() + sample_bytes, 0);
+                continue;
+            }
+            unsafe {
+                let src = channel.data.add(f * channel.stride * sample_bytes);
+                bytes.extend_from_slice(slice::from_raw_parts(src, sample_bytes));
+            }
+        }
+    }
+}
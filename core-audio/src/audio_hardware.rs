@@ -5,8 +5,11 @@ use core_foundation::dictionary::CFDictionary;
 use core_foundation::string::CFString;
 use ffi;
 use libc::pid_t;
-use std::{fmt, iter, mem, ops, ptr, slice};
+use backend::AudioHardwareBackend;
+use four_char_code::{fmt_four_char_code, parse_four_char_code};
+use std::{fmt, iter, mem, ops, panic, slice, str};
 use std::os::raw::c_void;
+use std::sync::{Arc, Mutex};
 
 macro_rules! addr {
     ($sel:ident) => {
@@ -35,7 +38,7 @@ macro_rules! addr {
 macro_rules! audio_object {
     (struct $name:ident : $class:ident {}) => {
         #[derive(Clone, Copy, PartialEq, Eq)]
-        pub struct $name(ffi::AudioObjectID);
+        pub struct $name(pub(crate) ffi::AudioObjectID);
 
         impl Unknown for $name {
             type Type = $name;
@@ -137,41 +140,50 @@ macro_rules! setters {
             let data: u32 = data as u32;
             ao::set_property_data(self, &addr, &data)
         }
+        setters! {
+            $( $rest )*
+        }
     };
     ($name:ident => $sel:ident($p:ty); $($rest:tt)*) => {
         pub fn $name(&mut self, data: &$p) -> Result<()> {
             let addr = addr!($sel);
             ao::set_property_data(self, &addr, data)
         }
-    }
+        setters! {
+            $( $rest )*
+        }
+    };
+    () => {}
 }
 
 //==============================================================================
 
 mod ao {
-    use super::{AudioObject, ObjectID, Result};
-    use call;
+    use super::{AudioObject, ListenerHandle, ObjectID, PropertyListenerThunk, Result,
+                _property_listener_shim};
+    use backend;
     use ffi::{self, AudioObjectPropertyAddress};
-    use std::{mem, ptr};
+    use std::os::raw::c_void;
+    use std::{mem, slice};
+
+    fn as_bytes<T>(data: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(data as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    fn as_bytes_mut<T>(data: &mut T) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(data as *mut T as *mut u8, mem::size_of::<T>()) }
+    }
 
     // Introspection
     pub fn has_property(id: &AudioObject, addr: &AudioObjectPropertyAddress) -> bool {
-        unsafe { ffi::AudioObjectHasProperty(id.id(), addr) != 0 }
+        backend::backend().has_property(id.id(), addr)
     }
 
     pub fn is_property_settable(
         id: &AudioObject,
         addr: &AudioObjectPropertyAddress,
     ) -> Result<bool> {
-        let mut result: ffi::Boolean = 0;
-        unsafe {
-            call::cvt_r(ffi::AudioObjectIsPropertySettable(
-                id.id(),
-                addr,
-                &mut result,
-            ))?;
-        }
-        Ok(result == 1)
+        backend::backend().is_property_settable(id.id(), addr)
     }
 
     // Property Access
@@ -179,17 +191,7 @@ mod ao {
         id: &AudioObject,
         addr: &AudioObjectPropertyAddress,
     ) -> Result<u32> {
-        let mut data_size: u32 = 0;
-        unsafe {
-            call::cvt_r(ffi::AudioObjectGetPropertyDataSize(
-                id.id(),
-                addr,
-                0,
-                ptr::null(),
-                &mut data_size,
-            ))?;
-        }
-        Ok(data_size)
+        backend::backend().get_property_data_size(id.id(), addr, &[])
     }
 
     pub fn get_property_data_size_with_qualifier<Q>(
@@ -197,17 +199,7 @@ mod ao {
         addr: &AudioObjectPropertyAddress,
         qual: &Q,
     ) -> Result<u32> {
-        let mut data_size: u32 = 0;
-        unsafe {
-            call::cvt_r(ffi::AudioObjectGetPropertyDataSize(
-                id.id(),
-                addr,
-                mem::size_of::<Q>() as u32,
-                qual as *const _ as *const _,
-                &mut data_size,
-            ))?;
-        }
-        Ok(data_size)
+        backend::backend().get_property_data_size(id.id(), addr, as_bytes(qual))
     }
 
     pub fn get_property_data<T>(id: &AudioObject, addr: &AudioObjectPropertyAddress) -> Result<T> {
@@ -215,19 +207,11 @@ mod ao {
             mem::size_of::<T>(),
             try!(get_property_data_size(id, addr)) as _
         );
-        let mut data: T = unsafe { mem::uninitialized() };
-        let mut data_size = mem::size_of::<T>() as u32;
+        let mut data = mem::MaybeUninit::<T>::zeroed();
         unsafe {
-            call::cvt_r(ffi::AudioObjectGetPropertyData(
-                id.id(),
-                addr,
-                0,
-                ptr::null(),
-                &mut data_size as *mut _,
-                &mut data as *mut _ as *mut _,
-            ))?;
+            backend::backend().get_property_data(id.id(), addr, &[], as_bytes_mut(&mut data))?;
+            Ok(data.assume_init())
         }
-        Ok(data)
     }
 
     pub fn get_property_array<T>(
@@ -237,18 +221,17 @@ mod ao {
     where
         T: Sized,
     {
-        let mut data_size = get_property_data_size(id, addr)?;
-        let mut data = Vec::<T>::with_capacity(data_size as _);
+        let backend = backend::backend();
+        let data_size = backend.get_property_data_size(id.id(), addr, &[])? as usize;
+        let len = data_size / mem::size_of::<T>();
+        let mut data = Vec::<T>::with_capacity(len);
         unsafe {
-            data.set_len(data_size as _);
-            call::cvt_r(ffi::AudioObjectGetPropertyData(
-                id.id(),
-                addr,
-                0,
-                ptr::null(),
-                &mut data_size as *mut _,
-                data.as_mut_ptr() as *mut _,
-            ))?;
+            let bytes = slice::from_raw_parts_mut(
+                data.as_mut_ptr() as *mut u8,
+                len * mem::size_of::<T>(),
+            );
+            let data_size = backend.get_property_data(id.id(), addr, &[], bytes)? as usize;
+            data.set_len(data_size / mem::size_of::<T>());
         }
         Ok(data)
     }
@@ -262,19 +245,27 @@ mod ao {
         T: Sized,
         Q: Sized,
     {
-        let mut data: T = unsafe { mem::uninitialized() };
-        let mut data_size = mem::size_of::<T>() as u32;
+        let mut data = mem::MaybeUninit::<T>::zeroed();
         unsafe {
-            call::cvt_r(ffi::AudioObjectGetPropertyData(
+            backend::backend().get_property_data(
                 id.id(),
                 addr,
-                mem::size_of::<Q>() as u32,
-                qual as *const _ as *const _,
-                &mut data_size as *mut _,
-                &mut data as *mut _ as *mut _,
-            ))?;
+                as_bytes(qual),
+                as_bytes_mut(&mut data),
+            )?;
+            Ok(data.assume_init())
         }
-        Ok(data)
+    }
+
+    /// Get property data where `data` is passed in to the HAL as well
+    /// as being returned from it.
+    pub fn get_property_data_inout<T>(
+        id: &AudioObject,
+        addr: &AudioObjectPropertyAddress,
+        data: &mut T,
+    ) -> Result<()> {
+        backend::backend().get_property_data(id.id(), addr, &[], as_bytes_mut(data))?;
+        Ok(())
     }
 
     // Property Setting
@@ -286,17 +277,7 @@ mod ao {
     where
         T: Sized,
     {
-        unsafe {
-            call::cvt_r(ffi::AudioObjectSetPropertyData(
-                id.id(),
-                addr,
-                0,
-                ptr::null(),
-                mem::size_of::<T>() as u32,
-                data as *const _ as *const _,
-            ))?;
-        }
-        Ok(())
+        backend::backend().set_property_data(id.id(), addr, &[], as_bytes(data))
     }
 
     pub fn set_property_data_with_qualifier<T, Q>(
@@ -309,52 +290,100 @@ mod ao {
         T: Sized,
         Q: Sized,
     {
-        unsafe {
-            call::cvt_r(ffi::AudioObjectSetPropertyData(
-                id.id(),
-                addr,
-                mem::size_of::<Q>() as u32,
-                qual as *const _ as *const _,
-                mem::size_of::<T>() as u32,
-                data as *const _ as *const _,
-            ))?;
-        }
-        Ok(())
+        backend::backend().set_property_data(id.id(), addr, as_bytes(qual), as_bytes(data))
     }
 
     // Property Listeners
-/*
-    pub fn add_property_listener<F>(id: AudioObjectID,
-                                    addr: &AudioObjectPropertyAddress,
-                                    f: F) -> Result<ListenerHandle>
-        where F: FnMut(ffi::AudioObjectID, &[ffi::AudioObjectPropertyAddress]) -> Result<()> + Send + 'static
+    pub fn add_property_listener<F>(
+        id: &AudioObject,
+        addr: &AudioObjectPropertyAddress,
+        f: F,
+    ) -> Result<ListenerHandle>
+    where
+        F: FnMut(ffi::AudioObjectID, &[AudioObjectPropertyAddress]) -> Result<()> + Send + 'static,
     {
+        let backend = backend::backend();
         let cb_thunk = Box::new(PropertyListenerThunk::new(f));
-        let cb_thunk_ptr = Box::into_raw(cb_thunk) as *mut c_void;
-        
-        unsafe { try_call!(ffi::AudioObjectAddPropertyListener(id, addr,
-                                                               Some(_property_listener_shim as _),
-                                                               cb_thunk_ptr)); }
-        Ok(ListenerHandle(*addr, cb_thunk_ptr))
+        let cb_thunk_ptr = Box::into_raw(cb_thunk);
+        let result = backend.add_property_listener(
+            id.id(),
+            addr,
+            Some(_property_listener_shim),
+            cb_thunk_ptr as *mut c_void,
+        );
+        if let Err(e) = result {
+            drop(unsafe { Box::from_raw(cb_thunk_ptr) });
+            return Err(e);
+        }
+        Ok(ListenerHandle {
+            backend,
+            id: id.id(),
+            addr: *addr,
+            thunk: cb_thunk_ptr,
+        })
     }
 
-    pub fn remove_property_listener(id: ffi::AudioObjectID, handle: ListenerHandle) -> Result<()> {
-        let addr = handle.0;
-        let cb_thunk_ptr = handle.1;
-        unsafe {
-            try_call!(ffi::AudioObjectRemovePropertyListener(id, &addr,
-                                                             Some(_property_listener_shim as _),
-                                                             cb_thunk_ptr));
-            drop(Box::from_raw(cb_thunk_ptr as *mut PropertyListenerThunk));
-        }
-        Ok(())
+    pub fn remove_property_listener(handle: &ListenerHandle) -> Result<()> {
+        handle.backend.remove_property_listener(
+            handle.id,
+            &handle.addr,
+            Some(_property_listener_shim),
+            handle.thunk as *mut c_void,
+        )
     }
-*/
 }
 
 //==============================================================================
 
 pub type AudioObjectPropertySelector = ffi::AudioObjectPropertySelector;
+
+/// A property selector, such as `AudioDevice::NOMINAL_SAMPLE_RATE`,
+/// that prints as its four char code.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PropertySelector(AudioObjectPropertySelector);
+
+impl From<AudioObjectPropertySelector> for PropertySelector {
+    fn from(x: AudioObjectPropertySelector) -> Self {
+        PropertySelector(x)
+    }
+}
+
+impl From<PropertySelector> for AudioObjectPropertySelector {
+    fn from(x: PropertySelector) -> Self {
+        x.0
+    }
+}
+
+impl PartialEq<AudioObjectPropertySelector> for PropertySelector {
+    fn eq(&self, other: &AudioObjectPropertySelector) -> bool {
+        self.0 == *other
+    }
+}
+
+impl fmt::Debug for PropertySelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PropertySelector(")?;
+        fmt_four_char_code(self.0, f)?;
+        f.write_str(")")
+    }
+}
+
+impl fmt::Display for PropertySelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_four_char_code(self.0, f)
+    }
+}
+
+impl str::FromStr for PropertySelector {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        parse_four_char_code(s)
+            .map(PropertySelector)
+            .ok_or(ParseFormatError::FourCharCode)
+    }
+}
+
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AudioObjectPropertyScope {
@@ -362,6 +391,8 @@ pub enum AudioObjectPropertyScope {
     Input = ffi::kAudioObjectPropertyScopeInput,
     Output = ffi::kAudioObjectPropertyScopeOutput,
     PlayThrought = ffi::kAudioObjectPropertyScopePlayThrough,
+    /// Matches any scope when adding a property listener.
+    Wildcard = ffi::kAudioObjectPropertyScopeWildcard,
 }
 pub type AudioObjectPropertyElement = ffi::AudioObjectPropertyElement;
 
@@ -376,6 +407,15 @@ impl ObjectID for AudioObject {
 }
 
 impl AudioObject {
+    /// Matches any selector when adding a property listener.
+    pub const SELECTOR_WILDCARD: AudioObjectPropertySelector =
+        ffi::kAudioObjectPropertySelectorWildcard;
+    /// The element that refers to the object as a whole.
+    pub const ELEMENT_MASTER: AudioObjectPropertyElement = ffi::kAudioObjectPropertyElementMaster;
+    /// Matches any element when adding a property listener.
+    pub const ELEMENT_WILDCARD: AudioObjectPropertyElement =
+        ffi::kAudioObjectPropertyElementWildcard;
+
     pub fn is(&self, class: AudioClassID) -> bool {
         match self.class() {
             Ok(my_class) => my_class == class,
@@ -402,6 +442,30 @@ impl AudioObject {
         ao::is_property_settable(self, addr)
     }
 
+    // Notifications
+
+    /// Registers `f` to be called when the property at `addr` changes.
+    ///
+    /// `f` receives the object ID and the addresses that changed. The
+    /// scope and element of `addr` may be wildcards, as may the
+    /// selector. The listener stays registered until the returned
+    /// handle is dropped.
+    ///
+    /// `f` is called on a thread owned by the HAL. A panic in `f` is
+    /// caught and reported to the HAL as an unspecified error.
+    pub fn add_listener<F>(
+        &self,
+        addr: &ffi::AudioObjectPropertyAddress,
+        f: F,
+    ) -> Result<ListenerHandle>
+    where
+        F: FnMut(ffi::AudioObjectID, &[ffi::AudioObjectPropertyAddress]) -> Result<()>
+            + Send
+            + 'static,
+    {
+        ao::add_property_listener(self, addr, f)
+    }
+
     getters! {
         base_class => kAudioObjectPropertyBaseClass -> AudioClassID;
         class => kAudioObjectPropertyClass -> AudioClassID;
@@ -447,6 +511,22 @@ impl fmt::Debug for AudioClassID {
     }
 }
 
+impl fmt::Display for AudioClassID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_four_char_code(self.0, f)
+    }
+}
+
+impl str::FromStr for AudioClassID {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        parse_four_char_code(s)
+            .map(AudioClassID)
+            .ok_or(ParseFormatError::FourCharCode)
+    }
+}
+
 //==============================================================================
 // Iterator
 type AudioObjectIter<'a> = iter::TakeWhile<slice::Iter<'a, AudioObject>, fn(&&AudioObject) -> bool>;
@@ -457,7 +537,65 @@ pub fn audio_object_iter(v: &[AudioObject]) -> AudioObjectIter {
 //==============================================================================
 // Audio Object
 
-pub struct ListenerHandle(ffi::AudioObjectPropertyAddress, *mut c_void);
+/// A property listener registered with `AudioObject::add_listener`.
+///
+/// Dropping the handle unregisters the listener and frees the closure.
+pub struct ListenerHandle {
+    backend: Arc<dyn AudioHardwareBackend>,
+    id: ffi::AudioObjectID,
+    addr: ffi::AudioObjectPropertyAddress,
+    thunk: *mut PropertyListenerThunk,
+}
+
+// The thunk is only touched by the HAL and by `drop`, and the closure
+// it owns is `Send`.
+unsafe impl Send for ListenerHandle {}
+
+impl ListenerHandle {
+    /// The address the listener was registered for.
+    pub fn address(&self) -> &ffi::AudioObjectPropertyAddress {
+        &self.addr
+    }
+
+    /// Unregisters the listener, reporting any error from the HAL.
+    ///
+    /// A listener on an object that no longer exists is already gone,
+    /// so removing it succeeds.
+    pub fn remove(self) -> Result<()> {
+        let result = self.release();
+        mem::forget(self);
+        result
+    }
+
+    fn release(&self) -> Result<()> {
+        let result = match ao::remove_property_listener(self) {
+            // The HAL drops the listeners of an object when it goes away.
+            Err(ref e) if e.kind() == ErrorKind::BadObject => Ok(()),
+            result => result,
+        };
+        if result.is_ok() {
+            unsafe { drop(Box::from_raw(self.thunk)) };
+        }
+        // Otherwise the HAL may still call the thunk, so it has to be
+        // leaked.
+        result
+    }
+}
+
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+impl fmt::Debug for ListenerHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ListenerHandle")
+            .field("id", &self.id)
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
 
 unsafe extern "C" fn _property_listener_shim(
     id: ffi::AudioObjectID,
@@ -466,19 +604,29 @@ unsafe extern "C" fn _property_listener_shim(
     client_data: *mut c_void,
 ) -> ffi::OSStatus {
     debug_assert!(!client_data.is_null());
-    let addrs = slice::from_raw_parts(addr, addr_count as _);
-    let thunk: &mut PropertyListenerThunk = &mut *(client_data as *mut _);
-    match (thunk.cb)(id, addrs) {
-        Ok(_) => ffi::kAudioHardwareNoError,
-        Err(e) => e.raw_osstatus(),
+    let addrs = if addr.is_null() || addr_count == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(addr, addr_count as _)
+    };
+    let thunk: &PropertyListenerThunk = &*(client_data as *const _);
+    // Unwinding into the HAL is undefined behavior.
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let mut cb = thunk.cb.lock().unwrap_or_else(|e| e.into_inner());
+        (*cb)(id, addrs)
+    }));
+    match result {
+        Ok(Ok(_)) => ffi::kAudioHardwareNoError,
+        Ok(Err(e)) => e.raw_osstatus(),
+        Err(_) => ffi::kAudioHardwareUnspecifiedError,
     }
 }
 
 pub type PropertyListenerFn =
-    FnMut(ffi::AudioObjectID, &[ffi::AudioObjectPropertyAddress]) -> Result<()>;
+    dyn FnMut(ffi::AudioObjectID, &[ffi::AudioObjectPropertyAddress]) -> Result<()> + Send;
 
 struct PropertyListenerThunk {
-    cb: Box<PropertyListenerFn>,
+    cb: Mutex<Box<PropertyListenerFn>>,
 }
 
 impl PropertyListenerThunk {
@@ -488,13 +636,16 @@ impl PropertyListenerThunk {
             + Send
             + 'static,
     {
-        PropertyListenerThunk { cb: Box::new(f) }
+        PropertyListenerThunk {
+            cb: Mutex::new(Box::new(f)),
+        }
     }
 }
 
 //==============================================================================
 // AudioStream
 
+#[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AudioStreamDirection {
     Output = 0,
@@ -567,6 +718,11 @@ impl AudioStream {
         available_physical_formats =>
             kAudioStreamPropertyAvailablePhysicalFormats -> [AudioStreamRangedDescription];
     }
+
+    setters! {
+        set_virtual_format => kAudioStreamPropertyVirtualFormat(AudioStreamBasicDescription);
+        set_physical_format => kAudioStreamPropertyPhysicalFormat(AudioStreamBasicDescription);
+    }
 }
 
 //==============================================================================
@@ -595,21 +751,11 @@ impl AudioPlugIn {
         // strange. You pass the device id *into* a get call.
         let mut data = aggregate_device;
         let addr = addr!(kAudioPlugInDestroyAggregateDevice);
-        let mut data_size = mem::size_of::<AudioDevice>() as u32;
         debug_assert_eq!(
-            data_size,
+            mem::size_of::<AudioDevice>(),
             try!(ao::get_property_data_size(self, &addr)) as _
         );
-        unsafe {
-            call::cvt_r(ffi::AudioObjectGetPropertyData(
-                self.id(),
-                &addr,
-                0,
-                ptr::null(),
-                &mut data_size as *mut _,
-                &mut data as *mut _ as *mut _,
-            ))?;
-        }
+        ao::get_property_data_inout(self, &addr, &mut data)?;
         Ok(())
     }
 }
@@ -683,17 +829,144 @@ pub fn audio_system_object() -> AudioSystemObject {
 
 //==================================================================================================
 // AudioDevice
-pub type AudioDeviceIOProc = FnMut(
+pub type AudioDeviceIOProc = dyn FnMut(
     AudioObject,
-    &AudioTimeStamp,      // inNow
-    &AudioBufferList,     // inInputData
-    &AudioTimeStamp,      // inInputTime
-    &mut AudioBufferList, // outOutputData
-    &AudioTimeStamp,
-); // inOutputTime
+    &AudioTimeStampRef,      // inNow
+    &AudioBufferListRef,     // inInputData
+    &AudioTimeStampRef,      // inInputTime
+    &mut AudioBufferListRef, // outOutputData
+    &AudioTimeStampRef,      // inOutputTime
+) + Send;
 
 pub type AudioDeviceIOProcID = ffi::AudioDeviceIOProcID;
 
+/// An IOProc registered with `AudioDevice::create_io_proc_id`.
+///
+/// Dropping the handle stops IO for the proc, destroys the proc ID and
+/// frees the closure.
+pub struct IoProcHandle {
+    device: AudioDevice,
+    proc_id: AudioDeviceIOProcID,
+    thunk: *mut IoProcThunk,
+}
+
+// The thunk is only touched by the HAL's IO thread and by `drop`, and
+// the closure it owns is `Send`.
+unsafe impl Send for IoProcHandle {}
+
+impl IoProcHandle {
+    /// The device the proc was created on.
+    pub fn device(&self) -> AudioDevice {
+        self.device
+    }
+
+    /// The proc ID, for use with `AudioDevice::start` and friends.
+    pub fn proc_id(&self) -> AudioDeviceIOProcID {
+        self.proc_id
+    }
+
+    /// Starts IO for the proc.
+    pub fn start(&self) -> Result<()> {
+        unsafe { call::cvt_r(ffi::AudioDeviceStart(self.device.id(), self.proc_id)) }
+    }
+
+    /// Stops IO for the proc.
+    pub fn stop(&self) -> Result<()> {
+        self.device.stop(self.proc_id)
+    }
+
+    /// Stops IO and destroys the proc ID, reporting any error from the
+    /// HAL.
+    pub fn destroy(self) -> Result<()> {
+        let result = self.release();
+        mem::forget(self);
+        result
+    }
+
+    fn release(&self) -> Result<()> {
+        let _ = self.stop();
+        let result = self.device.destroy_io_proc_id(self.proc_id);
+        if result.is_ok() {
+            unsafe { drop(Box::from_raw(self.thunk)) };
+        }
+        // Otherwise the HAL may still call the proc, so the thunk has
+        // to be leaked.
+        result
+    }
+}
+
+impl Drop for IoProcHandle {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+impl fmt::Debug for IoProcHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IoProcHandle")
+            .field("device", &self.device)
+            .field("proc_id", &self.proc_id)
+            .finish()
+    }
+}
+
+unsafe extern "C" fn _io_proc_shim(
+    device: ffi::AudioObjectID,
+    now: *const ffi::AudioTimeStamp,
+    input_data: *const ffi::AudioBufferList,
+    input_time: *const ffi::AudioTimeStamp,
+    output_data: *mut ffi::AudioBufferList,
+    output_time: *const ffi::AudioTimeStamp,
+    client_data: *mut c_void,
+) -> ffi::OSStatus {
+    debug_assert!(!client_data.is_null());
+    let thunk: &mut IoProcThunk = &mut *(client_data as *mut _);
+    // The HAL passes NULL for a direction the device doesn't have.
+    let mut no_input: ffi::AudioBufferList = mem::zeroed();
+    let mut no_output: ffi::AudioBufferList = mem::zeroed();
+    let input_data = if input_data.is_null() {
+        &mut no_input
+    } else {
+        input_data as *mut _
+    };
+    let output_data = if output_data.is_null() {
+        &mut no_output
+    } else {
+        output_data
+    };
+
+    if !thunk.panicked {
+        // Unwinding into the HAL is undefined behavior.
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            (*thunk.cb)(
+                AudioObject(device),
+                AudioTimeStampRef::from_ptr(now as *mut _),
+                AudioBufferListRef::from_ptr(input_data),
+                AudioTimeStampRef::from_ptr(input_time as *mut _),
+                AudioBufferListRef::from_ptr_mut(output_data),
+                AudioTimeStampRef::from_ptr(output_time as *mut _),
+            )
+        }));
+        thunk.panicked = result.is_err();
+    }
+    // The closure's state can't be trusted after a panic, so the proc
+    // stays silent from then on.
+    if thunk.panicked {
+        for buffer in AudioBufferListRef::from_ptr_mut(output_data).iter_mut() {
+            let ab: &ffi::AudioBuffer = &*buffer.as_ptr();
+            if !ab.mData.is_null() {
+                ::std::ptr::write_bytes(ab.mData as *mut u8, 0, ab.mDataByteSize as _);
+            }
+        }
+    }
+    ffi::kAudioHardwareNoError
+}
+
+struct IoProcThunk {
+    cb: Box<AudioDeviceIOProc>,
+    panicked: bool,
+}
+
 audio_object! {
     struct AudioDevice: kAudioDeviceClassID {}
 }
@@ -922,9 +1195,10 @@ impl AudioDevice {
     setters! {
         set_nominal_sample_rate => kAudioDevicePropertyNominalSampleRate(f64);
         set_preferred_channels_for_stereo =>
-            kAudioDevicePropertyPreferredChannelsForStereo((u32,32));
-        set_preferred_channel_layout =>
-            kAudioDevicePropertyPreferredChannelLayout(AudioChannelLayout);
+            kAudioDevicePropertyPreferredChannelsForStereo((u32, u32));
+        // set_preferred_channel_layout =>
+        //     kAudioDevicePropertyPreferredChannelLayout(AudioChannelLayout);
+        set_buffer_frame_size => kAudioDevicePropertyBufferFrameSize(u32);
 
         set_hog_mode =>  kAudioDevicePropertyHogMode(pid_t);
         // A pid_t indicating the process that currently owns exclusive
@@ -963,28 +1237,50 @@ impl AudioDevice {
     //                     for releasing the returned CFObject.
     }
 
-    /// Creates an AudioDeviceIOProcID from an AudioDeviceIOProc and a client data  pointer.
+    /// Creates an AudioDeviceIOProcID that calls `f` on the device's IO
+    /// thread.
+    ///
+    /// `f` receives the device, the current time, the input data and
+    /// its time, and the output data to fill and its time. `f` stays
+    /// registered until the returned handle is dropped. A panic in `f`
+    /// is caught and the proc outputs silence from then on.
     ///
     /// AudioDeviceIOProcIDs allow for the client to register the same function pointer with a device multiple times.
-    pub fn create_io_proc_id<F>(&self, _f: F) -> Result<AudioDeviceIOProcID>
+    pub fn create_io_proc_id<F>(&self, f: F) -> Result<IoProcHandle>
     where
         F: FnMut(
             AudioObject,
-            &AudioTimeStamp,
-            &AudioBufferList,
-            &AudioTimeStamp,
-            &mut AudioBufferList,
-            &AudioTimeStamp,
+            &AudioTimeStampRef,
+            &AudioBufferListRef,
+            &AudioTimeStampRef,
+            &mut AudioBufferListRef,
+            &AudioTimeStampRef,
         )
             + Send
             + 'static,
     {
-        let result: AudioDeviceIOProcID = unsafe { mem::uninitialized() };
-        // try_unsafe_call!(ffi::AudioDeviceCreateIOProcID(self.0,
-        //                                                AudioDeviceIOProc inProc,
-        //                                                inClientData: *mut c_void,
-        //                                                &mut result));
-        Ok(result)
+        let thunk = Box::into_raw(Box::new(IoProcThunk {
+            cb: Box::new(f),
+            panicked: false,
+        }));
+        let mut proc_id: AudioDeviceIOProcID = None;
+        let result = unsafe {
+            call::cvt_r(ffi::AudioDeviceCreateIOProcID(
+                self.id(),
+                Some(_io_proc_shim),
+                thunk as *mut _,
+                &mut proc_id,
+            ))
+        };
+        if let Err(e) = result {
+            unsafe { drop(Box::from_raw(thunk)) };
+            return Err(e);
+        }
+        Ok(IoProcHandle {
+            device: *self,
+            proc_id,
+            thunk,
+        })
     }
 
     /// Destroys an AudioDeviceIOProcID.
@@ -1158,3 +1454,208 @@ impl AudioSubDevice {
             kAudioSubDevicePropertyDriftCompensationQuality(AudioSubDeviceDriftCompensation);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simulated_hal::tests::{add_device, install};
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn recording_listener(
+        calls: &Arc<Mutex<Vec<(ffi::AudioObjectID, usize)>>>,
+    ) -> impl FnMut(ffi::AudioObjectID, &[ffi::AudioObjectPropertyAddress]) -> Result<()> {
+        let calls = calls.clone();
+        move |id, addrs| {
+            calls.lock().unwrap().push((id, addrs.len()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn listeners_fire_until_dropped() {
+        let hal = install();
+        let device = add_device(&hal);
+        let rate = addr!(kAudioDevicePropertyNominalSampleRate);
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let handle = device.add_listener(&rate, recording_listener(&calls)).unwrap();
+        assert_eq!(handle.address().mSelector, rate.mSelector);
+
+        hal.set_property(&device, &rate, &48000f64);
+        hal.notify(&device, &[addr!(kAudioDevicePropertyBufferFrameSize)]);
+        assert_eq!(*calls.lock().unwrap(), [(device.id(), 1)]);
+
+        drop(handle);
+        // Dropping the handle frees the closure.
+        assert_eq!(Arc::strong_count(&calls), 1);
+        hal.set_property(&device, &rate, &44100f64);
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn wildcard_listeners() {
+        let hal = install();
+        let device = add_device(&hal);
+        let any = addr!(
+            kAudioObjectPropertySelectorWildcard,
+            AudioObjectPropertyScope::Wildcard as u32,
+            AudioObject::ELEMENT_WILDCARD
+        );
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let _handle = device.add_listener(&any, recording_listener(&calls)).unwrap();
+
+        let input_rate = addr!(
+            kAudioDevicePropertyNominalSampleRate,
+            AudioObjectPropertyScope::Input as u32,
+            1
+        );
+        hal.notify(&device, &[input_rate, addr!(kAudioDevicePropertyBufferFrameSize)]);
+        assert_eq!(*calls.lock().unwrap(), [(device.id(), 2)]);
+    }
+
+    #[test]
+    fn remove_frees_the_closure() {
+        let hal = install();
+        let device = add_device(&hal);
+        let rate = addr!(kAudioDevicePropertyNominalSampleRate);
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        let handle = device.add_listener(&rate, recording_listener(&calls)).unwrap();
+        handle.remove().unwrap();
+        assert_eq!(Arc::strong_count(&calls), 1);
+
+        // The listeners of a removed object are already gone.
+        let handle = device.add_listener(&rate, recording_listener(&calls)).unwrap();
+        hal.remove_object(&device);
+        handle.remove().unwrap();
+        assert_eq!(Arc::strong_count(&calls), 1);
+
+        let handle = device.add_listener(&rate, recording_listener(&calls));
+        assert_eq!(handle.unwrap_err().kind(), ErrorKind::BadObject);
+        assert_eq!(Arc::strong_count(&calls), 1);
+    }
+
+    #[test]
+    fn panics_are_caught() {
+        let hal = install();
+        let device = add_device(&hal);
+        let rate = addr!(kAudioDevicePropertyNominalSampleRate);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        let _handle = device
+            .add_listener(&rate, move |_, _| -> Result<()> {
+                seen.fetch_add(1, Ordering::SeqCst);
+                panic!("listener panicked");
+            })
+            .unwrap();
+
+        hal.notify(&device, &[rate]);
+        hal.notify(&device, &[rate]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn shim_reports_listener_errors() {
+        let rate = addr!(kAudioDevicePropertyNominalSampleRate);
+        let call = |thunk: &PropertyListenerThunk| unsafe {
+            _property_listener_shim(1, 1, &rate, thunk as *const _ as *mut c_void)
+        };
+        let ok = PropertyListenerThunk::new(|_, _| Ok(()));
+        assert_eq!(call(&ok), ffi::kAudioHardwareNoError);
+        let err = PropertyListenerThunk::new(|_, _| Err(ErrorKind::BadPropertySize.into()));
+        assert_eq!(call(&err), ffi::kAudioHardwareBadPropertySizeError);
+        let panics = PropertyListenerThunk::new(|_, _| panic!("listener panicked"));
+        assert_eq!(call(&panics), ffi::kAudioHardwareUnspecifiedError);
+    }
+
+    #[test]
+    fn shim_accepts_missing_addresses() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let thunk = PropertyListenerThunk::new(recording_listener(&calls));
+        let client_data = &thunk as *const _ as *mut c_void;
+        unsafe {
+            assert_eq!(
+                _property_listener_shim(1, 0, ptr::null(), client_data),
+                ffi::kAudioHardwareNoError
+            );
+            assert_eq!(
+                _property_listener_shim(1, 3, ptr::null(), client_data),
+                ffi::kAudioHardwareNoError
+            );
+        }
+        assert_eq!(*calls.lock().unwrap(), [(1, 0), (1, 0)]);
+    }
+
+    fn run_io_proc(
+        thunk: &mut IoProcThunk,
+        input: *const ffi::AudioBufferList,
+        output: *mut ffi::AudioBufferList,
+    ) -> ffi::OSStatus {
+        let time = AudioTimeStamp::with_sample_time(256.);
+        unsafe {
+            _io_proc_shim(
+                7,
+                time.as_ptr(),
+                input,
+                time.as_ptr(),
+                output,
+                time.as_ptr(),
+                thunk as *mut IoProcThunk as *mut c_void,
+            )
+        }
+    }
+
+    #[test]
+    fn io_proc_shim_passes_buffers() {
+        let format: AudioStreamBasicDescription = "s16le@48000x2".parse().unwrap();
+        let mut input = AudioBufferList::allocate(&format, 4);
+        input[0].copy_from_slice(&[1; 16]);
+        let output = AudioBufferList::allocate(&format, 4);
+        let mut thunk = IoProcThunk {
+            cb: Box::new(|device, now, input, _, output, _| {
+                assert_eq!(device, AudioObject(7));
+                assert_eq!(now.sample_time(), Some(256.));
+                output[0].copy_from_slice(&input[0]);
+            }),
+            panicked: false,
+        };
+        let status = run_io_proc(&mut thunk, input.as_ptr(), output.as_ptr());
+        assert_eq!(status, ffi::kAudioHardwareNoError);
+        assert_eq!(&output[0][..], &[1; 16]);
+
+        // A direction the device doesn't have is an empty list.
+        let mut thunk = IoProcThunk {
+            cb: Box::new(|_, _, input, _, output, _| {
+                assert_eq!(input.len(), 0);
+                assert_eq!(output.len(), 0);
+            }),
+            panicked: false,
+        };
+        let status = run_io_proc(&mut thunk, ptr::null(), ptr::null_mut());
+        assert_eq!(status, ffi::kAudioHardwareNoError);
+    }
+
+    #[test]
+    fn io_proc_panics_output_silence() {
+        let format: AudioStreamBasicDescription = "f32le@48000x2".parse().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = calls.clone();
+        let mut thunk = IoProcThunk {
+            cb: Box::new(move |_, _, _, _, output, _| {
+                output[0][0] = 1;
+                seen.fetch_add(1, Ordering::SeqCst);
+                panic!("io proc panicked");
+            }),
+            panicked: false,
+        };
+        for _ in 0..2 {
+            let mut output = AudioBufferList::allocate(&format, 4);
+            output[0].copy_from_slice(&[0xff; 32]);
+            let status = run_io_proc(&mut thunk, ptr::null(), output.as_ptr());
+            assert_eq!(status, ffi::kAudioHardwareNoError);
+            assert_eq!(&output[0][..], &[0; 32]);
+        }
+        // The closure isn't called again after panicking.
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
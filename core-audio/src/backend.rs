@@ -0,0 +1,241 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! The property access layer between the typed `AudioObject` API and
+//! the HAL that actually services the requests.
+
+use Result;
+use ffi::{self, AudioObjectID, AudioObjectPropertyAddress};
+use std::os::raw::c_void;
+use std::sync::{Arc, RwLock};
+
+/// The operations the typed `AudioObject` API needs from a HAL.
+///
+/// Each function mirrors the `AudioObject*` function of the same name
+/// in `AudioHardware.h`, with property and qualifier data passed as
+/// raw bytes. The default backend forwards to CoreAudio. A
+/// `SimulatedHal` can be installed with `set_backend` to run the same
+/// API against an in-memory object graph.
+pub trait AudioHardwareBackend: Send + Sync {
+    /// Queries whether the object has the property at the given address.
+    fn has_property(&self, id: AudioObjectID, addr: &AudioObjectPropertyAddress) -> bool;
+
+    /// Queries whether the property at the given address can be set.
+    fn is_property_settable(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+    ) -> Result<bool>;
+
+    /// Queries the size, in bytes, of the data of the property.
+    fn get_property_data_size(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+    ) -> Result<u32>;
+
+    /// Copies the data of the property into `data` and returns the
+    /// number of bytes written.
+    fn get_property_data(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+        data: &mut [u8],
+    ) -> Result<u32>;
+
+    /// Tells the object to change the value of the property.
+    fn set_property_data(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+        data: &[u8],
+    ) -> Result<()>;
+
+    /// Registers `listener` to be called with `client_data` when the
+    /// property at the given address changes.
+    fn add_property_listener(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        listener: ffi::AudioObjectPropertyListenerProc,
+        client_data: *mut c_void,
+    ) -> Result<()>;
+
+    /// Unregisters a listener previously added with `add_property_listener`.
+    fn remove_property_listener(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        listener: ffi::AudioObjectPropertyListenerProc,
+        client_data: *mut c_void,
+    ) -> Result<()>;
+}
+
+static BACKEND: RwLock<Option<Arc<dyn AudioHardwareBackend>>> = RwLock::new(None);
+
+/// Returns the backend currently servicing property requests.
+///
+/// Unless `set_backend` has been called, this is `CoreAudioBackend`
+/// on Apple platforms and an empty `SimulatedHal` everywhere else.
+pub fn backend() -> Arc<dyn AudioHardwareBackend> {
+    if let Some(ref backend) = *BACKEND.read().unwrap() {
+        return backend.clone();
+    }
+    let mut guard = BACKEND.write().unwrap();
+    guard.get_or_insert_with(default_backend).clone()
+}
+
+/// Installs `backend` for all subsequent property requests and
+/// returns the backend that was previously installed.
+///
+/// # Note
+///
+/// The backend is process wide. Listeners and objects obtained from
+/// the previous backend are not migrated.
+pub fn set_backend(backend: Arc<dyn AudioHardwareBackend>) -> Arc<dyn AudioHardwareBackend> {
+    let mut guard = BACKEND.write().unwrap();
+    let previous = guard.take().unwrap_or_else(default_backend);
+    *guard = Some(backend);
+    previous
+}
+
+#[cfg(target_vendor = "apple")]
+fn default_backend() -> Arc<dyn AudioHardwareBackend> {
+    Arc::new(CoreAudioBackend)
+}
+
+#[cfg(not(target_vendor = "apple"))]
+fn default_backend() -> Arc<dyn AudioHardwareBackend> {
+    Arc::new(::SimulatedHal::new())
+}
+
+//==============================================================================
+// CoreAudioBackend
+
+/// The backend that forwards every request to the CoreAudio HAL.
+#[cfg(target_vendor = "apple")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CoreAudioBackend;
+
+#[cfg(target_vendor = "apple")]
+impl AudioHardwareBackend for CoreAudioBackend {
+    fn has_property(&self, id: AudioObjectID, addr: &AudioObjectPropertyAddress) -> bool {
+        unsafe { ffi::AudioObjectHasProperty(id, addr) != 0 }
+    }
+
+    fn is_property_settable(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+    ) -> Result<bool> {
+        let mut result: ffi::Boolean = 0;
+        unsafe {
+            ::call::cvt_r(ffi::AudioObjectIsPropertySettable(id, addr, &mut result))?;
+        }
+        Ok(result == 1)
+    }
+
+    fn get_property_data_size(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+    ) -> Result<u32> {
+        let mut data_size: u32 = 0;
+        unsafe {
+            ::call::cvt_r(ffi::AudioObjectGetPropertyDataSize(
+                id,
+                addr,
+                qualifier.len() as u32,
+                qualifier_ptr(qualifier),
+                &mut data_size,
+            ))?;
+        }
+        Ok(data_size)
+    }
+
+    fn get_property_data(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+        data: &mut [u8],
+    ) -> Result<u32> {
+        let mut data_size = data.len() as u32;
+        unsafe {
+            ::call::cvt_r(ffi::AudioObjectGetPropertyData(
+                id,
+                addr,
+                qualifier.len() as u32,
+                qualifier_ptr(qualifier),
+                &mut data_size,
+                data.as_mut_ptr() as *mut _,
+            ))?;
+        }
+        Ok(data_size)
+    }
+
+    fn set_property_data(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+        data: &[u8],
+    ) -> Result<()> {
+        unsafe {
+            ::call::cvt_r(ffi::AudioObjectSetPropertyData(
+                id,
+                addr,
+                qualifier.len() as u32,
+                qualifier_ptr(qualifier),
+                data.len() as u32,
+                data.as_ptr() as *const _,
+            ))
+        }
+    }
+
+    fn add_property_listener(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        listener: ffi::AudioObjectPropertyListenerProc,
+        client_data: *mut c_void,
+    ) -> Result<()> {
+        unsafe {
+            ::call::cvt_r(ffi::AudioObjectAddPropertyListener(
+                id,
+                addr,
+                listener,
+                client_data,
+            ))
+        }
+    }
+
+    fn remove_property_listener(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        listener: ffi::AudioObjectPropertyListenerProc,
+        client_data: *mut c_void,
+    ) -> Result<()> {
+        unsafe {
+            ::call::cvt_r(ffi::AudioObjectRemovePropertyListener(
+                id,
+                addr,
+                listener,
+                client_data,
+            ))
+        }
+    }
+}
+
+#[cfg(target_vendor = "apple")]
+fn qualifier_ptr(qualifier: &[u8]) -> *const c_void {
+    if qualifier.is_empty() {
+        ::std::ptr::null()
+    } else {
+        qualifier.as_ptr() as *const _
+    }
+}
@@ -0,0 +1,979 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Streaming reading and writing of Core Audio Format (CAF) files.
+//!
+//! A CAF file holds the crate's own types: its `desc` chunk is an
+//! `AudioStreamBasicDescription`, its `chan` chunk an
+//! `AudioChannelLayout` and its `pakt` chunk the sizes of the packets
+//! of formats whose packets vary.
+
+use {AudioBufferListRef, AudioChannelLayout, AudioChannelLayoutRef, AudioFormat, AudioFormatFlags,
+     AudioStreamBasicDescription, AudioStreamBasicDescriptionRef, AudioStreamPacketDesc,
+     SampleKind};
+use audio_file::{buffer_frames, check_sample_rate, gather, read_chunk, scatter, skip,
+                 AudioFileError, AudioFileResult, ReadBytes};
+use ffi;
+use interleave::frame_count;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The `mFormatFlags` of linear PCM in a `desc` chunk.
+const CAF_LPCM_IS_FLOAT: u32 = 1 << 0;
+const CAF_LPCM_IS_LITTLE_ENDIAN: u32 = 1 << 1;
+
+/// The size of a data chunk that runs to the end of the file.
+const UNKNOWN_SIZE: i64 = -1;
+
+/// The size of the edit count at the start of the data chunk.
+const EDIT_COUNT_SIZE: u64 = 4;
+
+/// The size of a `pakt` chunk without its table.
+const PAKT_HEADER_SIZE: usize = 24;
+
+/// Reads a `desc` chunk.
+fn read_description<R: Read>(reader: &mut R) -> AudioFileResult<AudioStreamBasicDescription> {
+    let sample_rate = f64::from_bits(reader.u64_be()?);
+    let format_id = reader.u32_be()?;
+    let flags = reader.u32_be()?;
+    let bytes_per_packet = reader.u32_be()?;
+    let frames_per_packet = reader.u32_be()?;
+    let channels = reader.u32_be()?;
+    let bits = reader.u32_be()?;
+    if !sample_rate.is_finite() || sample_rate <= 0. || channels == 0 {
+        return Err(AudioFileError::Malformed("desc chunk has no sample rate or channels"));
+    }
+
+    if format_id != ffi::kAudioFormatLinearPCM {
+        let mut desc = ffi::AudioStreamBasicDescription::default();
+        desc.mSampleRate = sample_rate;
+        desc.mFormatID = format_id;
+        desc.mFormatFlags = flags;
+        desc.mBytesPerPacket = bytes_per_packet;
+        desc.mFramesPerPacket = frames_per_packet;
+        desc.mBytesPerFrame = if frames_per_packet == 1 { bytes_per_packet } else { 0 };
+        desc.mChannelsPerFrame = channels;
+        desc.mBitsPerChannel = bits;
+        return Ok(AudioStreamBasicDescription::from(desc));
+    }
+
+    // Linear PCM packets are frames, and integer samples are signed.
+    if frames_per_packet != 1 || bytes_per_packet == 0 || bytes_per_packet % channels != 0 {
+        return Err(AudioFileError::Malformed("desc chunk has an invalid frame size"));
+    }
+    let word_bits = bytes_per_packet / channels * 8;
+    let float = flags & CAF_LPCM_IS_FLOAT != 0;
+    if bits == 0 || bits > word_bits || (float && bits != 32 && bits != 64) {
+        return Err(AudioFileError::Unsupported("CAF sample size"));
+    }
+    let kind = if float {
+        SampleKind::Float
+    } else {
+        SampleKind::SignedInteger
+    };
+    Ok(
+        AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, sample_rate, channels)
+            .sample_kind(kind)
+            .bits_per_channel(bits)
+            .word_bits(word_bits)
+            .big_endian(flags & CAF_LPCM_IS_LITTLE_ENDIAN == 0)
+            .build(),
+    )
+}
+
+/// Reads a number from a packet table, where each byte holds 7 bits,
+/// most significant first, and has its top bit set if more follow.
+fn read_packet_number(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    loop {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        if value >> 57 != 0 {
+            return None;
+        }
+        value = (value << 7) | u64::from(byte & 0x7F);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+fn write_packet_number(value: u64, bytes: &mut Vec<u8>) {
+    let mut groups = 1;
+    while groups < 10 && value >> (7 * groups) != 0 {
+        groups += 1;
+    }
+    for group in (0..groups).rev() {
+        let byte = (value >> (7 * group)) as u8 & 0x7F;
+        bytes.push(if group > 0 { byte | 0x80 } else { byte });
+    }
+}
+
+/// Whether packets of `format` all have the same size and duration.
+fn constant_packets(format: &AudioStreamBasicDescriptionRef) -> bool {
+    format.bytes_per_packet() > 0 && format.frames_per_packet() > 0
+}
+
+/// The contents of a `pakt` chunk.
+struct PacketTable {
+    packets: u64,
+    valid_frames: u64,
+    priming_frames: u32,
+    remainder_frames: u32,
+    /// The description of each packet, for formats whose packets vary.
+    descriptions: Vec<AudioStreamPacketDesc>,
+}
+
+impl PacketTable {
+    fn parse(
+        bytes: &[u8],
+        format: &AudioStreamBasicDescriptionRef,
+        data_size: u64,
+    ) -> AudioFileResult<PacketTable> {
+        let malformed = AudioFileError::Malformed("pakt chunk doesn't match the data");
+        if bytes.len() < PAKT_HEADER_SIZE {
+            return Err(malformed);
+        }
+        let mut header = &bytes[..PAKT_HEADER_SIZE];
+        let packets = header.u64_be()?;
+        let valid_frames = header.u64_be()?;
+        let priming_frames = header.u32_be()?;
+        let remainder_frames = header.u32_be()?;
+        let mut table = PacketTable {
+            packets,
+            valid_frames,
+            priming_frames,
+            remainder_frames,
+            descriptions: Vec::new(),
+        };
+        if constant_packets(format) {
+            return Ok(table);
+        }
+
+        // Every entry takes at least a byte, which bounds the allocation.
+        let mut entries = &bytes[PAKT_HEADER_SIZE..];
+        if packets > entries.len() as u64 {
+            return Err(malformed);
+        }
+        table.descriptions.reserve(packets as usize);
+        let mut offset = 0u64;
+        for _ in 0..packets {
+            let size = match format.bytes_per_packet() {
+                0 => read_packet_number(&mut entries).ok_or(AudioFileError::Malformed(
+                    "pakt chunk doesn't match the data",
+                ))?,
+                size => u64::from(size),
+            };
+            let frames = match format.frames_per_packet() {
+                0 => read_packet_number(&mut entries).ok_or(AudioFileError::Malformed(
+                    "pakt chunk doesn't match the data",
+                ))?,
+                _ => 0,
+            };
+            if size > u64::from(u32::MAX) || frames > u64::from(u32::MAX)
+                || offset + size > data_size
+            {
+                return Err(malformed);
+            }
+            table
+                .descriptions
+                .push(AudioStreamPacketDesc::new(offset as i64, frames as u32, size as u32));
+            offset += size;
+        }
+        Ok(table)
+    }
+}
+
+/// Reads the packets of a CAF file, or for linear PCM, its frames.
+///
+/// Any format can be read packet by packet with `read_packets`, using
+/// the packet table for formats whose packets vary in size or duration.
+/// If the size of the data is unknown, as it is when a recording
+/// stopped before its header was finished, the data is taken to run to
+/// the end of the file.
+pub struct CafReader<R> {
+    reader: R,
+    format: AudioStreamBasicDescription,
+    channel_layout: Option<AudioChannelLayout>,
+    magic_cookie: Option<Vec<u8>>,
+    data_start: u64,
+    data_size: u64,
+    packets: u64,
+    frames: u64,
+    priming_frames: u32,
+    remainder_frames: u32,
+    descriptions: Vec<AudioStreamPacketDesc>,
+    position: u64,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read + Seek> CafReader<R> {
+    /// Reads the header of the file, leaving `reader` at the first
+    /// packet.
+    pub fn new(mut reader: R) -> AudioFileResult<CafReader<R>> {
+        if &reader.four_cc()? != b"caff" {
+            return Err(AudioFileError::Malformed("not a CAF file"));
+        }
+        if reader.u16_be()? != 1 {
+            return Err(AudioFileError::Unsupported("CAF file version"));
+        }
+        let _flags = reader.u16_be()?;
+
+        let mut format = None;
+        let mut channel_layout = None;
+        let mut magic_cookie = None;
+        let mut packet_table = None;
+        let mut data = None;
+        loop {
+            let id = match reader.four_cc() {
+                Ok(id) => id,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && data.is_some() => break,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(AudioFileError::Malformed("no data chunk"))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let size = reader.u64_be()? as i64;
+            if &id == b"data" && size == UNKNOWN_SIZE {
+                let _edit_count = reader.u32_be()?;
+                data = Some((reader.stream_position()?, None));
+                break;
+            }
+            if size < 0 {
+                return Err(AudioFileError::Malformed("chunk has a negative size"));
+            }
+            let size = size as u64;
+            match &id {
+                b"desc" => {
+                    if size < 32 {
+                        return Err(AudioFileError::Malformed("desc chunk is too short"));
+                    }
+                    format = Some(read_description(&mut reader)?);
+                    skip(&mut reader, size - 32)?;
+                }
+                b"chan" => {
+                    let bytes = read_chunk(&mut reader, size)?;
+                    let layout = AudioChannelLayout::from_bytes(&bytes)
+                        .map_err(|_| AudioFileError::Malformed("invalid chan chunk"))?;
+                    channel_layout = Some(layout);
+                }
+                b"kuki" => magic_cookie = Some(read_chunk(&mut reader, size)?),
+                b"pakt" => packet_table = Some(read_chunk(&mut reader, size)?),
+                b"data" => {
+                    if size < EDIT_COUNT_SIZE {
+                        return Err(AudioFileError::Malformed("data chunk is too short"));
+                    }
+                    let _edit_count = reader.u32_be()?;
+                    let start = reader.stream_position()?;
+                    data = Some((start, Some(size - EDIT_COUNT_SIZE)));
+                    reader.seek(SeekFrom::Start(start + size - EDIT_COUNT_SIZE))?;
+                }
+                _ => skip(&mut reader, size)?,
+            }
+        }
+        let format = format.ok_or(AudioFileError::Malformed("no desc chunk"))?;
+        let (data_start, data_size) = data.unwrap();
+        let available = reader.seek(SeekFrom::End(0))?.saturating_sub(data_start);
+        let data_size = data_size.map_or(available, |size| size.min(available));
+
+        let packet_table = match packet_table {
+            Some(bytes) => Some(PacketTable::parse(&bytes, &format, data_size)?),
+            None if constant_packets(&format) => None,
+            None => return Err(AudioFileError::Malformed("no pakt chunk")),
+        };
+        let packets = if constant_packets(&format) {
+            data_size / u64::from(format.bytes_per_packet())
+        } else {
+            packet_table.as_ref().map_or(0, |table| table.packets)
+        };
+        let mut reader = CafReader {
+            reader,
+            frames: packets * u64::from(format.frames_per_packet()),
+            format,
+            channel_layout,
+            magic_cookie,
+            data_start,
+            data_size,
+            packets,
+            priming_frames: 0,
+            remainder_frames: 0,
+            descriptions: Vec::new(),
+            position: 0,
+            buffer: Vec::new(),
+        };
+        if let Some(table) = packet_table {
+            reader.frames = table.valid_frames;
+            reader.priming_frames = table.priming_frames;
+            reader.remainder_frames = table.remainder_frames;
+            reader.descriptions = table.descriptions;
+        }
+        reader.reader.seek(SeekFrom::Start(data_start))?;
+        Ok(reader)
+    }
+}
+
+impl<R> CafReader<R> {
+    /// The format of the file's packets. Linear PCM is always
+    /// interleaved.
+    pub fn format(&self) -> &AudioStreamBasicDescriptionRef {
+        &self.format
+    }
+
+    /// The layout of the file's channels, if it has one.
+    pub fn channel_layout(&self) -> Option<&AudioChannelLayoutRef> {
+        self.channel_layout.as_deref()
+    }
+
+    /// The magic cookie a decoder of the file's format needs, if it
+    /// has one.
+    pub fn magic_cookie(&self) -> Option<&[u8]> {
+        self.magic_cookie.as_deref()
+    }
+
+    /// The number of packets in the file.
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    /// The number of frames in the file, not counting priming and
+    /// remainder frames.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// The number of frames at the start of the first packet that are
+    /// only there to prime the decoder.
+    pub fn priming_frames(&self) -> u32 {
+        self.priming_frames
+    }
+
+    /// The number of frames at the end of the last packet that are
+    /// only there to fill the packet.
+    pub fn remainder_frames(&self) -> u32 {
+        self.remainder_frames
+    }
+
+    /// The index of the next packet to be read, which for linear PCM
+    /// is the next frame.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Where `packet` starts, from the start of the data.
+    fn packet_offset(&self, packet: u64) -> u64 {
+        if constant_packets(&self.format) {
+            packet * u64::from(self.format.bytes_per_packet())
+        } else if packet < self.descriptions.len() as u64 {
+            self.descriptions[packet as usize].start_offset() as u64
+        } else {
+            self.descriptions
+                .last()
+                .map_or(0, |last| last.start_offset() as u64 + u64::from(last.data_byte_size()))
+        }
+    }
+}
+
+impl<R> fmt::Debug for CafReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CafReader")
+            .field("format", &self.format.to_string())
+            .field("packets", &self.packets)
+            .field("frames", &self.frames)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl<R: Read + Seek> CafReader<R> {
+    /// Moves to `packet`, or to the end of the file if it's past the
+    /// end.
+    pub fn seek(&mut self, packet: u64) -> AudioFileResult<()> {
+        let packet = packet.min(self.packets);
+        let offset = self.packet_offset(packet);
+        self.reader.seek(SeekFrom::Start(self.data_start + offset))?;
+        self.position = packet;
+        Ok(())
+    }
+
+    /// Reads up to `packets` packets into `data`, replacing its
+    /// contents, with a description of each in `descriptions`, and
+    /// returns the number read. The descriptions' offsets are from the
+    /// start of `data`. Returns 0 at the end of the file.
+    pub fn read_packets(
+        &mut self,
+        packets: usize,
+        data: &mut Vec<u8>,
+        descriptions: &mut Vec<AudioStreamPacketDesc>,
+    ) -> AudioFileResult<usize> {
+        let end = self.position.saturating_add(packets as u64).min(self.packets);
+        let start_offset = self.packet_offset(self.position);
+        let end_offset = self.packet_offset(end).min(self.data_size);
+        data.clear();
+        descriptions.clear();
+        (&mut self.reader).take(end_offset - start_offset).read_to_end(data)?;
+        if (data.len() as u64) < end_offset - start_offset {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        for packet in self.position..end {
+            let offset = (self.packet_offset(packet) - start_offset) as i64;
+            let description = if constant_packets(&self.format) {
+                AudioStreamPacketDesc::new(offset, 0, self.format.bytes_per_packet())
+            } else {
+                let packet = &self.descriptions[packet as usize];
+                AudioStreamPacketDesc::new(
+                    offset,
+                    packet.variable_frames_in_packet(),
+                    packet.data_byte_size(),
+                )
+            };
+            descriptions.push(description);
+        }
+        let read = (end - self.position) as usize;
+        self.position = end;
+        Ok(read)
+    }
+
+    /// Reads as many frames of a linear PCM file as fit in `abl`, and
+    /// returns the number read. The list can be interleaved or not, but
+    /// must have a channel for each of the file's channels, with
+    /// samples of the file's size. Returns 0 at the end of the file.
+    pub fn read(&mut self, abl: &mut AudioBufferListRef) -> AudioFileResult<usize> {
+        if !self.format.is_lpcm() {
+            return Err(AudioFileError::Unsupported("reading frames of a compressed format"));
+        }
+        let channels = self.format.channels_per_frame() as usize;
+        let sample_bytes = self.format.sample_word_size() as usize;
+        let frames = buffer_frames(abl, channels, sample_bytes)?;
+        let frames = (frames as u64).min(self.packets - self.position) as usize;
+        self.buffer.resize(frames * channels * sample_bytes, 0);
+        self.reader.read_exact(&mut self.buffer)?;
+        scatter(&self.buffer, abl, sample_bytes);
+        self.position += frames as u64;
+        Ok(frames)
+    }
+}
+
+/// Writes the packets of a CAF file, or for linear PCM, its frames.
+///
+/// The header is written first with the size of the data unknown, so
+/// the file can be read even if the writer never finishes it. `finalize`
+/// fills in the size and, for formats whose packets vary, appends the
+/// packet table. The file is finalized when the writer is dropped,
+/// ignoring errors, so call `finalize` or `into_inner` to see them.
+pub struct CafWriter<W: Write + Seek> {
+    writer: Option<W>,
+    format: AudioStreamBasicDescription,
+    data_size_at: u64,
+    data_start: u64,
+    data_size: u64,
+    packets: u64,
+    frames: u64,
+    priming_frames: u32,
+    remainder_frames: u32,
+    /// The encoded packet table, for formats whose packets vary.
+    table: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write + Seek> CafWriter<W> {
+    /// Starts a file of packets in `format`, without a channel layout.
+    pub fn new(
+        writer: W,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> AudioFileResult<CafWriter<W>> {
+        CafWriter::start(writer, format, None, None)
+    }
+
+    /// Starts a file of packets in `format`, with the channels in
+    /// `layout`.
+    pub fn with_channel_layout(
+        writer: W,
+        format: &AudioStreamBasicDescriptionRef,
+        layout: &AudioChannelLayoutRef,
+    ) -> AudioFileResult<CafWriter<W>> {
+        CafWriter::start(writer, format, Some(layout), None)
+    }
+
+    /// Starts a file of packets in a compressed `format` whose decoder
+    /// needs `magic_cookie`, with the channels in `layout` if there is
+    /// one.
+    pub fn with_magic_cookie(
+        writer: W,
+        format: &AudioStreamBasicDescriptionRef,
+        magic_cookie: &[u8],
+        layout: Option<&AudioChannelLayoutRef>,
+    ) -> AudioFileResult<CafWriter<W>> {
+        CafWriter::start(writer, format, layout, Some(magic_cookie))
+    }
+
+    fn start(
+        mut writer: W,
+        format: &AudioStreamBasicDescriptionRef,
+        layout: Option<&AudioChannelLayoutRef>,
+        magic_cookie: Option<&[u8]>,
+    ) -> AudioFileResult<CafWriter<W>> {
+        check_sample_rate(format)?;
+        let file_format = format.to_owned();
+        let channels = format.channels_per_frame();
+        let flags = if format.is_lpcm() {
+            if format.validate().is_err() {
+                return Err(AudioFileError::Unsupported("inconsistent stream description"));
+            }
+            let format_flags = format.format_flags();
+            let raw_flags = unsafe { (*format.as_ptr()).mFormatFlags };
+            if raw_flags & ffi::kLinearPCMFormatFlagsSampleFractionMask != 0 {
+                return Err(AudioFileError::Unsupported("fixed point CAF samples"));
+            }
+            let word_bits = format.sample_word_size().saturating_mul(8);
+            if format.bits_per_channel() < word_bits
+                && !format_flags.contains(AudioFormatFlags::IS_ALIGNED_HIGH)
+            {
+                return Err(AudioFileError::Unsupported("low aligned CAF samples"));
+            }
+            if !format.is_float() && !format_flags.contains(AudioFormatFlags::IS_SIGNED_INTEGER) {
+                return Err(AudioFileError::Unsupported("unsigned CAF samples"));
+            }
+            let frame_bytes = channels * word_bits / 8;
+            unsafe {
+                let asbd = &mut *file_format.as_ptr();
+                asbd.mFormatFlags &= !AudioFormatFlags::IS_NON_INTERLEAVED.bits();
+                asbd.mBytesPerFrame = frame_bytes;
+                asbd.mBytesPerPacket = frame_bytes;
+                asbd.mFramesPerPacket = 1;
+            }
+            let mut flags = 0;
+            if format.is_float() {
+                flags |= CAF_LPCM_IS_FLOAT;
+            }
+            if !format_flags.contains(AudioFormatFlags::IS_BIG_ENDIAN) {
+                flags |= CAF_LPCM_IS_LITTLE_ENDIAN;
+            }
+            flags
+        } else {
+            let rate = format.sample_rate();
+            if !rate.is_finite() || rate <= 0. || channels == 0 {
+                return Err(AudioFileError::Unsupported("inconsistent stream description"));
+            }
+            unsafe { (*format.as_ptr()).mFormatFlags }
+        };
+        if let Some(layout) = layout {
+            let described = layout.channel_labels().map(|labels| labels.len() as u32);
+            if described.map_or(false, |n| n != channels) {
+                return Err(AudioFileError::BufferLayout);
+            }
+        }
+
+        let mut header = Vec::with_capacity(128);
+        header.extend_from_slice(b"caff");
+        header.extend_from_slice(&1u16.to_be_bytes());
+        header.extend_from_slice(&0u16.to_be_bytes());
+        header.extend_from_slice(b"desc");
+        header.extend_from_slice(&32u64.to_be_bytes());
+        header.extend_from_slice(&file_format.sample_rate().to_bits().to_be_bytes());
+        let format_id: ffi::AudioFormatID = file_format.format_id().into();
+        header.extend_from_slice(&format_id.to_be_bytes());
+        header.extend_from_slice(&flags.to_be_bytes());
+        header.extend_from_slice(&file_format.bytes_per_packet().to_be_bytes());
+        header.extend_from_slice(&file_format.frames_per_packet().to_be_bytes());
+        header.extend_from_slice(&channels.to_be_bytes());
+        header.extend_from_slice(&file_format.bits_per_channel().to_be_bytes());
+        if let Some(layout) = layout {
+            let bytes = layout.to_bytes();
+            header.extend_from_slice(b"chan");
+            header.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+            header.extend_from_slice(&bytes);
+        }
+        if let Some(cookie) = magic_cookie {
+            header.extend_from_slice(b"kuki");
+            header.extend_from_slice(&(cookie.len() as u64).to_be_bytes());
+            header.extend_from_slice(cookie);
+        }
+        header.extend_from_slice(b"data");
+        let data_size_at = header.len() as u64;
+        header.extend_from_slice(&UNKNOWN_SIZE.to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes());
+
+        let start = writer.stream_position()?;
+        writer.write_all(&header)?;
+        Ok(CafWriter {
+            writer: Some(writer),
+            format: file_format,
+            data_size_at: start + data_size_at,
+            data_start: start + header.len() as u64,
+            data_size: 0,
+            packets: 0,
+            frames: 0,
+            priming_frames: 0,
+            remainder_frames: 0,
+            table: Vec::new(),
+            buffer: Vec::new(),
+        })
+    }
+
+    /// The format of the file's packets. It's the format the writer was
+    /// created with, but linear PCM is interleaved.
+    pub fn format(&self) -> &AudioStreamBasicDescriptionRef {
+        &self.format
+    }
+
+    /// The number of packets written so far.
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    /// The number of frames written so far, including priming and
+    /// remainder frames.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Records that the first `priming_frames` frames only prime the
+    /// decoder, and the last `remainder_frames` only fill the last
+    /// packet, as encoders such as AAC's report.
+    pub fn set_priming_and_remainder_frames(&mut self, priming_frames: u32, remainder_frames: u32) {
+        self.priming_frames = priming_frames;
+        self.remainder_frames = remainder_frames;
+    }
+
+    /// Appends every frame in `abl` to a linear PCM file, and returns
+    /// the number written. The list can be interleaved or not, but must
+    /// have a channel for each of the file's channels, with samples of
+    /// the file's size.
+    pub fn write(&mut self, abl: &AudioBufferListRef) -> AudioFileResult<usize> {
+        if !self.format.is_lpcm() {
+            return Err(AudioFileError::Unsupported("writing frames of a compressed format"));
+        }
+        let channels = self.format.channels_per_frame() as usize;
+        let sample_bytes = self.format.sample_word_size() as usize;
+        buffer_frames(abl, channels, sample_bytes)?;
+        let frames = frame_count(abl, sample_bytes);
+        self.buffer.clear();
+        gather(abl, frames, sample_bytes, &mut self.buffer);
+        let writer = self.writer.as_mut().unwrap();
+        writer.seek(SeekFrom::Start(self.data_start + self.data_size))?;
+        writer.write_all(&self.buffer)?;
+        self.data_size += self.buffer.len() as u64;
+        self.packets += frames as u64;
+        self.frames += frames as u64;
+        Ok(frames)
+    }
+
+    /// Appends the packets in `data` described by `descriptions`, in
+    /// the order of the descriptions.
+    ///
+    /// For formats whose packets are all the same size, `descriptions`
+    /// can be empty and `data` is taken to be whole packets. For formats
+    /// whose packets vary in duration, each description must give the
+    /// packet's frames.
+    pub fn write_packets(
+        &mut self,
+        data: &[u8],
+        descriptions: &[AudioStreamPacketDesc],
+    ) -> AudioFileResult<()> {
+        let bytes_per_packet = u64::from(self.format.bytes_per_packet());
+        let frames_per_packet = u64::from(self.format.frames_per_packet());
+        let writer = self.writer.as_mut().unwrap();
+        writer.seek(SeekFrom::Start(self.data_start + self.data_size))?;
+
+        if descriptions.is_empty() {
+            if !constant_packets(&self.format)
+                || data.len() as u64 % bytes_per_packet != 0
+            {
+                return Err(AudioFileError::PacketLayout);
+            }
+            writer.write_all(data)?;
+            let packets = data.len() as u64 / bytes_per_packet;
+            self.data_size += data.len() as u64;
+            self.packets += packets;
+            self.frames += packets * frames_per_packet;
+            return Ok(());
+        }
+
+        let mut packets = Vec::with_capacity(descriptions.len());
+        for description in descriptions {
+            let start = description.start_offset();
+            let size = u64::from(description.data_byte_size());
+            let frames = u64::from(description.variable_frames_in_packet());
+            if start < 0 || start as u64 + size > data.len() as u64
+                || (bytes_per_packet != 0 && size != bytes_per_packet)
+                || (frames_per_packet == 0 && frames == 0)
+            {
+                return Err(AudioFileError::PacketLayout);
+            }
+            packets.push((start as usize, size as usize, frames));
+        }
+        for &(start, size, frames) in &packets {
+            writer.write_all(&data[start..start + size])?;
+            self.data_size += size as u64;
+            self.packets += 1;
+            // Each entry is the packet's size, then its frames, each
+            // only if the format doesn't fix them.
+            if bytes_per_packet == 0 {
+                write_packet_number(size as u64, &mut self.table);
+            }
+            if frames_per_packet == 0 {
+                self.frames += frames;
+                write_packet_number(frames, &mut self.table);
+            } else {
+                self.frames += frames_per_packet;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> CafWriter<W> {
+    /// Fills in the size of the data and writes the packet table after
+    /// it, if the file needs one. More packets can be written
+    /// afterwards.
+    pub fn finalize(&mut self) -> AudioFileResult<()> {
+        let writer = self.writer.as_mut().unwrap();
+        let data_end = self.data_start + self.data_size;
+        writer.seek(SeekFrom::Start(self.data_size_at))?;
+        writer.write_all(&(self.data_size + EDIT_COUNT_SIZE).to_be_bytes())?;
+
+        let has_trim = self.priming_frames != 0 || self.remainder_frames != 0;
+        if !constant_packets(&self.format) || has_trim {
+            let trim = u64::from(self.priming_frames) + u64::from(self.remainder_frames);
+            let mut pakt = Vec::with_capacity(12 + PAKT_HEADER_SIZE + self.table.len());
+            pakt.extend_from_slice(b"pakt");
+            pakt.extend_from_slice(&((PAKT_HEADER_SIZE + self.table.len()) as u64).to_be_bytes());
+            pakt.extend_from_slice(&self.packets.to_be_bytes());
+            pakt.extend_from_slice(&self.frames.saturating_sub(trim).to_be_bytes());
+            pakt.extend_from_slice(&self.priming_frames.to_be_bytes());
+            pakt.extend_from_slice(&self.remainder_frames.to_be_bytes());
+            pakt.extend_from_slice(&self.table);
+            writer.seek(SeekFrom::Start(data_end))?;
+            writer.write_all(&pakt)?;
+        }
+        writer.seek(SeekFrom::Start(data_end))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Finalizes the file and returns the underlying writer.
+    pub fn into_inner(mut self) -> AudioFileResult<W> {
+        self.finalize()?;
+        Ok(self.writer.take().unwrap())
+    }
+}
+
+impl<W: Write + Seek> fmt::Debug for CafWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CafWriter")
+            .field("format", &self.format.to_string())
+            .field("packets", &self.packets)
+            .field("frames", &self.frames)
+            .finish()
+    }
+}
+
+impl<W: Write + Seek> Drop for CafWriter<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.finalize();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use {AudioBufferList, StandardChannelLayoutTag};
+
+    fn format(s: &str) -> AudioStreamBasicDescription {
+        s.parse().unwrap()
+    }
+
+    /// A buffer list of `format` holding `frames` of counting bytes.
+    fn frames(format: &AudioStreamBasicDescriptionRef, frames: usize) -> AudioBufferList {
+        let mut abl = AudioBufferList::allocate(format, frames);
+        for buffer in abl.iter_mut() {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+        }
+        abl
+    }
+
+    fn packet_layout(descriptions: &[AudioStreamPacketDesc]) -> Vec<(i64, u32, u32)> {
+        descriptions
+            .iter()
+            .map(|d| (d.start_offset(), d.variable_frames_in_packet(), d.data_byte_size()))
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn lpcm_round_trips() {
+        for s in &[
+            "s16be@44100x2",
+            "s16le@48000x1",
+            "s24be@48000x2",
+            "s32le@96000x1",
+            "f32le@48000x2",
+            "f64be@48000x1",
+            "2 ch, 48000 Hz, Int24/32, big-endian, interleaved",
+        ] {
+            let format = format(s);
+            let written = frames(&format, 5);
+            let mut writer = CafWriter::new(Cursor::new(Vec::new()), &format).unwrap();
+            assert_eq!(writer.write(&written).unwrap(), 5);
+            let file = writer.into_inner().unwrap().into_inner();
+
+            let mut reader = CafReader::new(Cursor::new(file)).unwrap();
+            assert_eq!(reader.format().to_string(), format.to_string(), "{}", s);
+            assert_eq!(reader.packets(), 5);
+            assert_eq!(reader.frames(), 5);
+            assert!(reader.channel_layout().is_none());
+            let mut read = AudioBufferList::allocate(&format, 8);
+            assert_eq!(reader.read(&mut read).unwrap(), 5);
+            assert_eq!(&read[0][..written[0].len()], &written[0][..], "{}", s);
+            assert_eq!(reader.read(&mut read).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn channel_layouts_round_trip() {
+        let format = format("f32le@48000x2");
+        let layout = AudioChannelLayout::with_tag(StandardChannelLayoutTag::STEREO);
+        let mut writer =
+            CafWriter::with_channel_layout(Cursor::new(Vec::new()), &format, &layout).unwrap();
+        writer.write(&frames(&format, 2)).unwrap();
+        let file = writer.into_inner().unwrap().into_inner();
+        let reader = CafReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.channel_layout(), Some(&*layout));
+    }
+
+    #[test]
+    fn non_interleaved_buffers_round_trip() {
+        let format = AudioStreamBasicDescription::with_lpcm(48000., 2, 16, 16, false, true, true);
+        let written = frames(&format, 4);
+        let mut writer = CafWriter::new(Cursor::new(Vec::new()), &format).unwrap();
+        writer.write(&written).unwrap();
+        let file = writer.into_inner().unwrap().into_inner();
+        let mut reader = CafReader::new(Cursor::new(file)).unwrap();
+        assert!(reader.format().is_interleaved());
+        let mut read = AudioBufferList::allocate(&format, 4);
+        assert_eq!(reader.read(&mut read).unwrap(), 4);
+        assert_eq!(&read[0][..], &written[0][..]);
+        assert_eq!(&read[1][..], &written[1][..]);
+    }
+
+    #[test]
+    fn variable_packets_round_trip() {
+        let aac = format("2 ch, 44100 Hz, 'aac ', 1024 frames/packet");
+        let cookie = [1, 2, 3, 4, 5];
+        let sizes = [7u32, 300, 1, 129, 16384];
+        let mut data = Vec::new();
+        let mut descriptions = Vec::new();
+        for (i, &size) in sizes.iter().enumerate() {
+            descriptions.push(AudioStreamPacketDesc::new(data.len() as i64, 0, size));
+            data.extend((0..size).map(|b| (b as usize + i) as u8));
+        }
+        let mut writer =
+            CafWriter::with_magic_cookie(Cursor::new(Vec::new()), &aac, &cookie, None).unwrap();
+        writer.write_packets(&data, &descriptions[..2]).unwrap();
+        writer.write_packets(&data, &descriptions[2..]).unwrap();
+        writer.set_priming_and_remainder_frames(2112, 100);
+        assert_eq!(writer.packets(), 5);
+        assert_eq!(writer.frames(), 5 * 1024);
+        let file = writer.into_inner().unwrap().into_inner();
+
+        let mut reader = CafReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.format().to_string(), aac.to_string());
+        assert_eq!(reader.magic_cookie(), Some(&cookie[..]));
+        assert_eq!(reader.packets(), 5);
+        assert_eq!(reader.frames(), 5 * 1024 - 2112 - 100);
+        assert_eq!(reader.priming_frames(), 2112);
+        assert_eq!(reader.remainder_frames(), 100);
+
+        let (mut read, mut read_descriptions) = (Vec::new(), Vec::new());
+        assert_eq!(reader.read_packets(3, &mut read, &mut read_descriptions).unwrap(), 3);
+        assert_eq!(read, &data[..308]);
+        assert_eq!(packet_layout(&read_descriptions), packet_layout(&descriptions[..3]));
+        assert_eq!(reader.read_packets(3, &mut read, &mut read_descriptions).unwrap(), 2);
+        assert_eq!(read, &data[308..]);
+        assert_eq!(packet_layout(&read_descriptions), [(0, 0, 129), (129, 0, 16384)]);
+        assert_eq!(reader.read_packets(3, &mut read, &mut read_descriptions).unwrap(), 0);
+
+        reader.seek(3).unwrap();
+        assert_eq!(reader.read_packets(1, &mut read, &mut read_descriptions).unwrap(), 1);
+        assert_eq!(read, &data[308..437]);
+        // Frames can't be read from compressed files.
+        let mut abl = AudioBufferList::allocate(&format("f32@44100x2"), 1);
+        assert!(reader.read(&mut abl).is_err());
+    }
+
+    #[test]
+    fn variable_durations_round_trip() {
+        let format = format("1 ch, 48000 Hz, 'opus'");
+        let mut writer = CafWriter::new(Cursor::new(Vec::new()), &format).unwrap();
+        // Without a duration, a packet of a format whose packets vary in
+        // duration is refused.
+        assert!(writer.write_packets(&[0; 4], &[AudioStreamPacketDesc::new(0, 0, 4)]).is_err());
+        let descriptions = [
+            AudioStreamPacketDesc::new(0, 960, 3),
+            AudioStreamPacketDesc::new(3, 480, 5),
+        ];
+        writer.write_packets(&[9; 8], &descriptions).unwrap();
+        let file = writer.into_inner().unwrap().into_inner();
+
+        let mut reader = CafReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.frames(), 1440);
+        let (mut read, mut read_descriptions) = (Vec::new(), Vec::new());
+        assert_eq!(reader.read_packets(2, &mut read, &mut read_descriptions).unwrap(), 2);
+        assert_eq!(packet_layout(&read_descriptions), packet_layout(&descriptions));
+    }
+
+    #[test]
+    fn constant_packets_round_trip() {
+        let format = format("1 ch, 44100 Hz, 'ima4', 64 frames/packet, 34 bytes/packet");
+        let data: Vec<u8> = (0..34 * 3).map(|b| b as u8).collect();
+        let mut writer = CafWriter::new(Cursor::new(Vec::new()), &format).unwrap();
+        assert!(writer.write_packets(&data[..33], &[]).is_err());
+        writer.write_packets(&data, &[]).unwrap();
+        let file = writer.into_inner().unwrap().into_inner();
+
+        let mut reader = CafReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.packets(), 3);
+        assert_eq!(reader.frames(), 3 * 64);
+        let (mut read, mut read_descriptions) = (Vec::new(), Vec::new());
+        reader.seek(1).unwrap();
+        assert_eq!(reader.read_packets(5, &mut read, &mut read_descriptions).unwrap(), 2);
+        assert_eq!(read, &data[34..]);
+        assert_eq!(packet_layout(&read_descriptions), [(0, 0, 34), (34, 0, 34)]);
+    }
+
+    #[test]
+    fn unknown_data_sizes_run_to_the_end() {
+        // A recording that stopped before its header was finished still
+        // has the size of -1 the writer starts with.
+        let format = format("s16be@48000x2");
+        let written = frames(&format, 6);
+        let mut writer = CafWriter::new(Cursor::new(Vec::new()), &format).unwrap();
+        writer.write(&written).unwrap();
+        let file = writer.writer.take().unwrap().into_inner();
+        let data = file.windows(4).position(|w| w == b"data").unwrap();
+        assert_eq!(&file[data + 4..data + 12], &UNKNOWN_SIZE.to_be_bytes());
+
+        let mut reader = CafReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.packets(), 6);
+        let mut read = AudioBufferList::allocate(&format, 6);
+        assert_eq!(reader.read(&mut read).unwrap(), 6);
+        assert_eq!(&read[0][..], &written[0][..]);
+    }
+
+    #[test]
+    fn writers_reject_what_caf_cant_hold() {
+        let unsupported = |s: &str| CafWriter::new(Cursor::new(Vec::new()), &format(s)).is_err();
+        assert!(unsupported("u16le@48000x2"));
+        assert!(unsupported("s16le@0x2"));
+        assert!(unsupported("1 ch, 48000 Hz, Fixed8.24"));
+        assert!(unsupported("1 ch, 48000 Hz, Int20/32, aligned low"));
+        assert!(!unsupported("2 ch, 48000 Hz, 'aac ', 1024 frames/packet"));
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! The channels of the predefined channel layouts, so layouts can be
+//! expanded and matched without asking the system.
+
+use {AudioChannelDescription, AudioChannelLabel, AudioChannelLayout,
+     AudioChannelLayoutRef, AudioChannelLayoutTag};
+use ffi;
+
+const L: u32 = ffi::kAudioChannelLabel_Left;
+const R: u32 = ffi::kAudioChannelLabel_Right;
+const C: u32 = ffi::kAudioChannelLabel_Center;
+const LFE: u32 = ffi::kAudioChannelLabel_LFEScreen;
+const LS: u32 = ffi::kAudioChannelLabel_LeftSurround;
+const RS: u32 = ffi::kAudioChannelLabel_RightSurround;
+const LC: u32 = ffi::kAudioChannelLabel_LeftCenter;
+const RC: u32 = ffi::kAudioChannelLabel_RightCenter;
+const CS: u32 = ffi::kAudioChannelLabel_CenterSurround;
+const LSD: u32 = ffi::kAudioChannelLabel_LeftSurroundDirect;
+const RSD: u32 = ffi::kAudioChannelLabel_RightSurroundDirect;
+const TS: u32 = ffi::kAudioChannelLabel_TopCenterSurround;
+const VHL: u32 = ffi::kAudioChannelLabel_VerticalHeightLeft;
+const VHC: u32 = ffi::kAudioChannelLabel_VerticalHeightCenter;
+const VHR: u32 = ffi::kAudioChannelLabel_VerticalHeightRight;
+const TBL: u32 = ffi::kAudioChannelLabel_TopBackLeft;
+const TBR: u32 = ffi::kAudioChannelLabel_TopBackRight;
+const RLS: u32 = ffi::kAudioChannelLabel_RearSurroundLeft;
+const RRS: u32 = ffi::kAudioChannelLabel_RearSurroundRight;
+const LW: u32 = ffi::kAudioChannelLabel_LeftWide;
+const RW: u32 = ffi::kAudioChannelLabel_RightWide;
+const LFE2: u32 = ffi::kAudioChannelLabel_LFE2;
+const LT: u32 = ffi::kAudioChannelLabel_LeftTotal;
+const RT: u32 = ffi::kAudioChannelLabel_RightTotal;
+const HI: u32 = ffi::kAudioChannelLabel_HearingImpaired;
+const VI: u32 = ffi::kAudioChannelLabel_Narration;
+const MONO: u32 = ffi::kAudioChannelLabel_Mono;
+const CSD: u32 = ffi::kAudioChannelLabel_CenterSurroundDirect;
+const HAPTIC: u32 = ffi::kAudioChannelLabel_Haptic;
+const HPL: u32 = ffi::kAudioChannelLabel_HeadphonesLeft;
+const HPR: u32 = ffi::kAudioChannelLabel_HeadphonesRight;
+
+/// The channels of each predefined layout, in order, from the comments
+/// on the tags in `CoreAudioBaseTypes.h`. Tags that are aliases of
+/// another appear once, under the first name. Where two layouts have
+/// the same channels, the earlier one is preferred when matching.
+const LAYOUTS: &[(ffi::AudioChannelLayoutTag, &[u32])] = &[
+    (ffi::kAudioChannelLayoutTag_Mono, &[MONO]),
+    (ffi::kAudioChannelLayoutTag_Stereo, &[L, R]),
+    (ffi::kAudioChannelLayoutTag_StereoHeadphones, &[HPL, HPR]),
+    (ffi::kAudioChannelLayoutTag_MatrixStereo, &[LT, RT]),
+    (ffi::kAudioChannelLayoutTag_MidSide,
+     &[ffi::kAudioChannelLabel_MS_Mid, ffi::kAudioChannelLabel_MS_Side]),
+    (ffi::kAudioChannelLayoutTag_XY, &[ffi::kAudioChannelLabel_XY_X, ffi::kAudioChannelLabel_XY_Y]),
+    (ffi::kAudioChannelLayoutTag_Binaural, &[HPL, HPR]),
+    (ffi::kAudioChannelLayoutTag_Ambisonic_B_Format,
+     &[ffi::kAudioChannelLabel_Ambisonic_W, ffi::kAudioChannelLabel_Ambisonic_X,
+       ffi::kAudioChannelLabel_Ambisonic_Y, ffi::kAudioChannelLabel_Ambisonic_Z]),
+    (ffi::kAudioChannelLayoutTag_Quadraphonic, &[L, R, LS, RS]),
+    (ffi::kAudioChannelLayoutTag_Pentagonal, &[L, R, RLS, RRS, C]),
+    (ffi::kAudioChannelLayoutTag_Hexagonal, &[L, R, RLS, RRS, C, CS]),
+    (ffi::kAudioChannelLayoutTag_Octagonal, &[L, R, RLS, RRS, C, CS, LW, RW]),
+    (ffi::kAudioChannelLayoutTag_Cube, &[L, R, RLS, RRS, VHL, VHR, TBL, TBR]),
+    (ffi::kAudioChannelLayoutTag_MPEG_3_0_A, &[L, R, C]),
+    (ffi::kAudioChannelLayoutTag_MPEG_3_0_B, &[C, L, R]),
+    (ffi::kAudioChannelLayoutTag_MPEG_4_0_A, &[L, R, C, CS]),
+    (ffi::kAudioChannelLayoutTag_MPEG_4_0_B, &[C, L, R, CS]),
+    (ffi::kAudioChannelLayoutTag_MPEG_5_0_A, &[L, R, C, LS, RS]),
+    (ffi::kAudioChannelLayoutTag_MPEG_5_0_B, &[L, R, LS, RS, C]),
+    (ffi::kAudioChannelLayoutTag_MPEG_5_0_C, &[L, C, R, LS, RS]),
+    (ffi::kAudioChannelLayoutTag_MPEG_5_0_D, &[C, L, R, LS, RS]),
+    (ffi::kAudioChannelLayoutTag_MPEG_5_1_A, &[L, R, C, LFE, LS, RS]),
+    (ffi::kAudioChannelLayoutTag_MPEG_5_1_B, &[L, R, LS, RS, C, LFE]),
+    (ffi::kAudioChannelLayoutTag_MPEG_5_1_C, &[L, C, R, LS, RS, LFE]),
+    (ffi::kAudioChannelLayoutTag_MPEG_5_1_D, &[C, L, R, LS, RS, LFE]),
+    (ffi::kAudioChannelLayoutTag_MPEG_6_1_A, &[L, R, C, LFE, LS, RS, CS]),
+    (ffi::kAudioChannelLayoutTag_MPEG_7_1_A, &[L, R, C, LFE, LS, RS, LC, RC]),
+    (ffi::kAudioChannelLayoutTag_MPEG_7_1_B, &[C, LC, RC, L, R, LS, RS, LFE]),
+    (ffi::kAudioChannelLayoutTag_MPEG_7_1_C, &[L, R, C, LFE, LS, RS, RLS, RRS]),
+    (ffi::kAudioChannelLayoutTag_Emagic_Default_7_1, &[L, R, LS, RS, C, LFE, LC, RC]),
+    (ffi::kAudioChannelLayoutTag_SMPTE_DTV, &[L, R, C, LFE, LS, RS, LT, RT]),
+    (ffi::kAudioChannelLayoutTag_ITU_2_1, &[L, R, CS]),
+    (ffi::kAudioChannelLayoutTag_ITU_2_2, &[L, R, LS, RS]),
+    (ffi::kAudioChannelLayoutTag_DVD_4, &[L, R, LFE]),
+    (ffi::kAudioChannelLayoutTag_DVD_5, &[L, R, LFE, CS]),
+    (ffi::kAudioChannelLayoutTag_DVD_6, &[L, R, LFE, LS, RS]),
+    (ffi::kAudioChannelLayoutTag_DVD_10, &[L, R, C, LFE]),
+    (ffi::kAudioChannelLayoutTag_DVD_11, &[L, R, C, LFE, CS]),
+    (ffi::kAudioChannelLayoutTag_DVD_18, &[L, R, LS, RS, LFE]),
+    (ffi::kAudioChannelLayoutTag_AudioUnit_6_0, &[L, R, LS, RS, C, CS]),
+    (ffi::kAudioChannelLayoutTag_AudioUnit_7_0, &[L, R, LS, RS, C, RLS, RRS]),
+    (ffi::kAudioChannelLayoutTag_AudioUnit_7_0_Front, &[L, R, LS, RS, C, LC, RC]),
+    (ffi::kAudioChannelLayoutTag_AAC_6_0, &[C, L, R, LS, RS, CS]),
+    (ffi::kAudioChannelLayoutTag_AAC_6_1, &[C, L, R, LS, RS, CS, LFE]),
+    (ffi::kAudioChannelLayoutTag_AAC_7_0, &[C, L, R, LS, RS, RLS, RRS]),
+    (ffi::kAudioChannelLayoutTag_AAC_7_1_B, &[C, L, R, LS, RS, RLS, RRS, LFE]),
+    (ffi::kAudioChannelLayoutTag_AAC_7_1_C, &[C, L, R, LS, RS, LFE, VHL, VHR]),
+    (ffi::kAudioChannelLayoutTag_AAC_Octagonal, &[C, L, R, LS, RS, RLS, RRS, CS]),
+    (ffi::kAudioChannelLayoutTag_TMH_10_2_std,
+     &[L, R, C, VHC, LSD, RSD, LS, RS, VHL, VHR, LW, RW, CSD, CS, LFE, LFE2]),
+    (ffi::kAudioChannelLayoutTag_TMH_10_2_full,
+     &[L, R, C, VHC, LSD, RSD, LS, RS, VHL, VHR, LW, RW, CSD, CS, LFE, LFE2,
+       LC, RC, HI, VI, HAPTIC]),
+    (ffi::kAudioChannelLayoutTag_AC3_1_0_1, &[C, LFE]),
+    (ffi::kAudioChannelLayoutTag_AC3_3_0, &[L, C, R]),
+    (ffi::kAudioChannelLayoutTag_AC3_3_1, &[L, C, R, CS]),
+    (ffi::kAudioChannelLayoutTag_AC3_3_0_1, &[L, C, R, LFE]),
+    (ffi::kAudioChannelLayoutTag_AC3_2_1_1, &[L, R, CS, LFE]),
+    (ffi::kAudioChannelLayoutTag_AC3_3_1_1, &[L, C, R, CS, LFE]),
+    (ffi::kAudioChannelLayoutTag_EAC_6_0_A, &[L, C, R, LS, RS, CS]),
+    (ffi::kAudioChannelLayoutTag_EAC_7_0_A, &[L, C, R, LS, RS, RLS, RRS]),
+    (ffi::kAudioChannelLayoutTag_EAC3_6_1_A, &[L, C, R, LS, RS, LFE, CS]),
+    (ffi::kAudioChannelLayoutTag_EAC3_6_1_B, &[L, C, R, LS, RS, LFE, TS]),
+    (ffi::kAudioChannelLayoutTag_EAC3_6_1_C, &[L, C, R, LS, RS, LFE, VHC]),
+    (ffi::kAudioChannelLayoutTag_EAC3_7_1_A, &[L, C, R, LS, RS, LFE, RLS, RRS]),
+    (ffi::kAudioChannelLayoutTag_EAC3_7_1_B, &[L, C, R, LS, RS, LFE, LC, RC]),
+    (ffi::kAudioChannelLayoutTag_EAC3_7_1_C, &[L, C, R, LS, RS, LFE, LSD, RSD]),
+    (ffi::kAudioChannelLayoutTag_EAC3_7_1_D, &[L, C, R, LS, RS, LFE, LW, RW]),
+    (ffi::kAudioChannelLayoutTag_EAC3_7_1_E, &[L, C, R, LS, RS, LFE, VHL, VHR]),
+    (ffi::kAudioChannelLayoutTag_EAC3_7_1_F, &[L, C, R, LS, RS, LFE, CS, TS]),
+    (ffi::kAudioChannelLayoutTag_EAC3_7_1_G, &[L, C, R, LS, RS, LFE, CS, VHC]),
+    (ffi::kAudioChannelLayoutTag_EAC3_7_1_H, &[L, C, R, LS, RS, LFE, TS, VHC]),
+    (ffi::kAudioChannelLayoutTag_DTS_3_1, &[C, L, R, LFE]),
+    (ffi::kAudioChannelLayoutTag_DTS_4_1, &[C, L, R, CS, LFE]),
+    (ffi::kAudioChannelLayoutTag_DTS_6_0_A, &[LC, RC, L, R, LS, RS]),
+    (ffi::kAudioChannelLayoutTag_DTS_6_0_B, &[C, L, R, RLS, RRS, TS]),
+    (ffi::kAudioChannelLayoutTag_DTS_6_0_C, &[C, CS, L, R, RLS, RRS]),
+    (ffi::kAudioChannelLayoutTag_DTS_6_1_A, &[LC, RC, L, R, LS, RS, LFE]),
+    (ffi::kAudioChannelLayoutTag_DTS_6_1_B, &[C, L, R, RLS, RRS, TS, LFE]),
+    (ffi::kAudioChannelLayoutTag_DTS_6_1_C, &[C, CS, L, R, RLS, RRS, LFE]),
+    (ffi::kAudioChannelLayoutTag_DTS_7_0, &[LC, C, RC, L, R, LS, RS]),
+    (ffi::kAudioChannelLayoutTag_DTS_7_1, &[LC, C, RC, L, R, LS, RS, LFE]),
+    (ffi::kAudioChannelLayoutTag_DTS_8_0_A, &[LC, RC, L, R, LS, RS, RLS, RRS]),
+    (ffi::kAudioChannelLayoutTag_DTS_8_0_B, &[LC, C, RC, L, R, LS, CS, RS]),
+    (ffi::kAudioChannelLayoutTag_DTS_8_1_A, &[LC, RC, L, R, LS, RS, RLS, RRS, LFE]),
+    (ffi::kAudioChannelLayoutTag_DTS_8_1_B, &[LC, C, RC, L, R, LS, CS, RS, LFE]),
+    (ffi::kAudioChannelLayoutTag_DTS_6_1_D, &[C, L, R, LS, RS, LFE, CS]),
+];
+
+/// Layouts whose channels are numbered from a first label rather than
+/// listed, with any number of channels.
+const NUMBERED_LAYOUTS: &[(ffi::AudioChannelLayoutTag, u32)] = &[
+    (ffi::kAudioChannelLayoutTag_DiscreteInOrder, ffi::kAudioChannelLabel_Discrete_0),
+    (ffi::kAudioChannelLayoutTag_HOA_ACN_SN3D, ffi::kAudioChannelLabel_HOA_ACN_0),
+    (ffi::kAudioChannelLayoutTag_HOA_ACN_N3D, ffi::kAudioChannelLabel_HOA_ACN_0),
+];
+
+/// The ranges of the channel labels Core Audio defines.
+const DEFINED_LABELS: &[(u32, u32)] = &[
+    (ffi::kAudioChannelLabel_Unused, ffi::kAudioChannelLabel_TopBackRight),
+    (ffi::kAudioChannelLabel_RearSurroundLeft, ffi::kAudioChannelLabel_Haptic),
+    (ffi::kAudioChannelLabel_UseCoordinates, ffi::kAudioChannelLabel_UseCoordinates),
+    (ffi::kAudioChannelLabel_Ambisonic_W, ffi::kAudioChannelLabel_XY_Y),
+    (ffi::kAudioChannelLabel_HeadphonesLeft, ffi::kAudioChannelLabel_HeadphonesRight),
+    (ffi::kAudioChannelLabel_ClickTrack, ffi::kAudioChannelLabel_ForeignLanguage),
+    (ffi::kAudioChannelLabel_Discrete, ffi::kAudioChannelLabel_Discrete),
+    (ffi::kAudioChannelLabel_HOA_ACN, ffi::kAudioChannelLabel_HOA_ACN),
+    (ffi::kAudioChannelLabel_Discrete_0, ffi::kAudioChannelLabel_Discrete_65535),
+    (ffi::kAudioChannelLabel_HOA_ACN_0, ffi::kAudioChannelLabel_HOA_ACN_65024),
+    (ffi::kAudioChannelLabel_Unknown, ffi::kAudioChannelLabel_Unknown),
+];
+
+impl AudioChannelLabel {
+    /// Whether Core Audio defines the label.
+    pub(crate) fn is_defined(&self) -> bool {
+        let label = ffi::AudioChannelLabel::from(*self);
+        DEFINED_LABELS
+            .iter()
+            .any(|&(first, last)| first <= label && label <= last)
+    }
+}
+
+fn to_labels(labels: &[u32]) -> Vec<AudioChannelLabel> {
+    labels.iter().map(|&l| AudioChannelLabel::from(l)).collect()
+}
+
+impl AudioChannelLayoutTag {
+    /// The labels of the channels of the layout, in order, as
+    /// `kAudioFormatProperty_ChannelLayoutForTag` would describe them.
+    ///
+    /// An unknown layout has channels labelled `UNKNOWN`. Returns `None`
+    /// for `USE_CHANNEL_DESCRIPTIONS` and `USE_CHANNEL_BITMAP`, which
+    /// say nothing about the channels themselves, and for tags not in
+    /// the table.
+    pub fn channel_labels(&self) -> Option<Vec<AudioChannelLabel>> {
+        let tag = ffi::AudioChannelLayoutTag::from(*self);
+        if let Some(&(_, labels)) = LAYOUTS.iter().find(|&&(t, _)| t == tag) {
+            return Some(to_labels(labels));
+        }
+        let kind = tag & 0xFFFF_0000;
+        let channels = self.channels() as u32;
+        if let Some(&(_, first)) = NUMBERED_LAYOUTS.iter().find(|&&(t, _)| t == kind) {
+            return Some((first..first + channels).map(AudioChannelLabel::from).collect());
+        }
+        if kind == ffi::kAudioChannelLayoutTag_Unknown {
+            return Some(vec![AudioChannelLabel::UNKNOWN; channels as usize]);
+        }
+        None
+    }
+
+    /// Whether Core Audio defines the tag: a predefined layout with its
+    /// own number of channels, numbered or unknown channels of any
+    /// number, or `USE_CHANNEL_DESCRIPTIONS` or `USE_CHANNEL_BITMAP`.
+    pub(crate) fn is_defined(&self) -> bool {
+        let tag = ffi::AudioChannelLayoutTag::from(*self);
+        let kind = tag & 0xFFFF_0000;
+        self.use_channel_descriptions()
+            || self.use_channel_bitmap()
+            || kind == ffi::kAudioChannelLayoutTag_Unknown
+            || NUMBERED_LAYOUTS.iter().any(|&(t, _)| t == kind)
+            || LAYOUTS.iter().any(|&(t, _)| t == tag)
+    }
+
+    /// The predefined layout with exactly `labels`, in order, if there
+    /// is one. Failing that, the predefined layout with the same
+    /// channels in a different order.
+    pub fn for_labels(labels: &[AudioChannelLabel]) -> Option<AudioChannelLayoutTag> {
+        if labels.is_empty() || labels.len() > 0xFFFF {
+            return None;
+        }
+        let labels = labels
+            .iter()
+            .map(|&l| ffi::AudioChannelLabel::from(l))
+            .collect::<Vec<_>>();
+        // A lone center channel is mono.
+        if labels == [C] {
+            return Some(AudioChannelLayoutTag::from(ffi::kAudioChannelLayoutTag_Mono));
+        }
+        if let Some(&(tag, _)) = LAYOUTS.iter().find(|&&(_, l)| l == &labels[..]) {
+            return Some(AudioChannelLayoutTag::from(tag));
+        }
+        for &(tag, first) in NUMBERED_LAYOUTS {
+            if labels.iter().zip(first..).all(|(&l, n)| l == n) {
+                return Some(AudioChannelLayoutTag::from(tag | labels.len() as u32));
+            }
+        }
+        let mut sorted = labels.clone();
+        sorted.sort_unstable();
+        LAYOUTS
+            .iter()
+            .find(|&&(_, l)| {
+                let mut l = l.to_vec();
+                l.sort_unstable();
+                l == sorted
+            })
+            .map(|&(tag, _)| AudioChannelLayoutTag::from(tag))
+    }
+}
+
+/// The labels of the channels in `bitmap`, in the order of the bits.
+fn bitmap_labels(bitmap: ffi::AudioChannelBitmap) -> Vec<AudioChannelLabel> {
+    // Channel bit `n` is for the label with value `n + 1`.
+    (0..32)
+        .filter(|n| bitmap & (1 << n) != 0)
+        .map(|n| AudioChannelLabel::from(n + 1))
+        .collect()
+}
+
+impl AudioChannelLayout {
+    /// A layout of the predefined channels of `tag`, without channel
+    /// descriptions.
+    pub fn with_tag(tag: AudioChannelLayoutTag) -> Self {
+        let mut layout = AudioChannelLayout::with_len(0);
+        layout.set_channel_layout_tag(tag);
+        layout
+    }
+
+    /// A layout of the channels in `bitmap`, in the order of the bits.
+    pub fn with_bitmap(bitmap: ::AudioChannelBitmap) -> Self {
+        let mut layout = AudioChannelLayout::with_tag(AudioChannelLayoutTag::USE_CHANNEL_BITMAP);
+        layout.set_channel_bitmap(bitmap);
+        layout
+    }
+
+    /// A layout with a description for each of `labels`.
+    pub fn with_labels(labels: &[AudioChannelLabel]) -> Self {
+        let mut layout = AudioChannelLayout::with_len(labels.len());
+        layout.set_channel_layout_tag(AudioChannelLayoutTag::USE_CHANNEL_DESCRIPTIONS);
+        for (description, &label) in layout.iter_mut().zip(labels) {
+            *description = AudioChannelDescription::new(label);
+        }
+        layout
+    }
+}
+
+impl AudioChannelLayoutRef {
+    /// The labels of the channels in the layout, in order, whether it's
+    /// given by a tag, a bitmap or channel descriptions.
+    pub fn channel_labels(&self) -> Option<Vec<AudioChannelLabel>> {
+        let tag = self.channel_layout_tag();
+        if tag.use_channel_descriptions() {
+            Some(self.iter().map(|d| d.channel_label).collect())
+        } else if tag.use_channel_bitmap() {
+            Some(bitmap_labels(self.channel_bitmap().bits()))
+        } else {
+            tag.channel_labels()
+        }
+    }
+
+    /// The layout as a channel bitmap, if it can be one: every channel
+    /// has a label with a bit, and the channels are in the order of
+    /// their bits.
+    pub fn to_bitmap(&self) -> Option<::AudioChannelBitmap> {
+        if self.channel_layout_tag().use_channel_bitmap() {
+            return Some(self.channel_bitmap());
+        }
+        let mut bits: ffi::AudioChannelBitmap = 0;
+        for label in self.channel_labels()? {
+            // Channel bit `n` is for the label with value `n + 1`.
+            let label = ffi::AudioChannelLabel::from(label);
+            let bit = 1u32.checked_shl(label.checked_sub(1)?)?;
+            if bits >= bit {
+                return None;
+            }
+            bits |= bit;
+        }
+        ::AudioChannelBitmap::from_bits(bits)
+    }
+
+    /// The predefined layout that best describes this layout: its own
+    /// tag, or the tag matching its descriptions or bitmap. See
+    /// `AudioChannelLayoutTag::for_labels`.
+    pub fn best_matching_tag(&self) -> Option<AudioChannelLayoutTag> {
+        let tag = self.channel_layout_tag();
+        if !tag.use_channel_descriptions() && !tag.use_channel_bitmap() {
+            return Some(tag);
+        }
+        AudioChannelLayoutTag::for_labels(&self.channel_labels()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioChannelBitmap, StandardChannelLayoutTag};
+
+    fn tag(tag: ffi::AudioChannelLayoutTag) -> AudioChannelLayoutTag {
+        AudioChannelLayoutTag::from(tag)
+    }
+
+    #[test]
+    fn table_matches_the_channel_counts() {
+        for &(t, labels) in LAYOUTS {
+            assert_eq!(tag(t).channels(), labels.len(), "tag {:#x}", t);
+            assert_eq!(tag(t).channel_labels(), Some(to_labels(labels)));
+        }
+    }
+
+    #[test]
+    fn labels_of_tags() {
+        assert_eq!(
+            StandardChannelLayoutTag::STEREO.channel_labels(),
+            Some(to_labels(&[L, R]))
+        );
+        assert_eq!(
+            AudioChannelLayoutTag::discrete_in_order(3).channel_labels(),
+            Some(to_labels(&[
+                ffi::kAudioChannelLabel_Discrete_0,
+                ffi::kAudioChannelLabel_Discrete_1,
+                ffi::kAudioChannelLabel_Discrete_2,
+            ]))
+        );
+        assert_eq!(
+            tag(ffi::kAudioChannelLayoutTag_HOA_ACN_SN3D | 4).channel_labels(),
+            Some(to_labels(&[
+                ffi::kAudioChannelLabel_HOA_ACN_0,
+                ffi::kAudioChannelLabel_HOA_ACN_1,
+                ffi::kAudioChannelLabel_HOA_ACN_2,
+                ffi::kAudioChannelLabel_HOA_ACN_3,
+            ]))
+        );
+        assert_eq!(
+            AudioChannelLayoutTag::unknown(2).channel_labels(),
+            Some(vec![AudioChannelLabel::UNKNOWN; 2])
+        );
+        assert_eq!(AudioChannelLayoutTag::USE_CHANNEL_DESCRIPTIONS.channel_labels(), None);
+        assert_eq!(AudioChannelLayoutTag::USE_CHANNEL_BITMAP.channel_labels(), None);
+        assert_eq!(tag(0x7fff_0002).channel_labels(), None);
+    }
+
+    #[test]
+    fn tags_for_labels() {
+        let labels = |l: &[u32]| to_labels(l);
+        assert_eq!(
+            AudioChannelLayoutTag::for_labels(&labels(&[L, R])),
+            Some(StandardChannelLayoutTag::STEREO)
+        );
+        assert_eq!(
+            AudioChannelLayoutTag::for_labels(&labels(&[C])),
+            Some(StandardChannelLayoutTag::MONO)
+        );
+        // The earlier of two layouts with the same channels wins.
+        assert_eq!(
+            AudioChannelLayoutTag::for_labels(&labels(&[HPL, HPR])),
+            Some(StandardChannelLayoutTag::STEREO_HEADPHONES)
+        );
+        // Same channels, different order.
+        assert_eq!(
+            AudioChannelLayoutTag::for_labels(&labels(&[R, L])),
+            Some(StandardChannelLayoutTag::STEREO)
+        );
+        assert_eq!(
+            AudioChannelLayoutTag::for_labels(&labels(&[
+                ffi::kAudioChannelLabel_Discrete_0,
+                ffi::kAudioChannelLabel_Discrete_1,
+            ])),
+            Some(AudioChannelLayoutTag::discrete_in_order(2))
+        );
+        assert_eq!(AudioChannelLayoutTag::for_labels(&labels(&[L, L])), None);
+        assert_eq!(AudioChannelLayoutTag::for_labels(&[]), None);
+    }
+
+    #[test]
+    fn layout_labels_and_bitmaps() {
+        let bitmap = AudioChannelBitmap::LEFT | AudioChannelBitmap::RIGHT
+            | AudioChannelBitmap::LFE_SCREEN;
+        let layout = AudioChannelLayout::with_bitmap(bitmap);
+        assert_eq!(layout.channel_labels(), Some(to_labels(&[L, R, LFE])));
+        assert_eq!(layout.to_bitmap(), Some(bitmap));
+        assert_eq!(layout.best_matching_tag(), Some(tag(ffi::kAudioChannelLayoutTag_DVD_4)));
+
+        let layout = AudioChannelLayout::with_labels(&to_labels(&[L, R, C]));
+        assert_eq!(layout.channel_labels(), Some(to_labels(&[L, R, C])));
+        assert_eq!(
+            layout.to_bitmap(),
+            Some(AudioChannelBitmap::LEFT | AudioChannelBitmap::RIGHT | AudioChannelBitmap::CENTER)
+        );
+        assert_eq!(
+            layout.best_matching_tag(),
+            Some(tag(ffi::kAudioChannelLayoutTag_MPEG_3_0_A))
+        );
+
+        // Out of bit order, or labels without a bit, have no bitmap.
+        let layout = AudioChannelLayout::with_tag(tag(ffi::kAudioChannelLayoutTag_MPEG_3_0_B));
+        assert_eq!(layout.to_bitmap(), None);
+        assert_eq!(
+            layout.best_matching_tag(),
+            Some(tag(ffi::kAudioChannelLayoutTag_MPEG_3_0_B))
+        );
+        let layout = AudioChannelLayout::with_labels(&to_labels(&[HPL, HPR]));
+        assert_eq!(layout.to_bitmap(), None);
+    }
+}
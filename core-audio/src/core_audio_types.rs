@@ -1,6 +1,8 @@
 use audio_channel_layout::AudioChannelLayoutRef;
 use ffi;
-use std::{fmt, mem, ops, slice};
+use four_char_code::{fmt_four_char_code, parse_four_char_code};
+use std::{fmt, mem, ops, slice, str};
+use ParseFormatError;
 
 /// This struct represents a continuous range of values.
 pub type AudioValueRange = ffi::AudioValueRange;
@@ -35,6 +37,9 @@ impl ops::Deref for AudioBufferRef {
     fn deref(&self) -> &Self::Target {
         unsafe {
             let ab: &ffi::AudioBuffer = &*self.as_ptr();
+            if ab.mData.is_null() {
+                return &[];
+            }
             slice::from_raw_parts(ab.mData as *const _, ab.mDataByteSize as _)
         }
     }
@@ -44,6 +49,9 @@ impl ops::DerefMut for AudioBufferRef {
     fn deref_mut(&mut self) -> &mut [u8] {
         unsafe {
             let ab: &ffi::AudioBuffer = &*self.as_ptr();
+            if ab.mData.is_null() {
+                return &mut [];
+            }
             slice::from_raw_parts_mut(ab.mData as *mut _, ab.mDataByteSize as _)
         }
     }
@@ -260,6 +268,24 @@ impl Into<ffi::AudioFormatID> for AudioFormat {
     }
 }
 
+impl fmt::Display for AudioFormat {
+    /// Writes the format's four char code, such as `'lpcm'`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_four_char_code((*self).into(), f)
+    }
+}
+
+impl str::FromStr for AudioFormat {
+    type Err = ParseFormatError;
+
+    /// Parses a four char code, with or without quotes, or a hex number.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_four_char_code(s)
+            .map(AudioFormat::from)
+            .ok_or(ParseFormatError::FourCharCode)
+    }
+}
+
 bitflags! {
     /// Flags that are specific to each `AudioFormat`.
     pub struct AudioFormatFlags: ffi::AudioFormatFlags {
@@ -314,7 +340,8 @@ impl AudioFormatFlags {
     /// # Note
     ///
     /// This function does not support specifying sample formats that
-    /// are either unsigned integer or low-aligned.
+    /// are either unsigned integer or low-aligned. Use `AsbdBuilder`
+    /// for those.
     pub fn with_lpcm_flags(
         valid_bits_per_channel: u32,
         total_bits_per_channel: u32,
@@ -356,6 +383,13 @@ impl LinearPcmFlags {
     const SAMPLE_FRACTION_SHIFT: u32 = ffi::kLinearPCMFormatFlagsSampleFractionShift;
     const SAMPLE_FRACTION_MASK: u32 = ffi::kLinearPCMFormatFlagsSampleFractionMask;
 
+    /// Linear PCM `flags` for fixed point samples with `bits` of
+    /// fraction.
+    pub fn with_fractional_bits(flags: AudioFormatFlags, bits: usize) -> Self {
+        let fraction = (bits as u32) << Self::SAMPLE_FRACTION_SHIFT;
+        LinearPcmFlags(flags.bits() | (fraction & Self::SAMPLE_FRACTION_MASK))
+    }
+
     pub fn number_fractional_bits(&self) -> usize {
         ((self.0 & Self::SAMPLE_FRACTION_MASK) >> Self::SAMPLE_FRACTION_SHIFT) as usize
     }
@@ -440,6 +474,7 @@ impl AudioStreamBasicDescription {
     /// # Note
     ///
     /// This function does not support specifying sample formats that are either unsigned integer or low-aligned.
+    /// Use `AudioStreamBasicDescription::builder` for those.
     pub fn with_lpcm(
         sample_rate: f64,
         channels_per_frame: u32,
@@ -537,6 +572,19 @@ ffi_type_stack! {
     pub struct AudioStreamPacketDescRef;
 }
 
+impl AudioStreamPacketDesc {
+    /// A description of a packet of `data_byte_size` bytes at
+    /// `start_offset`. `variable_frames_in_packet` is 0 for formats
+    /// with a constant number of frames per packet.
+    pub fn new(start_offset: i64, variable_frames_in_packet: u32, data_byte_size: u32) -> Self {
+        AudioStreamPacketDesc(ffi::AudioStreamPacketDescription {
+            mStartOffset: start_offset,
+            mVariableFramesInPacket: variable_frames_in_packet,
+            mDataByteSize: data_byte_size,
+        })
+    }
+}
+
 impl AudioStreamPacketDescRef {
     #[doc(hidden)]
     #[inline]
@@ -745,7 +793,7 @@ impl fmt::Debug for AudioTimeStamp {
 
 /// A tag identifying how the channel is to be used.
 #[repr(C)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct AudioChannelLabel(ffi::AudioChannelLabel);
 
 impl AudioChannelLabel {
@@ -755,6 +803,18 @@ impl AudioChannelLabel {
         AudioChannelLabel(ffi::kAudioChannelLabel_UseCoordinates);
 }
 
+impl From<ffi::AudioChannelLabel> for AudioChannelLabel {
+    fn from(x: ffi::AudioChannelLabel) -> Self {
+        AudioChannelLabel(x)
+    }
+}
+
+impl From<AudioChannelLabel> for ffi::AudioChannelLabel {
+    fn from(x: AudioChannelLabel) -> Self {
+        x.0
+    }
+}
+
 /// A tag identifying a particular pre-defined channel layout.
 pub struct StandardChannelLabel {}
 impl StandardChannelLabel {
@@ -928,8 +988,21 @@ bitflags! {
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct AudioChannelLayoutTag(u32);
+
+impl From<ffi::AudioChannelLayoutTag> for AudioChannelLayoutTag {
+    fn from(x: ffi::AudioChannelLayoutTag) -> Self {
+        AudioChannelLayoutTag(x)
+    }
+}
+
+impl From<AudioChannelLayoutTag> for ffi::AudioChannelLayoutTag {
+    fn from(x: AudioChannelLayoutTag) -> Self {
+        x.0
+    }
+}
+
 impl AudioChannelLayoutTag {
     pub const USE_CHANNEL_DESCRIPTIONS: AudioChannelLayoutTag =
         AudioChannelLayoutTag(ffi::kAudioChannelLayoutTag_UseChannelDescriptions);
@@ -1272,7 +1345,7 @@ pub struct AudioChannelSphericalCoordinates {
 }
 
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct AudioChannelDescription {
     pub channel_label: AudioChannelLabel,
     pub channel_flags: AudioChannelFlags,
@@ -1281,6 +1354,41 @@ pub struct AudioChannelDescription {
 
 /// Describes a single channel.
 impl AudioChannelDescription {
+    /// A description of a channel with `label` and no coordinates.
+    pub fn new(channel_label: AudioChannelLabel) -> Self {
+        AudioChannelDescription {
+            channel_label,
+            channel_flags: AudioChannelFlags::ALL_OFF,
+            coordinates: [0.; 3],
+        }
+    }
+
+    /// A description of a channel with `label` at a position given in
+    /// rectangular coordinates.
+    pub fn with_rectangular_coordinate(
+        channel_label: AudioChannelLabel,
+        coordinate: AudioChannelRectangularCoordinates,
+    ) -> Self {
+        AudioChannelDescription {
+            channel_label,
+            channel_flags: AudioChannelFlags::RECTANGULAR_COORDINATES,
+            coordinates: [coordinate.left_right, coordinate.back_front, coordinate.down_up],
+        }
+    }
+
+    /// A description of a channel with `label` at a position given in
+    /// spherical coordinates.
+    pub fn with_spherical_coordinate(
+        channel_label: AudioChannelLabel,
+        coordinate: AudioChannelSphericalCoordinates,
+    ) -> Self {
+        AudioChannelDescription {
+            channel_label,
+            channel_flags: AudioChannelFlags::SPHERICAL_COORDINATES,
+            coordinates: [coordinate.azimuth, coordinate.elevation, coordinate.distance],
+        }
+    }
+
     pub fn rectangular_coordinate(&self) -> Option<&AudioChannelRectangularCoordinates> {
         if self.channel_flags
             .contains(AudioChannelFlags::RECTANGULAR_COORDINATES)
@@ -1302,6 +1410,26 @@ impl AudioChannelDescription {
     }
 }
 
+impl From<ffi::AudioChannelDescription> for AudioChannelDescription {
+    fn from(x: ffi::AudioChannelDescription) -> Self {
+        AudioChannelDescription {
+            channel_label: AudioChannelLabel(x.mChannelLabel),
+            channel_flags: AudioChannelFlags::from_bits_truncate(x.mChannelFlags),
+            coordinates: x.mCoordinates,
+        }
+    }
+}
+
+impl From<AudioChannelDescription> for ffi::AudioChannelDescription {
+    fn from(x: AudioChannelDescription) -> Self {
+        ffi::AudioChannelDescription {
+            mChannelLabel: x.channel_label.0,
+            mChannelFlags: x.channel_flags.bits(),
+            mCoordinates: x.coordinates,
+        }
+    }
+}
+
 impl AudioChannelLayoutRef {
     pub fn channel_layout_tag(&self) -> AudioChannelLayoutTag {
         unsafe {
@@ -1310,12 +1438,44 @@ impl AudioChannelLayoutRef {
         }
     }
 
-    pub fn channel_bitmap(&self) -> AudioChannelBitmap {
+    pub fn channel_bitmap(&self) -> ::AudioChannelBitmap {
         unsafe {
             let acl = &(*self.as_ptr());
-            AudioChannelBitmap::from_bits_truncate(acl.mChannelBitmap)
+            ::AudioChannelBitmap::from_bits_truncate(acl.mChannelBitmap)
+        }
+    }
+
+    pub fn set_channel_layout_tag(&mut self, tag: AudioChannelLayoutTag) {
+        unsafe {
+            (*self.as_ptr()).mChannelLayoutTag = tag.0;
+        }
+    }
+
+    pub fn set_channel_bitmap(&mut self, bitmap: ::AudioChannelBitmap) {
+        unsafe {
+            (*self.as_ptr()).mChannelBitmap = bitmap.bits();
         }
     }
 }
 
 //==============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coordinates_follow_their_flags() {
+        let mut description = AudioChannelDescription::new(AudioChannelLabel::UNKNOWN);
+        assert!(description.rectangular_coordinate().is_none());
+        assert!(description.spherical_coordinate().is_none());
+
+        description.channel_flags = AudioChannelFlags::RECTANGULAR_COORDINATES;
+        assert!(description.rectangular_coordinate().is_some());
+        assert!(description.spherical_coordinate().is_none());
+
+        description.channel_flags = AudioChannelFlags::SPHERICAL_COORDINATES;
+        assert!(description.rectangular_coordinate().is_none());
+        assert!(description.spherical_coordinate().is_some());
+    }
+}
@@ -0,0 +1,651 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Opening an `AudioDevice` for input or output in one step.
+
+use {audio_system_object, best_format, AudioBufferListRef, AudioDevice, AudioObject, AudioStream,
+     AudioStreamBasicDescription, AudioStreamBasicDescriptionRef, AudioStreamDirection,
+     AudioTimeStampRef, AudioValueRange, Error, FormatPreferences, IoProcHandle, Unknown};
+use std::{error, fmt, result};
+
+/// Why a stream couldn't be opened.
+#[derive(Debug)]
+pub enum StreamError {
+    /// No device was given and the system has no default device for
+    /// the direction.
+    NoDefaultDevice,
+    /// The device has no streams in the direction.
+    NoStreams(AudioDevice),
+    /// The device doesn't support the sample rate.
+    UnsupportedSampleRate {
+        requested: f64,
+        available: Vec<AudioValueRange>,
+    },
+    /// The buffer size is outside of the device's range.
+    UnsupportedBufferFrames {
+        requested: u32,
+        range: AudioValueRange,
+    },
+    /// The device's stream has no linear PCM format with the channel
+    /// count at the sample rate.
+    UnsupportedFormat { sample_rate: f64, channels: u32 },
+    /// The HAL failed a request.
+    Hal(Error),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::NoDefaultDevice => write!(f, "no default device"),
+            StreamError::NoStreams(ref device) => {
+                write!(f, "device {} has no streams in the requested direction", device.0)
+            }
+            StreamError::UnsupportedSampleRate {
+                requested,
+                ref available,
+            } => {
+                write!(f, "sample rate {} Hz is not supported, available:", requested)?;
+                for range in available {
+                    if range.mMinimum == range.mMaximum {
+                        write!(f, " {}", range.mMinimum)?;
+                    } else {
+                        write!(f, " {}-{}", range.mMinimum, range.mMaximum)?;
+                    }
+                }
+                Ok(())
+            }
+            StreamError::UnsupportedBufferFrames { requested, range } => write!(
+                f,
+                "buffer size of {} frames is outside of the device's range of {}-{}",
+                requested,
+                range.mMinimum,
+                range.mMaximum
+            ),
+            StreamError::UnsupportedFormat {
+                sample_rate,
+                channels,
+            } => write!(
+                f,
+                "no linear PCM format with {} channels at {} Hz",
+                channels,
+                sample_rate
+            ),
+            StreamError::Hal(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for StreamError {
+    fn description(&self) -> &str {
+        match *self {
+            StreamError::NoDefaultDevice => "no default device",
+            StreamError::NoStreams(_) => "no streams in the requested direction",
+            StreamError::UnsupportedSampleRate { .. } => "unsupported sample rate",
+            StreamError::UnsupportedBufferFrames { .. } => "unsupported buffer size",
+            StreamError::UnsupportedFormat { .. } => "unsupported format",
+            StreamError::Hal(_) => "HAL error",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            StreamError::Hal(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for StreamError {
+    fn from(e: Error) -> Self {
+        StreamError::Hal(e)
+    }
+}
+
+pub type StreamResult<T> = result::Result<T, StreamError>;
+
+/// The choices common to input and output streams.
+#[derive(Clone, Copy, Debug, Default)]
+struct StreamConfig {
+    device: Option<AudioDevice>,
+    sample_rate: Option<f64>,
+    channels: Option<u32>,
+    buffer_frames: Option<u32>,
+}
+
+impl StreamConfig {
+    /// Validates the configuration against the device, applies it and
+    /// returns the device and the format its IO proc will see.
+    fn apply(
+        &self,
+        direction: AudioStreamDirection,
+    ) -> StreamResult<(AudioDevice, AudioStreamBasicDescription)> {
+        let mut device = match self.device {
+            Some(device) => device,
+            None => default_device(direction)?,
+        };
+
+        let sample_rate = match self.sample_rate {
+            Some(rate) => {
+                let available = device.available_nominal_sample_rates()?;
+                if !available
+                    .iter()
+                    .any(|r| r.mMinimum <= rate && rate <= r.mMaximum)
+                {
+                    return Err(StreamError::UnsupportedSampleRate {
+                        requested: rate,
+                        available,
+                    });
+                }
+                rate
+            }
+            None => device.nominal_sample_rate()?,
+        };
+
+        if let Some(frames) = self.buffer_frames {
+            let range = device.buffer_frame_size_range()?;
+            let requested = f64::from(frames);
+            if requested < range.mMinimum || range.mMaximum < requested {
+                return Err(StreamError::UnsupportedBufferFrames {
+                    requested: frames,
+                    range,
+                });
+            }
+        }
+
+        let mut stream = device
+            .streams()?
+            .into_iter()
+            .find(|s| s.direction().ok() == Some(direction))
+            .ok_or(StreamError::NoStreams(device))?;
+        let current = stream.virtual_format()?;
+        let channels = self.channels.unwrap_or_else(|| current.channels_per_frame());
+        let format = choose_format(&stream, sample_rate, channels)?;
+
+        // Everything is supported, so it's safe to start changing the
+        // device. The HAL can still refuse a change, in which case the
+        // ones already made are undone.
+        let previous_rate = device.nominal_sample_rate()?;
+        let previous_frames = match self.buffer_frames {
+            Some(_) => Some(device.buffer_frame_size()?),
+            None => None,
+        };
+        let changed = (|| -> ::Result<()> {
+            if previous_rate != sample_rate {
+                device.set_nominal_sample_rate(&sample_rate)?;
+            }
+            if let Some(frames) = self.buffer_frames {
+                device.set_buffer_frame_size(&frames)?;
+            }
+            if !same_format(&current, &format) {
+                stream.set_virtual_format(&format)?;
+            }
+            Ok(())
+        })();
+        if let Err(e) = changed {
+            restore(&mut device, previous_rate, previous_frames);
+            return Err(e.into());
+        }
+        Ok((device, format))
+    }
+}
+
+/// Puts back the sample rate and buffer size a failed `apply` may have
+/// changed. This is best effort, as the device already failed once.
+fn restore(device: &mut AudioDevice, rate: f64, frames: Option<u32>) {
+    if device.nominal_sample_rate().ok() != Some(rate) {
+        let _ = device.set_nominal_sample_rate(&rate);
+    }
+    if let Some(frames) = frames {
+        if device.buffer_frame_size().ok() != Some(frames) {
+            let _ = device.set_buffer_frame_size(&frames);
+        }
+    }
+}
+
+fn default_device(direction: AudioStreamDirection) -> StreamResult<AudioDevice> {
+    let aso = audio_system_object();
+    let object = match direction {
+        AudioStreamDirection::Output => aso.default_output_device()?,
+        AudioStreamDirection::Input => aso.default_input_device()?,
+    };
+    if object == AudioObject::UNKNOWN {
+        return Err(StreamError::NoDefaultDevice);
+    }
+    Ok(AudioDevice(object.0))
+}
+
+/// Picks the stream's linear PCM format with `channels` channels at
+/// `sample_rate`, preferring 32-bit float.
+fn choose_format(
+    stream: &AudioStream,
+    sample_rate: f64,
+    channels: u32,
+) -> StreamResult<AudioStreamBasicDescription> {
+    let candidates = stream.available_virtual_formats()?;
+    let desired = AudioStreamBasicDescription::with_lpcm(
+        sample_rate,
+        channels,
+        32,
+        32,
+        true,
+        cfg!(target_endian = "big"),
+        false,
+    );
+    best_format(&desired, &candidates, &FormatPreferences::default()).ok_or(
+        StreamError::UnsupportedFormat {
+            sample_rate,
+            channels,
+        },
+    )
+}
+
+fn same_format(a: &AudioStreamBasicDescriptionRef, b: &AudioStreamBasicDescriptionRef) -> bool {
+    a.sample_rate() == b.sample_rate() && a.format_id() == b.format_id()
+        && a.format_flags() == b.format_flags()
+        && a.bytes_per_packet() == b.bytes_per_packet()
+        && a.frames_per_packet() == b.frames_per_packet()
+        && a.bytes_per_frame() == b.bytes_per_frame()
+        && a.channels_per_frame() == b.channels_per_frame()
+        && a.bits_per_channel() == b.bits_per_channel()
+}
+
+macro_rules! stream_builder {
+    ($builder:ident) => {
+        impl $builder {
+            /// Uses `device` instead of the system default.
+            pub fn device(mut self, device: AudioDevice) -> Self {
+                self.config.device = Some(device);
+                self
+            }
+
+            /// Sets the nominal sample rate of the device. By default
+            /// the current rate is kept.
+            pub fn sample_rate(mut self, sample_rate: f64) -> Self {
+                self.config.sample_rate = Some(sample_rate);
+                self
+            }
+
+            /// Sets the number of channels. By default the stream's
+            /// current channel count is kept.
+            pub fn channels(mut self, channels: u32) -> Self {
+                self.config.channels = Some(channels);
+                self
+            }
+
+            /// Sets the number of frames the device processes in each
+            /// IO cycle. By default the current size is kept.
+            pub fn buffer_frames(mut self, frames: u32) -> Self {
+                self.config.buffer_frames = Some(frames);
+                self
+            }
+        }
+    };
+}
+
+macro_rules! device_stream {
+    ($stream:ident) => {
+        impl $stream {
+            /// The device the stream is running on.
+            pub fn device(&self) -> AudioDevice {
+                self.io_proc.device()
+            }
+
+            /// The format of the buffers passed to the callback.
+            pub fn format(&self) -> &AudioStreamBasicDescriptionRef {
+                &self.format
+            }
+
+            /// Starts calling the callback.
+            pub fn start(&self) -> StreamResult<()> {
+                Ok(self.io_proc.start()?)
+            }
+
+            /// Stops calling the callback.
+            pub fn stop(&self) -> StreamResult<()> {
+                Ok(self.io_proc.stop()?)
+            }
+        }
+
+        impl fmt::Debug for $stream {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.debug_struct(stringify!($stream))
+                    .field("device", &self.device())
+                    .field("sample_rate", &self.format.sample_rate())
+                    .field("channels", &self.format.channels_per_frame())
+                    .finish()
+            }
+        }
+    };
+}
+
+/// Builds an `OutputStream`. See `OutputStream::builder`.
+#[derive(Clone, Debug, Default)]
+pub struct OutputStreamBuilder {
+    config: StreamConfig,
+}
+
+stream_builder!(OutputStreamBuilder);
+
+impl OutputStreamBuilder {
+    /// Validates and applies the configuration, and creates a stream
+    /// that calls `callback` to fill each output buffer.
+    ///
+    /// Nothing on the device is changed unless every choice is
+    /// supported, and a change the HAL refuses undoes the others. The
+    /// stream is created stopped.
+    pub fn build<F>(self, mut callback: F) -> StreamResult<OutputStream>
+    where
+        F: FnMut(&mut AudioBufferListRef, &AudioTimeStampRef) + Send + 'static,
+    {
+        let (device, format) = self.config.apply(AudioStreamDirection::Output)?;
+        let io_proc = device.create_io_proc_id(move |_, _, _, _, output, output_time| {
+            callback(output, output_time)
+        })?;
+        Ok(OutputStream { io_proc, format })
+    }
+}
+
+/// A connection to the output of an `AudioDevice`.
+///
+/// Dropping the stream stops it.
+pub struct OutputStream {
+    io_proc: IoProcHandle,
+    format: AudioStreamBasicDescription,
+}
+
+impl OutputStream {
+    /// Starts building a stream on the default output device.
+    ///
+    /// ```no_run
+    /// # use core_audio::OutputStream;
+    /// let stream = OutputStream::builder()
+    ///     .sample_rate(48000.0)
+    ///     .channels(2)
+    ///     .buffer_frames(256)
+    ///     .build(|output, _| {
+    ///         for buffer in output.iter_mut() {
+    ///             for b in buffer.iter_mut() {
+    ///                 *b = 0;
+    ///             }
+    ///         }
+    ///     })
+    ///     .unwrap();
+    /// stream.start().unwrap();
+    /// ```
+    pub fn builder() -> OutputStreamBuilder {
+        OutputStreamBuilder::default()
+    }
+}
+
+device_stream!(OutputStream);
+
+/// Builds an `InputStream`. See `InputStream::builder`.
+#[derive(Clone, Debug, Default)]
+pub struct InputStreamBuilder {
+    config: StreamConfig,
+}
+
+stream_builder!(InputStreamBuilder);
+
+impl InputStreamBuilder {
+    /// Validates and applies the configuration, and creates a stream
+    /// that passes each input buffer to `callback`.
+    ///
+    /// Nothing on the device is changed unless every choice is
+    /// supported, and a change the HAL refuses undoes the others. The
+    /// stream is created stopped.
+    pub fn build<F>(self, mut callback: F) -> StreamResult<InputStream>
+    where
+        F: FnMut(&AudioBufferListRef, &AudioTimeStampRef) + Send + 'static,
+    {
+        let (device, format) = self.config.apply(AudioStreamDirection::Input)?;
+        let io_proc = device.create_io_proc_id(move |_, _, input, input_time, _, _| {
+            callback(input, input_time)
+        })?;
+        Ok(InputStream { io_proc, format })
+    }
+}
+
+/// A connection to the input of an `AudioDevice`.
+///
+/// Dropping the stream stops it.
+pub struct InputStream {
+    io_proc: IoProcHandle,
+    format: AudioStreamBasicDescription,
+}
+
+impl InputStream {
+    /// Starts building a stream on the default input device.
+    pub fn builder() -> InputStreamBuilder {
+        InputStreamBuilder::default()
+    }
+}
+
+device_stream!(InputStream);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {ffi, AudioObject, ClassID, ObjectID};
+    use simulated_hal::tests::{add_device, install, TestHal};
+
+    fn addr(selector: ffi::AudioObjectPropertySelector) -> ffi::AudioObjectPropertyAddress {
+        ffi::AudioObjectPropertyAddress {
+            mSelector: selector,
+            mScope: ffi::kAudioObjectPropertyScopeGlobal,
+            mElement: ffi::kAudioObjectPropertyElementMaster,
+        }
+    }
+
+    fn lpcm(sample_rate: f64, channels: u32, bits: u32, float: bool) -> ffi::AudioStreamBasicDescription {
+        let asbd = AudioStreamBasicDescription::with_lpcm(
+            sample_rate,
+            channels,
+            bits,
+            bits,
+            float,
+            cfg!(target_endian = "big"),
+            false,
+        );
+        unsafe { *asbd.as_ptr() }
+    }
+
+    fn ranged(format: ffi::AudioStreamBasicDescription) -> ffi::AudioStreamRangedDescription {
+        ffi::AudioStreamRangedDescription {
+            mFormat: format,
+            mSampleRateRange: ffi::AudioValueRange {
+                mMinimum: format.mSampleRate,
+                mMaximum: format.mSampleRate,
+            },
+        }
+    }
+
+    /// A default output device at 44.1 kHz with 512 frame buffers and
+    /// one stereo output stream in 16-bit integer.
+    fn output_device(hal: &TestHal) -> (AudioDevice, AudioStream) {
+        let device = add_device(hal);
+        let rate = addr(ffi::kAudioDevicePropertyNominalSampleRate);
+        hal.set_property(&device, &rate, &44100f64);
+        hal.set_property_settable(&device, &rate, true);
+        hal.set_property_array(
+            &device,
+            &addr(ffi::kAudioDevicePropertyAvailableNominalSampleRates),
+            &[
+                ffi::AudioValueRange { mMinimum: 44100., mMaximum: 44100. },
+                ffi::AudioValueRange { mMinimum: 48000., mMaximum: 48000. },
+            ],
+        );
+        let frames = addr(ffi::kAudioDevicePropertyBufferFrameSize);
+        hal.set_property(&device, &frames, &512u32);
+        hal.set_property_settable(&device, &frames, true);
+        hal.set_property(
+            &device,
+            &addr(ffi::kAudioDevicePropertyBufferFrameSizeRange),
+            &ffi::AudioValueRange { mMinimum: 64., mMaximum: 4096. },
+        );
+
+        let stream = AudioStream(hal.add_object(AudioStream::CLASS_ID, &device).id());
+        hal.set_property(
+            &stream,
+            &addr(ffi::kAudioStreamPropertyDirection),
+            &AudioStreamDirection::Output,
+        );
+        let virtual_format = addr(ffi::kAudioStreamPropertyVirtualFormat);
+        hal.set_property(&stream, &virtual_format, &lpcm(44100., 2, 16, false));
+        hal.set_property_settable(&stream, &virtual_format, true);
+        hal.set_property_array(
+            &stream,
+            &addr(ffi::kAudioStreamPropertyAvailableVirtualFormats),
+            &[
+                ranged(lpcm(44100., 2, 16, false)),
+                ranged(lpcm(44100., 2, 32, true)),
+                ranged(lpcm(48000., 2, 32, true)),
+            ],
+        );
+
+        hal.set_property(
+            &audio_system_object(),
+            &addr(ffi::kAudioHardwarePropertyDefaultOutputDevice),
+            &device,
+        );
+        (device, stream)
+    }
+
+    fn config() -> StreamConfig {
+        StreamConfig::default()
+    }
+
+    fn error(config: &StreamConfig, direction: AudioStreamDirection) -> StreamError {
+        match config.apply(direction) {
+            Ok(_) => panic!("{:?} applied", config),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn applies_a_supported_configuration() {
+        let hal = install();
+        let (device, stream) = output_device(&hal);
+        let config = StreamConfig {
+            sample_rate: Some(48000.),
+            channels: Some(2),
+            buffer_frames: Some(256),
+            ..config()
+        };
+        let (opened, format) = config.apply(AudioStreamDirection::Output).unwrap();
+        assert_eq!(opened, device);
+        assert_eq!(format.sample_rate(), 48000.);
+        assert_eq!(format.channels_per_frame(), 2);
+        assert_eq!(format.bits_per_channel(), 32);
+        assert_eq!(device.nominal_sample_rate().unwrap(), 48000.);
+        assert_eq!(device.buffer_frame_size().unwrap(), 256);
+        assert!(same_format(&stream.virtual_format().unwrap(), &format));
+    }
+
+    #[test]
+    fn keeps_the_current_rate_and_channels_by_default() {
+        let hal = install();
+        let (device, _) = output_device(&hal);
+        let (_, format) = config().apply(AudioStreamDirection::Output).unwrap();
+        assert_eq!(format.sample_rate(), 44100.);
+        assert_eq!(format.channels_per_frame(), 2);
+        assert_eq!(device.buffer_frame_size().unwrap(), 512);
+    }
+
+    #[test]
+    fn unsupported_choices_change_nothing() {
+        let hal = install();
+        let (device, stream) = output_device(&hal);
+        let before = stream.virtual_format().unwrap();
+
+        let rate = StreamConfig {
+            sample_rate: Some(96000.),
+            buffer_frames: Some(256),
+            ..config()
+        };
+        match error(&rate, AudioStreamDirection::Output) {
+            StreamError::UnsupportedSampleRate { requested, available } => {
+                assert_eq!(requested, 96000.);
+                assert_eq!(available.len(), 2);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let frames = StreamConfig {
+            sample_rate: Some(48000.),
+            buffer_frames: Some(8192),
+            ..config()
+        };
+        match error(&frames, AudioStreamDirection::Output) {
+            StreamError::UnsupportedBufferFrames { requested: 8192, .. } => {}
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let channels = StreamConfig {
+            sample_rate: Some(48000.),
+            channels: Some(6),
+            ..config()
+        };
+        match error(&channels, AudioStreamDirection::Output) {
+            StreamError::UnsupportedFormat { channels: 6, .. } => {}
+            e => panic!("unexpected {:?}", e),
+        }
+
+        assert_eq!(device.nominal_sample_rate().unwrap(), 44100.);
+        assert_eq!(device.buffer_frame_size().unwrap(), 512);
+        assert!(same_format(&stream.virtual_format().unwrap(), &before));
+    }
+
+    #[test]
+    fn missing_devices_and_streams() {
+        let hal = install();
+        let (device, _) = output_device(&hal);
+        hal.set_property(
+            &audio_system_object(),
+            &addr(ffi::kAudioHardwarePropertyDefaultInputDevice),
+            &AudioObject::UNKNOWN,
+        );
+        match error(&config(), AudioStreamDirection::Input) {
+            StreamError::NoDefaultDevice => {}
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let input = StreamConfig {
+            device: Some(device),
+            ..config()
+        };
+        match error(&input, AudioStreamDirection::Input) {
+            StreamError::NoStreams(d) => assert_eq!(d, device),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        hal.set_property(
+            &audio_system_object(),
+            &addr(ffi::kAudioHardwarePropertyDefaultOutputDevice),
+            &AudioObject::UNKNOWN,
+        );
+        match error(&config(), AudioStreamDirection::Output) {
+            StreamError::NoDefaultDevice => {}
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn a_refused_format_restores_the_device() {
+        let hal = install();
+        let (device, stream) = output_device(&hal);
+        hal.set_property_settable(&stream, &addr(ffi::kAudioStreamPropertyVirtualFormat), false);
+
+        let config = StreamConfig {
+            sample_rate: Some(48000.),
+            buffer_frames: Some(256),
+            ..config()
+        };
+        match error(&config, AudioStreamDirection::Output) {
+            StreamError::Hal(_) => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(device.nominal_sample_rate().unwrap(), 44100.);
+        assert_eq!(device.buffer_frame_size().unwrap(), 512);
+        assert_eq!(stream.virtual_format().unwrap().bits_per_channel(), 16);
+    }
+}
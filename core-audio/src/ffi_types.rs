@@ -4,6 +4,12 @@ use std::cell::UnsafeCell;
 pub struct Opaque(UnsafeCell<()>);
 /// Generate a newtype wrapper `$owned` and reference wrapper
 /// `$borrowed` around a POD FFI type that lives on the heap.
+///
+/// `$borrowed` must implement `Debug` and `PartialEq` over the data
+/// the pointer reaches, and `$owned` gets both through it.
+///
+/// The `clone` function must copy everything the pointer reaches, so
+/// that a clone shares no memory with the original.
 macro_rules! ffi_type_heap {
     (
         $(#[$impl_attr:meta])*
@@ -112,12 +118,15 @@ macro_rules! ffi_type_heap {
             }
         }
 
-        impl ::std::fmt::Debug for $borrowed {
+        impl ::std::fmt::Debug for $owned {
             fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-                let ptr = self as *const $borrowed as usize;
-                f.debug_tuple(stringify!($borrowed))
-                    .field(&ptr)
-                    .finish()
+                ::std::fmt::Debug::fmt(&**self, f)
+            }
+        }
+
+        impl PartialEq for $owned {
+            fn eq(&self, other: &$owned) -> bool {
+                **self == **other
             }
         }
     }
@@ -0,0 +1,425 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Conversion of sample data between linear PCM formats.
+
+use {AudioBufferListRef, AudioFormat, AudioFormatFlags, AudioStreamBasicDescription,
+     AudioStreamBasicDescriptionRef, LinearPcmFlags};
+use interleave::{channel_count, channels, frame_count};
+use std::{error, fmt, ptr, result};
+
+/// Why a `FormatConverter` couldn't be created or run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConverterError {
+    /// The format isn't linear PCM.
+    NotLinearPcm(AudioFormat),
+    /// The linear PCM sample layout isn't supported, for example a
+    /// 24-bit float or a sample wider than its container.
+    UnsupportedFormat,
+    /// The formats have different numbers of channels.
+    ChannelMismatch { src: u32, dst: u32 },
+    /// The formats have different sample rates.
+    SampleRateMismatch { src: f64, dst: f64 },
+    /// A buffer list doesn't hold the number of channels its format
+    /// describes.
+    BufferLayout,
+}
+
+impl fmt::Display for ConverterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConverterError::NotLinearPcm(format) => {
+                write!(f, "{:?} is not a linear PCM format", format)
+            }
+            ConverterError::ChannelMismatch { src, dst } => write!(
+                f,
+                "can't convert {} channels to {} channels",
+                src,
+                dst
+            ),
+            ConverterError::SampleRateMismatch { src, dst } => write!(
+                f,
+                "can't convert a sample rate of {} Hz to {} Hz",
+                src,
+                dst
+            ),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+impl error::Error for ConverterError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl ConverterError {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            ConverterError::NotLinearPcm(_) => "not a linear PCM format",
+            ConverterError::UnsupportedFormat => "unsupported linear PCM sample layout",
+            ConverterError::ChannelMismatch { .. } => "channel counts differ",
+            ConverterError::SampleRateMismatch { .. } => "sample rates differ",
+            ConverterError::BufferLayout => "the buffers don't match the format's channels",
+        }
+    }
+}
+
+pub type ConverterResult<T> = result::Result<T, ConverterError>;
+
+/// How a single sample is stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Encoding {
+    /// Bytes in the container the sample is stored in.
+    bytes: usize,
+    /// Significant bits in the sample.
+    bits: u32,
+    float: bool,
+    signed: bool,
+    big_endian: bool,
+    /// Whether a sample narrower than its container is in the high
+    /// bits rather than the low bits.
+    aligned_high: bool,
+    /// Full scale of an integer sample: `2^(bits - 1)`, or
+    /// `2^fractional_bits` for fixed point.
+    scale: f64,
+}
+
+impl Encoding {
+    fn new(format: &AudioStreamBasicDescriptionRef) -> ConverterResult<Encoding> {
+        let id = format.format_id();
+        if id != AudioFormat::LinearPcm {
+            return Err(ConverterError::NotLinearPcm(id));
+        }
+        let flags = format.format_flags();
+        let channels = format.channels_per_frame();
+        if channels == 0 {
+            return Err(ConverterError::UnsupportedFormat);
+        }
+        let bytes = format.sample_word_size() as usize;
+        let bits = format.bits_per_channel();
+        let float = flags.contains(AudioFormatFlags::IS_FLOAT);
+        let supported = if float {
+            (bytes == 4 && bits == 32) || (bytes == 8 && bits == 64)
+        } else {
+            (1..=4).contains(&bytes) && bits >= 1 && bits as usize <= bytes * 8
+        };
+        if !supported {
+            return Err(ConverterError::UnsupportedFormat);
+        }
+        // `format_flags` drops the fixed point bitfield, so read it from
+        // the raw flags.
+        let raw_flags = unsafe { (*format.as_ptr()).mFormatFlags };
+        let fraction = LinearPcmFlags::from(raw_flags).number_fractional_bits() as i32;
+        let scale = if fraction > 0 {
+            2f64.powi(fraction)
+        } else {
+            2f64.powi(bits as i32 - 1)
+        };
+        Ok(Encoding {
+            bytes,
+            bits,
+            float,
+            signed: flags.contains(AudioFormatFlags::IS_SIGNED_INTEGER),
+            big_endian: flags.contains(AudioFormatFlags::IS_BIG_ENDIAN),
+            aligned_high: flags.contains(AudioFormatFlags::IS_ALIGNED_HIGH),
+            scale,
+        })
+    }
+
+    fn shift(&self) -> u32 {
+        if self.aligned_high {
+            self.bytes as u32 * 8 - self.bits
+        } else {
+            0
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        (1u64 << self.bits) - 1
+    }
+
+    unsafe fn read(&self, src: *const u8) -> f64 {
+        let mut raw = 0u64;
+        for i in 0..self.bytes {
+            let byte = *src.add(i) as u64;
+            if self.big_endian {
+                raw = (raw << 8) | byte;
+            } else {
+                raw |= byte << (8 * i);
+            }
+        }
+        if self.float {
+            return if self.bytes == 4 {
+                f64::from(f32::from_bits(raw as u32))
+            } else {
+                f64::from_bits(raw)
+            };
+        }
+        let v = (raw >> self.shift()) & self.mask();
+        let v = if self.signed {
+            let unused = 64 - self.bits;
+            ((v << unused) as i64) >> unused
+        } else {
+            v as i64 - (1i64 << (self.bits - 1))
+        };
+        v as f64 / self.scale
+    }
+
+    unsafe fn write(&self, dst: *mut u8, x: f64) {
+        let raw = if self.float {
+            if self.bytes == 4 {
+                u64::from((x as f32).to_bits())
+            } else {
+                x.to_bits()
+            }
+        } else {
+            let max = (1i64 << (self.bits - 1)) - 1;
+            let min = -(1i64 << (self.bits - 1));
+            // Round to nearest and clip to the range of the sample. NaN
+            // becomes silence.
+            let v = (x * self.scale).round();
+            let v = if v.is_nan() {
+                0
+            } else {
+                (v.max(min as f64).min(max as f64)) as i64
+            };
+            let v = if self.signed { v } else { v + (1i64 << (self.bits - 1)) };
+            ((v as u64) & self.mask()) << self.shift()
+        };
+        for i in 0..self.bytes {
+            let byte = if self.big_endian {
+                (raw >> (8 * (self.bytes - 1 - i))) as u8
+            } else {
+                (raw >> (8 * i)) as u8
+            };
+            ptr::write(dst.add(i), byte);
+        }
+    }
+}
+
+/// Converts linear PCM sample data from one format to another.
+///
+/// Integer samples of 1 to 32 bits in 1 to 4 byte containers, packed or
+/// aligned high or low, signed or unsigned, and 32- and 64-bit float
+/// samples in either endianness are supported. Samples are converted
+/// through `f64`, which represents every supported integer exactly.
+/// Integer results are rounded to the nearest value and clipped to the
+/// range of the destination.
+pub struct FormatConverter {
+    src_format: AudioStreamBasicDescription,
+    dst_format: AudioStreamBasicDescription,
+    src: Encoding,
+    dst: Encoding,
+}
+
+impl FormatConverter {
+    /// Creates a converter from `src` to `dst`.
+    ///
+    /// The formats must be linear PCM with the same number of channels.
+    /// Their sample rates must match unless either is zero.
+    pub fn new(
+        src: &AudioStreamBasicDescriptionRef,
+        dst: &AudioStreamBasicDescriptionRef,
+    ) -> ConverterResult<FormatConverter> {
+        let src_encoding = Encoding::new(src)?;
+        let dst_encoding = Encoding::new(dst)?;
+        if src.channels_per_frame() != dst.channels_per_frame() {
+            return Err(ConverterError::ChannelMismatch {
+                src: src.channels_per_frame(),
+                dst: dst.channels_per_frame(),
+            });
+        }
+        if src.sample_rate() != 0. && dst.sample_rate() != 0.
+            && src.sample_rate() != dst.sample_rate()
+        {
+            return Err(ConverterError::SampleRateMismatch {
+                src: src.sample_rate(),
+                dst: dst.sample_rate(),
+            });
+        }
+        Ok(FormatConverter {
+            src_format: src.to_owned(),
+            dst_format: dst.to_owned(),
+            src: src_encoding,
+            dst: dst_encoding,
+        })
+    }
+
+    /// The format converted from.
+    pub fn src_format(&self) -> &AudioStreamBasicDescriptionRef {
+        &self.src_format
+    }
+
+    /// The format converted to.
+    pub fn dst_format(&self) -> &AudioStreamBasicDescriptionRef {
+        &self.dst_format
+    }
+
+    /// Converts the frames in `src` into `dst` and returns the number
+    /// of frames converted, which is limited by the smaller list.
+    ///
+    /// Channels are numbered across the buffers of each list, so
+    /// interleaved data can be converted to non-interleaved and back.
+    /// Buffers with no data are skipped.
+    pub fn convert(
+        &self,
+        src: &AudioBufferListRef,
+        dst: &mut AudioBufferListRef,
+    ) -> ConverterResult<usize> {
+        let channel_total = self.src_format.channels_per_frame() as usize;
+        if channel_count(src) != channel_total || channel_count(dst) != channel_total {
+            return Err(ConverterError::BufferLayout);
+        }
+        let (sb, db) = (self.src.bytes, self.dst.bytes);
+        let frames = frame_count(src, sb).min(frame_count(dst, db));
+        for (s, d) in channels(src, sb).zip(channels(dst, db)) {
+            if s.data.is_null() || d.data.is_null() {
+                continue;
+            }
+            for f in 0..frames {
+                unsafe {
+                    let x = self.src.read(s.data.add(f * s.stride * sb));
+                    self.dst.write(d.data.add(f * d.stride * db), x);
+                }
+            }
+        }
+        Ok(frames)
+    }
+}
+
+impl fmt::Debug for FormatConverter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FormatConverter")
+            .field("src", &self.src)
+            .field("dst", &self.dst)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioBufferList, SampleKind};
+
+    fn format(s: &str) -> AudioStreamBasicDescription {
+        s.parse().unwrap()
+    }
+
+    /// Converts `src` bytes of `src_format` to `frames` frames of
+    /// `dst_format`.
+    fn convert(src_format: &str, src: &[u8], dst_format: &str, frames: usize) -> Vec<u8> {
+        let (src_format, dst_format) = (format(src_format), format(dst_format));
+        let converter = FormatConverter::new(&src_format, &dst_format).unwrap();
+        let mut src_abl = AudioBufferList::allocate(&src_format, frames);
+        src_abl[0].copy_from_slice(src);
+        let mut dst_abl = AudioBufferList::allocate(&dst_format, frames);
+        assert_eq!(converter.convert(&src_abl, &mut dst_abl), Ok(frames));
+        dst_abl[0].to_vec()
+    }
+
+    fn i16s(v: &[i16]) -> Vec<u8> {
+        v.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn f32s(v: &[f32]) -> Vec<u8> {
+        v.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn integer_to_float() {
+        let out = convert("s16le@48000x1", &i16s(&[0, 16384, -32768, 32767]), "f32le@48000x1", 4);
+        assert_eq!(out, f32s(&[0., 0.5, -1., 32767. / 32768.]));
+    }
+
+    #[test]
+    fn float_to_integer_rounds_and_clips() {
+        let out = convert(
+            "f32le@48000x1",
+            &f32s(&[0.5, -1., 2., -2., 1.5 / 32768., f32::NAN]),
+            "s16le@48000x1",
+            6,
+        );
+        assert_eq!(out, i16s(&[16384, -32768, 32767, -32768, 2, 0]));
+    }
+
+    #[test]
+    fn endianness_and_packing() {
+        // Big endian packed 24-bit to little endian 32-bit.
+        let out = convert("s24be@48000x1", &[0x12, 0x34, 0x56, 0xff, 0xff, 0xff], "s32le@48000x1", 2);
+        assert_eq!(out, [0x00, 0x56, 0x34, 0x12, 0x00, 0xff, 0xff, 0xff]);
+
+        // 24 bits aligned high and low in 32-bit containers.
+        let high = AudioStreamBasicDescription::with_lpcm(48000., 1, 24, 32, false, false, false);
+        let low = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, 48000., 1)
+            .sample_kind(SampleKind::SignedInteger)
+            .bits_per_channel(24)
+            .word_bits(32)
+            .aligned_low()
+            .big_endian(false)
+            .build();
+        let converter = FormatConverter::new(&high, &low).unwrap();
+        let mut src = AudioBufferList::allocate(&high, 1);
+        src[0].copy_from_slice(&[0x00, 0x56, 0x34, 0x92]);
+        let mut dst = AudioBufferList::allocate(&low, 1);
+        assert_eq!(converter.convert(&src, &mut dst), Ok(1));
+        assert_eq!(&dst[0][..], &[0x56, 0x34, 0x92, 0x00][..]);
+    }
+
+    #[test]
+    fn unsigned_and_fixed_point() {
+        let out = convert("u8@48000x1", &[0x80, 0xc0, 0x00, 0xff], "s16le@48000x1", 4);
+        assert_eq!(out, i16s(&[0, 16384, -32768, 32512]));
+
+        // 8.24 fixed point has a full scale of 2^24.
+        let fixed = format("1 ch, 48000 Hz, Fixed8.24, little-endian");
+        let float = format("f32le@48000x1");
+        let converter = FormatConverter::new(&fixed, &float).unwrap();
+        let mut src = AudioBufferList::allocate(&fixed, 2);
+        src[0].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0x80, 0xff]);
+        let mut dst = AudioBufferList::allocate(&float, 2);
+        assert_eq!(converter.convert(&src, &mut dst), Ok(2));
+        assert_eq!(&dst[0][..], &f32s(&[1., -0.5])[..]);
+    }
+
+    #[test]
+    fn interleaved_to_non_interleaved() {
+        let src_format = format("s16le@48000x2");
+        let dst_format = AudioStreamBasicDescription::with_lpcm(48000., 2, 32, 32, true, false, true);
+        let converter = FormatConverter::new(&src_format, &dst_format).unwrap();
+        let mut src = AudioBufferList::allocate(&src_format, 2);
+        src[0].copy_from_slice(&i16s(&[16384, -16384, 8192, -8192]));
+        let mut dst = AudioBufferList::allocate(&dst_format, 4);
+        assert_eq!(converter.convert(&src, &mut dst), Ok(2));
+        assert_eq!(&dst[0][..8], &f32s(&[0.5, 0.25])[..]);
+        assert_eq!(&dst[1][..8], &f32s(&[-0.5, -0.25])[..]);
+    }
+
+    #[test]
+    fn unsupported_formats() {
+        let stereo = format("s16le@48000x2");
+        let aac = format("2 ch, 48000 Hz, 'aac ', 1024 frames/packet");
+        match FormatConverter::new(&aac, &stereo) {
+            Err(ConverterError::NotLinearPcm(AudioFormat::Mpeg4Aac)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match FormatConverter::new(&stereo, &format("f16le@48000x2")) {
+            Err(ConverterError::UnsupportedFormat) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match FormatConverter::new(&stereo, &format("s16le@48000x1")) {
+            Err(ConverterError::ChannelMismatch { src: 2, dst: 1 }) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match FormatConverter::new(&stereo, &format("s16le@44100x2")) {
+            Err(ConverterError::SampleRateMismatch { .. }) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(FormatConverter::new(&stereo, &format("s16le@0x2")).is_ok());
+
+        let converter = FormatConverter::new(&stereo, &stereo).unwrap();
+        let mono = AudioBufferList::allocate(&format("s16le@48000x1"), 4);
+        let mut dst = AudioBufferList::allocate(&stereo, 4);
+        assert_eq!(converter.convert(&mono, &mut dst), Err(ConverterError::BufferLayout));
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Choosing the best of a stream's available formats for a desired
+//! format.
+
+use {AudioFormat, AudioFormatFlags, AudioStreamBasicDescription, AudioStreamBasicDescriptionRef,
+     AudioStreamRangedDescription, AudioStreamRangedDescriptionRef};
+use std::cmp::{Ordering, Reverse};
+
+/// What to do when no format has the desired sample rate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateMatch {
+    /// Only formats that support the desired rate match.
+    Exact,
+    /// Use the supported rate nearest the desired one.
+    Nearest,
+}
+
+/// Preferences for `best_format`, applied in the order of the fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FormatPreferences {
+    pub rate: RateMatch,
+    /// Prefer floating point samples to integers.
+    pub prefer_float: bool,
+    /// Among formats that don't have the desired bit depth, prefer
+    /// deeper ones to shallower ones.
+    pub prefer_higher_bit_depth: bool,
+}
+
+impl Default for FormatPreferences {
+    /// Exact rates, preferring deep floating point samples.
+    fn default() -> Self {
+        FormatPreferences {
+            rate: RateMatch::Exact,
+            prefer_float: true,
+            prefer_higher_bit_depth: true,
+        }
+    }
+}
+
+/// The sample rates a ranged description supports.
+fn rate_range(format: &AudioStreamRangedDescriptionRef) -> (f64, f64) {
+    let range = format.sample_rate_range();
+    if range.mMinimum > 0. || range.mMaximum > 0. {
+        (range.mMinimum, range.mMaximum)
+    } else {
+        (format.sample_rate(), format.sample_rate())
+    }
+}
+
+/// The rate `format` would run at for a desired `rate`, if it can.
+fn resolve_rate(
+    format: &AudioStreamRangedDescriptionRef,
+    rate: f64,
+    rate_match: RateMatch,
+) -> Option<f64> {
+    let (min, max) = rate_range(format);
+    if rate == AudioStreamBasicDescription::ANY_RATE {
+        // Keep the format's own rate if it has one.
+        let own = format.sample_rate();
+        return Some(if own > 0. && own >= min && own <= max { own } else { max });
+    }
+    if max == AudioStreamBasicDescription::ANY_RATE || (min <= rate && rate <= max) {
+        return Some(rate);
+    }
+    match rate_match {
+        RateMatch::Exact => None,
+        RateMatch::Nearest if max > 0. => Some(rate.max(min).min(max)),
+        RateMatch::Nearest => None,
+    }
+}
+
+/// How well a format matches, compared field by field: greater is
+/// better.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Score {
+    /// How far the rate is from the desired one, in bits of the
+    /// non-negative log ratio. Their order is the order of the ratios.
+    rate_distance: Reverse<u64>,
+    float: bool,
+    bit_depth_matches: bool,
+    bit_depth: i64,
+    interleaving_matches: bool,
+    native_endian: bool,
+}
+
+fn score(
+    desired: &AudioStreamBasicDescriptionRef,
+    format: &AudioStreamBasicDescriptionRef,
+    rate: f64,
+    preferences: &FormatPreferences,
+) -> Score {
+    let rate_distance = if desired.sample_rate() > 0. {
+        (rate / desired.sample_rate()).ln().abs()
+    } else {
+        0.
+    };
+    let bits = i64::from(format.bits_per_channel());
+    Score {
+        rate_distance: Reverse(rate_distance.to_bits()),
+        float: preferences.prefer_float && format.is_float(),
+        bit_depth_matches: desired.bits_per_channel() == 0
+            || desired.bits_per_channel() == format.bits_per_channel(),
+        bit_depth: if preferences.prefer_higher_bit_depth { bits } else { -bits },
+        interleaving_matches: desired.is_interleaved() == format.is_interleaved(),
+        native_endian: !format.is_lpcm()
+            || format.format_flags().contains(AudioFormatFlags::IS_BIG_ENDIAN)
+                == cfg!(target_endian = "big"),
+    }
+}
+
+/// Chooses the format in `available` that best matches `desired`, and
+/// resolves its sample rate.
+///
+/// Formats must have the desired format ID and channel count, unless
+/// `desired` leaves them as 0. A desired rate of
+/// `AudioStreamBasicDescription::ANY_RATE` takes each format's own
+/// rate, or the top of its range. Otherwise formats are ranked by how
+/// near their rate is, then by `preferences`, then by how well their
+/// bit depth, interleaving and endianness match. Ties go to the earlier
+/// format.
+///
+/// This only looks at the descriptions, so it works as well on lists
+/// made up for testing as on `AudioStream::available_virtual_formats`
+/// or `available_physical_formats`.
+pub fn best_format(
+    desired: &AudioStreamBasicDescriptionRef,
+    available: &[AudioStreamRangedDescription],
+    preferences: &FormatPreferences,
+) -> Option<AudioStreamBasicDescription> {
+    let format_id = desired.format_id();
+    let channels = desired.channels_per_frame();
+    let mut best: Option<(Score, &AudioStreamRangedDescriptionRef, f64)> = None;
+    for format in available {
+        if format_id != AudioFormat::Unknown(0) && format.format_id() != format_id {
+            continue;
+        }
+        if channels != 0 && format.channels_per_frame() != channels {
+            continue;
+        }
+        let rate = match resolve_rate(format, desired.sample_rate(), preferences.rate) {
+            Some(rate) => rate,
+            None => continue,
+        };
+        let score = score(desired, format, rate, preferences);
+        let better = match best {
+            Some((ref best, _, _)) => score.cmp(best) == Ordering::Greater,
+            None => true,
+        };
+        if better {
+            best = Some((score, format, rate));
+        }
+    }
+    best.map(|(_, format, rate)| {
+        let format: &AudioStreamBasicDescriptionRef = format;
+        let format = format.to_owned();
+        unsafe { (*format.as_ptr()).mSampleRate = rate };
+        format
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffi;
+
+    fn format(s: &str) -> AudioStreamBasicDescription {
+        s.parse().unwrap()
+    }
+
+    fn ranged(s: &str, min: f64, max: f64) -> AudioStreamRangedDescription {
+        AudioStreamRangedDescription::from(ffi::AudioStreamRangedDescription {
+            mFormat: unsafe { *format(s).as_ptr() },
+            mSampleRateRange: ffi::AudioValueRange {
+                mMinimum: min,
+                mMaximum: max,
+            },
+        })
+    }
+
+    fn fixed(s: &str) -> AudioStreamRangedDescription {
+        ranged(s, 0., 0.)
+    }
+
+    fn best(
+        desired: &str,
+        available: &[AudioStreamRangedDescription],
+        preferences: &FormatPreferences,
+    ) -> Option<String> {
+        best_format(&format(desired), available, preferences).map(|f| f.to_string())
+    }
+
+    fn nearest() -> FormatPreferences {
+        FormatPreferences {
+            rate: RateMatch::Nearest,
+            ..FormatPreferences::default()
+        }
+    }
+
+    #[test]
+    fn prefers_float() {
+        let available = [fixed("s32@48000x2"), fixed("f32@48000x2")];
+        assert_eq!(
+            best("f32@48000x2", &available, &FormatPreferences::default()),
+            Some(format("f32@48000x2").to_string())
+        );
+        let integers = FormatPreferences {
+            prefer_float: false,
+            ..FormatPreferences::default()
+        };
+        // Without the preference it's a tie, which the earlier one wins.
+        assert_eq!(
+            best("f32@48000x2", &available, &integers),
+            Some(format("s32@48000x2").to_string())
+        );
+    }
+
+    #[test]
+    fn prefers_higher_bit_depth() {
+        let available = [fixed("s16@48000x2"), fixed("s24@48000x2")];
+        assert_eq!(
+            best("s20@48000x2", &available, &FormatPreferences::default()),
+            Some(format("s24@48000x2").to_string())
+        );
+        let shallow = FormatPreferences {
+            prefer_higher_bit_depth: false,
+            ..FormatPreferences::default()
+        };
+        assert_eq!(
+            best("s20@48000x2", &available, &shallow),
+            Some(format("s16@48000x2").to_string())
+        );
+        // The desired depth beats a deeper one.
+        assert_eq!(
+            best("s16@48000x2", &available, &FormatPreferences::default()),
+            Some(format("s16@48000x2").to_string())
+        );
+    }
+
+    #[test]
+    fn exact_rates() {
+        let available = [fixed("f32@44100x2"), fixed("s16@48000x2")];
+        assert_eq!(
+            best("f32@48000x2", &available, &FormatPreferences::default()),
+            Some(format("s16@48000x2").to_string())
+        );
+        assert_eq!(best("f32@96000x2", &available, &FormatPreferences::default()), None);
+    }
+
+    #[test]
+    fn nearest_rates() {
+        let available = [fixed("f32@22050x2"), fixed("f32@44100x2")];
+        assert_eq!(
+            best("f32@32000x2", &available, &nearest()),
+            Some(format("f32@44100x2").to_string())
+        );
+        assert_eq!(
+            best("f32@8000x2", &available, &nearest()),
+            Some(format("f32@22050x2").to_string())
+        );
+        // A nearer rate beats the preferences.
+        let available = [fixed("f32@96000x2"), fixed("s16@44100x2")];
+        assert_eq!(
+            best("f32@48000x2", &available, &nearest()),
+            Some(format("s16@44100x2").to_string())
+        );
+    }
+
+    #[test]
+    fn any_rate_resolution() {
+        let any = "f32@0x2";
+        // A format's own rate is kept.
+        assert_eq!(
+            best(any, &[fixed("f32@44100x2")], &FormatPreferences::default()),
+            Some(format("f32@44100x2").to_string())
+        );
+        assert_eq!(
+            best(any, &[ranged("f32@44100x2", 8000., 96000.)], &FormatPreferences::default()),
+            Some(format("f32@44100x2").to_string())
+        );
+        // Without one in its range, the top of the range is used.
+        assert_eq!(
+            best(any, &[ranged("f32@0x2", 8000., 96000.)], &FormatPreferences::default()),
+            Some(format("f32@96000x2").to_string())
+        );
+        assert_eq!(
+            best(any, &[ranged("f32@192000x2", 8000., 96000.)], &FormatPreferences::default()),
+            Some(format("f32@96000x2").to_string())
+        );
+        // A format that takes any rate takes the desired one.
+        assert_eq!(
+            best("f32@48000x2", &[fixed("f32@0x2")], &FormatPreferences::default()),
+            Some(format("f32@48000x2").to_string())
+        );
+    }
+
+    #[test]
+    fn rate_ranges() {
+        let available = [ranged("f32@44100x2", 8000., 48000.)];
+        assert_eq!(
+            best("f32@32000x2", &available, &FormatPreferences::default()),
+            Some(format("f32@32000x2").to_string())
+        );
+        assert_eq!(best("f32@96000x2", &available, &FormatPreferences::default()), None);
+        assert_eq!(
+            best("f32@96000x2", &available, &nearest()),
+            Some(format("f32@48000x2").to_string())
+        );
+        assert_eq!(
+            best("f32@4000x2", &available, &nearest()),
+            Some(format("f32@8000x2").to_string())
+        );
+        // A range with the exact rate beats a fixed format without it.
+        let available = [fixed("f32@44100x2"), ranged("s16@44100x2", 8000., 96000.)];
+        assert_eq!(
+            best("f32@96000x2", &available, &nearest()),
+            Some(format("s16@96000x2").to_string())
+        );
+    }
+
+    #[test]
+    fn format_ids_and_channels_must_match() {
+        let available = [
+            fixed("f32@48000x1"),
+            fixed("2 ch, 48000 Hz, 'aac ', 1024 frames/packet"),
+            fixed("f32@48000x2"),
+        ];
+        assert_eq!(
+            best("f32@48000x2", &available, &FormatPreferences::default()),
+            Some(format("f32@48000x2").to_string())
+        );
+        assert_eq!(best("f32@48000x6", &available, &FormatPreferences::default()), None);
+        let aac = "2 ch, 48000 Hz, 'aac ', 1024 frames/packet";
+        assert_eq!(
+            best(aac, &available, &FormatPreferences::default()),
+            Some(format(aac).to_string())
+        );
+        // Leaving the format ID and channels as 0 matches anything, and
+        // the preferences pick the float.
+        let anything = AudioStreamBasicDescription::default();
+        unsafe { (*anything.as_ptr()).mSampleRate = 48000. };
+        assert_eq!(
+            best_format(&anything, &available, &FormatPreferences::default())
+                .map(|f| f.to_string()),
+            Some(format("f32@48000x1").to_string())
+        );
+    }
+}
@@ -0,0 +1,34 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Printing and parsing of four char codes such as `'lpcm'`.
+
+use std::fmt;
+
+/// Writes `code` as its four characters in quotes, or in hex when they
+/// aren't all printable ASCII.
+pub(crate) fn fmt_four_char_code(code: u32, f: &mut fmt::Formatter) -> fmt::Result {
+    let bytes = [(code >> 24) as u8, (code >> 16) as u8, (code >> 8) as u8, code as u8];
+    if bytes.iter().all(|&b| (b' '..=b'~').contains(&b)) {
+        let chars = bytes.iter().map(|&b| b as char).collect::<String>();
+        write!(f, "'{}'", chars)
+    } else {
+        write!(f, "{:#010x}", code)
+    }
+}
+
+/// Parses four characters, optionally in single quotes, or a `0x`
+/// prefixed hex number.
+pub(crate) fn parse_four_char_code(s: &str) -> Option<u32> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        return u32::from_str_radix(&s[2..], 16).ok();
+    }
+    let s = if s.len() == 6 && s.starts_with('\'') && s.ends_with('\'') {
+        &s[1..5]
+    } else {
+        s
+    };
+    if s.len() != 4 || !s.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+        return None;
+    }
+    Some(s.bytes().fold(0, |code, b| (code << 8) | u32::from(b)))
+}
@@ -0,0 +1,424 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Conversion between the interleaved and non-interleaved shapes of an
+//! `AudioBufferList`.
+
+use {AudioBufferListRef, AudioFormat, AudioFormatFlags, AudioStreamBasicDescriptionRef,
+     SampleError, SampleResult};
+use ffi;
+use std::{mem, ptr, slice};
+
+/// One channel of the samples in a list: the first sample of the
+/// channel, and the distance in samples between consecutive frames.
+#[derive(Clone, Copy)]
+pub(crate) struct Channel {
+    pub(crate) data: *mut u8,
+    pub(crate) stride: usize,
+}
+
+/// The channels of `abl`, in order, with `sample_bytes` per sample.
+pub(crate) fn channels<'a>(
+    abl: &'a AudioBufferListRef,
+    sample_bytes: usize,
+) -> impl Iterator<Item = Channel> + Clone + 'a {
+    abl.iter().flat_map(move |buffer| {
+        let ab: &ffi::AudioBuffer = unsafe { &*buffer.as_ptr() };
+        let stride = ab.mNumberChannels as usize;
+        let data = ab.mData as *mut u8;
+        (0..stride).map(move |c| Channel {
+            data: if data.is_null() {
+                data
+            } else {
+                unsafe { data.add(c * sample_bytes) }
+            },
+            stride,
+        })
+    })
+}
+
+pub(crate) fn channel_count(abl: &AudioBufferListRef) -> usize {
+    abl.iter().map(|b| b.num_channels()).sum()
+}
+
+/// The number of whole frames every buffer in `abl` holds.
+pub(crate) fn frame_count(abl: &AudioBufferListRef, sample_bytes: usize) -> usize {
+    abl.iter()
+        .filter_map(|buffer| {
+            let ab: &ffi::AudioBuffer = unsafe { &*buffer.as_ptr() };
+            let frame_bytes = ab.mNumberChannels as usize * sample_bytes;
+            if ab.mData.is_null() || frame_bytes == 0 {
+                None
+            } else {
+                Some(ab.mDataByteSize as usize / frame_bytes)
+            }
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+/// The size of one sample of packed linear PCM `format`.
+fn sample_bytes(format: &AudioStreamBasicDescriptionRef) -> SampleResult<usize> {
+    if format.format_id() != AudioFormat::LinearPcm {
+        return Err(SampleError::NotLinearPcm);
+    }
+    let flags = format.format_flags();
+    let channels = format.channels_per_frame();
+    let bytes = if flags.contains(AudioFormatFlags::IS_NON_INTERLEAVED) || channels == 0 {
+        format.bytes_per_frame()
+    } else {
+        format.bytes_per_frame() / channels
+    } as usize;
+    match bytes {
+        1 | 2 | 3 | 4 | 8 if format.bits_per_channel() as usize == bytes * 8 => Ok(bytes),
+        _ => Err(SampleError::TypeMismatch),
+    }
+}
+
+/// Copies `frames` samples from `src` to `dst`.
+///
+/// `T` only needs to be the size of a sample; the bits are copied
+/// unchanged.
+unsafe fn copy_channel<T: Copy>(src: Channel, dst: Channel, frames: usize) {
+    if src.stride == 1 && dst.stride == 1 {
+        ptr::copy_nonoverlapping(src.data as *const T, dst.data as *mut T, frames);
+        return;
+    }
+    let (src_stride, dst_stride) = (src.stride, dst.stride);
+    let src = slice::from_raw_parts(src.data as *const T, (frames - 1) * src_stride + 1);
+    let dst = slice::from_raw_parts_mut(dst.data as *mut T, (frames - 1) * dst_stride + 1);
+    let src = src.iter().step_by(src_stride);
+    for (d, s) in dst.iter_mut().step_by(dst_stride).zip(src) {
+        *d = *s;
+    }
+}
+
+/// Interleaves two channels into a stereo buffer.
+unsafe fn interleave_stereo<T: Copy>(left: Channel, right: Channel, dst: Channel, frames: usize) {
+    let left = slice::from_raw_parts(left.data as *const T, frames);
+    let right = slice::from_raw_parts(right.data as *const T, frames);
+    let dst = slice::from_raw_parts_mut(dst.data as *mut T, frames * 2);
+    for (d, (l, r)) in dst.chunks_exact_mut(2).zip(left.iter().zip(right)) {
+        d[0] = *l;
+        d[1] = *r;
+    }
+}
+
+/// Splits a stereo buffer into two channels.
+unsafe fn deinterleave_stereo<T: Copy>(src: Channel, left: Channel, right: Channel, frames: usize) {
+    let src = slice::from_raw_parts(src.data as *const T, frames * 2);
+    let left = slice::from_raw_parts_mut(left.data as *mut T, frames);
+    let right = slice::from_raw_parts_mut(right.data as *mut T, frames);
+    for (s, (l, r)) in src.chunks_exact(2).zip(left.iter_mut().zip(right.iter_mut())) {
+        *l = s[0];
+        *r = s[1];
+    }
+}
+
+fn is_aligned<T>(channels: &[Channel]) -> bool {
+    channels
+        .iter()
+        .all(|c| c.data as usize % mem::align_of::<T>() == 0)
+}
+
+/// Calls `$f::<T>` with `T` an integer the size of a sample when every
+/// channel is aligned for it, falling back to a byte array.
+macro_rules! dispatch_width {
+    ($bytes:expr, $channels:expr, $f:ident($($arg:expr),*)) => {
+        match $bytes {
+            1 => $f::<u8>($($arg),*),
+            2 if is_aligned::<u16>($channels) => $f::<u16>($($arg),*),
+            2 => $f::<[u8; 2]>($($arg),*),
+            3 => $f::<[u8; 3]>($($arg),*),
+            4 if is_aligned::<u32>($channels) => $f::<u32>($($arg),*),
+            4 => $f::<[u8; 4]>($($arg),*),
+            8 if is_aligned::<u64>($channels) => $f::<u64>($($arg),*),
+            _ => $f::<[u8; 8]>($($arg),*),
+        }
+    };
+}
+
+impl AudioBufferListRef {
+    /// Copies the channels of this list to the channels of `dst`,
+    /// whatever the shape of either list, and returns the number of
+    /// frames copied.
+    ///
+    /// Channels are numbered across the buffers of a list in order.
+    /// Channel `i` of `dst` receives channel `map[i]` of this list, or
+    /// channel `i` without a map, in which case both lists must have
+    /// the same number of channels. Only the sample size of `format`
+    /// is used, so it may describe either shape. Every packed linear
+    /// PCM sample size is supported. Buffers with no data are skipped.
+    pub fn copy_channels_into(
+        &self,
+        dst: &mut AudioBufferListRef,
+        format: &AudioStreamBasicDescriptionRef,
+        map: Option<&[usize]>,
+    ) -> SampleResult<usize> {
+        let sb = sample_bytes(format)?;
+        let src_channels = channel_count(self);
+        let dst_channels = channel_count(dst);
+        match map {
+            Some(map) => {
+                if map.len() != dst_channels {
+                    return Err(SampleError::BufferLayout);
+                }
+                if let Some(&c) = map.iter().find(|&&c| c >= src_channels) {
+                    return Err(SampleError::NoSuchChannel(c));
+                }
+            }
+            None => if src_channels != dst_channels {
+                return Err(SampleError::BufferLayout);
+            },
+        }
+        let frames = frame_count(self, sb).min(frame_count(dst, sb));
+        if frames == 0 {
+            return Ok(0);
+        }
+
+        let src = channels(self, sb);
+        for (i, d) in channels(dst, sb).enumerate() {
+            let s = src.clone()
+                .nth(map.map_or(i, |m| m[i]))
+                .expect("channel was validated");
+            if s.data.is_null() || d.data.is_null() {
+                continue;
+            }
+            unsafe { dispatch_width!(sb, &[s, d], copy_channel(s, d, frames)) };
+        }
+        Ok(frames)
+    }
+
+    /// Interleaves the channels of this list into the single buffer of
+    /// `dst`. See `copy_channels_into` for `format` and `map`.
+    ///
+    /// Stereo with 16- and 32-bit samples, which covers `i16` and
+    /// `f32`, is copied by a loop the compiler can vectorize.
+    pub fn interleave_into(
+        &self,
+        dst: &mut AudioBufferListRef,
+        format: &AudioStreamBasicDescriptionRef,
+        map: Option<&[usize]>,
+    ) -> SampleResult<usize> {
+        if dst.buffer_count() != 1 {
+            return Err(SampleError::BufferLayout);
+        }
+        let sb = sample_bytes(format)?;
+        let identity = map.map_or(true, |m| m == [0, 1]);
+        if identity && self.buffer_count() == 2 && channel_count(self) == 2
+            && channel_count(dst) == 2
+        {
+            let frames = frame_count(self, sb).min(frame_count(dst, sb));
+            let mut src = channels(self, sb);
+            let (l, r) = (src.next().unwrap(), src.next().unwrap());
+            let d = channels(dst, sb).next().unwrap();
+            let all = [l, r, d];
+            if frames > 0 && all.iter().all(|c| !c.data.is_null()) {
+                match sb {
+                    2 if is_aligned::<u16>(&all) => unsafe {
+                        interleave_stereo::<u16>(l, r, d, frames)
+                    },
+                    4 if is_aligned::<u32>(&all) => unsafe {
+                        interleave_stereo::<u32>(l, r, d, frames)
+                    },
+                    _ => return self.copy_channels_into(dst, format, map),
+                }
+                return Ok(frames);
+            }
+        }
+        self.copy_channels_into(dst, format, map)
+    }
+
+    /// Splits the channels of this list into the single-channel buffers
+    /// of `dst`. See `copy_channels_into` for `format` and `map`.
+    ///
+    /// Stereo with 16- and 32-bit samples, which covers `i16` and
+    /// `f32`, is copied by a loop the compiler can vectorize.
+    pub fn deinterleave_into(
+        &self,
+        dst: &mut AudioBufferListRef,
+        format: &AudioStreamBasicDescriptionRef,
+        map: Option<&[usize]>,
+    ) -> SampleResult<usize> {
+        if dst.iter().any(|b| b.num_channels() != 1) {
+            return Err(SampleError::BufferLayout);
+        }
+        let sb = sample_bytes(format)?;
+        let identity = map.map_or(true, |m| m == [0, 1]);
+        if identity && self.buffer_count() == 1 && channel_count(self) == 2
+            && dst.buffer_count() == 2
+        {
+            let frames = frame_count(self, sb).min(frame_count(dst, sb));
+            let s = channels(self, sb).next().unwrap();
+            let (l, r) = {
+                let mut dsts = channels(dst, sb);
+                (dsts.next().unwrap(), dsts.next().unwrap())
+            };
+            let all = [s, l, r];
+            if frames > 0 && all.iter().all(|c| !c.data.is_null()) {
+                match sb {
+                    2 if is_aligned::<u16>(&all) => unsafe {
+                        deinterleave_stereo::<u16>(s, l, r, frames)
+                    },
+                    4 if is_aligned::<u32>(&all) => unsafe {
+                        deinterleave_stereo::<u32>(s, l, r, frames)
+                    },
+                    _ => return self.copy_channels_into(dst, format, map),
+                }
+                return Ok(frames);
+            }
+        }
+        self.copy_channels_into(dst, format, map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioBufferList, AudioStreamBasicDescription};
+
+    fn format(bits: u32, channels: u32, non_interleaved: bool) -> AudioStreamBasicDescription {
+        let builder = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, 48000., channels)
+            .bits_per_channel(bits)
+            .big_endian(false);
+        if non_interleaved {
+            builder.non_interleaved().build()
+        } else {
+            builder.build()
+        }
+    }
+
+    /// Fills every byte of sample `s` of channel `c` with `c * 16 + s`,
+    /// counting channels across the buffers.
+    fn fill(abl: &mut AudioBufferListRef, sample_bytes: usize) {
+        let mut first = 0;
+        for buffer in abl.iter_mut() {
+            let channels = buffer.num_channels();
+            for (i, sample) in buffer.chunks_mut(sample_bytes).enumerate() {
+                let (s, c) = (i / channels, first + i % channels);
+                for b in sample {
+                    *b = (c * 16 + s) as u8;
+                }
+            }
+            first += channels;
+        }
+    }
+
+    fn round_trip(bits: u32) {
+        let sb = bits as usize / 8;
+        let mut planar = AudioBufferList::allocate(&format(bits, 2, true), 5);
+        fill(&mut planar, sb);
+        let mut interleaved = AudioBufferList::allocate(&format(bits, 2, false), 5);
+        let fmt = format(bits, 2, false);
+        assert_eq!(planar.interleave_into(&mut interleaved, &fmt, None), Ok(5));
+        // Interleaved, channel 1 follows channel 0 in every frame.
+        for (i, sample) in interleaved[0].chunks(sb).enumerate() {
+            assert!(sample.iter().all(|&b| b as usize == (i % 2) * 16 + i / 2));
+        }
+
+        let mut back = AudioBufferList::allocate(&format(bits, 2, true), 5);
+        assert_eq!(interleaved.deinterleave_into(&mut back, &fmt, None), Ok(5));
+        assert_eq!(&*back, &*planar);
+    }
+
+    #[test]
+    fn stereo_round_trips() {
+        for &bits in &[8, 16, 24, 32, 64] {
+            round_trip(bits);
+        }
+    }
+
+    #[test]
+    fn channel_maps() {
+        let fmt = format(16, 3, false);
+        let mut src = AudioBufferList::allocate(&fmt, 4);
+        fill(&mut src, 2);
+        let mut dst = AudioBufferList::allocate(&format(16, 2, true), 4);
+        assert_eq!(src.deinterleave_into(&mut dst, &fmt, Some(&[2, 0])), Ok(4));
+        assert!(dst[0].iter().enumerate().all(|(i, &b)| b as usize == 32 + i / 2));
+        assert!(dst[1].iter().enumerate().all(|(i, &b)| b as usize == i / 2));
+
+        // Swapping the channels of stereo skips the fast path.
+        let stereo = format(16, 2, false);
+        let mut swapped = AudioBufferList::allocate(&stereo, 4);
+        assert_eq!(dst.interleave_into(&mut swapped, &stereo, Some(&[1, 0])), Ok(4));
+        assert_eq!(&swapped[0][..4], &[0, 0, 32, 32]);
+    }
+
+    #[test]
+    fn copies_the_shortest_length() {
+        let fmt = format(32, 2, true);
+        let mut src = AudioBufferList::allocate(&fmt, 8);
+        fill(&mut src, 4);
+        let mut dst = AudioBufferList::allocate(&format(32, 2, false), 3);
+        assert_eq!(src.interleave_into(&mut dst, &fmt, None), Ok(3));
+    }
+
+    #[test]
+    fn misaligned_buffers_use_the_byte_path() {
+        let fmt = format(32, 2, true);
+        let mut src = AudioBufferList::allocate(&fmt, 5);
+        fill(&mut src, 4);
+        let dst = AudioBufferList::allocate(&format(32, 2, false), 5);
+        unsafe {
+            let buffer = &mut (*dst.as_ptr()).mBuffers[0];
+            buffer.mData = (buffer.mData as *mut u8).add(1) as *mut _;
+            buffer.mDataByteSize -= 8;
+        }
+        let mut dst = dst;
+        assert_eq!(src.interleave_into(&mut dst, &fmt, None), Ok(4));
+        assert_eq!(&dst[0][..8], &[0, 0, 0, 0, 16, 16, 16, 16]);
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        let fmt = format(16, 2, false);
+        let src = AudioBufferList::allocate(&fmt, 4);
+        let mut mono = AudioBufferList::allocate(&format(16, 1, false), 4);
+        assert_eq!(
+            src.copy_channels_into(&mut mono, &fmt, None),
+            Err(SampleError::BufferLayout)
+        );
+        assert_eq!(
+            src.copy_channels_into(&mut mono, &fmt, Some(&[0, 1])),
+            Err(SampleError::BufferLayout)
+        );
+        assert_eq!(
+            src.copy_channels_into(&mut mono, &fmt, Some(&[2])),
+            Err(SampleError::NoSuchChannel(2))
+        );
+        // Interleaving needs one buffer and deinterleaving needs
+        // single-channel buffers.
+        let mut planar = AudioBufferList::allocate(&format(16, 2, true), 4);
+        assert_eq!(
+            planar.clone().interleave_into(&mut planar, &fmt, None),
+            Err(SampleError::BufferLayout)
+        );
+        assert_eq!(
+            src.deinterleave_into(&mut src.clone(), &fmt, None),
+            Err(SampleError::BufferLayout)
+        );
+
+        let aac: AudioStreamBasicDescription =
+            "2 ch, 44100 Hz, 'aac ', 1024 frames/packet".parse().unwrap();
+        assert_eq!(
+            src.copy_channels_into(&mut src.clone(), &aac, None),
+            Err(SampleError::NotLinearPcm)
+        );
+        let padded = AudioStreamBasicDescription::with_lpcm(48000., 2, 20, 32, false, false, false);
+        assert_eq!(
+            src.copy_channels_into(&mut src.clone(), &padded, None),
+            Err(SampleError::TypeMismatch)
+        );
+    }
+
+    #[test]
+    fn buffers_without_data_are_skipped() {
+        let fmt = format(16, 2, true);
+        let mut src = AudioBufferList::with_len(2);
+        for buffer in src.iter_mut() {
+            unsafe { (*buffer.as_ptr()).mNumberChannels = 1 };
+        }
+        let mut dst = AudioBufferList::allocate(&fmt, 4);
+        assert_eq!(src.copy_channels_into(&mut dst, &fmt, None), Ok(0));
+    }
+}
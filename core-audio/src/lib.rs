@@ -1,6 +1,8 @@
 #[macro_use]
 extern crate bitflags;
 extern crate core_foundation;
+#[cfg(feature = "async")]
+extern crate futures_core;
 extern crate libc;
 pub extern crate core_audio_sys as ffi;
 
@@ -9,20 +11,61 @@ mod ffi_types;
 
 mod error;
 mod call;
+mod channel_layout_tag;
 mod core_audio_types;
 mod audio_hardware;
+mod device_stream;
+mod backend;
+mod simulated_hal;
+mod format_converter;
+mod format_negotiation;
+mod four_char_code;
 mod host_time;
+mod interleave;
+mod mix_matrix;
+mod panner;
+pub mod property_events;
+#[cfg(feature = "async")]
+mod property_stream;
 mod audio_buffer_list;
+mod aiff_file;
+mod audio_file;
+mod caf_file;
+mod resampler;
+mod ring_buffer;
+mod sample;
+mod stream_description;
+mod wav_file;
 mod audio_channel_layout;
+mod ambisonics;
 
 pub type Result<T> = ::std::result::Result<T, error::Error>;
 
+pub use aiff_file::*;
+pub use ambisonics::*;
 pub use audio_buffer_list::*;
+pub use audio_file::*;
 pub use audio_channel_layout::*;
+pub use caf_file::*;
 pub use audio_hardware::*;
+pub use backend::*;
 pub use core_audio_types::*;
+pub use device_stream::*;
 pub use error::*;
+pub use format_converter::*;
+pub use format_negotiation::*;
 pub use host_time::*;
+pub use mix_matrix::*;
+pub use panner::*;
+pub use property_events::{PropertyChange, PropertyEvent, PropertyEventReceiver};
+pub use resampler::*;
+pub use ring_buffer::*;
+pub use sample::*;
+pub use stream_description::*;
+#[cfg(feature = "async")]
+pub use property_stream::*;
+pub use simulated_hal::*;
+pub use wav_file::*;
 
 bitflags! {
     pub struct AudioChannelBitmap: ffi::AudioChannelBitmap {
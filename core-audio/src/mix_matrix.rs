@@ -0,0 +1,490 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Mixing matrices between channel layouts, for downmixing and upmixing.
+
+use {AudioBufferListRef, AudioChannelLayoutRef, AudioStreamBasicDescriptionRef};
+use ffi;
+use interleave::{channel_count, channels, frame_count};
+use sample::check_format;
+use std::{error, f32, fmt, ptr, result};
+
+/// Why a `MixMatrix` couldn't be created or applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MixError {
+    /// A layout doesn't say what its channels are.
+    UnknownLayout,
+    /// The format isn't native endian 32-bit float linear PCM.
+    UnsupportedFormat,
+    /// A buffer list doesn't hold the channels of the matrix.
+    BufferLayout,
+}
+
+impl fmt::Display for MixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl error::Error for MixError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl MixError {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            MixError::UnknownLayout => "the channel layout doesn't describe its channels",
+            MixError::UnsupportedFormat => "mixing requires native endian 32-bit float linear PCM",
+            MixError::BufferLayout => "the buffers don't match the matrix's channels",
+        }
+    }
+}
+
+pub type MixResult<T> = result::Result<T, MixError>;
+
+/// What to do with a low frequency effects channel when the
+/// destination has none.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LfeMode {
+    /// Drop it, as ITU-R BS.775 does.
+    Discard,
+    /// Mix it into the center channel with the given gain. Without a
+    /// center it goes where a center channel would, so the front left
+    /// and right channels each get the gain times -3 dB.
+    MixIntoMains(f32),
+}
+
+/// Options for generating a `MixMatrix`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MixOptions {
+    pub lfe: LfeMode,
+    /// Scale the matrix down, if needed, so that no output channel can
+    /// exceed full scale when the inputs are within it.
+    pub normalize: bool,
+}
+
+impl Default for MixOptions {
+    fn default() -> Self {
+        MixOptions {
+            lfe: LfeMode::Discard,
+            normalize: true,
+        }
+    }
+}
+
+const L: u32 = ffi::kAudioChannelLabel_Left;
+const R: u32 = ffi::kAudioChannelLabel_Right;
+const C: u32 = ffi::kAudioChannelLabel_Center;
+const LFE: u32 = ffi::kAudioChannelLabel_LFEScreen;
+const LS: u32 = ffi::kAudioChannelLabel_LeftSurround;
+const RS: u32 = ffi::kAudioChannelLabel_RightSurround;
+const CS: u32 = ffi::kAudioChannelLabel_CenterSurround;
+const RLS: u32 = ffi::kAudioChannelLabel_RearSurroundLeft;
+const RRS: u32 = ffi::kAudioChannelLabel_RearSurroundRight;
+
+/// -3 dB.
+const HALF_POWER: f32 = f32::consts::FRAC_1_SQRT_2;
+
+/// Channels that are the same speaker as another label for mixing.
+fn canonical(label: u32) -> u32 {
+    match label {
+        ffi::kAudioChannelLabel_Mono => C,
+        ffi::kAudioChannelLabel_HeadphonesLeft | ffi::kAudioChannelLabel_LeftTotal => L,
+        ffi::kAudioChannelLabel_HeadphonesRight | ffi::kAudioChannelLabel_RightTotal => R,
+        _ => label,
+    }
+}
+
+/// Channels and the gain to send to each.
+type Gains = &'static [(u32, f32)];
+
+/// Where a channel goes when the destination doesn't have it: first
+/// the sets of channels to use if they're all in the destination, then
+/// the channels to fall back to, which are themselves mapped.
+///
+/// The fallbacks follow ITU-R BS.775: surrounds and the center mix
+/// into the front channels at -3 dB. Rear and side surrounds
+/// substitute for each other.
+fn fallbacks(label: u32) -> (&'static [Gains], Gains) {
+    match label {
+        L | R => (&[], &[(C, HALF_POWER)]),
+        C => (&[], &[(L, HALF_POWER), (R, HALF_POWER)]),
+        LS => (&[&[(RLS, 1.)]], &[(L, HALF_POWER)]),
+        RS => (&[&[(RRS, 1.)]], &[(R, HALF_POWER)]),
+        RLS => (&[&[(LS, 1.)]], &[(L, HALF_POWER)]),
+        RRS => (&[&[(RS, 1.)]], &[(R, HALF_POWER)]),
+        CS => (
+            &[
+                &[(LS, HALF_POWER), (RS, HALF_POWER)],
+                &[(RLS, HALF_POWER), (RRS, HALF_POWER)],
+            ],
+            &[(L, 0.5), (R, 0.5)],
+        ),
+        ffi::kAudioChannelLabel_LeftCenter | ffi::kAudioChannelLabel_LeftWide => (&[], &[(L, 1.)]),
+        ffi::kAudioChannelLabel_RightCenter | ffi::kAudioChannelLabel_RightWide => {
+            (&[], &[(R, 1.)])
+        }
+        ffi::kAudioChannelLabel_LeftSurroundDirect => (&[], &[(LS, 1.)]),
+        ffi::kAudioChannelLabel_RightSurroundDirect => (&[], &[(RS, 1.)]),
+        ffi::kAudioChannelLabel_CenterSurroundDirect => (&[], &[(CS, 1.)]),
+        ffi::kAudioChannelLabel_VerticalHeightLeft => (&[], &[(L, HALF_POWER)]),
+        ffi::kAudioChannelLabel_VerticalHeightRight => (&[], &[(R, HALF_POWER)]),
+        ffi::kAudioChannelLabel_VerticalHeightCenter
+        | ffi::kAudioChannelLabel_TopCenterSurround => (&[], &[(C, HALF_POWER)]),
+        ffi::kAudioChannelLabel_TopBackLeft => (&[], &[(LS, HALF_POWER)]),
+        ffi::kAudioChannelLabel_TopBackRight => (&[], &[(RS, HALF_POWER)]),
+        ffi::kAudioChannelLabel_TopBackCenter => (&[], &[(CS, HALF_POWER)]),
+        ffi::kAudioChannelLabel_LFE2 => (&[], &[(LFE, 1.)]),
+        _ => (&[], &[]),
+    }
+}
+
+/// Deep enough for the longest chain of fallbacks, and stops the
+/// front channels falling back to each other forever.
+const MAX_DEPTH: usize = 4;
+
+/// Adds `gain` times the contribution of a `label` channel to each
+/// destination channel to `row`. Returns whether it went anywhere.
+fn route(
+    label: u32,
+    gain: f32,
+    dst: &[u32],
+    options: &MixOptions,
+    depth: usize,
+    row: &mut [f32],
+) -> bool {
+    if depth > MAX_DEPTH {
+        return false;
+    }
+    if let Some(i) = dst.iter().position(|&d| d == label) {
+        row[i] += gain;
+        return true;
+    }
+    let (alternatives, parents) = fallbacks(label);
+    for alternative in alternatives {
+        let indices = alternative
+            .iter()
+            .map(|&(l, _)| dst.iter().position(|&d| d == l))
+            .collect::<Option<Vec<_>>>();
+        if let Some(indices) = indices {
+            for (i, &(_, g)) in indices.into_iter().zip(alternative.iter()) {
+                row[i] += gain * g;
+            }
+            return true;
+        }
+    }
+    let lfe_parents;
+    let parents = match (label, options.lfe) {
+        (LFE, LfeMode::MixIntoMains(g)) => {
+            lfe_parents = [(C, g)];
+            &lfe_parents[..]
+        }
+        _ => parents,
+    };
+    if parents.is_empty() {
+        return false;
+    }
+    // Only go through the parents if they all arrive somewhere, so a
+    // channel isn't sent to half of a pair.
+    let mut scratch = vec![0.; row.len()];
+    for &(parent, g) in parents {
+        if !route(parent, gain * g, dst, options, depth + 1, &mut scratch) {
+            return false;
+        }
+    }
+    for (r, s) in row.iter_mut().zip(scratch) {
+        *r += s;
+    }
+    true
+}
+
+/// Gains for mixing each channel of one layout into each channel of
+/// another.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixMatrix {
+    src_channels: usize,
+    dst_channels: usize,
+    /// Row `d` holds the gains of the source channels in destination
+    /// channel `d`.
+    coefficients: Vec<f32>,
+}
+
+impl MixMatrix {
+    /// Creates a matrix mixing `src` into `dst` with the default
+    /// options: the LFE channel is discarded when `dst` has none, and
+    /// the matrix is normalized.
+    pub fn new(src: &AudioChannelLayoutRef, dst: &AudioChannelLayoutRef) -> MixResult<MixMatrix> {
+        MixMatrix::with_options(src, dst, &MixOptions::default())
+    }
+
+    /// Creates a matrix mixing `src` into `dst`.
+    ///
+    /// Channels in both layouts pass straight through. Other channels
+    /// are mixed into the nearest channels of `dst` with the ITU-R
+    /// BS.775 coefficients, so 5.1 to stereo gives
+    /// `L' = L + 0.707 C + 0.707 Ls`, and mono to stereo sends the
+    /// center to both sides at -3 dB. Channels with no sensible
+    /// destination, such as discrete channels `dst` doesn't have, are
+    /// dropped. Channels of `dst` nothing maps to are silent.
+    pub fn with_options(
+        src: &AudioChannelLayoutRef,
+        dst: &AudioChannelLayoutRef,
+        options: &MixOptions,
+    ) -> MixResult<MixMatrix> {
+        let src_labels = src.channel_labels().ok_or(MixError::UnknownLayout)?;
+        let dst_labels = dst.channel_labels().ok_or(MixError::UnknownLayout)?;
+        let dst_labels = dst_labels
+            .into_iter()
+            .map(|l| canonical(ffi::AudioChannelLabel::from(l)))
+            .collect::<Vec<_>>();
+        let src_channels = src_labels.len();
+        let dst_channels = dst_labels.len();
+
+        let mut matrix = MixMatrix::zeroed(src_channels, dst_channels);
+        let mut column = vec![0.; dst_channels];
+        for (s, &label) in src_labels.iter().enumerate() {
+            let label = canonical(ffi::AudioChannelLabel::from(label));
+            column.fill(0.);
+            route(label, 1., &dst_labels, options, 0, &mut column);
+            for (d, &gain) in column.iter().enumerate() {
+                matrix.coefficients[d * src_channels + s] = gain;
+            }
+        }
+        if options.normalize {
+            matrix.normalize();
+        }
+        Ok(matrix)
+    }
+
+    /// A matrix mixing nothing from `src_channels` channels into
+    /// `dst_channels` channels, for filling in with `set_coefficient`.
+    pub fn zeroed(src_channels: usize, dst_channels: usize) -> MixMatrix {
+        MixMatrix {
+            src_channels,
+            dst_channels,
+            coefficients: vec![0.; src_channels * dst_channels],
+        }
+    }
+
+    /// A matrix passing `channels` channels straight through.
+    pub fn identity(channels: usize) -> MixMatrix {
+        let mut matrix = MixMatrix::zeroed(channels, channels);
+        for c in 0..channels {
+            matrix.coefficients[c * channels + c] = 1.;
+        }
+        matrix
+    }
+
+    pub fn src_channels(&self) -> usize {
+        self.src_channels
+    }
+
+    pub fn dst_channels(&self) -> usize {
+        self.dst_channels
+    }
+
+    /// The gain of source channel `src` in destination channel `dst`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either channel is out of range.
+    pub fn coefficient(&self, dst: usize, src: usize) -> f32 {
+        assert!(dst < self.dst_channels && src < self.src_channels);
+        self.coefficients[dst * self.src_channels + src]
+    }
+
+    /// Sets the gain of source channel `src` in destination channel
+    /// `dst`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either channel is out of range.
+    pub fn set_coefficient(&mut self, dst: usize, src: usize, gain: f32) {
+        assert!(dst < self.dst_channels && src < self.src_channels);
+        self.coefficients[dst * self.src_channels + src] = gain;
+    }
+
+    /// The gains of the source channels in destination channel `dst`.
+    pub fn row(&self, dst: usize) -> &[f32] {
+        &self.coefficients[dst * self.src_channels..(dst + 1) * self.src_channels]
+    }
+
+    /// Scales the matrix down so that the gains in each destination
+    /// channel add up to at most 1, keeping the balance between the
+    /// channels. A matrix that can't clip is left alone.
+    pub fn normalize(&mut self) {
+        let loudest = (0..self.dst_channels)
+            .map(|d| self.row(d).iter().map(|g| g.abs()).sum::<f32>())
+            .fold(0., f32::max);
+        if loudest > 1. {
+            for c in &mut self.coefficients {
+                *c /= loudest;
+            }
+        }
+    }
+
+    /// Mixes the frames of `src` into `dst`, replacing its contents,
+    /// and returns the number of frames mixed.
+    ///
+    /// `format` gives the sample type of both lists, which must be
+    /// native endian 32-bit float linear PCM. Either list may be
+    /// interleaved or not. Buffers with no data are skipped.
+    pub fn apply(
+        &self,
+        src: &AudioBufferListRef,
+        dst: &mut AudioBufferListRef,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> MixResult<usize> {
+        check_format::<f32>(format).map_err(|_| MixError::UnsupportedFormat)?;
+        if channel_count(src) != self.src_channels || channel_count(dst) != self.dst_channels {
+            return Err(MixError::BufferLayout);
+        }
+        let sample_bytes = 4;
+        let frames = frame_count(src, sample_bytes).min(frame_count(dst, sample_bytes));
+        let src_channels = channels(src, sample_bytes);
+        for (d, out) in channels(dst, sample_bytes).enumerate() {
+            if out.data.is_null() {
+                continue;
+            }
+            let out_at = |f: usize| unsafe { out.data.add(f * out.stride * sample_bytes) as *mut f32 };
+            for f in 0..frames {
+                unsafe { ptr::write_unaligned(out_at(f), 0.) };
+            }
+            for (input, &gain) in src_channels.clone().zip(self.row(d)) {
+                if gain == 0. || input.data.is_null() {
+                    continue;
+                }
+                for f in 0..frames {
+                    unsafe {
+                        let p = input.data.add(f * input.stride * sample_bytes) as *const f32;
+                        let sum = ptr::read_unaligned(out_at(f)) + gain * ptr::read_unaligned(p);
+                        ptr::write_unaligned(out_at(f), sum);
+                    }
+                }
+            }
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioBufferList, AudioChannelLayout, AudioChannelLayoutTag, AudioStreamBasicDescription};
+
+    fn layout(tag: ffi::AudioChannelLayoutTag) -> AudioChannelLayout {
+        AudioChannelLayout::with_tag(AudioChannelLayoutTag::from(tag))
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    fn raw_options() -> MixOptions {
+        MixOptions {
+            normalize: false,
+            ..MixOptions::default()
+        }
+    }
+
+    #[test]
+    fn surround_to_stereo() {
+        // L R C LFE Ls Rs
+        let src = layout(ffi::kAudioChannelLayoutTag_MPEG_5_1_A);
+        let dst = layout(ffi::kAudioChannelLayoutTag_Stereo);
+        let matrix = MixMatrix::with_options(&src, &dst, &raw_options()).unwrap();
+        assert_eq!((matrix.src_channels(), matrix.dst_channels()), (6, 2));
+        assert_eq!(matrix.row(0), &[1., 0., HALF_POWER, 0., HALF_POWER, 0.]);
+        assert_eq!(matrix.row(1), &[0., 1., HALF_POWER, 0., 0., HALF_POWER]);
+
+        let normalized = MixMatrix::new(&src, &dst).unwrap();
+        let sum: f32 = normalized.row(0).iter().sum();
+        assert!(close(sum, 1.));
+        assert!(close(normalized.coefficient(0, 0) / normalized.coefficient(0, 2), 1. / HALF_POWER));
+    }
+
+    #[test]
+    fn mono_and_stereo() {
+        let mono = layout(ffi::kAudioChannelLayoutTag_Mono);
+        let stereo = layout(ffi::kAudioChannelLayoutTag_Stereo);
+        let up = MixMatrix::new(&mono, &stereo).unwrap();
+        assert_eq!(up.row(0), &[HALF_POWER]);
+        assert_eq!(up.row(1), &[HALF_POWER]);
+        let down = MixMatrix::with_options(&stereo, &mono, &raw_options()).unwrap();
+        assert_eq!(down.row(0), &[HALF_POWER, HALF_POWER]);
+        assert_eq!(MixMatrix::new(&stereo, &stereo).unwrap(), MixMatrix::identity(2));
+    }
+
+    #[test]
+    fn lfe_and_surrounds() {
+        let src = layout(ffi::kAudioChannelLayoutTag_MPEG_5_1_A);
+        let dst = layout(ffi::kAudioChannelLayoutTag_MPEG_3_0_A);
+        let options = MixOptions {
+            lfe: LfeMode::MixIntoMains(0.5),
+            normalize: false,
+        };
+        let matrix = MixMatrix::with_options(&src, &dst, &options).unwrap();
+        assert_eq!(matrix.coefficient(2, 3), 0.5);
+        let matrix = MixMatrix::with_options(&src, &dst, &raw_options()).unwrap();
+        assert_eq!(matrix.coefficient(2, 3), 0.);
+        let stereo = layout(ffi::kAudioChannelLayoutTag_Stereo);
+        let matrix = MixMatrix::with_options(&src, &stereo, &options).unwrap();
+        assert_eq!(matrix.coefficient(0, 3), 0.5 * HALF_POWER);
+        assert_eq!(matrix.coefficient(1, 3), 0.5 * HALF_POWER);
+
+        // A center surround splits between the side surrounds, and rear
+        // surrounds stand in for side surrounds.
+        let src = AudioChannelLayout::with_labels(&[
+            CS.into(),
+            RLS.into(),
+            ffi::kAudioChannelLabel_Discrete_0.into(),
+        ]);
+        let dst = layout(ffi::kAudioChannelLayoutTag_MPEG_5_0_A);
+        let matrix = MixMatrix::with_options(&src, &dst, &raw_options()).unwrap();
+        let column = |s| (0..5).map(|d| matrix.coefficient(d, s)).collect::<Vec<_>>();
+        assert_eq!(column(0), [0., 0., 0., HALF_POWER, HALF_POWER]);
+        assert_eq!(column(1), [0., 0., 0., 1., 0.]);
+        // Discrete channels have nowhere to go.
+        assert_eq!(column(2), [0.; 5]);
+    }
+
+    #[test]
+    fn unknown_layouts() {
+        let stereo = layout(ffi::kAudioChannelLayoutTag_Stereo);
+        let unknown = layout(0x7fff_0002);
+        assert_eq!(MixMatrix::new(&unknown, &stereo), Err(MixError::UnknownLayout));
+        assert_eq!(MixMatrix::new(&stereo, &unknown), Err(MixError::UnknownLayout));
+    }
+
+    #[test]
+    fn apply_mixes_buffers() {
+        let mut matrix = MixMatrix::zeroed(2, 1);
+        matrix.set_coefficient(0, 0, 0.5);
+        matrix.set_coefficient(0, 1, -1.);
+        let stereo: AudioStreamBasicDescription = "f32@48000x2".parse().unwrap();
+        let mono: AudioStreamBasicDescription = "f32@48000x1".parse().unwrap();
+        let mut src = AudioBufferList::allocate(&stereo, 2);
+        for (b, s) in src[0].chunks_mut(4).zip(&[1f32, 0.25, -1., 0.5]) {
+            b.copy_from_slice(&s.to_ne_bytes());
+        }
+        let mut dst = AudioBufferList::allocate(&mono, 4);
+        dst[0].copy_from_slice(&[0xff; 16]);
+        assert_eq!(matrix.apply(&src, &mut dst, &mono), Ok(2));
+        let out = dst[0]
+            .chunks(4)
+            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>();
+        assert_eq!(&out[..2], &[0.25, -1.]);
+
+        assert_eq!(matrix.apply(&dst.clone(), &mut dst, &mono), Err(MixError::BufferLayout));
+        let s16: AudioStreamBasicDescription = "s16@48000x1".parse().unwrap();
+        assert_eq!(matrix.apply(&src, &mut dst, &s16), Err(MixError::UnsupportedFormat));
+    }
+
+    #[test]
+    #[should_panic]
+    fn coefficients_out_of_range_panic() {
+        MixMatrix::identity(2).set_coefficient(2, 0, 1.);
+    }
+}
@@ -0,0 +1,580 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Vector base amplitude panning of a mono source to the speakers of a
+//! channel layout.
+
+use {AudioBufferListRef, AudioChannelDescription, AudioChannelLayout, AudioChannelLayoutRef,
+     AudioChannelLayoutTag, AudioStreamBasicDescriptionRef, MixMatrix};
+use ambisonics::{coordinate_direction, is_lfe};
+use ffi;
+use interleave::{channel_count, channels, frame_count};
+use sample::check_format;
+use std::{error, fmt, ptr, result};
+
+/// Why a panner couldn't be created or couldn't render.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PannerError {
+    /// The layout's tag has no known channel labels.
+    UnknownLayout,
+    /// A speaker has neither finite coordinates nor a label with a
+    /// standard position. The index is the speaker's channel.
+    MissingCoordinates(usize),
+    /// The layout has no speakers to pan to.
+    NoSpeakers,
+    /// Rendering needs native-endian packed `f32` samples.
+    UnsupportedFormat,
+    /// The buffer list doesn't have a channel for each speaker.
+    BufferLayout,
+}
+
+impl fmt::Display for PannerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PannerError::MissingCoordinates(channel) => {
+                write!(f, "speaker {} has no position", channel)
+            }
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+impl error::Error for PannerError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PannerError {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            PannerError::UnknownLayout => "unknown channel layout",
+            PannerError::MissingCoordinates(_) => "a speaker has no position",
+            PannerError::NoSpeakers => "the layout has no speakers",
+            PannerError::UnsupportedFormat => "unsupported sample format",
+            PannerError::BufferLayout => "buffer list doesn't match the layout",
+        }
+    }
+}
+
+pub type PannerResult<T> = result::Result<T, PannerError>;
+
+/// Speakers with a `down_up` component smaller than this are treated as
+/// being on the horizon, about 6° either side.
+const HORIZON: f64 = 0.1;
+
+/// Gains smaller than this are taken as zero when finding the region a
+/// source is in.
+const EPSILON: f64 = 1e-9;
+
+/// A unit vector pointing front, left and up.
+type Vector = [f64; 3];
+
+fn vector(azimuth: f64, elevation: f64) -> Vector {
+    [
+        elevation.cos() * azimuth.cos(),
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+    ]
+}
+
+fn dot(a: &Vector, b: &Vector) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: &Vector, b: &Vector) -> Vector {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+/// The usual position of a speaker with `label`, as Core Audio azimuth
+/// and elevation in degrees.
+fn label_position(label: u32) -> Option<(f64, f64)> {
+    Some(match label {
+        ffi::kAudioChannelLabel_Left
+        | ffi::kAudioChannelLabel_LeftTotal
+        | ffi::kAudioChannelLabel_XY_X => (-30., 0.),
+        ffi::kAudioChannelLabel_Right
+        | ffi::kAudioChannelLabel_RightTotal
+        | ffi::kAudioChannelLabel_XY_Y => (30., 0.),
+        ffi::kAudioChannelLabel_Center | ffi::kAudioChannelLabel_Mono => (0., 0.),
+        ffi::kAudioChannelLabel_LeftSurround => (-110., 0.),
+        ffi::kAudioChannelLabel_RightSurround => (110., 0.),
+        ffi::kAudioChannelLabel_LeftCenter => (-15., 0.),
+        ffi::kAudioChannelLabel_RightCenter => (15., 0.),
+        ffi::kAudioChannelLabel_CenterSurround => (180., 0.),
+        ffi::kAudioChannelLabel_LeftSurroundDirect
+        | ffi::kAudioChannelLabel_HeadphonesLeft => (-90., 0.),
+        ffi::kAudioChannelLabel_RightSurroundDirect
+        | ffi::kAudioChannelLabel_HeadphonesRight => (90., 0.),
+        ffi::kAudioChannelLabel_TopCenterSurround => (0., 90.),
+        ffi::kAudioChannelLabel_VerticalHeightLeft => (-30., 45.),
+        ffi::kAudioChannelLabel_VerticalHeightCenter => (0., 45.),
+        ffi::kAudioChannelLabel_VerticalHeightRight => (30., 45.),
+        ffi::kAudioChannelLabel_TopBackLeft => (-135., 45.),
+        ffi::kAudioChannelLabel_TopBackCenter => (180., 45.),
+        ffi::kAudioChannelLabel_TopBackRight => (135., 45.),
+        ffi::kAudioChannelLabel_RearSurroundLeft => (-150., 0.),
+        ffi::kAudioChannelLabel_RearSurroundRight => (150., 0.),
+        ffi::kAudioChannelLabel_LeftWide => (-60., 0.),
+        ffi::kAudioChannelLabel_RightWide => (60., 0.),
+        _ => return None,
+    })
+}
+
+/// A pair or triangle of speakers, and the inverse of the matrix of
+/// their directions.
+#[derive(Clone, Debug)]
+struct Region {
+    speakers: Vec<usize>,
+    inverse: [Vector; 3],
+}
+
+impl Region {
+    fn pair(a: usize, b: usize, positions: &[Vector]) -> Option<Region> {
+        let (p, q) = (&positions[a], &positions[b]);
+        let det = p[0] * q[1] - p[1] * q[0];
+        // Pairs more than 180° apart don't enclose the arc between them.
+        if det <= EPSILON {
+            return None;
+        }
+        Some(Region {
+            speakers: vec![a, b],
+            inverse: [
+                [q[1] / det, -q[0] / det, 0.],
+                [-p[1] / det, p[0] / det, 0.],
+                [0.; 3],
+            ],
+        })
+    }
+
+    fn triangle(a: usize, b: usize, c: usize, positions: &[Vector]) -> Option<Region> {
+        let (p, q, r) = (&positions[a], &positions[b], &positions[c]);
+        let (qr, rp, pq) = (cross(q, r), cross(r, p), cross(p, q));
+        let det = dot(p, &qr);
+        if det.abs() <= EPSILON {
+            return None;
+        }
+        let row = |v: Vector| [v[0] / det, v[1] / det, v[2] / det];
+        Some(Region {
+            speakers: vec![a, b, c],
+            inverse: [row(qr), row(rp), row(pq)],
+        })
+    }
+
+    /// The gain of each speaker in the region for a source in
+    /// `direction`, if the source is inside it.
+    fn gains(&self, direction: &Vector) -> Option<Vec<f64>> {
+        let gains = self.inverse[..self.speakers.len()]
+            .iter()
+            .map(|row| dot(row, direction))
+            .collect::<Vec<_>>();
+        if gains.iter().all(|&g| g >= -EPSILON) {
+            Some(gains)
+        } else {
+            None
+        }
+    }
+}
+
+/// Pans a mono source to the speakers of a layout by vector base
+/// amplitude panning.
+///
+/// Speakers on the horizon are panned between in pairs. When any
+/// speaker is above or below the horizon, the speakers are triangulated
+/// into the faces of their convex hull. An imaginary speaker is added
+/// straight down or up when no speakers are there, and its gain is
+/// shared between the speakers around it.
+#[derive(Clone, Debug)]
+pub struct VbapPanner {
+    channels: usize,
+    /// The channel of each real speaker. Positions after these are
+    /// imaginary.
+    speakers: Vec<usize>,
+    positions: Vec<Vector>,
+    /// The real speakers next to each imaginary one.
+    neighbours: Vec<Vec<usize>>,
+    regions: Vec<Region>,
+    three_d: bool,
+}
+
+impl VbapPanner {
+    /// A panner for the speakers in `layout`.
+    ///
+    /// Speakers are placed by the coordinates in their descriptions, or
+    /// else at the usual position for their label. LFE channels are
+    /// never panned to.
+    pub fn new(layout: &AudioChannelLayoutRef) -> PannerResult<VbapPanner> {
+        let descriptions = if layout.channel_layout_tag().use_channel_descriptions() {
+            layout.to_vec()
+        } else {
+            layout
+                .channel_labels()
+                .ok_or(PannerError::UnknownLayout)?
+                .into_iter()
+                .map(AudioChannelDescription::new)
+                .collect()
+        };
+
+        let mut speakers = Vec::new();
+        let mut positions = Vec::new();
+        for (channel, description) in descriptions.iter().enumerate() {
+            if is_lfe(description.channel_label) {
+                continue;
+            }
+            let (azimuth, elevation) = match coordinate_direction(description) {
+                Some((azimuth, elevation)) if azimuth.is_finite() && elevation.is_finite() => {
+                    (azimuth, elevation)
+                }
+                Some(_) => return Err(PannerError::MissingCoordinates(channel)),
+                None => {
+                    let label = ffi::AudioChannelLabel::from(description.channel_label);
+                    let (azimuth, elevation) =
+                        label_position(label).ok_or(PannerError::MissingCoordinates(channel))?;
+                    (-azimuth.to_radians(), elevation.to_radians())
+                }
+            };
+            speakers.push(channel);
+            positions.push(vector(azimuth, elevation));
+        }
+        if speakers.is_empty() {
+            return Err(PannerError::NoSpeakers);
+        }
+
+        let mut panner = VbapPanner {
+            channels: descriptions.len(),
+            speakers,
+            positions,
+            neighbours: Vec::new(),
+            regions: Vec::new(),
+            three_d: false,
+        };
+        panner.three_d = panner.positions.iter().any(|p| p[2].abs() > HORIZON);
+        if panner.three_d {
+            panner.triangulate();
+        } else {
+            panner.pair();
+        }
+        Ok(panner)
+    }
+
+    /// A panner for the speakers in the layout with `tag`, at their usual
+    /// positions.
+    pub fn with_tag(tag: AudioChannelLayoutTag) -> PannerResult<VbapPanner> {
+        VbapPanner::new(&AudioChannelLayout::with_tag(tag))
+    }
+
+    /// Pairs neighbouring speakers on the horizon.
+    fn pair(&mut self) {
+        for p in &mut self.positions {
+            let length = p[0].hypot(p[1]);
+            *p = [p[0] / length, p[1] / length, 0.];
+        }
+        let mut order = (0..self.positions.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| {
+            let angle = |i: usize| self.positions[i][1].atan2(self.positions[i][0]);
+            angle(a).total_cmp(&angle(b))
+        });
+        if order.len() < 2 {
+            return;
+        }
+        for (i, &a) in order.iter().enumerate() {
+            let b = order[(i + 1) % order.len()];
+            if let Some(region) = Region::pair(a, b, &self.positions) {
+                self.regions.push(region);
+            }
+        }
+    }
+
+    /// Splits the sphere into triangles of speakers, the faces of the
+    /// convex hull of their positions.
+    fn triangulate(&mut self) {
+        let real = self.positions.len();
+        if self.positions.iter().all(|p| p[2] > -HORIZON) {
+            self.positions.push([0., 0., -1.]);
+        }
+        if self.positions.iter().all(|p| p[2] < HORIZON) {
+            self.positions.push([0., 0., 1.]);
+        }
+        self.neighbours = vec![Vec::new(); self.positions.len() - real];
+
+        let count = self.positions.len();
+        for a in 0..count {
+            for b in a + 1..count {
+                for c in b + 1..count {
+                    if !self.is_face(a, b, c) {
+                        continue;
+                    }
+                    let region = match Region::triangle(a, b, c, &self.positions) {
+                        Some(region) => region,
+                        None => continue,
+                    };
+                    for &v in region.speakers.iter().filter(|&&s| s >= real) {
+                        let neighbours = &mut self.neighbours[v - real];
+                        for &s in region.speakers.iter().filter(|&&s| s < real) {
+                            if !neighbours.contains(&s) {
+                                neighbours.push(s);
+                            }
+                        }
+                    }
+                    self.regions.push(region);
+                }
+            }
+        }
+    }
+
+    /// Whether every other speaker is on the same side of the plane
+    /// through `a`, `b` and `c`.
+    fn is_face(&self, a: usize, b: usize, c: usize) -> bool {
+        let p = &self.positions;
+        let edge = |from: usize, to: usize| {
+            [p[to][0] - p[from][0], p[to][1] - p[from][1], p[to][2] - p[from][2]]
+        };
+        let normal = cross(&edge(a, b), &edge(a, c));
+        if dot(&normal, &normal) <= EPSILON {
+            return false;
+        }
+        let offset = dot(&normal, &p[a]);
+        let (mut above, mut below) = (false, false);
+        for point in p {
+            let side = dot(&normal, point) - offset;
+            above |= side > EPSILON;
+            below |= side < -EPSILON;
+        }
+        !(above && below)
+    }
+
+    /// The number of channels in the layout, including any that are
+    /// never panned to.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// The gain of each channel for a source at `azimuth` and
+    /// `elevation`, in degrees as in `AudioChannelSphericalCoordinates`.
+    /// The gains have unit power. When all the speakers are on the
+    /// horizon, only the azimuth is used. A direction that isn't finite
+    /// gives silence.
+    pub fn gains(&self, azimuth: f32, elevation: f32) -> Vec<f32> {
+        if !azimuth.is_finite() || (self.three_d && !elevation.is_finite()) {
+            return vec![0.; self.channels];
+        }
+        let elevation = if self.three_d { f64::from(elevation) } else { 0. };
+        let direction = vector(-f64::from(azimuth).to_radians(), elevation.to_radians());
+
+        let mut position_gains = vec![0.; self.positions.len()];
+        let found = self.regions.iter().find_map(|region| {
+            region.gains(&direction).map(|gains| (region, gains))
+        });
+        match found {
+            Some((region, gains)) => {
+                for (&s, g) in region.speakers.iter().zip(gains) {
+                    position_gains[s] = g.max(0.);
+                }
+            }
+            None => {
+                // Outside every region: use the closest speaker.
+                let closest = (0..self.speakers.len())
+                    .max_by(|&a, &b| {
+                        let d = |i: usize| dot(&self.positions[i], &direction);
+                        d(a).total_cmp(&d(b))
+                    })
+                    .unwrap();
+                position_gains[closest] = 1.;
+            }
+        }
+
+        let real = self.speakers.len();
+        for (v, neighbours) in self.neighbours.iter().enumerate() {
+            let gain = position_gains[real + v];
+            for &s in neighbours {
+                position_gains[s] += gain / neighbours.len() as f64;
+            }
+        }
+
+        let power = position_gains[..real].iter().map(|g| g * g).sum::<f64>().sqrt();
+        let mut gains = vec![0.; self.channels];
+        for (&channel, g) in self.speakers.iter().zip(&position_gains) {
+            gains[channel] = if power > 0. { (g / power) as f32 } else { 0. };
+        }
+        gains
+    }
+
+    /// A one-input matrix panning to `azimuth` and `elevation`.
+    pub fn mix_matrix(&self, azimuth: f32, elevation: f32) -> MixMatrix {
+        let mut matrix = MixMatrix::zeroed(1, self.channels);
+        for (channel, gain) in self.gains(azimuth, elevation).into_iter().enumerate() {
+            matrix.set_coefficient(channel, 0, gain);
+        }
+        matrix
+    }
+
+    /// Pans `source` to `azimuth` and `elevation`, overwriting every
+    /// channel of `dst`. Returns the number of frames written.
+    pub fn render(
+        &self,
+        source: &[f32],
+        azimuth: f32,
+        elevation: f32,
+        dst: &mut AudioBufferListRef,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> PannerResult<usize> {
+        check_format::<f32>(format).map_err(|_| PannerError::UnsupportedFormat)?;
+        if channel_count(dst) != self.channels {
+            return Err(PannerError::BufferLayout);
+        }
+        let sample_bytes = 4;
+        let frames = frame_count(dst, sample_bytes).min(source.len());
+        let gains = self.gains(azimuth, elevation);
+        for (out, &gain) in channels(dst, sample_bytes).zip(&gains) {
+            if out.data.is_null() {
+                continue;
+            }
+            for (f, &sample) in source[..frames].iter().enumerate() {
+                unsafe {
+                    let p = out.data.add(f * out.stride * sample_bytes) as *mut f32;
+                    ptr::write_unaligned(p, gain * sample);
+                }
+            }
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioBufferList, AudioChannelLabel, AudioChannelSphericalCoordinates,
+         AudioStreamBasicDescription};
+
+    fn tag(tag: ffi::AudioChannelLayoutTag) -> AudioChannelLayoutTag {
+        AudioChannelLayoutTag::from(tag)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    fn power(gains: &[f32]) -> f32 {
+        gains.iter().map(|g| g * g).sum::<f32>().sqrt()
+    }
+
+    fn speakers(positions: &[(f32, f32)]) -> AudioChannelLayout {
+        let mut layout = AudioChannelLayout::with_len(positions.len());
+        layout.set_channel_layout_tag(AudioChannelLayoutTag::USE_CHANNEL_DESCRIPTIONS);
+        for (d, &(azimuth, elevation)) in layout.iter_mut().zip(positions) {
+            *d = AudioChannelDescription::with_spherical_coordinate(
+                AudioChannelLabel::from(ffi::kAudioChannelLabel_Unused),
+                AudioChannelSphericalCoordinates {
+                    azimuth,
+                    elevation,
+                    distance: 1.,
+                },
+            );
+        }
+        layout
+    }
+
+    #[test]
+    fn stereo_pairs() {
+        let panner = VbapPanner::with_tag(tag(ffi::kAudioChannelLayoutTag_Stereo)).unwrap();
+        assert_eq!(panner.channels(), 2);
+        let gains = panner.gains(0., 0.);
+        assert!(close(gains[0], gains[1]) && close(power(&gains), 1.));
+        let gains = panner.gains(-30., 0.);
+        assert!(close(gains[0], 1.) && close(gains[1], 0.));
+        assert!(panner.gains(15., 0.)[1] > panner.gains(15., 0.)[0]);
+        // Behind the pair the closest speaker plays, and elevation is
+        // ignored on the horizon.
+        assert!(close(power(&panner.gains(170., 0.)), 1.));
+        assert_eq!(panner.gains(10., 60.), panner.gains(10., 0.));
+    }
+
+    #[test]
+    fn surround_skips_the_lfe() {
+        // L R C LFE Ls Rs
+        let panner = VbapPanner::with_tag(tag(ffi::kAudioChannelLayoutTag_MPEG_5_1_A)).unwrap();
+        let gains = panner.gains(0., 0.);
+        assert!(close(gains[2], 1.));
+        assert_eq!(gains[3], 0.);
+        let gains = panner.gains(180., 0.);
+        assert!(close(gains[4], gains[5]) && gains[4] > 0.);
+        for azimuth in (-180..180).step_by(7) {
+            let gains = panner.gains(azimuth as f32, 0.);
+            assert_eq!(gains[3], 0.);
+            assert!(close(power(&gains), 1.), "azimuth {}", azimuth);
+        }
+    }
+
+    #[test]
+    fn three_d_triangles() {
+        // Four speakers on the horizon and one above. Below is an
+        // imaginary speaker shared between the others.
+        let layout = speakers(&[(-45., 0.), (45., 0.), (135., 0.), (-135., 0.), (0., 90.)]);
+        let panner = VbapPanner::new(&layout).unwrap();
+        let up = panner.gains(0., 90.);
+        assert!(close(up[4], 1.));
+        let down = panner.gains(0., -90.);
+        assert!((0..4).all(|s| close(down[s], 0.5)) && close(down[4], 0.));
+        for &(azimuth, elevation) in &[(10., 20.), (-100., -40.), (170., 5.), (0., 0.)] {
+            assert!(close(power(&panner.gains(azimuth, elevation)), 1.));
+        }
+    }
+
+    #[test]
+    fn non_finite_input() {
+        let panner = VbapPanner::with_tag(tag(ffi::kAudioChannelLayoutTag_Stereo)).unwrap();
+        assert_eq!(panner.gains(f32::NAN, 0.), [0., 0.]);
+        assert_eq!(panner.gains(f32::INFINITY, 0.), [0., 0.]);
+        let layout = speakers(&[(-30., 0.), (f32::NAN, 0.)]);
+        assert_eq!(VbapPanner::new(&layout).err(), Some(PannerError::MissingCoordinates(1)));
+        let layout = speakers(&[(-30., 0.), (30., f32::INFINITY)]);
+        assert_eq!(VbapPanner::new(&layout).err(), Some(PannerError::MissingCoordinates(1)));
+    }
+
+    #[test]
+    fn layout_errors() {
+        assert_eq!(
+            VbapPanner::with_tag(tag(0x7fff_0002)).err(),
+            Some(PannerError::UnknownLayout)
+        );
+        assert_eq!(
+            VbapPanner::with_tag(AudioChannelLayoutTag::discrete_in_order(2)).err(),
+            Some(PannerError::MissingCoordinates(0))
+        );
+        let lfe = AudioChannelLayout::with_labels(&[ffi::kAudioChannelLabel_LFEScreen.into()]);
+        assert_eq!(VbapPanner::new(&lfe).err(), Some(PannerError::NoSpeakers));
+    }
+
+    #[test]
+    fn render_and_matrix() {
+        let panner = VbapPanner::with_tag(tag(ffi::kAudioChannelLayoutTag_Stereo)).unwrap();
+        let matrix = panner.mix_matrix(-30., 0.);
+        assert_eq!((matrix.src_channels(), matrix.dst_channels()), (1, 2));
+        assert!(close(matrix.coefficient(0, 0), 1.));
+
+        let format: AudioStreamBasicDescription = "f32@48000x2".parse().unwrap();
+        let mut dst = AudioBufferList::allocate(&format, 4);
+        dst[0].copy_from_slice(&[0xff; 32]);
+        assert_eq!(panner.render(&[1., -1.], 30., 0., &mut dst, &format), Ok(2));
+        let out = dst[0]
+            .chunks(4)
+            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>();
+        assert!(close(out[0], 0.) && close(out[1], 1.) && close(out[3], -1.));
+
+        let mono: AudioStreamBasicDescription = "f32@48000x1".parse().unwrap();
+        let mut wrong = AudioBufferList::allocate(&mono, 4);
+        assert_eq!(
+            panner.render(&[1.], 0., 0., &mut wrong, &mono),
+            Err(PannerError::BufferLayout)
+        );
+        let s16: AudioStreamBasicDescription = "s16@48000x2".parse().unwrap();
+        assert_eq!(
+            panner.render(&[1.], 0., 0., &mut dst, &s16),
+            Err(PannerError::UnsupportedFormat)
+        );
+    }
+}
@@ -0,0 +1,402 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Delivery of property change notifications to application threads.
+
+use {AudioObject, AudioObjectPropertyElement, ListenerHandle, PropertySelector, Result};
+use ffi;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// A property that changed on an `AudioObject`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PropertyChange {
+    /// The object whose property changed.
+    pub object: AudioObject,
+    /// The selector of the property that changed.
+    pub selector: PropertySelector,
+    /// The scope of the property that changed.
+    pub scope: ffi::AudioObjectPropertyScope,
+    /// The element of the property that changed.
+    pub element: AudioObjectPropertyElement,
+}
+
+impl PropertyChange {
+    fn new(id: ffi::AudioObjectID, addr: &ffi::AudioObjectPropertyAddress) -> Self {
+        PropertyChange {
+            object: AudioObject(id),
+            selector: addr.mSelector.into(),
+            scope: addr.mScope,
+            element: addr.mElement,
+        }
+    }
+
+    /// The full address of the property that changed.
+    pub fn address(&self) -> ffi::AudioObjectPropertyAddress {
+        ffi::AudioObjectPropertyAddress {
+            mSelector: self.selector.into(),
+            mScope: self.scope,
+            mElement: self.element,
+        }
+    }
+}
+
+/// An event delivered by a `PropertyEventReceiver`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PropertyEvent {
+    /// A watched property changed.
+    Changed(PropertyChange),
+    /// The given number of changes were discarded because the
+    /// receiver wasn't keeping up.
+    Lagged(usize),
+}
+
+struct Queue {
+    events: VecDeque<PropertyChange>,
+    capacity: usize,
+    dropped: usize,
+}
+
+pub(crate) struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    #[cfg(feature = "async")]
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Shared {
+    pub(crate) fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be greater than zero");
+        Shared {
+            queue: Mutex::new(Queue {
+                events: VecDeque::with_capacity(capacity),
+                capacity,
+                dropped: 0,
+            }),
+            available: Condvar::new(),
+            #[cfg(feature = "async")]
+            waker: Mutex::new(None),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queue the changes reported by a listener callback. A change
+    /// already waiting in the queue is not queued again.
+    pub(crate) fn push(&self, id: ffi::AudioObjectID, addrs: &[ffi::AudioObjectPropertyAddress]) {
+        let mut queue = self.lock();
+        for addr in addrs {
+            let change = PropertyChange::new(id, addr);
+            if queue.events.contains(&change) {
+                continue;
+            }
+            if queue.events.len() == queue.capacity {
+                queue.dropped += 1;
+                continue;
+            }
+            queue.events.push_back(change);
+        }
+        drop(queue);
+        self.available.notify_all();
+        #[cfg(feature = "async")]
+        if let Some(waker) = self.waker.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn try_pop(&self) -> Option<PropertyEvent> {
+        pop(&mut self.lock())
+    }
+
+    /// Like `try_pop`, but arranges for the task in `cx` to be woken
+    /// by the next `push` when the queue is empty.
+    #[cfg(feature = "async")]
+    pub(crate) fn poll_pop(&self, cx: &mut Context) -> Poll<PropertyEvent> {
+        let mut queue = self.lock();
+        if let Some(event) = pop(&mut queue) {
+            return Poll::Ready(event);
+        }
+        // Register while still holding the queue lock so a push can't
+        // slip in between the check and the registration.
+        *self.waker.lock().unwrap_or_else(|e| e.into_inner()) = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+fn pop(queue: &mut Queue) -> Option<PropertyEvent> {
+    if queue.dropped > 0 {
+        let dropped = queue.dropped;
+        queue.dropped = 0;
+        return Some(PropertyEvent::Lagged(dropped));
+    }
+    queue.events.pop_front().map(PropertyEvent::Changed)
+}
+
+pub(crate) fn listen(
+    object: &AudioObject,
+    addrs: &[ffi::AudioObjectPropertyAddress],
+    shared: &Arc<Shared>,
+) -> Result<Vec<ListenerHandle>> {
+    addrs
+        .iter()
+        .map(|addr| {
+            let shared = shared.clone();
+            object.add_listener(addr, move |id, addrs| {
+                shared.push(id, addrs);
+                Ok(())
+            })
+        })
+        .collect()
+}
+
+/// The receiving end of `AudioObject::watch`.
+///
+/// Changes are queued by the HAL's notification thread and received
+/// on any application thread. Bursts of notifications for the same
+/// property coalesce into a single `PropertyEvent::Changed` until it
+/// is received. Once the queue is full, further changes are dropped
+/// and reported by a `PropertyEvent::Lagged`.
+///
+/// Dropping the receiver unregisters its listeners.
+pub struct PropertyEventReceiver {
+    shared: Arc<Shared>,
+    _listeners: Vec<ListenerHandle>,
+}
+
+impl PropertyEventReceiver {
+    /// Blocks until an event is available.
+    pub fn recv(&self) -> PropertyEvent {
+        let mut queue = self.shared.lock();
+        loop {
+            if let Some(event) = pop(&mut queue) {
+                return event;
+            }
+            queue = self
+                .shared
+                .available
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Waits up to `timeout` for an event.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<PropertyEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.lock();
+        loop {
+            if let Some(event) = pop(&mut queue) {
+                return Some(event);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            queue = self
+                .shared
+                .available
+                .wait_timeout(queue, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Returns an event if one is available, without blocking.
+    pub fn try_recv(&self) -> Option<PropertyEvent> {
+        self.shared.try_pop()
+    }
+
+    /// An iterator that blocks waiting for events.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { rx: self }
+    }
+
+    /// An iterator over the events that are available now.
+    pub fn try_iter(&self) -> TryIter<'_> {
+        TryIter { rx: self }
+    }
+}
+
+/// Blocking iterator returned by `PropertyEventReceiver::iter`.
+pub struct Iter<'a> {
+    rx: &'a PropertyEventReceiver,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = PropertyEvent;
+
+    fn next(&mut self) -> Option<PropertyEvent> {
+        Some(self.rx.recv())
+    }
+}
+
+/// Non-blocking iterator returned by `PropertyEventReceiver::try_iter`.
+pub struct TryIter<'a> {
+    rx: &'a PropertyEventReceiver,
+}
+
+impl<'a> Iterator for TryIter<'a> {
+    type Item = PropertyEvent;
+
+    fn next(&mut self) -> Option<PropertyEvent> {
+        self.rx.try_recv()
+    }
+}
+
+impl AudioObject {
+    /// The number of changes a `PropertyEventReceiver` queues before
+    /// it starts dropping them.
+    pub const DEFAULT_WATCH_CAPACITY: usize = 64;
+
+    /// Watches the properties at `addrs` for changes.
+    ///
+    /// The addresses may contain wildcards. See `PropertyEventReceiver`.
+    pub fn watch(&self, addrs: &[ffi::AudioObjectPropertyAddress]) -> Result<PropertyEventReceiver> {
+        self.watch_with_capacity(addrs, Self::DEFAULT_WATCH_CAPACITY)
+    }
+
+    /// Like `watch`, queueing at most `capacity` changes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn watch_with_capacity(
+        &self,
+        addrs: &[ffi::AudioObjectPropertyAddress],
+        capacity: usize,
+    ) -> Result<PropertyEventReceiver> {
+        let shared = Arc::new(Shared::new(capacity));
+        let listeners = listen(self, addrs, &shared)?;
+        Ok(PropertyEventReceiver {
+            shared,
+            _listeners: listeners,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioDevice, ObjectID};
+    use simulated_hal::tests::{add_device, install};
+    use std::thread;
+
+    fn addr(selector: ffi::AudioObjectPropertySelector) -> ffi::AudioObjectPropertyAddress {
+        ffi::AudioObjectPropertyAddress {
+            mSelector: selector,
+            mScope: ffi::kAudioObjectPropertyScopeGlobal,
+            mElement: ffi::kAudioObjectPropertyElementMaster,
+        }
+    }
+
+    #[test]
+    fn changes_carry_typed_selectors() {
+        let hal = install();
+        let device = add_device(&hal);
+        let rate = addr(AudioDevice::NOMINAL_SAMPLE_RATE);
+        let rx = device.watch(&[rate]).unwrap();
+        assert_eq!(rx.try_recv(), None);
+
+        hal.set_property(&device, &rate, &48000f64);
+        let change = match rx.try_recv() {
+            Some(PropertyEvent::Changed(change)) => change,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(change.object, AudioObject(device.id()));
+        assert_eq!(change.selector, AudioDevice::NOMINAL_SAMPLE_RATE);
+        assert_eq!(change.selector, "nsrt".parse::<PropertySelector>().unwrap());
+        assert_eq!(change.selector.to_string(), "'nsrt'");
+        assert_eq!(change.address().mSelector, rate.mSelector);
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn bursts_coalesce() {
+        let hal = install();
+        let device = add_device(&hal);
+        let rate = addr(AudioDevice::NOMINAL_SAMPLE_RATE);
+        let frames = addr(AudioDevice::BUFFER_FRAME_SIZE);
+        let rx = device.watch(&[rate, frames]).unwrap();
+
+        hal.notify(&device, &[rate]);
+        hal.notify(&device, &[frames, rate]);
+        hal.notify(&device, &[rate]);
+        let selectors = rx
+            .try_iter()
+            .map(|event| match event {
+                PropertyEvent::Changed(change) => change.selector.into(),
+                PropertyEvent::Lagged(n) => panic!("lagged by {}", n),
+            })
+            .collect::<Vec<ffi::AudioObjectPropertySelector>>();
+        assert_eq!(selectors, [AudioDevice::NOMINAL_SAMPLE_RATE, AudioDevice::BUFFER_FRAME_SIZE]);
+
+        // Once received, the same change is queued again.
+        hal.notify(&device, &[rate]);
+        assert!(rx.try_recv().is_some());
+    }
+
+    #[test]
+    fn overflow_reports_lagged() {
+        let hal = install();
+        let device = add_device(&hal);
+        let rx = device
+            .watch_with_capacity(&[addr(AudioObject::SELECTOR_WILDCARD)], 2)
+            .unwrap();
+
+        let selectors = [
+            AudioDevice::NOMINAL_SAMPLE_RATE,
+            AudioDevice::BUFFER_FRAME_SIZE,
+            AudioDevice::LATENCY,
+            AudioDevice::SAFETY_OFFSET,
+            AudioDevice::STREAMS,
+        ];
+        for &selector in &selectors {
+            hal.notify(&device, &[addr(selector)]);
+        }
+        assert_eq!(rx.try_recv(), Some(PropertyEvent::Lagged(3)));
+        for &selector in &selectors[..2] {
+            match rx.try_recv() {
+                Some(PropertyEvent::Changed(change)) => assert_eq!(change.selector, selector),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        assert_eq!(rx.try_recv(), None);
+
+        // Room freed by receiving is used again.
+        hal.notify(&device, &[addr(AudioDevice::LATENCY)]);
+        assert!(matches!(rx.try_recv(), Some(PropertyEvent::Changed(_))));
+    }
+
+    #[test]
+    fn recv_waits_for_other_threads() {
+        let hal = install();
+        let device = add_device(&hal);
+        let rate = addr(AudioDevice::NOMINAL_SAMPLE_RATE);
+        let rx = device.watch(&[rate]).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_millis(10)), None);
+
+        thread::scope(|s| {
+            s.spawn(|| hal.set_property(&device, &rate, &96000f64));
+            assert!(matches!(rx.recv(), PropertyEvent::Changed(_)));
+        });
+        hal.notify(&device, &[rate]);
+        let event = rx.recv_timeout(Duration::from_secs(10));
+        assert!(matches!(event, Some(PropertyEvent::Changed(_))));
+    }
+
+    #[test]
+    fn watching_a_missing_object_fails() {
+        let _hal = install();
+        let e = AudioObject(0xdead).watch(&[addr(AudioDevice::NOMINAL_SAMPLE_RATE)]);
+        assert_eq!(e.err().unwrap().kind(), ::ErrorKind::BadObject);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_capacity_panics() {
+        Shared::new(0);
+    }
+}
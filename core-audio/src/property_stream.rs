@@ -0,0 +1,349 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! `futures` adapters for property change notifications.
+
+use {AudioDevice, AudioObject, ListenerHandle, PropertyEvent, Result};
+use ffi;
+use futures_core::Stream;
+use property_events::{listen, Shared};
+use std::future::Future;
+use std::pin::Pin;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A `Stream` of the changes to the properties of an `AudioObject`.
+///
+/// Returned by `AudioObject::property_changes`. Changes are queued,
+/// coalesced and reported as lagged exactly as for a
+/// `PropertyEventReceiver`. The stream never ends; drop it to
+/// unregister its listener.
+pub struct PropertyChanges {
+    shared: Arc<Shared>,
+    _listeners: Vec<ListenerHandle>,
+}
+
+impl Stream for PropertyChanges {
+    type Item = PropertyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<PropertyEvent>> {
+        self.shared.poll_pop(cx).map(Some)
+    }
+}
+
+impl AudioObject {
+    /// Returns a `Stream` of the changes to the property at `addr`.
+    ///
+    /// The address may contain wildcards. The stream queues at most
+    /// `AudioObject::DEFAULT_WATCH_CAPACITY` changes.
+    pub fn property_changes(
+        &self,
+        addr: &ffi::AudioObjectPropertyAddress,
+    ) -> Result<PropertyChanges> {
+        let shared = Arc::new(Shared::new(Self::DEFAULT_WATCH_CAPACITY));
+        let listeners = listen(self, &[*addr], &shared)?;
+        Ok(PropertyChanges {
+            shared,
+            _listeners: listeners,
+        })
+    }
+}
+
+impl AudioDevice {
+    /// Waits for the nominal sample rate of the device to become `rate`.
+    ///
+    /// The returned future resolves to `Ok(true)` as soon as the device
+    /// reports `rate`, which may be immediately, or to `Ok(false)` if
+    /// `timeout` elapses first.
+    pub fn wait_for_sample_rate(&self, rate: f64, timeout: Duration) -> WaitForSampleRate {
+        WaitForSampleRate {
+            device: *self,
+            rate,
+            deadline: Instant::now() + timeout,
+            changes: None,
+            timer: None,
+        }
+    }
+}
+
+/// Future returned by `AudioDevice::wait_for_sample_rate`.
+pub struct WaitForSampleRate {
+    device: AudioDevice,
+    rate: f64,
+    deadline: Instant,
+    changes: Option<PropertyChanges>,
+    timer: Option<Timer>,
+}
+
+impl Future for WaitForSampleRate {
+    type Output = Result<bool>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<bool>> {
+        let this = self.get_mut();
+        if this.changes.is_none() {
+            // Start listening before the first read so a change between
+            // the two isn't missed.
+            let addr = ffi::AudioObjectPropertyAddress {
+                mSelector: ffi::kAudioDevicePropertyNominalSampleRate,
+                mScope: ffi::kAudioObjectPropertyScopeGlobal,
+                mElement: ffi::kAudioObjectPropertyElementMaster,
+            };
+            this.changes = Some(this.device.property_changes(&addr)?);
+        }
+        // Drain pending notifications, leaving the task registered for
+        // the next one.
+        if let Some(ref changes) = this.changes {
+            while changes.shared.poll_pop(cx).is_ready() {}
+        }
+
+        let result = match this.device.nominal_sample_rate() {
+            Ok(rate) if rate == this.rate => Some(Ok(true)),
+            Ok(_) if Instant::now() >= this.deadline => Some(Ok(false)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        };
+        if let Some(result) = result {
+            this.timer = None;
+            return Poll::Ready(result);
+        }
+
+        match this.timer {
+            Some(ref timer) => timer.register(cx.waker()),
+            None => this.timer = Some(Timer::start(this.deadline, cx.waker())),
+        }
+        Poll::Pending
+    }
+}
+
+/// The deadlines of every pending `Timer`. A single thread, started by
+/// the first timer, sleeps until the earliest deadline and wakes the
+/// task waiting on it.
+struct Timers {
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    wakers: HashMap<u64, Waker>,
+    next_id: u64,
+}
+
+static TIMERS: Mutex<Option<Timers>> = Mutex::new(None);
+static TIMERS_CHANGED: Condvar = Condvar::new();
+
+fn lock_timers() -> MutexGuard<'static, Option<Timers>> {
+    TIMERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn run_timers() {
+    let mut guard = lock_timers();
+    loop {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let next = {
+            let timers = guard.as_mut().expect("timers are running");
+            while let Some(&Reverse((deadline, id))) = timers.deadlines.peek() {
+                // Skip timers that have been dropped.
+                if !timers.wakers.contains_key(&id) {
+                    timers.deadlines.pop();
+                    continue;
+                }
+                if deadline > now {
+                    break;
+                }
+                timers.deadlines.pop();
+                expired.extend(timers.wakers.remove(&id));
+            }
+            timers.deadlines.peek().map(|&Reverse((deadline, _))| deadline)
+        };
+        if !expired.is_empty() {
+            // Wake without the lock, in case a task is polled inline.
+            drop(guard);
+            expired.into_iter().for_each(Waker::wake);
+            guard = lock_timers();
+            continue;
+        }
+        guard = match next {
+            Some(deadline) => {
+                TIMERS_CHANGED
+                    .wait_timeout(guard, deadline - now)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0
+            }
+            None => TIMERS_CHANGED.wait(guard).unwrap_or_else(|e| e.into_inner()),
+        };
+    }
+}
+
+/// Wakes the task waiting on a `WaitForSampleRate` when its timeout
+/// elapses. Dropping the timer cancels the wake up.
+struct Timer {
+    id: u64,
+}
+
+impl Timer {
+    fn start(deadline: Instant, waker: &Waker) -> Timer {
+        let mut guard = lock_timers();
+        let timers = guard.get_or_insert_with(|| {
+            thread::spawn(run_timers);
+            Timers {
+                deadlines: BinaryHeap::new(),
+                wakers: HashMap::new(),
+                next_id: 0,
+            }
+        });
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.deadlines.push(Reverse((deadline, id)));
+        timers.wakers.insert(id, waker.clone());
+        TIMERS_CHANGED.notify_one();
+        Timer { id }
+    }
+
+    fn register(&self, waker: &Waker) {
+        if let Some(timers) = lock_timers().as_mut() {
+            if let Some(registered) = timers.wakers.get_mut(&self.id) {
+                if !registered.will_wake(waker) {
+                    *registered = waker.clone();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(timers) = lock_timers().as_mut() {
+            timers.wakers.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {ObjectID, PropertyChange};
+    use simulated_hal::tests::{add_device, install, TestHal};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+    use std::thread::Thread;
+
+    struct Wakes(AtomicUsize);
+
+    impl Wake for Wakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    fn rate_address() -> ffi::AudioObjectPropertyAddress {
+        ffi::AudioObjectPropertyAddress {
+            mSelector: ffi::kAudioDevicePropertyNominalSampleRate,
+            mScope: ffi::kAudioObjectPropertyScopeGlobal,
+            mElement: ffi::kAudioObjectPropertyElementMaster,
+        }
+    }
+
+    fn device_at(hal: &TestHal, rate: f64) -> AudioDevice {
+        let device = add_device(hal);
+        hal.set_property(&device, &rate_address(), &rate);
+        device
+    }
+
+    fn pending_timers() -> usize {
+        lock_timers().as_ref().map_or(0, |timers| timers.wakers.len())
+    }
+
+    #[test]
+    fn stream_wakes_on_change() {
+        let hal = install();
+        let device = device_at(&hal, 44100.);
+        let mut changes = device.property_changes(&rate_address()).unwrap();
+        let wakes = Arc::new(Wakes(AtomicUsize::new(0)));
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut changes).poll_next(&mut cx).is_pending());
+        hal.set_property(&device, &rate_address(), &48000f64);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        let change = PropertyChange {
+            object: AudioObject(device.id()),
+            selector: ffi::kAudioDevicePropertyNominalSampleRate.into(),
+            scope: ffi::kAudioObjectPropertyScopeGlobal,
+            element: ffi::kAudioObjectPropertyElementMaster,
+        };
+        match Pin::new(&mut changes).poll_next(&mut cx) {
+            Poll::Ready(Some(PropertyEvent::Changed(c))) => assert_eq!(c, change),
+            _ => panic!("expected a change"),
+        }
+        assert!(Pin::new(&mut changes).poll_next(&mut cx).is_pending());
+    }
+
+    #[test]
+    fn wait_for_sample_rate() {
+        let hal = install();
+        let device = device_at(&hal, 44100.);
+        let wait = device.wait_for_sample_rate(44100., Duration::from_secs(60));
+        assert!(block_on(wait).unwrap());
+
+        let wait = device.wait_for_sample_rate(48000., Duration::from_secs(60));
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                hal.set_property(&device, &rate_address(), &48000f64);
+            });
+            assert!(block_on(wait).unwrap());
+        });
+        // Resolving cancels the timer.
+        assert_eq!(pending_timers(), 0);
+    }
+
+    #[test]
+    fn wait_for_sample_rate_times_out() {
+        let hal = install();
+        let device = device_at(&hal, 44100.);
+        let start = Instant::now();
+        let waits = (0..8)
+            .map(|_| device.wait_for_sample_rate(48000., Duration::from_millis(20)))
+            .collect::<Vec<_>>();
+        for wait in waits {
+            assert!(!block_on(wait).unwrap());
+        }
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(pending_timers(), 0);
+
+        // Dropping a pending future cancels its timer.
+        let mut wait = device.wait_for_sample_rate(48000., Duration::from_secs(60));
+        let waker = Waker::from(Arc::new(Wakes(AtomicUsize::new(0))));
+        assert!(Pin::new(&mut wait).poll(&mut Context::from_waker(&waker)).is_pending());
+        assert_eq!(pending_timers(), 1);
+        drop(wait);
+        assert_eq!(pending_timers(), 0);
+    }
+
+    #[test]
+    fn wait_for_sample_rate_of_a_missing_device() {
+        let _hal = install();
+        let wait = AudioDevice(0xdead).wait_for_sample_rate(48000., Duration::from_secs(60));
+        assert_eq!(block_on(wait).unwrap_err().kind(), ::ErrorKind::BadObject);
+    }
+}
@@ -0,0 +1,536 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Sample rate conversion of linear PCM with a windowed sinc filter.
+
+use {AudioBufferListRef, AudioFormat, AudioFormatFlags, AudioStreamBasicDescription,
+     AudioStreamBasicDescriptionRef, AudioSubDeviceDriftCompensation};
+use interleave::{channel_count, channels, frame_count};
+use std::{error, f64, fmt, ptr, result};
+
+/// Why a `Resampler` couldn't be created or run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResamplerError {
+    /// The format isn't native endian 32-bit float linear PCM. Use a
+    /// `FormatConverter` to get there from other formats.
+    UnsupportedFormat,
+    /// A sample rate isn't a positive, finite number.
+    InvalidSampleRate(f64),
+    /// The requested ratio is further from the nominal ratio than
+    /// `Resampler::MAX_RATIO_ADJUSTMENT` allows.
+    RatioOutOfRange { ratio: f64, nominal: f64 },
+    /// A buffer list doesn't hold the number of channels its format
+    /// describes.
+    BufferLayout,
+}
+
+impl fmt::Display for ResamplerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResamplerError::InvalidSampleRate(rate) => write!(f, "invalid sample rate {}", rate),
+            ResamplerError::RatioOutOfRange { ratio, nominal } => write!(
+                f,
+                "ratio {} is too far from the nominal ratio {}",
+                ratio,
+                nominal
+            ),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+impl error::Error for ResamplerError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl ResamplerError {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            ResamplerError::UnsupportedFormat => {
+                "the resampler requires native endian 32-bit float linear PCM"
+            }
+            ResamplerError::InvalidSampleRate(_) => "invalid sample rate",
+            ResamplerError::RatioOutOfRange { .. } => "ratio out of range",
+            ResamplerError::BufferLayout => "the buffers don't match the format's channels",
+        }
+    }
+}
+
+pub type ResamplerResult<T> = result::Result<T, ResamplerError>;
+
+/// Trade off between the cost of a `Resampler` and how well it
+/// preserves the signal, on the same scale as
+/// `AudioSubDeviceDriftCompensation`.
+///
+/// Higher qualities use longer filters, which pass more of the
+/// spectrum, reject more aliasing and add more latency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResamplerQuality {
+    MinQuality,
+    LowQuality,
+    MediumQuality,
+    HighQuality,
+    MaxQuality,
+}
+
+impl ResamplerQuality {
+    /// Zero crossings of the sinc on each side of the centre, the
+    /// passband edge as a fraction of the Nyquist frequency and the
+    /// Kaiser window's beta.
+    fn filter_params(self) -> (usize, f64, f64) {
+        match self {
+            ResamplerQuality::MinQuality => (4, 0.80, 4.0),
+            ResamplerQuality::LowQuality => (8, 0.86, 5.5),
+            ResamplerQuality::MediumQuality => (16, 0.91, 7.0),
+            ResamplerQuality::HighQuality => (32, 0.945, 8.6),
+            ResamplerQuality::MaxQuality => (64, 0.97, 10.0),
+        }
+    }
+}
+
+impl From<AudioSubDeviceDriftCompensation> for ResamplerQuality {
+    fn from(quality: AudioSubDeviceDriftCompensation) -> Self {
+        match quality {
+            AudioSubDeviceDriftCompensation::MinQuality => ResamplerQuality::MinQuality,
+            AudioSubDeviceDriftCompensation::LowQuality => ResamplerQuality::LowQuality,
+            AudioSubDeviceDriftCompensation::MediumQuality => ResamplerQuality::MediumQuality,
+            AudioSubDeviceDriftCompensation::HighQuality => ResamplerQuality::HighQuality,
+            AudioSubDeviceDriftCompensation::MaxQuality => ResamplerQuality::MaxQuality,
+        }
+    }
+}
+
+/// Table entries per zero crossing of the filter.
+const PHASES: usize = 512;
+
+/// The zeroth order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.;
+    let mut term = 1.;
+    let mut k = 1.;
+    while term > sum * 1e-12 {
+        term *= (x / (2. * k)) * (x / (2. * k));
+        sum += term;
+        k += 1.;
+    }
+    sum
+}
+
+/// One side of the windowed sinc, sampled `PHASES` times per zero
+/// crossing, with trailing zeros so lookups needn't check the end.
+fn filter_table(zero_crossings: usize, cutoff: f64, beta: f64) -> Vec<f32> {
+    let len = zero_crossings * PHASES;
+    let i0_beta = bessel_i0(beta);
+    (0..len + 2)
+        .map(|i| {
+            if i >= len {
+                return 0.;
+            }
+            let t = i as f64 / PHASES as f64;
+            let x = f64::consts::PI * cutoff * t;
+            let sinc = if i == 0 { 1. } else { x.sin() / x };
+            let w = i as f64 / len as f64;
+            let window = bessel_i0(beta * (1. - w * w).sqrt()) / i0_beta;
+            (cutoff * sinc * window) as f32
+        })
+        .collect()
+}
+
+/// Converts 32-bit float linear PCM from one sample rate to another.
+///
+/// The resampler is streaming: it keeps the input frames its filter
+/// still needs between calls to `process`, so audio can be fed to it in
+/// blocks of any size. The ratio can be adjusted slightly while running
+/// to correct for drift between two clocks.
+pub struct Resampler {
+    format: AudioStreamBasicDescription,
+    channels: usize,
+    /// Output frames per input frame the filter was designed for.
+    nominal_ratio: f64,
+    ratio: f64,
+    table: Vec<f32>,
+    zero_crossings: usize,
+    /// Scales the filter down to the output Nyquist frequency when
+    /// reducing the rate.
+    filter_scale: f64,
+    /// Input frames either side of an output frame that contribute to
+    /// it.
+    half_width: usize,
+    latency: usize,
+    /// The recent input of each channel.
+    history: Vec<Vec<f32>>,
+    /// The position in `history` of the next output frame.
+    position: f64,
+    /// Filter weights for the output frame being computed.
+    weights: Vec<f32>,
+}
+
+impl Resampler {
+    /// How far, as a fraction of the nominal ratio, `set_ratio` may move
+    /// the ratio.
+    pub const MAX_RATIO_ADJUSTMENT: f64 = 0.1;
+
+    /// Creates a resampler converting audio in `format` from
+    /// `input_rate` to `output_rate`.
+    ///
+    /// `format` must be native endian 32-bit float linear PCM. Its
+    /// sample rate is ignored.
+    pub fn new(
+        format: &AudioStreamBasicDescriptionRef,
+        input_rate: f64,
+        output_rate: f64,
+        quality: ResamplerQuality,
+    ) -> ResamplerResult<Resampler> {
+        check_format(format)?;
+        for &rate in &[input_rate, output_rate] {
+            if !(rate.is_finite() && rate > 0.) {
+                return Err(ResamplerError::InvalidSampleRate(rate));
+            }
+        }
+        let channels = format.channels_per_frame() as usize;
+        let ratio = output_rate / input_rate;
+        let (zero_crossings, cutoff, beta) = quality.filter_params();
+        let filter_scale = ratio.min(1.);
+        let half_width = (zero_crossings as f64 / filter_scale).ceil() as usize;
+        let latency = (half_width as f64 * ratio).ceil() as usize;
+        let mut resampler = Resampler {
+            format: format.to_owned(),
+            channels,
+            nominal_ratio: ratio,
+            ratio,
+            table: filter_table(zero_crossings, cutoff, beta),
+            zero_crossings,
+            filter_scale,
+            half_width,
+            latency,
+            history: vec![Vec::new(); channels],
+            position: 0.,
+            weights: vec![0.; 2 * half_width],
+        };
+        resampler.reset();
+        Ok(resampler)
+    }
+
+    /// The format of the audio being converted.
+    pub fn format(&self) -> &AudioStreamBasicDescriptionRef {
+        &self.format
+    }
+
+    /// The output frames produced per input frame.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// The ratio given by the rates the resampler was created with.
+    pub fn nominal_ratio(&self) -> f64 {
+        self.nominal_ratio
+    }
+
+    /// Changes the output frames produced per input frame, to follow a
+    /// clock that drifts from its nominal rate.
+    ///
+    /// The new ratio takes effect from the next output frame. It must
+    /// be within `MAX_RATIO_ADJUSTMENT` of the nominal ratio, since the
+    /// filter is designed for that.
+    pub fn set_ratio(&mut self, ratio: f64) -> ResamplerResult<()> {
+        let deviation = (ratio / self.nominal_ratio - 1.).abs();
+        if deviation.is_nan() || deviation > Self::MAX_RATIO_ADJUSTMENT {
+            return Err(ResamplerError::RatioOutOfRange {
+                ratio,
+                nominal: self.nominal_ratio,
+            });
+        }
+        self.ratio = ratio;
+        Ok(())
+    }
+
+    /// The delay, in output frames, between an input frame and the
+    /// output frame at the same time.
+    ///
+    /// The delay is exact at the nominal ratio. Adjusting the ratio
+    /// moves it by the accumulated difference in timing.
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// Discards the buffered input, as though the resampler had just
+    /// been created, and restores the nominal ratio.
+    pub fn reset(&mut self) {
+        self.ratio = self.nominal_ratio;
+        // Start from enough silence that the first output frame is
+        // `latency` output frames before the first input frame, with a
+        // full filter's worth of history behind it.
+        let lead = self.latency as f64 / self.ratio;
+        let padding = self.half_width + lead.ceil() as usize;
+        for history in &mut self.history {
+            history.clear();
+            history.resize(padding, 0.);
+        }
+        self.position = padding as f64 - lead;
+    }
+
+    /// Resamples all of `input` and writes as many frames as are ready,
+    /// up to the size of `output`, returning the number written.
+    ///
+    /// Frames that aren't ready yet or don't fit are kept and written
+    /// by later calls. Both lists must have the resampler's channels,
+    /// in either shape.
+    pub fn process(
+        &mut self,
+        input: &AudioBufferListRef,
+        output: &mut AudioBufferListRef,
+    ) -> ResamplerResult<usize> {
+        if channel_count(input) != self.channels || channel_count(output) != self.channels {
+            return Err(ResamplerError::BufferLayout);
+        }
+        let sample_bytes = 4;
+
+        let input_frames = frame_count(input, sample_bytes);
+        for (history, channel) in self.history.iter_mut().zip(channels(input, sample_bytes)) {
+            history.reserve(input_frames);
+            for f in 0..input_frames {
+                let sample = if channel.data.is_null() {
+                    0.
+                } else {
+                    let p = unsafe { channel.data.add(f * channel.stride * sample_bytes) };
+                    unsafe { ptr::read_unaligned(p as *const f32) }
+                };
+                history.push(sample);
+            }
+        }
+
+        let output_frames = frame_count(output, sample_bytes);
+        let available = self.history[0].len();
+        let step = 1. / self.ratio;
+        let mut written = 0;
+        while written < output_frames {
+            let centre = self.position.floor() as usize;
+            if centre + self.half_width >= available {
+                break;
+            }
+            self.compute_weights(centre);
+            let first = centre + 1 - self.half_width;
+            for (history, channel) in self.history.iter().zip(channels(output, sample_bytes)) {
+                if channel.data.is_null() {
+                    continue;
+                }
+                let window = &history[first..first + 2 * self.half_width];
+                let sample: f32 = window.iter().zip(&self.weights).map(|(x, w)| x * w).sum();
+                unsafe {
+                    let p = channel.data.add(written * channel.stride * sample_bytes);
+                    ptr::write_unaligned(p as *mut f32, sample);
+                }
+            }
+            self.position += step;
+            written += 1;
+        }
+
+        // Drop the history no later output frame can reach.
+        let keep_from = (self.position.floor() as usize + 1).saturating_sub(self.half_width);
+        for history in &mut self.history {
+            history.drain(..keep_from);
+        }
+        self.position -= keep_from as f64;
+        Ok(written)
+    }
+
+    /// Fills `weights` for the output frame at `position`, which lies
+    /// between the input frames `centre` and `centre + 1`.
+    fn compute_weights(&mut self, centre: usize) {
+        let frac = self.position - centre as f64;
+        let limit = (self.zero_crossings * PHASES) as f64;
+        let scale = self.filter_scale;
+        for (i, weight) in self.weights.iter_mut().enumerate() {
+            let distance = (i as f64 + 1. - self.half_width as f64 - frac).abs();
+            let t = distance * scale * PHASES as f64;
+            *weight = if t >= limit {
+                0.
+            } else {
+                let index = t as usize;
+                let f = (t - index as f64) as f32;
+                let (a, b) = (self.table[index], self.table[index + 1]);
+                (a + (b - a) * f) * scale as f32
+            };
+        }
+    }
+}
+
+impl fmt::Debug for Resampler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Resampler")
+            .field("channels", &self.channels)
+            .field("ratio", &self.ratio)
+            .field("nominal_ratio", &self.nominal_ratio)
+            .field("latency", &self.latency)
+            .finish()
+    }
+}
+
+fn check_format(format: &AudioStreamBasicDescriptionRef) -> ResamplerResult<()> {
+    let flags = format.format_flags();
+    let channels = format.channels_per_frame();
+    let sample_bytes = if flags.contains(AudioFormatFlags::IS_NON_INTERLEAVED) || channels == 0 {
+        format.bytes_per_frame()
+    } else {
+        format.bytes_per_frame() / channels
+    };
+    if format.format_id() != AudioFormat::LinearPcm || channels == 0
+        || !flags.contains(AudioFormatFlags::IS_FLOAT)
+        || format.bits_per_channel() != 32 || sample_bytes != 4
+        || flags.contains(AudioFormatFlags::IS_BIG_ENDIAN) != cfg!(target_endian = "big")
+    {
+        return Err(ResamplerError::UnsupportedFormat);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AudioBufferList;
+
+    fn format(channels: u32) -> AudioStreamBasicDescription {
+        format!("f32@48000x{}", channels).parse().unwrap()
+    }
+
+    fn list(samples: &[f32]) -> AudioBufferList {
+        let mut abl = AudioBufferList::allocate(&format(1), samples.len());
+        for (b, s) in abl[0].chunks_mut(4).zip(samples) {
+            b.copy_from_slice(&s.to_ne_bytes());
+        }
+        abl
+    }
+
+    fn samples(abl: &AudioBufferListRef, frames: usize) -> Vec<f32> {
+        abl[0]
+            .chunks(4)
+            .take(frames)
+            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    /// Feeds `input` in blocks of `block` frames and collects all the
+    /// output that's ready.
+    fn run(resampler: &mut Resampler, input: &[f32], block: usize) -> Vec<f32> {
+        let mut output = Vec::new();
+        let mut out = AudioBufferList::allocate(&format(1), 4096);
+        for chunk in input.chunks(block) {
+            let written = resampler.process(&list(chunk), &mut out).unwrap();
+            output.extend(samples(&out, written));
+        }
+        loop {
+            let written = resampler.process(&list(&[]), &mut out).unwrap();
+            if written == 0 {
+                return output;
+            }
+            output.extend(samples(&out, written));
+        }
+    }
+
+    fn sine(frequency: f64, rate: f64, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2. * f64::consts::PI * frequency * i as f64 / rate).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let quality = ResamplerQuality::LowQuality;
+        let s16 = "s16@48000x1".parse::<AudioStreamBasicDescription>().unwrap();
+        assert_eq!(
+            Resampler::new(&s16, 44100., 48000., quality).err(),
+            Some(ResamplerError::UnsupportedFormat)
+        );
+        assert_eq!(
+            Resampler::new(&format(1), 0., 48000., quality).err(),
+            Some(ResamplerError::InvalidSampleRate(0.))
+        );
+        assert!(Resampler::new(&format(1), 44100., f64::NAN, quality).is_err());
+
+        let mut resampler = Resampler::new(&format(1), 44100., 48000., quality).unwrap();
+        assert!(resampler.set_ratio(resampler.nominal_ratio() * 1.05).is_ok());
+        assert!(resampler.set_ratio(resampler.nominal_ratio() * 1.2).is_err());
+        assert!(resampler.set_ratio(f64::NAN).is_err());
+        let stereo = AudioBufferList::allocate(&format(2), 16);
+        let mut out = AudioBufferList::allocate(&format(1), 16);
+        assert_eq!(
+            resampler.process(&stereo, &mut out),
+            Err(ResamplerError::BufferLayout)
+        );
+    }
+
+    #[test]
+    fn output_length_follows_the_ratio() {
+        for &(from, to) in &[(44100., 48000.), (48000., 44100.), (48000., 16000.)] {
+            let mut resampler =
+                Resampler::new(&format(1), from, to, ResamplerQuality::MediumQuality).unwrap();
+            let output = run(&mut resampler, &vec![0.; 48000], 1000);
+            // The output starts `latency` frames early, and ends as
+            // much early again while the filter waits for more input.
+            let expected = 48000. * to / from;
+            assert!(
+                (output.len() as f64 - expected).abs() <= 2.,
+                "{} -> {}: {} frames, expected {}",
+                from,
+                to,
+                output.len(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn block_size_does_not_change_the_output() {
+        let input = sine(1000., 44100., 4000);
+        let mut a = Resampler::new(&format(1), 44100., 48000., ResamplerQuality::HighQuality)
+            .unwrap();
+        let mut b = Resampler::new(&format(1), 44100., 48000., ResamplerQuality::HighQuality)
+            .unwrap();
+        let (a, b) = (run(&mut a, &input, 4000), run(&mut b, &input, 37));
+        assert_eq!(a.len(), b.len());
+        let error = a.iter().zip(&b).map(|(a, b)| (a - b).abs()).fold(0., f32::max);
+        assert!(error < 1e-5, "error {}", error);
+    }
+
+    #[test]
+    fn preserves_a_sine() {
+        let (from, to) = (44100., 48000.);
+        let mut resampler = Resampler::new(&format(1), from, to, ResamplerQuality::HighQuality)
+            .unwrap();
+        let output = run(&mut resampler, &sine(1000., from, 8000), 512);
+        let latency = resampler.latency();
+        // Output frame `n` is input time `n - latency` at the output
+        // rate. Skip the filter warming up.
+        let expected = sine(1000., to, output.len());
+        let error = output[2 * latency..]
+            .iter()
+            .zip(&expected[latency..])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0., f32::max);
+        assert!(error < 1e-3, "error {}", error);
+    }
+
+    #[test]
+    fn reset_restores_the_nominal_ratio() {
+        let mut resampler = Resampler::new(&format(1), 48000., 48000., ResamplerQuality::MinQuality)
+            .unwrap();
+        let first = run(&mut resampler, &sine(1000., 48000., 500), 500);
+        resampler.set_ratio(1.05).unwrap();
+        run(&mut resampler, &sine(1000., 48000., 500), 500);
+        resampler.reset();
+        assert_eq!(resampler.ratio(), 1.);
+        assert_eq!(run(&mut resampler, &sine(1000., 48000., 500), 500), first);
+    }
+
+    #[test]
+    fn quality_from_drift_compensation() {
+        assert_eq!(
+            ResamplerQuality::from(AudioSubDeviceDriftCompensation::MaxQuality),
+            ResamplerQuality::MaxQuality
+        );
+        let low = Resampler::new(&format(1), 44100., 48000., ResamplerQuality::MinQuality).unwrap();
+        let high = Resampler::new(&format(1), 44100., 48000., ResamplerQuality::MaxQuality).unwrap();
+        assert!(low.latency() < high.latency());
+    }
+}
@@ -0,0 +1,514 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! A single-producer, single-consumer ring buffer of audio frames.
+
+use {AudioBufferListRef, AudioFormat, AudioFormatFlags, AudioStreamBasicDescriptionRef};
+use ffi;
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{fmt, ptr};
+
+struct Shared {
+    data: Box<[UnsafeCell<u8>]>,
+    /// Frames the buffer can hold. Always a power of two.
+    capacity: usize,
+    channels: usize,
+    sample_bytes: usize,
+    /// Total frames written. Only stored by the producer.
+    write_pos: AtomicUsize,
+    /// Total frames read. Only stored by the consumer.
+    read_pos: AtomicUsize,
+    overruns: AtomicUsize,
+    underruns: AtomicUsize,
+}
+
+// The producer only writes to the frames between `write_pos` and
+// `read_pos + capacity`, and the consumer only reads the frames
+// between `read_pos` and `write_pos`, so the two never touch the same
+// bytes at the same time.
+unsafe impl Sync for Shared {}
+
+/// A buffer holding interleaved channels `first_channel..` of a frame.
+struct Plane {
+    data: *mut u8,
+    channels: usize,
+    first_channel: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Write,
+    Read,
+}
+
+impl Shared {
+    fn frame_bytes(&self) -> usize {
+        self.channels * self.sample_bytes
+    }
+
+    fn ptr_at(&self, frame: usize) -> *mut u8 {
+        let offset = (frame & (self.capacity - 1)) * self.frame_bytes();
+        unsafe { (self.data.as_ptr() as *mut u8).add(offset) }
+    }
+
+    fn len(&self) -> usize {
+        self.write_pos
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.read_pos.load(Ordering::Acquire))
+    }
+
+    /// Copies `frames` frames between the ring, starting at the
+    /// absolute frame `pos`, and the start of `plane`.
+    ///
+    /// A null plane reads nothing and writes silence.
+    unsafe fn copy_plane(&self, dir: Direction, pos: usize, plane: &Plane, frames: usize) {
+        let Plane {
+            data: plane,
+            channels: plane_channels,
+            first_channel,
+        } = *plane;
+        if first_channel >= self.channels || plane_channels == 0 {
+            return;
+        }
+        let sb = self.sample_bytes;
+        let frame_bytes = self.frame_bytes();
+        let plane_stride = plane_channels * sb;
+        let copy_bytes = plane_channels.min(self.channels - first_channel) * sb;
+        let whole_frames = first_channel == 0 && plane_channels == self.channels;
+
+        let mut done = 0;
+        while done < frames {
+            // The run of frames up to the end of the ring's storage.
+            let start = (pos + done) & (self.capacity - 1);
+            let run = (frames - done).min(self.capacity - start);
+            let ring = self.ptr_at(pos + done);
+            let plane = if plane.is_null() {
+                plane
+            } else {
+                plane.add(done * plane_stride)
+            };
+            match (dir, plane.is_null(), whole_frames) {
+                (Direction::Read, true, _) => {}
+                (Direction::Write, true, _) => {
+                    for f in 0..run {
+                        let dst = ring.add(f * frame_bytes + first_channel * sb);
+                        ptr::write_bytes(dst, 0, copy_bytes);
+                    }
+                }
+                (Direction::Write, false, true) => {
+                    ptr::copy_nonoverlapping(plane, ring, run * frame_bytes)
+                }
+                (Direction::Read, false, true) => {
+                    ptr::copy_nonoverlapping(ring, plane, run * frame_bytes)
+                }
+                (dir, false, false) => for f in 0..run {
+                    let ring = ring.add(f * frame_bytes + first_channel * sb);
+                    let plane = plane.add(f * plane_stride);
+                    if dir == Direction::Write {
+                        ptr::copy_nonoverlapping(plane, ring, copy_bytes);
+                    } else {
+                        ptr::copy_nonoverlapping(ring, plane, copy_bytes);
+                    }
+                },
+            }
+            done += run;
+        }
+    }
+
+    /// Copies `frames` frames between the ring at `pos` and the start
+    /// of each buffer of `abl`.
+    unsafe fn copy_list(&self, dir: Direction, pos: usize, abl: &AudioBufferListRef, frames: usize) {
+        let mut first_channel = 0;
+        for buffer in abl.iter() {
+            let ab: &ffi::AudioBuffer = &*buffer.as_ptr();
+            let plane = Plane {
+                data: ab.mData as *mut u8,
+                channels: ab.mNumberChannels as usize,
+                first_channel,
+            };
+            self.copy_plane(dir, pos, &plane, frames);
+            first_channel += plane.channels;
+        }
+    }
+
+    /// The whole of an interleaved buffer.
+    fn interleaved(&self, data: *mut u8) -> Plane {
+        Plane {
+            data,
+            channels: self.channels,
+            first_channel: 0,
+        }
+    }
+
+    /// The number of frames every buffer in `abl` can hold.
+    fn list_frames(&self, abl: &AudioBufferListRef) -> usize {
+        abl.iter()
+            .map(|buffer| {
+                let ab: &ffi::AudioBuffer = unsafe { &*buffer.as_ptr() };
+                let stride = ab.mNumberChannels as usize * self.sample_bytes;
+                if ab.mData.is_null() || stride == 0 {
+                    usize::MAX
+                } else {
+                    ab.mDataByteSize as usize / stride
+                }
+            })
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// Creates a ring buffer holding at least `capacity_frames` frames of
+/// audio in `format`.
+///
+/// The buffer is allocated once, here. Reads and writes never
+/// allocate, lock or block, so either end can be used from an IO proc.
+/// Frames are stored interleaved whatever the layout of the buffers
+/// they're copied to and from.
+///
+/// # Panics
+///
+/// Panics if `format` isn't linear PCM with at least one channel.
+pub fn ring_buffer(
+    format: &AudioStreamBasicDescriptionRef,
+    capacity_frames: usize,
+) -> (RingBufferProducer, RingBufferConsumer) {
+    assert!(
+        format.format_id() == AudioFormat::LinearPcm,
+        "ring buffer requires linear PCM"
+    );
+    let channels = format.channels_per_frame() as usize;
+    assert!(channels > 0, "ring buffer requires at least one channel");
+    let sample_bytes = if format.format_flags().contains(AudioFormatFlags::IS_NON_INTERLEAVED) {
+        format.bytes_per_frame() as usize
+    } else {
+        format.bytes_per_frame() as usize / channels
+    };
+    assert!(sample_bytes > 0, "ring buffer requires a non-zero frame size");
+
+    let capacity = capacity_frames.max(1).next_power_of_two();
+    let data = (0..capacity * channels * sample_bytes)
+        .map(|_| UnsafeCell::new(0))
+        .collect::<Vec<_>>()
+        .into_boxed_slice();
+    let shared = Arc::new(Shared {
+        data,
+        capacity,
+        channels,
+        sample_bytes,
+        write_pos: AtomicUsize::new(0),
+        read_pos: AtomicUsize::new(0),
+        overruns: AtomicUsize::new(0),
+        underruns: AtomicUsize::new(0),
+    });
+    (
+        RingBufferProducer {
+            shared: shared.clone(),
+        },
+        RingBufferConsumer { shared },
+    )
+}
+
+macro_rules! ring_buffer_stats {
+    () => {
+        /// The number of frames the buffer can hold.
+        pub fn capacity(&self) -> usize {
+            self.shared.capacity
+        }
+
+        /// The number of channels in each frame.
+        pub fn channels(&self) -> usize {
+            self.shared.channels
+        }
+
+        /// The number of writes that found the buffer too full and
+        /// dropped frames.
+        pub fn overruns(&self) -> usize {
+            self.shared.overruns.load(Ordering::Relaxed)
+        }
+
+        /// The number of reads that found the buffer too empty and
+        /// returned silence.
+        pub fn underruns(&self) -> usize {
+            self.shared.underruns.load(Ordering::Relaxed)
+        }
+    };
+}
+
+/// The writing end of a `ring_buffer`.
+pub struct RingBufferProducer {
+    shared: Arc<Shared>,
+}
+
+impl RingBufferProducer {
+    ring_buffer_stats!();
+
+    /// The number of frames that can be written without overrunning.
+    pub fn free_frames(&self) -> usize {
+        self.shared.capacity - self.shared.len()
+    }
+
+    /// Writes up to `frames` frames from `abl` and returns the number
+    /// written.
+    ///
+    /// Each buffer of `abl` supplies the next `mNumberChannels`
+    /// channels of the frame, so both interleaved and non-interleaved
+    /// lists are accepted. Frames that don't fit are dropped and
+    /// counted as an overrun.
+    pub fn write(&mut self, abl: &AudioBufferListRef, frames: usize) -> usize {
+        let frames = frames.min(self.shared.list_frames(abl));
+        let n = self.reserve(frames);
+        let pos = self.shared.write_pos.load(Ordering::Relaxed);
+        unsafe { self.shared.copy_list(Direction::Write, pos, abl, n) };
+        self.commit(n)
+    }
+
+    /// Writes whole interleaved frames from `data` and returns the
+    /// number of frames written.
+    pub fn write_interleaved(&mut self, data: &[u8]) -> usize {
+        let shared = &self.shared;
+        let n = self.reserve(data.len() / shared.frame_bytes());
+        let pos = shared.write_pos.load(Ordering::Relaxed);
+        let plane = shared.interleaved(data.as_ptr() as *mut u8);
+        unsafe { shared.copy_plane(Direction::Write, pos, &plane, n) };
+        self.commit(n)
+    }
+
+    fn reserve(&self, frames: usize) -> usize {
+        let free = self.free_frames();
+        if frames > free {
+            self.shared.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        frames.min(free)
+    }
+
+    fn commit(&self, frames: usize) -> usize {
+        let pos = self.shared.write_pos.load(Ordering::Relaxed);
+        self.shared
+            .write_pos
+            .store(pos.wrapping_add(frames), Ordering::Release);
+        frames
+    }
+}
+
+impl fmt::Debug for RingBufferProducer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RingBufferProducer")
+            .field("capacity", &self.capacity())
+            .field("free_frames", &self.free_frames())
+            .finish()
+    }
+}
+
+/// The reading end of a `ring_buffer`.
+pub struct RingBufferConsumer {
+    shared: Arc<Shared>,
+}
+
+impl RingBufferConsumer {
+    ring_buffer_stats!();
+
+    /// The number of frames that can be read without underrunning.
+    pub fn available_frames(&self) -> usize {
+        self.shared.len()
+    }
+
+    /// Reads `frames` frames into `abl` and returns the number read.
+    ///
+    /// Each buffer of `abl` receives the next `mNumberChannels`
+    /// channels of the frame. If fewer than `frames` frames are
+    /// available, the remainder of `abl` is filled with silence and
+    /// counted as an underrun.
+    pub fn read(&mut self, abl: &mut AudioBufferListRef, frames: usize) -> usize {
+        let frames = frames.min(self.shared.list_frames(abl));
+        let n = self.reserve(frames);
+        let pos = self.shared.read_pos.load(Ordering::Relaxed);
+        unsafe { self.shared.copy_list(Direction::Read, pos, abl, n) };
+        if n < frames {
+            let sb = self.shared.sample_bytes;
+            for buffer in abl.iter_mut() {
+                let ab: &ffi::AudioBuffer = unsafe { &*buffer.as_ptr() };
+                if ab.mData.is_null() {
+                    continue;
+                }
+                let stride = ab.mNumberChannels as usize * sb;
+                unsafe {
+                    ptr::write_bytes(
+                        (ab.mData as *mut u8).add(n * stride),
+                        0,
+                        (frames - n) * stride,
+                    )
+                };
+            }
+        }
+        self.commit(n)
+    }
+
+    /// Reads whole interleaved frames into `data` and returns the
+    /// number of frames read. Unlike `read`, a short read leaves the
+    /// rest of `data` untouched.
+    pub fn read_interleaved(&mut self, data: &mut [u8]) -> usize {
+        let shared = &self.shared;
+        let n = self.reserve(data.len() / shared.frame_bytes());
+        let pos = shared.read_pos.load(Ordering::Relaxed);
+        let plane = shared.interleaved(data.as_mut_ptr());
+        unsafe { shared.copy_plane(Direction::Read, pos, &plane, n) };
+        self.commit(n)
+    }
+
+    fn reserve(&self, frames: usize) -> usize {
+        let available = self.available_frames();
+        if frames > available {
+            self.shared.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        frames.min(available)
+    }
+
+    fn commit(&self, frames: usize) -> usize {
+        let pos = self.shared.read_pos.load(Ordering::Relaxed);
+        self.shared
+            .read_pos
+            .store(pos.wrapping_add(frames), Ordering::Release);
+        frames
+    }
+}
+
+impl fmt::Debug for RingBufferConsumer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RingBufferConsumer")
+            .field("capacity", &self.capacity())
+            .field("available_frames", &self.available_frames())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioBufferList, AudioStreamBasicDescription};
+    use std::thread;
+
+    fn format(s: &str) -> AudioStreamBasicDescription {
+        s.parse().unwrap()
+    }
+
+    fn frames(values: &[u8]) -> Vec<u8> {
+        // Two channels of 16-bit samples, each byte of the sample the same.
+        values.iter().flat_map(|&v| [v, v, v + 100, v + 100]).collect()
+    }
+
+    #[test]
+    fn capacity_is_a_power_of_two() {
+        let (producer, consumer) = ring_buffer(&format("s16le@48000x2"), 5);
+        assert_eq!(producer.capacity(), 8);
+        assert_eq!(consumer.channels(), 2);
+        assert_eq!(producer.free_frames(), 8);
+        assert_eq!(consumer.available_frames(), 0);
+    }
+
+    #[test]
+    fn interleaved_round_trip_wraps() {
+        let (mut producer, mut consumer) = ring_buffer(&format("s16le@48000x2"), 8);
+        let mut out = vec![0; 4 * 4];
+        assert_eq!(producer.write_interleaved(&frames(&[1, 2, 3, 4, 5, 6])), 6);
+        assert_eq!(consumer.read_interleaved(&mut out), 4);
+        assert_eq!(out, frames(&[1, 2, 3, 4]));
+
+        // These wrap around the end of the storage.
+        assert_eq!(producer.write_interleaved(&frames(&[7, 8, 9, 10, 11, 12])), 6);
+        assert_eq!(consumer.available_frames(), 8);
+        let mut out = vec![0; 8 * 4];
+        assert_eq!(consumer.read_interleaved(&mut out), 8);
+        assert_eq!(out, frames(&[5, 6, 7, 8, 9, 10, 11, 12]));
+        assert_eq!(producer.overruns(), 0);
+        assert_eq!(consumer.underruns(), 0);
+    }
+
+    #[test]
+    fn overruns_and_underruns() {
+        let (mut producer, mut consumer) = ring_buffer(&format("s16le@48000x2"), 4);
+        assert_eq!(producer.write_interleaved(&frames(&[1, 2, 3, 4, 5, 6])), 4);
+        assert_eq!(producer.overruns(), 1);
+        assert_eq!(consumer.overruns(), 1);
+
+        let mut abl = AudioBufferList::allocate(&format("s16le@48000x2"), 6);
+        abl[0].copy_from_slice(&[0xff; 24]);
+        assert_eq!(consumer.read(&mut abl, 6), 4);
+        assert_eq!(consumer.underruns(), 1);
+        // The frames that weren't available are silent.
+        let mut expected = frames(&[1, 2, 3, 4]);
+        expected.resize(24, 0);
+        assert_eq!(&abl[0][..], &expected[..]);
+    }
+
+    #[test]
+    fn non_interleaved_lists() {
+        let non_interleaved = format("2 ch, 48000 Hz, Int16, non-interleaved");
+        let (mut producer, mut consumer) = ring_buffer(&non_interleaved, 8);
+        let mut abl = AudioBufferList::allocate(&non_interleaved, 3);
+        abl[0].copy_from_slice(&[1, 1, 2, 2, 3, 3]);
+        abl[1].copy_from_slice(&[101, 101, 102, 102, 103, 103]);
+        assert_eq!(producer.write(&abl, 3), 3);
+
+        // The ring holds frames interleaved.
+        let mut out = vec![0; 2 * 4];
+        assert_eq!(consumer.read_interleaved(&mut out), 2);
+        assert_eq!(out, frames(&[1, 2]));
+
+        assert_eq!(producer.write_interleaved(&frames(&[4, 5])), 2);
+        let mut abl = AudioBufferList::allocate(&non_interleaved, 3);
+        assert_eq!(consumer.read(&mut abl, 3), 3);
+        assert_eq!(&abl[0][..], &[3, 3, 4, 4, 5, 5]);
+        assert_eq!(&abl[1][..], &[103, 103, 104, 104, 105, 105]);
+    }
+
+    #[test]
+    fn missing_buffers_write_silence() {
+        let non_interleaved = format("2 ch, 48000 Hz, Int16, non-interleaved");
+        let (mut producer, mut consumer) = ring_buffer(&non_interleaved, 4);
+        let mut abl = AudioBufferList::allocate(&non_interleaved, 2);
+        abl[1].copy_from_slice(&[7; 4]);
+        unsafe { (*abl.as_ptr()).mBuffers[0].mData = ptr::null_mut() };
+        assert_eq!(producer.write(&abl, 2), 2);
+        let mut out = vec![0xff; 2 * 4];
+        assert_eq!(consumer.read_interleaved(&mut out), 2);
+        assert_eq!(out, [0, 0, 7, 7, 0, 0, 7, 7]);
+    }
+
+    #[test]
+    fn producer_and_consumer_threads() {
+        const FRAMES: u32 = 20_000;
+        let mono = format("s32le@48000x1");
+        let (mut producer, mut consumer) = ring_buffer(&mono, 64);
+        thread::scope(|s| {
+            s.spawn(move || {
+                let mut next = 0u32;
+                while next < FRAMES {
+                    let chunk = (next..FRAMES.min(next + 17))
+                        .flat_map(u32::to_ne_bytes)
+                        .collect::<Vec<_>>();
+                    next += producer.write_interleaved(&chunk) as u32;
+                    thread::yield_now();
+                }
+            });
+            let mut expected = 0u32;
+            let mut out = [0; 4 * 23];
+            while expected < FRAMES {
+                let n = consumer.read_interleaved(&mut out);
+                thread::yield_now();
+                for sample in out[..n * 4].chunks_exact(4) {
+                    let sample = u32::from_ne_bytes([sample[0], sample[1], sample[2], sample[3]]);
+                    assert_eq!(sample, expected);
+                    expected += 1;
+                }
+            }
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn non_lpcm_panics() {
+        let aac = format("2 ch, 44100 Hz, 'aac ', 1024 frames/packet");
+        ring_buffer(&aac, 16);
+    }
+}
@@ -0,0 +1,339 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Typed views of the data in an `AudioBufferRef`.
+
+use {AudioBufferRef, AudioFormat, AudioFormatFlags, AudioStreamBasicDescriptionRef};
+use ffi;
+use std::{error, fmt, iter, mem, ptr, result, slice};
+
+/// A native endian linear PCM sample type, implemented for `i16`,
+/// `i32`, `f32` and `f64`.
+///
+/// # Safety
+///
+/// Buffers are reinterpreted as slices of the implementing type, so
+/// every bit pattern of its size must be a valid value.
+pub unsafe trait Sample: Copy + Default + 'static {
+    /// Whether the type is floating point rather than signed integer.
+    const IS_FLOAT: bool;
+    /// The number of bits in the type.
+    const BITS: u32 = (mem::size_of::<Self>() * 8) as u32;
+}
+
+unsafe impl Sample for i16 {
+    const IS_FLOAT: bool = false;
+}
+
+unsafe impl Sample for i32 {
+    const IS_FLOAT: bool = false;
+}
+
+unsafe impl Sample for f32 {
+    const IS_FLOAT: bool = true;
+}
+
+unsafe impl Sample for f64 {
+    const IS_FLOAT: bool = true;
+}
+
+/// Why a buffer can't be viewed as samples of a type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleError {
+    /// The format isn't linear PCM.
+    NotLinearPcm,
+    /// The format's sample size or kind doesn't match the type.
+    TypeMismatch,
+    /// The format isn't native endian.
+    NotNativeEndian,
+    /// The buffer's data isn't aligned for the type.
+    Misaligned,
+    /// The buffer doesn't hold a whole number of frames.
+    PartialFrame,
+    /// The requested channel isn't in the buffer.
+    NoSuchChannel(usize),
+    /// The buffers don't have the shape the operation needs.
+    BufferLayout,
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SampleError::NoSuchChannel(channel) => {
+                write!(f, "the buffer has no channel {}", channel)
+            }
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+impl error::Error for SampleError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl SampleError {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            SampleError::NotLinearPcm => "the format isn't linear PCM",
+            SampleError::TypeMismatch => "the format's samples don't match the sample type",
+            SampleError::NotNativeEndian => "the format isn't native endian",
+            SampleError::Misaligned => "the buffer isn't aligned for the sample type",
+            SampleError::PartialFrame => "the buffer doesn't hold a whole number of frames",
+            SampleError::NoSuchChannel(_) => "the buffer has no such channel",
+            SampleError::BufferLayout => "the buffers don't have the required layout",
+        }
+    }
+}
+
+pub type SampleResult<T> = result::Result<T, SampleError>;
+
+/// Iterator over the samples of one channel of an interleaved buffer.
+pub type ChannelSamples<'a, T> = iter::StepBy<slice::Iter<'a, T>>;
+
+/// Iterator over mutable samples of one channel of an interleaved
+/// buffer.
+pub type ChannelSamplesMut<'a, T> = iter::StepBy<slice::IterMut<'a, T>>;
+
+pub(crate) fn check_format<T: Sample>(format: &AudioStreamBasicDescriptionRef) -> SampleResult<()> {
+    if format.format_id() != AudioFormat::LinearPcm {
+        return Err(SampleError::NotLinearPcm);
+    }
+    let flags = format.format_flags();
+    let is_float = flags.contains(AudioFormatFlags::IS_FLOAT);
+    let is_signed = flags.contains(AudioFormatFlags::IS_SIGNED_INTEGER);
+    let sample_bytes = format.sample_word_size();
+    let bits = format.bits_per_channel();
+    // Integer samples narrower than their container are usable if
+    // they're aligned to the high bits.
+    let bits_ok = bits == T::BITS
+        || (!T::IS_FLOAT && bits < T::BITS && flags.contains(AudioFormatFlags::IS_ALIGNED_HIGH));
+    if is_float != T::IS_FLOAT || (!T::IS_FLOAT && !is_signed)
+        || sample_bytes as usize != mem::size_of::<T>() || !bits_ok
+    {
+        return Err(SampleError::TypeMismatch);
+    }
+    if flags.contains(AudioFormatFlags::IS_BIG_ENDIAN) != cfg!(target_endian = "big") {
+        return Err(SampleError::NotNativeEndian);
+    }
+    Ok(())
+}
+
+impl AudioBufferRef {
+    fn checked_parts<T: Sample>(
+        &self,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> SampleResult<(*mut T, usize)> {
+        check_format::<T>(format)?;
+        let ab: &ffi::AudioBuffer = unsafe { &*self.as_ptr() };
+        let len = ab.mDataByteSize as usize / mem::size_of::<T>();
+        if ab.mData.is_null() || len == 0 {
+            return Ok((ptr::NonNull::dangling().as_ptr(), 0));
+        }
+        if ab.mData as usize % mem::align_of::<T>() != 0 {
+            return Err(SampleError::Misaligned);
+        }
+        let frame_bytes = mem::size_of::<T>() * self.num_channels().max(1);
+        if ab.mDataByteSize as usize % frame_bytes != 0 {
+            return Err(SampleError::PartialFrame);
+        }
+        Ok((ab.mData as *mut T, len))
+    }
+
+    /// Views the buffer as samples of type `T`, after checking that
+    /// `format` describes native endian linear PCM samples of that type
+    /// and that the buffer is suitably aligned.
+    ///
+    /// Interleaved samples are returned in frame order.
+    pub fn as_samples<T: Sample>(
+        &self,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> SampleResult<&[T]> {
+        let (ptr, len) = self.checked_parts::<T>(format)?;
+        Ok(unsafe { slice::from_raw_parts(ptr, len) })
+    }
+
+    /// Mutable version of `as_samples`.
+    pub fn as_samples_mut<T: Sample>(
+        &mut self,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> SampleResult<&mut [T]> {
+        let (ptr, len) = self.checked_parts::<T>(format)?;
+        Ok(unsafe { slice::from_raw_parts_mut(ptr, len) })
+    }
+
+    /// Iterates over the frames in the buffer, each a slice holding a
+    /// sample for every channel in the buffer.
+    pub fn frames<T: Sample>(
+        &self,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> SampleResult<slice::Chunks<'_, T>> {
+        let channels = self.num_channels().max(1);
+        Ok(self.as_samples(format)?.chunks(channels))
+    }
+
+    /// Mutable version of `frames`.
+    pub fn frames_mut<T: Sample>(
+        &mut self,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> SampleResult<slice::ChunksMut<'_, T>> {
+        let channels = self.num_channels().max(1);
+        Ok(self.as_samples_mut(format)?.chunks_mut(channels))
+    }
+
+    /// Iterates over the samples of `channel`, counting from the first
+    /// channel in the buffer.
+    pub fn channel<T: Sample>(
+        &self,
+        format: &AudioStreamBasicDescriptionRef,
+        channel: usize,
+    ) -> SampleResult<ChannelSamples<'_, T>> {
+        let channels = self.num_channels().max(1);
+        if channel >= channels {
+            return Err(SampleError::NoSuchChannel(channel));
+        }
+        let samples = self.as_samples(format)?;
+        Ok(samples[channel.min(samples.len())..].iter().step_by(channels))
+    }
+
+    /// Mutable version of `channel`.
+    pub fn channel_mut<T: Sample>(
+        &mut self,
+        format: &AudioStreamBasicDescriptionRef,
+        channel: usize,
+    ) -> SampleResult<ChannelSamplesMut<'_, T>> {
+        let channels = self.num_channels().max(1);
+        if channel >= channels {
+            return Err(SampleError::NoSuchChannel(channel));
+        }
+        let samples = self.as_samples_mut(format)?;
+        let start = channel.min(samples.len());
+        Ok(samples[start..].iter_mut().step_by(channels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AudioBufferList, AudioStreamBasicDescription};
+
+    fn format(s: &str) -> AudioStreamBasicDescription {
+        s.parse().unwrap()
+    }
+
+    fn native(sample: &str, channels: u32) -> AudioStreamBasicDescription {
+        let endian = if cfg!(target_endian = "big") { "be" } else { "le" };
+        format(&format!("{}{}@48000x{}", sample, endian, channels))
+    }
+
+    #[test]
+    fn interleaved_views() {
+        let fmt = native("s16", 2);
+        let mut abl = AudioBufferList::allocate(&fmt, 3);
+        {
+            let samples = abl[0].as_samples_mut::<i16>(&fmt).unwrap();
+            assert_eq!(samples.len(), 6);
+            for (i, s) in samples.iter_mut().enumerate() {
+                *s = i as i16;
+            }
+        }
+        let frames: Vec<_> = abl[0].frames::<i16>(&fmt).unwrap().collect();
+        assert_eq!(frames, [&[0, 1][..], &[2, 3], &[4, 5]]);
+        let right: Vec<_> = abl[0].channel::<i16>(&fmt, 1).unwrap().cloned().collect();
+        assert_eq!(right, [1, 3, 5]);
+
+        for s in abl[0].channel_mut::<i16>(&fmt, 0).unwrap() {
+            *s = -*s;
+        }
+        for frame in abl[0].frames_mut::<i16>(&fmt).unwrap() {
+            frame[1] *= 10;
+        }
+        assert_eq!(abl[0].as_samples::<i16>(&fmt).unwrap(), [0, 10, -2, 30, -4, 50]);
+        assert_eq!(
+            abl[0].channel::<i16>(&fmt, 2).err(),
+            Some(SampleError::NoSuchChannel(2))
+        );
+    }
+
+    #[test]
+    fn non_interleaved_views() {
+        let fmt = AudioStreamBasicDescription::with_lpcm(
+            48000.,
+            2,
+            32,
+            32,
+            true,
+            cfg!(target_endian = "big"),
+            true,
+        );
+        let mut abl = AudioBufferList::allocate(&fmt, 4);
+        assert_eq!(abl.buffer_count(), 2);
+        abl[1].as_samples_mut::<f32>(&fmt).unwrap()[3] = 0.5;
+        assert_eq!(abl[0].as_samples::<f32>(&fmt).unwrap(), [0.; 4]);
+        assert_eq!(abl[1].as_samples::<f32>(&fmt).unwrap(), [0., 0., 0., 0.5]);
+        // Each buffer holds one channel.
+        assert_eq!(abl[1].channel::<f32>(&fmt, 0).unwrap().count(), 4);
+        assert!(abl[1].channel::<f32>(&fmt, 1).is_err());
+    }
+
+    #[test]
+    fn formats_must_match_the_type() {
+        let fmt = native("s16", 2);
+        let abl = AudioBufferList::allocate(&fmt, 4);
+        assert_eq!(abl[0].as_samples::<i32>(&fmt).err(), Some(SampleError::TypeMismatch));
+        assert_eq!(abl[0].as_samples::<f32>(&fmt).err(), Some(SampleError::TypeMismatch));
+        let unsigned = native("u16", 2);
+        assert_eq!(abl[0].as_samples::<i16>(&unsigned).err(), Some(SampleError::TypeMismatch));
+        let foreign = format(if cfg!(target_endian = "big") {
+            "s16le@48000x2"
+        } else {
+            "s16be@48000x2"
+        });
+        assert_eq!(abl[0].as_samples::<i16>(&foreign).err(), Some(SampleError::NotNativeEndian));
+        let aac = format("2 ch, 48000 Hz, 'aac ', 1024 frames/packet");
+        assert_eq!(abl[0].as_samples::<i16>(&aac).err(), Some(SampleError::NotLinearPcm));
+    }
+
+    #[test]
+    fn narrow_integers_aligned_high() {
+        let fmt = AudioStreamBasicDescription::with_lpcm(
+            48000.,
+            1,
+            24,
+            32,
+            false,
+            cfg!(target_endian = "big"),
+            false,
+        );
+        let abl = AudioBufferList::allocate(&fmt, 2);
+        assert_eq!(abl[0].as_samples::<i32>(&fmt).unwrap().len(), 2);
+        assert_eq!(abl[0].as_samples::<i16>(&fmt).err(), Some(SampleError::TypeMismatch));
+    }
+
+    #[test]
+    fn misaligned_and_partial_buffers() {
+        let fmt = native("f32", 2);
+        let abl = AudioBufferList::allocate(&fmt, 4);
+        unsafe {
+            let buffer = &mut (*abl.as_ptr()).mBuffers[0];
+            buffer.mData = (buffer.mData as *mut u8).add(2) as *mut _;
+            buffer.mDataByteSize -= 4;
+        }
+        assert_eq!(abl[0].as_samples::<f32>(&fmt).err(), Some(SampleError::Misaligned));
+
+        let fmt = native("s16", 2);
+        let abl = AudioBufferList::allocate(&fmt, 4);
+        unsafe {
+            (*abl.as_ptr()).mBuffers[0].mDataByteSize = 6;
+        }
+        assert_eq!(abl[0].as_samples::<i16>(&fmt).err(), Some(SampleError::PartialFrame));
+    }
+
+    #[test]
+    fn buffers_without_data_are_empty() {
+        let fmt = native("s16", 2);
+        let abl = AudioBufferList::with_len(1);
+        assert!(abl[0].as_samples::<i16>(&fmt).unwrap().is_empty());
+        assert_eq!(abl[0].frames::<i16>(&fmt).unwrap().count(), 0);
+    }
+}
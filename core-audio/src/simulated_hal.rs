@@ -0,0 +1,904 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! An in-memory HAL for exercising the typed `AudioObject` API
+//! without CoreAudio.
+
+use {AudioClassID, AudioObject, ClassID, ErrorKind, ObjectID, Result};
+use {AudioAggregateDevice, AudioDevice, AudioPlugIn, AudioStream, AudioSubDevice, AudioSystemObject};
+use backend::AudioHardwareBackend;
+use ffi::{self, AudioObjectID, AudioObjectPropertyAddress};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::raw::c_void;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use std::{mem, slice};
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct PropertyKey {
+    id: AudioObjectID,
+    selector: ffi::AudioObjectPropertySelector,
+    scope: ffi::AudioObjectPropertyScope,
+    element: ffi::AudioObjectPropertyElement,
+    qualifier: Vec<u8>,
+}
+
+impl PropertyKey {
+    fn new(id: AudioObjectID, addr: &AudioObjectPropertyAddress, qualifier: &[u8]) -> Self {
+        PropertyKey {
+            id,
+            selector: addr.mSelector,
+            scope: addr.mScope,
+            element: addr.mElement,
+            qualifier: qualifier.to_vec(),
+        }
+    }
+}
+
+struct Property {
+    data: Vec<u8>,
+    settable: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Listener {
+    id: AudioObjectID,
+    addr: AudioObjectPropertyAddress,
+    proc_: ffi::AudioObjectPropertyListenerProc,
+    client_data: *mut c_void,
+}
+
+impl Listener {
+    fn is(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        proc_: ffi::AudioObjectPropertyListenerProc,
+        client_data: *mut c_void,
+    ) -> bool {
+        self.id == id && same_address(&self.addr, addr)
+            && self.proc_.map(|f| f as usize) == proc_.map(|f| f as usize)
+            && self.client_data == client_data
+    }
+
+    fn matches(&self, addr: &AudioObjectPropertyAddress) -> bool {
+        (self.addr.mSelector == ffi::kAudioObjectPropertySelectorWildcard
+            || self.addr.mSelector == addr.mSelector)
+            && (self.addr.mScope == ffi::kAudioObjectPropertyScopeWildcard
+                || self.addr.mScope == addr.mScope)
+            && (self.addr.mElement == ffi::kAudioObjectPropertyElementWildcard
+                || self.addr.mElement == addr.mElement)
+    }
+}
+
+struct State {
+    next_id: AudioObjectID,
+    objects: HashSet<AudioObjectID>,
+    properties: HashMap<PropertyKey, Property>,
+    listeners: Vec<Listener>,
+    pending: VecDeque<(AudioObjectID, Vec<AudioObjectPropertyAddress>)>,
+    dispatcher: Option<ThreadId>,
+}
+
+// The raw client data pointers are only ever handed back to the
+// listener procs that registered them.
+unsafe impl Send for State {}
+
+/// An in-memory stand-in for the CoreAudio HAL.
+///
+/// The simulated HAL holds a graph of objects, each with a class, an
+/// owner and a set of property values stored as raw bytes. Installing
+/// it with `set_backend` routes every getter and setter of
+/// `AudioSystemObject`, `AudioDevice`, `AudioStream`, etc. to it, so
+/// code using those types can be exercised on any platform.
+///
+/// Property listeners are called one notification at a time. The
+/// thread that changes a value calls them before it returns, unless
+/// another thread is already calling listeners, in which case that
+/// thread calls them too before it finishes. Notifications raised from
+/// inside a listener are queued and delivered once the current listener
+/// returns.
+///
+/// # Note
+///
+/// Values are copied byte for byte. Properties whose getters return
+/// CoreFoundation types take ownership of whatever reference is stored.
+pub struct SimulatedHal {
+    state: Mutex<State>,
+    idle: Condvar,
+}
+
+impl SimulatedHal {
+    /// Create a simulated HAL containing only the system object.
+    pub fn new() -> Self {
+        let hal = SimulatedHal {
+            state: Mutex::new(State {
+                next_id: ffi::kAudioObjectSystemObject + 1,
+                objects: HashSet::new(),
+                properties: HashMap::new(),
+                listeners: Vec::new(),
+                pending: VecDeque::new(),
+                dispatcher: None,
+            }),
+            idle: Condvar::new(),
+        };
+        {
+            let mut state = hal.lock();
+            let id = ffi::kAudioObjectSystemObject;
+            state.objects.insert(id);
+            init_object(&mut state, id, AudioSystemObject::CLASS_ID, ffi::kAudioObjectUnknown);
+            store(&mut state, id, &addr(ffi::kAudioHardwarePropertyDevices), &[], &[], false);
+            store(&mut state, id, &addr(ffi::kAudioHardwarePropertyPlugInList), &[], &[], false);
+        }
+        hal
+    }
+
+    /// Add an object of the given class owned by `owner`.
+    ///
+    /// The new object gets `class`, `base_class`, `owner` and
+    /// `owned_objects` properties, and is appended to the owner's
+    /// `owned_objects`. Devices and plug-ins owned by the system
+    /// object are added to its `devices` and `plug_in_list`, and
+    /// streams owned by a device are added to its `streams`.
+    pub fn add_object(&self, class: AudioClassID, owner: &AudioObject) -> AudioObject {
+        let owner = owner.id();
+        let id;
+        let mut changed = Vec::new();
+        {
+            let mut state = self.lock();
+            id = state.next_id;
+            state.next_id += 1;
+            state.objects.insert(id);
+            init_object(&mut state, id, class, owner);
+
+            let mut lists = vec![ffi::kAudioObjectPropertyOwnedObjects];
+            if owner == ffi::kAudioObjectSystemObject {
+                if is_device_class(class) {
+                    lists.push(ffi::kAudioHardwarePropertyDevices);
+                } else if class == AudioPlugIn::CLASS_ID {
+                    lists.push(ffi::kAudioHardwarePropertyPlugInList);
+                }
+            } else if class == AudioStream::CLASS_ID {
+                lists.push(ffi::kAudioDevicePropertyStreams);
+            }
+            for selector in lists {
+                let addr = addr(selector);
+                let mut ids = read_ids(&state, owner, &addr);
+                ids.push(id);
+                if store(&mut state, owner, &addr, &[], as_bytes(&ids[..]), false) {
+                    changed.push(addr);
+                }
+            }
+        }
+        self.notify_ids(owner, changed);
+        AudioObject(id)
+    }
+
+    /// Remove an object, all of its properties and listeners, and any
+    /// references to it from its owner.
+    pub fn remove_object(&self, object: &AudioObject) {
+        let id = object.id();
+        let mut changed = Vec::new();
+        let owner;
+        {
+            let mut state = self.lock();
+            if !state.objects.remove(&id) {
+                return;
+            }
+            owner = read_ids(&state, id, &addr(ffi::kAudioObjectPropertyOwner))
+                .first()
+                .cloned()
+                .unwrap_or(ffi::kAudioObjectUnknown);
+            state.properties.retain(|k, _| k.id != id);
+            state.listeners.retain(|l| l.id != id);
+            for &selector in &[
+                ffi::kAudioObjectPropertyOwnedObjects,
+                ffi::kAudioHardwarePropertyDevices,
+                ffi::kAudioHardwarePropertyPlugInList,
+                ffi::kAudioDevicePropertyStreams,
+            ] {
+                let addr = addr(selector);
+                if !state.properties.contains_key(&PropertyKey::new(owner, &addr, &[])) {
+                    continue;
+                }
+                let mut ids = read_ids(&state, owner, &addr);
+                ids.retain(|&x| x != id);
+                if store(&mut state, owner, &addr, &[], as_bytes(&ids[..]), false) {
+                    changed.push(addr);
+                }
+            }
+        }
+        self.notify_ids(owner, changed);
+    }
+
+    /// Set the value of a read-only property, notifying listeners if
+    /// the value changed.
+    pub fn set_property<T: Copy>(
+        &self,
+        object: &AudioObject,
+        addr: &AudioObjectPropertyAddress,
+        value: &T,
+    ) {
+        self.set_raw(object.id(), addr, &[], as_bytes(slice::from_ref(value)));
+    }
+
+    /// Set the value of a read-only property whose data is an array.
+    pub fn set_property_array<T: Copy>(
+        &self,
+        object: &AudioObject,
+        addr: &AudioObjectPropertyAddress,
+        values: &[T],
+    ) {
+        self.set_raw(object.id(), addr, &[], as_bytes(values));
+    }
+
+    /// Set the value returned when the property is read with `qualifier`.
+    pub fn set_qualified_property<Q: Copy, T: Copy>(
+        &self,
+        object: &AudioObject,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &Q,
+        value: &T,
+    ) {
+        self.set_raw(
+            object.id(),
+            addr,
+            as_bytes(slice::from_ref(qualifier)),
+            as_bytes(slice::from_ref(value)),
+        );
+    }
+
+    /// Control whether clients may change the property through
+    /// `set_property_data`.
+    pub fn set_property_settable(
+        &self,
+        object: &AudioObject,
+        addr: &AudioObjectPropertyAddress,
+        settable: bool,
+    ) {
+        let mut state = self.lock();
+        let key = PropertyKey::new(object.id(), addr, &[]);
+        if let Some(property) = state.properties.get_mut(&key) {
+            property.settable = settable;
+        }
+    }
+
+    /// Remove a property from an object.
+    pub fn remove_property(&self, object: &AudioObject, addr: &AudioObjectPropertyAddress) {
+        let mut state = self.lock();
+        let id = object.id();
+        state.properties.retain(|k, _| {
+            !(k.id == id && k.selector == addr.mSelector && k.scope == addr.mScope
+                && k.element == addr.mElement)
+        });
+    }
+
+    /// Read back the current value of a property.
+    pub fn property<T: Copy>(
+        &self,
+        object: &AudioObject,
+        addr: &AudioObjectPropertyAddress,
+    ) -> Option<T> {
+        let state = self.lock();
+        let key = PropertyKey::new(object.id(), addr, &[]);
+        state.properties.get(&key).and_then(|p| {
+            if p.data.len() == mem::size_of::<T>() {
+                Some(unsafe { (p.data.as_ptr() as *const T).read_unaligned() })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Call the listeners registered for any of the given addresses
+    /// on `object`, as the HAL does when a property changes.
+    pub fn notify(&self, object: &AudioObject, addrs: &[AudioObjectPropertyAddress]) {
+        self.notify_ids(object.id(), addrs.to_vec());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_raw(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+        data: &[u8],
+    ) {
+        let changed = {
+            let mut state = self.lock();
+            let settable = state
+                .properties
+                .get(&PropertyKey::new(id, addr, qualifier))
+                .map_or(false, |p| p.settable);
+            store(&mut state, id, addr, qualifier, data, settable)
+        };
+        if changed {
+            self.notify_ids(id, vec![*addr]);
+        }
+    }
+
+    fn notify_ids(&self, id: AudioObjectID, addrs: Vec<AudioObjectPropertyAddress>) {
+        if addrs.is_empty() {
+            return;
+        }
+        {
+            let mut state = self.lock();
+            state.pending.push_back((id, addrs));
+            if state.dispatcher.is_some() {
+                return;
+            }
+            state.dispatcher = Some(thread::current().id());
+        }
+
+        // Clear the dispatcher, and drop what it was going to deliver,
+        // if a raw listener proc panics.
+        struct Dispatching<'a>(&'a SimulatedHal);
+        impl<'a> Drop for Dispatching<'a> {
+            fn drop(&mut self) {
+                if thread::panicking() {
+                    let mut state = self.0.lock();
+                    state.dispatcher = None;
+                    state.pending.clear();
+                    self.0.idle.notify_all();
+                }
+            }
+        }
+        let _dispatching = Dispatching(self);
+
+        loop {
+            let calls = {
+                let mut state = self.lock();
+                let (id, addrs) = match state.pending.pop_front() {
+                    Some(next) => next,
+                    None => {
+                        // Give up dispatching while the queue is seen to
+                        // be empty, so whatever is queued next has a
+                        // thread to deliver it.
+                        state.dispatcher = None;
+                        self.idle.notify_all();
+                        break;
+                    }
+                };
+                state
+                    .listeners
+                    .iter()
+                    .filter(|l| l.id == id)
+                    .filter_map(|l| {
+                        let matched: Vec<_> =
+                            addrs.iter().filter(|a| l.matches(a)).cloned().collect();
+                        if matched.is_empty() {
+                            None
+                        } else {
+                            Some((*l, matched))
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            };
+            for (listener, matched) in calls {
+                // Skip listeners removed by an earlier call in this batch.
+                let registered = self.lock().listeners.iter().any(|l| {
+                    l.is(listener.id, &listener.addr, listener.proc_, listener.client_data)
+                });
+                if !registered {
+                    continue;
+                }
+                if let Some(f) = listener.proc_ {
+                    unsafe {
+                        f(
+                            listener.id,
+                            matched.len() as u32,
+                            matched.as_ptr(),
+                            listener.client_data,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for SimulatedHal {
+    fn default() -> Self {
+        SimulatedHal::new()
+    }
+}
+
+impl AudioHardwareBackend for SimulatedHal {
+    fn has_property(&self, id: AudioObjectID, addr: &AudioObjectPropertyAddress) -> bool {
+        let state = self.lock();
+        state.objects.contains(&id) && find(&state, id, addr, &[]).is_some()
+    }
+
+    fn is_property_settable(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+    ) -> Result<bool> {
+        let state = self.lock();
+        lookup(&state, id, addr, &[]).map(|p| p.settable)
+    }
+
+    fn get_property_data_size(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+    ) -> Result<u32> {
+        let state = self.lock();
+        lookup(&state, id, addr, qualifier).map(|p| p.data.len() as u32)
+    }
+
+    fn get_property_data(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+        data: &mut [u8],
+    ) -> Result<u32> {
+        let state = self.lock();
+        let property = lookup(&state, id, addr, qualifier)?;
+        let len = data.len().min(property.data.len());
+        data[..len].copy_from_slice(&property.data[..len]);
+        Ok(len as u32)
+    }
+
+    fn set_property_data(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        qualifier: &[u8],
+        data: &[u8],
+    ) -> Result<()> {
+        let changed = {
+            let mut state = self.lock();
+            {
+                let property = lookup(&state, id, addr, qualifier)?;
+                if !property.settable {
+                    return Err(ErrorKind::UnsupportedOperation.into());
+                }
+                if property.data.len() != data.len() {
+                    return Err(ErrorKind::BadPropertySize.into());
+                }
+            }
+            store(&mut state, id, addr, qualifier, data, true)
+        };
+        if changed {
+            self.notify_ids(id, vec![*addr]);
+        }
+        Ok(())
+    }
+
+    fn add_property_listener(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        listener: ffi::AudioObjectPropertyListenerProc,
+        client_data: *mut c_void,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        if !state.objects.contains(&id) {
+            return Err(ErrorKind::BadObject.into());
+        }
+        if listener.is_none() {
+            return Err(ErrorKind::IllegalOperation.into());
+        }
+        state.listeners.push(Listener {
+            id,
+            addr: *addr,
+            proc_: listener,
+            client_data,
+        });
+        Ok(())
+    }
+
+    fn remove_property_listener(
+        &self,
+        id: AudioObjectID,
+        addr: &AudioObjectPropertyAddress,
+        listener: ffi::AudioObjectPropertyListenerProc,
+        client_data: *mut c_void,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        // Like the HAL, don't return while another thread may still be
+        // calling the listener.
+        let me = thread::current().id();
+        while state.dispatcher.map_or(false, |t| t != me) {
+            state = self.idle.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        if !state.objects.contains(&id) {
+            return Err(ErrorKind::BadObject.into());
+        }
+        let position = state
+            .listeners
+            .iter()
+            .position(|l| l.is(id, addr, listener, client_data));
+        match position {
+            Some(i) => {
+                state.listeners.remove(i);
+                Ok(())
+            }
+            None => Err(ErrorKind::IllegalOperation.into()),
+        }
+    }
+}
+
+//==============================================================================
+
+fn addr(selector: ffi::AudioObjectPropertySelector) -> AudioObjectPropertyAddress {
+    AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: ffi::kAudioObjectPropertyScopeGlobal,
+        mElement: ffi::kAudioObjectPropertyElementMaster,
+    }
+}
+
+fn same_address(a: &AudioObjectPropertyAddress, b: &AudioObjectPropertyAddress) -> bool {
+    a.mSelector == b.mSelector && a.mScope == b.mScope && a.mElement == b.mElement
+}
+
+fn as_bytes<T: Copy>(values: &[T]) -> &[u8] {
+    unsafe { slice::from_raw_parts(values.as_ptr() as *const u8, mem::size_of_val(values)) }
+}
+
+fn is_device_class(class: AudioClassID) -> bool {
+    class == AudioDevice::CLASS_ID || class == AudioAggregateDevice::CLASS_ID
+        || class == AudioSubDevice::CLASS_ID
+}
+
+fn base_class_of(class: AudioClassID) -> AudioClassID {
+    if class == AudioAggregateDevice::CLASS_ID || class == AudioSubDevice::CLASS_ID {
+        AudioDevice::CLASS_ID
+    } else {
+        AudioObject::CLASS_ID
+    }
+}
+
+fn init_object(state: &mut State, id: AudioObjectID, class: AudioClassID, owner: AudioObjectID) {
+    let base_class = base_class_of(class);
+    let props: [(ffi::AudioObjectPropertySelector, &[u8]); 4] = [
+        (ffi::kAudioObjectPropertyClass, as_bytes(slice::from_ref(&class))),
+        (ffi::kAudioObjectPropertyBaseClass, as_bytes(slice::from_ref(&base_class))),
+        (ffi::kAudioObjectPropertyOwner, as_bytes(slice::from_ref(&owner))),
+        (ffi::kAudioObjectPropertyOwnedObjects, &[]),
+    ];
+    for &(selector, data) in &props {
+        store(state, id, &addr(selector), &[], data, false);
+    }
+}
+
+fn find<'a>(
+    state: &'a State,
+    id: AudioObjectID,
+    addr: &AudioObjectPropertyAddress,
+    qualifier: &[u8],
+) -> Option<&'a Property> {
+    state.properties.get(&PropertyKey::new(id, addr, qualifier))
+}
+
+fn lookup<'a>(
+    state: &'a State,
+    id: AudioObjectID,
+    addr: &AudioObjectPropertyAddress,
+    qualifier: &[u8],
+) -> Result<&'a Property> {
+    if !state.objects.contains(&id) {
+        return Err(ErrorKind::BadObject.into());
+    }
+    find(state, id, addr, qualifier).ok_or_else(|| ErrorKind::UnknownProperty.into())
+}
+
+fn read_ids(
+    state: &State,
+    id: AudioObjectID,
+    addr: &AudioObjectPropertyAddress,
+) -> Vec<AudioObjectID> {
+    find(state, id, addr, &[])
+        .map(|p| {
+            p.data
+                .chunks(mem::size_of::<AudioObjectID>())
+                .filter(|c| c.len() == mem::size_of::<AudioObjectID>())
+                .map(|c| unsafe { (c.as_ptr() as *const AudioObjectID).read_unaligned() })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Store a property value and report whether it changed.
+fn store(
+    state: &mut State,
+    id: AudioObjectID,
+    addr: &AudioObjectPropertyAddress,
+    qualifier: &[u8],
+    data: &[u8],
+    settable: bool,
+) -> bool {
+    let key = PropertyKey::new(id, addr, qualifier);
+    if let Some(property) = state.properties.get_mut(&key) {
+        property.settable = settable;
+        if property.data[..] == *data {
+            return false;
+        }
+        property.data = data.to_vec();
+        return true;
+    }
+    state.properties.insert(
+        key,
+        Property {
+            data: data.to_vec(),
+            settable,
+        },
+    );
+    true
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {audio_system_object, AudioAggregateDevice, AudioDevice, AudioObject, AudioStream,
+         ClassID, ErrorKind, ObjectID};
+    use backend::{set_backend, AudioHardwareBackend};
+    use ffi;
+    use std::ops::Deref;
+    use std::os::raw::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier, Mutex, MutexGuard};
+    use std::{mem, slice, thread};
+    use super::{addr, SimulatedHal};
+
+    static BACKEND_LOCK: Mutex<()> = Mutex::new(());
+
+    /// A `SimulatedHal` installed as the process wide backend. Tests
+    /// going through the typed API hold one so they run one at a time.
+    pub(crate) struct TestHal {
+        hal: Arc<SimulatedHal>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl Deref for TestHal {
+        type Target = SimulatedHal;
+
+        fn deref(&self) -> &SimulatedHal {
+            &self.hal
+        }
+    }
+
+    pub(crate) fn install() -> TestHal {
+        let lock = BACKEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let hal = Arc::new(SimulatedHal::new());
+        set_backend(hal.clone());
+        TestHal { hal, _lock: lock }
+    }
+
+    /// Adds a device owned by the system object.
+    pub(crate) fn add_device(hal: &SimulatedHal) -> AudioDevice {
+        AudioDevice(hal.add_object(AudioDevice::CLASS_ID, &audio_system_object()).id())
+    }
+
+    unsafe extern "C" fn count_addresses(
+        _id: ffi::AudioObjectID,
+        addr_count: u32,
+        _addrs: *const ffi::AudioObjectPropertyAddress,
+        client_data: *mut c_void,
+    ) -> ffi::OSStatus {
+        (*(client_data as *const AtomicUsize)).fetch_add(addr_count as usize, Ordering::SeqCst);
+        ffi::kAudioHardwareNoError
+    }
+
+    fn counter(count: &AtomicUsize) -> *mut c_void {
+        count as *const AtomicUsize as *mut c_void
+    }
+
+    #[test]
+    fn typed_getters_and_setters() {
+        let hal = install();
+        let mut device = add_device(&hal);
+        let rate = addr(ffi::kAudioDevicePropertyNominalSampleRate);
+        hal.set_property(&device, &rate, &44100f64);
+        assert!(device.has_property(&rate));
+        assert_eq!(device.nominal_sample_rate().unwrap(), 44100.);
+        assert!(!device.is_property_settable(&rate).unwrap());
+        let e = device.set_nominal_sample_rate(&48000.).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnsupportedOperation);
+
+        hal.set_property_settable(&device, &rate, true);
+        device.set_nominal_sample_rate(&48000.).unwrap();
+        assert_eq!(device.nominal_sample_rate().unwrap(), 48000.);
+        assert_eq!(hal.property::<f64>(&device, &rate), Some(48000.));
+        let e = hal.set_property_data(device.id(), &rate, &[], &[0; 4]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadPropertySize);
+
+        let frames = addr(ffi::kAudioDevicePropertyBufferFrameSize);
+        assert!(!device.has_property(&frames));
+        assert_eq!(device.buffer_frame_size().unwrap_err().kind(), ErrorKind::UnknownProperty);
+        hal.set_property(&device, &frames, &512u32);
+        assert_eq!(device.buffer_frame_size().unwrap(), 512);
+        hal.remove_property(&device, &frames);
+        assert!(!device.has_property(&frames));
+    }
+
+    #[test]
+    fn qualified_properties() {
+        let hal = SimulatedHal::new();
+        let aso = audio_system_object();
+        let device = add_device(&hal);
+        let translate = addr(ffi::kAudioHardwarePropertyTranslateUIDToDevice);
+        hal.set_qualified_property(&aso, &translate, &7u32, &device.id());
+
+        let mut data = [0; 4];
+        let qualifier = 7u32.to_ne_bytes();
+        let size = hal.get_property_data(aso.id(), &translate, &qualifier, &mut data);
+        assert_eq!(size.unwrap(), 4);
+        assert_eq!(u32::from_ne_bytes(data), device.id());
+        let e = hal.get_property_data(aso.id(), &translate, &8u32.to_ne_bytes(), &mut data);
+        assert_eq!(e.unwrap_err().kind(), ErrorKind::UnknownProperty);
+    }
+
+    #[test]
+    fn add_and_remove_objects() {
+        let hal = install();
+        let aso = audio_system_object();
+        let device = add_device(&hal);
+        let stream = hal.add_object(AudioStream::CLASS_ID, &device);
+        let aggregate = hal.add_object(AudioAggregateDevice::CLASS_ID, &aso);
+
+        let devices = vec![AudioObject(device.id()), aggregate];
+        assert_eq!(aso.devices().unwrap(), devices);
+        assert_eq!(aso.owned_objects().unwrap(), devices);
+        assert_eq!(device.streams().unwrap(), vec![AudioStream(stream.id())]);
+        assert_eq!(device.owned_objects().unwrap(), vec![stream]);
+        assert_eq!(device.class().unwrap(), AudioDevice::CLASS_ID);
+        assert_eq!(device.base_class().unwrap(), AudioObject::CLASS_ID);
+        assert_eq!(aggregate.base_class().unwrap(), AudioDevice::CLASS_ID);
+        assert_eq!(stream.owner().unwrap(), AudioObject(device.id()));
+        assert!(stream.downcast_ref::<AudioStream>().is_some());
+        assert!(stream.downcast_ref::<AudioDevice>().is_none());
+
+        hal.remove_object(&stream);
+        assert!(device.streams().unwrap().is_empty());
+        assert_eq!(stream.class().unwrap_err().kind(), ErrorKind::BadObject);
+        assert!(!stream.has_property(&addr(ffi::kAudioObjectPropertyClass)));
+        hal.remove_object(&device);
+        assert_eq!(aso.devices().unwrap(), vec![aggregate]);
+        // Removing an object twice does nothing.
+        hal.remove_object(&device);
+        assert_eq!(aso.devices().unwrap(), vec![aggregate]);
+    }
+
+    #[test]
+    fn notify_calls_matching_listeners() {
+        let hal = SimulatedHal::new();
+        let device = add_device(&hal);
+        let other = add_device(&hal);
+        let rate = addr(ffi::kAudioDevicePropertyNominalSampleRate);
+        let any_scope = ffi::AudioObjectPropertyAddress {
+            mScope: ffi::kAudioObjectPropertyScopeWildcard,
+            mElement: ffi::kAudioObjectPropertyElementWildcard,
+            ..rate
+        };
+        let any_selector = ffi::AudioObjectPropertyAddress {
+            mSelector: ffi::kAudioObjectPropertySelectorWildcard,
+            ..any_scope
+        };
+        let (exact, scoped, all, unrelated) = Default::default();
+        let listeners: [(&AtomicUsize, ffi::AudioObjectPropertyAddress, ffi::AudioObjectID); 4] = [
+            (&exact, rate, device.id()),
+            (&scoped, any_scope, device.id()),
+            (&all, any_selector, device.id()),
+            (&unrelated, any_selector, other.id()),
+        ];
+        for &(count, ref addr, id) in &listeners {
+            hal.add_property_listener(id, addr, Some(count_addresses), counter(count))
+                .unwrap();
+        }
+
+        let input_rate = ffi::AudioObjectPropertyAddress {
+            mScope: ffi::kAudioObjectPropertyScopeInput,
+            ..rate
+        };
+        let frames = addr(ffi::kAudioDevicePropertyBufferFrameSize);
+        hal.notify(&device, &[rate, input_rate, frames]);
+        let counts = || {
+            [&exact, &scoped, &all, &unrelated].map(|c: &AtomicUsize| c.load(Ordering::SeqCst))
+        };
+        assert_eq!(counts(), [1, 2, 3, 0]);
+
+        // Storing a value only notifies when it changes.
+        hal.set_property(&device, &rate, &48000f64);
+        hal.set_property(&device, &rate, &48000f64);
+        assert_eq!(counts(), [2, 3, 4, 0]);
+
+        hal.remove_property_listener(device.id(), &rate, Some(count_addresses), counter(&exact))
+            .unwrap();
+        let e = hal
+            .remove_property_listener(device.id(), &rate, Some(count_addresses), counter(&exact))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::IllegalOperation);
+        hal.notify(&device, &[rate]);
+        assert_eq!(counts(), [2, 4, 5, 0]);
+
+        let e = hal
+            .add_property_listener(0xdead, &rate, Some(count_addresses), counter(&exact))
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::BadObject);
+    }
+
+    #[test]
+    fn concurrent_changes_are_all_delivered() {
+        const CHANGES: u32 = 50000;
+        let hal = SimulatedHal::new();
+        let device = add_device(&hal);
+        let count = AtomicUsize::new(0);
+        let all = addr(ffi::kAudioObjectPropertySelectorWildcard);
+        hal.add_property_listener(device.id(), &all, Some(count_addresses), counter(&count))
+            .unwrap();
+
+        let rate = addr(ffi::kAudioDevicePropertyNominalSampleRate);
+        let frames = addr(ffi::kAudioDevicePropertyBufferFrameSize);
+        let start = Barrier::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                start.wait();
+                for i in 1..=CHANGES {
+                    hal.set_property(&device, &rate, &f64::from(i));
+                }
+            });
+            s.spawn(|| {
+                start.wait();
+                for i in 1..=CHANGES {
+                    hal.set_property(&device, &frames, &i);
+                }
+            });
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 2 * CHANGES as usize);
+        assert!(hal.lock().dispatcher.is_none());
+    }
+
+    struct Recorder {
+        hal: *const SimulatedHal,
+        selectors: Mutex<Vec<ffi::AudioObjectPropertySelector>>,
+    }
+
+    unsafe extern "C" fn record_and_renotify(
+        id: ffi::AudioObjectID,
+        addr_count: u32,
+        addrs: *const ffi::AudioObjectPropertyAddress,
+        client_data: *mut c_void,
+    ) -> ffi::OSStatus {
+        let recorder = &*(client_data as *const Recorder);
+        for a in slice::from_raw_parts(addrs, addr_count as usize) {
+            recorder.selectors.lock().unwrap().push(a.mSelector);
+            if a.mSelector == ffi::kAudioDevicePropertyNominalSampleRate {
+                let frames = addr(ffi::kAudioDevicePropertyBufferFrameSize);
+                (*recorder.hal).notify(&AudioObject(id), &[frames]);
+                recorder.selectors.lock().unwrap().push(0);
+            }
+        }
+        ffi::kAudioHardwareNoError
+    }
+
+    #[test]
+    fn notifications_from_listeners_are_queued() {
+        let hal = SimulatedHal::new();
+        let device = add_device(&hal);
+        let recorder = Recorder {
+            hal: &hal,
+            selectors: Mutex::new(Vec::new()),
+        };
+        let all = addr(ffi::kAudioObjectPropertySelectorWildcard);
+        let client_data = &recorder as *const Recorder as *mut c_void;
+        hal.add_property_listener(device.id(), &all, Some(record_and_renotify), client_data)
+            .unwrap();
+
+        hal.notify(&device, &[addr(ffi::kAudioDevicePropertyNominalSampleRate)]);
+        assert_eq!(
+            *recorder.selectors.lock().unwrap(),
+            [
+                ffi::kAudioDevicePropertyNominalSampleRate,
+                0,
+                ffi::kAudioDevicePropertyBufferFrameSize,
+            ]
+        );
+        mem::drop(hal);
+    }
+}
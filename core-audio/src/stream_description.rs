@@ -0,0 +1,1171 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Building, inspection, validation, printing and parsing of
+//! `AudioStreamBasicDescription`s, following the rules of
+//! `CAStreamBasicDescription`.
+
+use {AppleLosslessFormatFlags, AudioFormat, AudioFormatFlags, AudioStreamBasicDescription,
+     AudioStreamBasicDescriptionRef, LinearPcmFlags};
+use ffi;
+use four_char_code::parse_four_char_code;
+use std::{cmp, error, fmt, str};
+
+/// A way in which an `AudioStreamBasicDescription` is inconsistent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AsbdIssue {
+    /// The sample rate is negative or not finite. Zero is
+    /// `AudioStreamBasicDescription::ANY_RATE`, which is allowed.
+    InvalidSampleRate(f64),
+    /// There are no channels.
+    NoChannels,
+    /// Linear PCM has exactly one frame per packet.
+    FramesPerPacket(u32),
+    /// `bytes_per_packet` isn't `bytes_per_frame * frames_per_packet`.
+    BytesPerPacket { bytes_per_packet: u32, expected: u32 },
+    /// Linear PCM has no bytes per frame.
+    NoBytesPerFrame,
+    /// `bytes_per_frame` doesn't divide evenly between the interleaved
+    /// channels.
+    BytesPerFrame { bytes_per_frame: u32, channels: u32 },
+    /// Linear PCM has no bits per channel.
+    NoBitsPerChannel,
+    /// There are more bits per channel than fit in each sample's word.
+    BitsExceedWord { bits_per_channel: u32, word_bits: u32 },
+    /// The format is packed, but the bits per channel don't fill the
+    /// sample word.
+    PackedBits { bits_per_channel: u32, word_bits: u32 },
+    /// Both `IS_PACKED` and `IS_ALIGNED_HIGH` are set.
+    PackedAndAlignedHigh,
+    /// Both `IS_FLOAT` and `IS_SIGNED_INTEGER` are set.
+    FloatAndSignedInteger,
+    /// Floating point samples must be 32 or 64 bits.
+    FloatBits(u32),
+    /// Floating point samples can't have fixed point fraction bits.
+    FloatFractionBits(u32),
+    /// There are more fixed point fraction bits than bits per channel.
+    FractionBits { fraction_bits: u32, bits_per_channel: u32 },
+}
+
+impl fmt::Display for AsbdIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AsbdIssue::InvalidSampleRate(rate) => write!(f, "invalid sample rate {}", rate),
+            AsbdIssue::FramesPerPacket(frames) => write!(
+                f,
+                "linear PCM must have 1 frame per packet, not {}",
+                frames
+            ),
+            AsbdIssue::BytesPerPacket {
+                bytes_per_packet,
+                expected,
+            } => write!(
+                f,
+                "{} bytes per packet, expected {}",
+                bytes_per_packet, expected
+            ),
+            AsbdIssue::BytesPerFrame {
+                bytes_per_frame,
+                channels,
+            } => write!(
+                f,
+                "{} bytes per frame don't divide between {} channels",
+                bytes_per_frame, channels
+            ),
+            AsbdIssue::BitsExceedWord {
+                bits_per_channel,
+                word_bits,
+            } => write!(
+                f,
+                "{} bits per channel don't fit in a {} bit sample",
+                bits_per_channel, word_bits
+            ),
+            AsbdIssue::PackedBits {
+                bits_per_channel,
+                word_bits,
+            } => write!(
+                f,
+                "packed {} bits per channel don't fill a {} bit sample",
+                bits_per_channel, word_bits
+            ),
+            AsbdIssue::FloatBits(bits) => {
+                write!(f, "floating point samples can't have {} bits", bits)
+            }
+            AsbdIssue::FloatFractionBits(fraction) => write!(
+                f,
+                "floating point samples can't have {} fraction bits",
+                fraction
+            ),
+            AsbdIssue::FractionBits {
+                fraction_bits,
+                bits_per_channel,
+            } => write!(
+                f,
+                "{} fraction bits exceed {} bits per channel",
+                fraction_bits, bits_per_channel
+            ),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+impl error::Error for AsbdIssue {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsbdIssue {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            AsbdIssue::InvalidSampleRate(_) => "invalid sample rate",
+            AsbdIssue::NoChannels => "no channels per frame",
+            AsbdIssue::FramesPerPacket(_) => "linear PCM must have 1 frame per packet",
+            AsbdIssue::BytesPerPacket { .. } => {
+                "bytes per packet don't match bytes per frame and frames per packet"
+            }
+            AsbdIssue::NoBytesPerFrame => "no bytes per frame",
+            AsbdIssue::BytesPerFrame { .. } => "bytes per frame don't divide between the channels",
+            AsbdIssue::NoBitsPerChannel => "no bits per channel",
+            AsbdIssue::BitsExceedWord { .. } => "bits per channel don't fit in the sample word",
+            AsbdIssue::PackedBits { .. } => "packed bits per channel don't fill the sample word",
+            AsbdIssue::PackedAndAlignedHigh => "both packed and aligned high",
+            AsbdIssue::FloatAndSignedInteger => "both floating point and signed integer",
+            AsbdIssue::FloatBits(_) => "floating point samples must be 32 or 64 bits",
+            AsbdIssue::FloatFractionBits(_) => "floating point samples can't have fraction bits",
+            AsbdIssue::FractionBits { .. } => "fraction bits exceed bits per channel",
+        }
+    }
+}
+
+impl AudioStreamBasicDescriptionRef {
+    /// Whether the stream is linear PCM.
+    pub fn is_lpcm(&self) -> bool {
+        self.format_id() == AudioFormat::LinearPcm
+    }
+
+    /// Whether the channels of each frame are together, rather than each
+    /// channel in its own buffer.
+    pub fn is_interleaved(&self) -> bool {
+        !self.format_flags()
+            .contains(AudioFormatFlags::IS_NON_INTERLEAVED)
+    }
+
+    /// Whether the stream is floating point linear PCM.
+    pub fn is_float(&self) -> bool {
+        self.is_lpcm() && self.format_flags().contains(AudioFormatFlags::IS_FLOAT)
+    }
+
+    /// The number of channels in each buffer of the stream: all of them
+    /// when interleaved, otherwise 1.
+    pub fn interleaved_channels(&self) -> u32 {
+        if self.is_interleaved() {
+            self.channels_per_frame()
+        } else {
+            1
+        }
+    }
+
+    /// The number of buffers the stream needs: 1 when interleaved,
+    /// otherwise one for each channel.
+    pub fn channel_streams(&self) -> u32 {
+        if self.is_interleaved() {
+            1
+        } else {
+            self.channels_per_frame()
+        }
+    }
+
+    /// The number of bytes in each sample, including any padding, or 0
+    /// if it can't be worked out.
+    pub fn sample_word_size(&self) -> u32 {
+        let channels = self.interleaved_channels();
+        if self.bytes_per_frame() > 0 && channels > 0 {
+            self.bytes_per_frame() / channels
+        } else {
+            0
+        }
+    }
+
+    /// Checks that the fields of the description agree with each other.
+    /// Every inconsistency found is returned, not only the first.
+    pub fn validate(&self) -> Result<(), Vec<AsbdIssue>> {
+        let mut issues = Vec::new();
+        let rate = self.sample_rate();
+        if !rate.is_finite() || rate < 0. {
+            issues.push(AsbdIssue::InvalidSampleRate(rate));
+        }
+        let channels = self.channels_per_frame();
+        if channels == 0 {
+            issues.push(AsbdIssue::NoChannels);
+        }
+        let frames = self.frames_per_packet();
+        let bytes_per_frame = self.bytes_per_frame();
+        if self.is_lpcm() && frames != 1 {
+            issues.push(AsbdIssue::FramesPerPacket(frames));
+        }
+        if bytes_per_frame > 0 && frames > 0 {
+            let expected = bytes_per_frame.saturating_mul(frames);
+            if self.bytes_per_packet() != expected {
+                issues.push(AsbdIssue::BytesPerPacket {
+                    bytes_per_packet: self.bytes_per_packet(),
+                    expected,
+                });
+            }
+        }
+        if self.is_lpcm() {
+            self.validate_lpcm(&mut issues);
+        }
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
+    fn validate_lpcm(&self, issues: &mut Vec<AsbdIssue>) {
+        let flags = self.format_flags();
+        let bytes_per_frame = self.bytes_per_frame();
+        let channels = self.interleaved_channels();
+        if bytes_per_frame == 0 {
+            issues.push(AsbdIssue::NoBytesPerFrame);
+        } else if channels > 0 && bytes_per_frame % channels != 0 {
+            issues.push(AsbdIssue::BytesPerFrame {
+                bytes_per_frame,
+                channels,
+            });
+        }
+
+        let bits = self.bits_per_channel();
+        let word_bits = self.sample_word_size().saturating_mul(8);
+        if bits == 0 {
+            issues.push(AsbdIssue::NoBitsPerChannel);
+        } else if word_bits > 0 {
+            if bits > word_bits {
+                issues.push(AsbdIssue::BitsExceedWord {
+                    bits_per_channel: bits,
+                    word_bits,
+                });
+            } else if bits < word_bits && flags.contains(AudioFormatFlags::IS_PACKED) {
+                issues.push(AsbdIssue::PackedBits {
+                    bits_per_channel: bits,
+                    word_bits,
+                });
+            }
+        }
+        if flags.contains(AudioFormatFlags::IS_PACKED | AudioFormatFlags::IS_ALIGNED_HIGH) {
+            issues.push(AsbdIssue::PackedAndAlignedHigh);
+        }
+
+        // `format_flags` drops the fixed point bitfield, so read it from
+        // the raw flags.
+        let raw_flags = unsafe { (*self.as_ptr()).mFormatFlags };
+        let fraction = LinearPcmFlags::from(raw_flags).number_fractional_bits() as u32;
+        if flags.contains(AudioFormatFlags::IS_FLOAT) {
+            if flags.contains(AudioFormatFlags::IS_SIGNED_INTEGER) {
+                issues.push(AsbdIssue::FloatAndSignedInteger);
+            }
+            if bits != 0 && bits != 32 && bits != 64 {
+                issues.push(AsbdIssue::FloatBits(bits));
+            }
+            if fraction > 0 {
+                issues.push(AsbdIssue::FloatFractionBits(fraction));
+            }
+        } else if fraction > bits {
+            issues.push(AsbdIssue::FractionBits {
+                fraction_bits: fraction,
+                bits_per_channel: bits,
+            });
+        }
+    }
+}
+
+/// How linear PCM samples are encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleKind {
+    Float,
+    SignedInteger,
+    /// Unsigned integers, offset so that silence is half the range.
+    UnsignedInteger,
+}
+
+/// Builds an `AudioStreamBasicDescription` for any `AudioFormat`. See
+/// `AudioStreamBasicDescription::builder`.
+///
+/// Linear PCM starts as native endian, interleaved, packed 32-bit
+/// float. Other formats start with the frames per packet they usually
+/// have, and the bytes per packet when that's constant. Anything can be
+/// overridden.
+#[derive(Clone, Debug)]
+pub struct AsbdBuilder {
+    format: AudioFormat,
+    sample_rate: f64,
+    channels: u32,
+    kind: SampleKind,
+    bits: Option<u32>,
+    word_bits: Option<u32>,
+    aligned_high: bool,
+    big_endian: bool,
+    non_interleaved: bool,
+    fraction_bits: u32,
+    flags: Option<ffi::AudioFormatFlags>,
+    frames_per_packet: Option<u32>,
+    bytes_per_packet: Option<u32>,
+}
+
+impl AudioStreamBasicDescription {
+    /// Starts building a description of `channels` of `format` at
+    /// `sample_rate`.
+    pub fn builder(format: AudioFormat, sample_rate: f64, channels: u32) -> AsbdBuilder {
+        AsbdBuilder {
+            format,
+            sample_rate,
+            channels,
+            kind: SampleKind::Float,
+            bits: None,
+            word_bits: None,
+            aligned_high: true,
+            big_endian: cfg!(target_endian = "big"),
+            non_interleaved: false,
+            fraction_bits: 0,
+            flags: None,
+            frames_per_packet: None,
+            bytes_per_packet: None,
+        }
+    }
+}
+
+/// The usual frames per packet of `format`, and its bytes per packet
+/// for each channel when that's constant.
+fn packet_size(format: AudioFormat) -> (u32, u32) {
+    use AudioFormat::*;
+    match format {
+        LinearPcm | ULaw | ALaw | ParameterValueStream => (1, 0),
+        Ac3 | _60958Ac3 | EnhancedAc3 => (1536, 0),
+        AppleIma4 => (64, 34),
+        Mace3 => (6, 2),
+        Mace6 => (6, 1),
+        MpegLayer1 => (384, 0),
+        MpegLayer2 | MpegLayer3 => (1152, 0),
+        Mpeg4Aac | Mpeg4AacEldSbr | Mpeg4AacEldV2 | Mpeg4AacSpatial => (1024, 0),
+        Mpeg4AacHe | Mpeg4AacHeV2 => (2048, 0),
+        Mpeg4AacLd | Mpeg4AacEld => (512, 0),
+        Qualcomm | Amr => (160, 0),
+        AmrWb => (320, 0),
+        MicrosoftGsm => (320, 65),
+        AppleLossless | Flac => (4096, 0),
+        Opus => (960, 0),
+        _ => (0, 0),
+    }
+}
+
+impl AsbdBuilder {
+    /// Sets how linear PCM samples are encoded. Float is the default.
+    pub fn sample_kind(mut self, kind: SampleKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Sets the number of valid bits in each sample. For Apple Lossless
+    /// and FLAC this is the bit depth of the source material, and sets
+    /// the format flags to match.
+    pub fn bits_per_channel(mut self, bits: u32) -> Self {
+        self.bits = Some(bits);
+        self
+    }
+
+    /// Sets the number of bits each linear PCM sample occupies. By
+    /// default samples occupy the whole bytes their valid bits need.
+    pub fn word_bits(mut self, bits: u32) -> Self {
+        self.word_bits = Some(bits);
+        self
+    }
+
+    /// Places samples narrower than their word in the low bits rather
+    /// than the high bits.
+    pub fn aligned_low(mut self) -> Self {
+        self.aligned_high = false;
+        self
+    }
+
+    /// Sets whether linear PCM samples are big endian. By default
+    /// they're native endian.
+    pub fn big_endian(mut self, big_endian: bool) -> Self {
+        self.big_endian = big_endian;
+        self
+    }
+
+    /// Puts each channel in its own buffer.
+    pub fn non_interleaved(mut self) -> Self {
+        self.non_interleaved = true;
+        self
+    }
+
+    /// Makes integer samples fixed point, with `bits` of fraction. The
+    /// format flags hold at most 63 fraction bits, and more saturate.
+    pub fn fraction_bits(mut self, bits: u32) -> Self {
+        self.fraction_bits = bits;
+        self
+    }
+
+    /// Sets the source bit depth of Apple Lossless or FLAC. Setting the
+    /// bits per channel does the same.
+    pub fn source_bit_depth(self, depth: AppleLosslessFormatFlags) -> Self {
+        self.format_flags(depth.into())
+    }
+
+    /// Sets the format flags directly, instead of working them out from
+    /// the other settings.
+    pub fn format_flags(mut self, flags: ffi::AudioFormatFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Overrides the number of frames in each packet.
+    pub fn frames_per_packet(mut self, frames: u32) -> Self {
+        self.frames_per_packet = Some(frames);
+        self
+    }
+
+    /// Overrides the number of bytes in each packet. 0 means packets
+    /// vary in size.
+    pub fn bytes_per_packet(mut self, bytes: u32) -> Self {
+        self.bytes_per_packet = Some(bytes);
+        self
+    }
+
+    /// Creates the description. The result isn't checked; see
+    /// `AudioStreamBasicDescriptionRef::validate`. Sizes and fraction
+    /// bits too large for the description's fields saturate.
+    ///
+    /// Apple Lossless and FLAC sources are 16, 20, 24 or 32 bits. Other
+    /// bits per channel round up to the next of those, or down to 32.
+    pub fn build(&self) -> AudioStreamBasicDescription {
+        let (frames_per_packet, channel_packet_bytes) = packet_size(self.format);
+        let mut desc = ffi::AudioStreamBasicDescription::default();
+        desc.mSampleRate = self.sample_rate;
+        desc.mFormatID = self.format.into();
+        desc.mChannelsPerFrame = self.channels;
+        desc.mFramesPerPacket = self.frames_per_packet.unwrap_or(frames_per_packet);
+        desc.mBytesPerPacket = channel_packet_bytes.saturating_mul(self.channels);
+
+        match self.format {
+            AudioFormat::LinearPcm | AudioFormat::_60958Ac3 | AudioFormat::ParameterValueStream => {
+                self.fill_lpcm(&mut desc)
+            }
+            AudioFormat::ULaw | AudioFormat::ALaw => {
+                desc.mBitsPerChannel = 8;
+                desc.mBytesPerFrame = self.channels;
+                desc.mBytesPerPacket = self.channels;
+            }
+            AudioFormat::AppleLossless | AudioFormat::Flac => {
+                desc.mFormatFlags = match self.bits.unwrap_or(16) {
+                    0..=16 => ffi::kAppleLosslessFormatFlag_16BitSourceData,
+                    17..=20 => ffi::kAppleLosslessFormatFlag_20BitSourceData,
+                    21..=24 => ffi::kAppleLosslessFormatFlag_24BitSourceData,
+                    _ => ffi::kAppleLosslessFormatFlag_32BitSourceData,
+                };
+            }
+            _ => {}
+        }
+
+        if let Some(flags) = self.flags {
+            desc.mFormatFlags = flags;
+        }
+        if let Some(bytes) = self.bytes_per_packet {
+            desc.mBytesPerPacket = bytes;
+        }
+        AudioStreamBasicDescription::from(desc)
+    }
+
+    fn fill_lpcm(&self, desc: &mut ffi::AudioStreamBasicDescription) {
+        // AC-3 over IEC 60958 is carried as 16-bit stereo samples, and
+        // parameter streams are floats.
+        let (kind, default_bits) = match self.format {
+            AudioFormat::_60958Ac3 => (SampleKind::SignedInteger, 16),
+            AudioFormat::ParameterValueStream => (SampleKind::Float, 32),
+            _ => (self.kind, if self.kind == SampleKind::Float { 32 } else { 16 }),
+        };
+        let bits = self.bits.unwrap_or(default_bits);
+        let word_bits = self.word_bits.unwrap_or(bits.saturating_add(7) / 8 * 8);
+
+        let mut flags = match kind {
+            SampleKind::Float => AudioFormatFlags::IS_FLOAT,
+            SampleKind::SignedInteger => AudioFormatFlags::IS_SIGNED_INTEGER,
+            SampleKind::UnsignedInteger => AudioFormatFlags::empty(),
+        };
+        if self.big_endian {
+            flags |= AudioFormatFlags::IS_BIG_ENDIAN;
+        }
+        if bits == word_bits {
+            flags |= AudioFormatFlags::IS_PACKED;
+        } else if self.aligned_high {
+            flags |= AudioFormatFlags::IS_ALIGNED_HIGH;
+        }
+        if self.non_interleaved {
+            flags |= AudioFormatFlags::IS_NON_INTERLEAVED;
+        }
+
+        let channels = if self.non_interleaved { 1 } else { self.channels };
+        let fraction_bits = cmp::min(
+            self.fraction_bits,
+            ffi::kLinearPCMFormatFlagsSampleFractionMask
+                >> ffi::kLinearPCMFormatFlagsSampleFractionShift,
+        );
+        desc.mFormatFlags =
+            LinearPcmFlags::with_fractional_bits(flags, fraction_bits as usize).into();
+        desc.mBitsPerChannel = bits;
+        desc.mBytesPerFrame = (word_bits / 8).saturating_mul(channels);
+        desc.mBytesPerPacket = desc.mBytesPerFrame.saturating_mul(desc.mFramesPerPacket);
+    }
+}
+
+/// Why a stream description, format ID or class ID couldn't be parsed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseFormatError {
+    /// A four char code isn't four printable characters or a hex
+    /// number.
+    FourCharCode,
+    /// The channel count is missing, isn't a number or is over 65535.
+    Channels,
+    /// The sample rate is missing or isn't a number.
+    SampleRate,
+    /// The sample type isn't one like `Float32`, `Int16` or `Fixed8.24`,
+    /// or has more than 64 bits.
+    SampleFormat,
+    /// A field isn't one that can appear at its position.
+    UnexpectedField,
+}
+
+impl fmt::Display for ParseFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl error::Error for ParseFormatError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl ParseFormatError {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            ParseFormatError::FourCharCode => "invalid four char code",
+            ParseFormatError::Channels => "invalid channel count",
+            ParseFormatError::SampleRate => "invalid sample rate",
+            ParseFormatError::SampleFormat => "invalid sample format",
+            ParseFormatError::UnexpectedField => "unexpected field",
+        }
+    }
+}
+
+impl AudioStreamBasicDescriptionRef {
+    fn fraction_bits(&self) -> u32 {
+        // `format_flags` drops the fixed point bitfield, so read it from
+        // the raw flags.
+        let raw_flags = unsafe { (*self.as_ptr()).mFormatFlags };
+        LinearPcmFlags::from(raw_flags).number_fractional_bits() as u32
+    }
+}
+
+/// Writes the description like `2 ch, 48000 Hz, Float32, interleaved`.
+///
+/// Linear PCM samples are `Float`, `Int`, `UInt` or `Fixed` with their
+/// bits, such as `Int16` or `Fixed8.24`. Samples narrower than their
+/// word add the word size, as in `Int24/32`, followed by `aligned low`
+/// when they're in the low bits. Endianness is only written when it
+/// isn't native. Other formats write their four char code, followed
+/// by their frames and bytes per packet and flags when they're set.
+impl fmt::Display for AudioStreamBasicDescriptionRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ch, {} Hz, ", self.channels_per_frame(), self.sample_rate())?;
+        let raw_flags = unsafe { (*self.as_ptr()).mFormatFlags };
+        if !self.is_lpcm() {
+            write!(f, "{}", self.format_id())?;
+            if self.frames_per_packet() > 0 {
+                write!(f, ", {} frames/packet", self.frames_per_packet())?;
+            }
+            if self.bytes_per_packet() > 0 {
+                write!(f, ", {} bytes/packet", self.bytes_per_packet())?;
+            }
+            if raw_flags != 0 {
+                write!(f, ", flags {:#x}", raw_flags)?;
+            }
+            return Ok(());
+        }
+
+        let flags = self.format_flags();
+        let bits = self.bits_per_channel();
+        let fraction = self.fraction_bits();
+        if self.is_float() {
+            write!(f, "Float{}", bits)?;
+        } else {
+            if !flags.contains(AudioFormatFlags::IS_SIGNED_INTEGER) {
+                f.write_str("U")?;
+            }
+            if fraction > 0 {
+                write!(f, "Fixed{}.{}", bits.saturating_sub(fraction), fraction)?;
+            } else {
+                write!(f, "Int{}", bits)?;
+            }
+        }
+        let word_bits = self.sample_word_size().saturating_mul(8);
+        if word_bits > 0 && word_bits != bits {
+            write!(f, "/{}", word_bits)?;
+            if !flags.contains(AudioFormatFlags::IS_ALIGNED_HIGH) {
+                f.write_str(", aligned low")?;
+            }
+        }
+        let big_endian = flags.contains(AudioFormatFlags::IS_BIG_ENDIAN);
+        if big_endian != cfg!(target_endian = "big") {
+            f.write_str(if big_endian { ", big-endian" } else { ", little-endian" })?;
+        }
+        f.write_str(if self.is_interleaved() {
+            ", interleaved"
+        } else {
+            ", non-interleaved"
+        })
+    }
+}
+
+impl fmt::Display for AudioStreamBasicDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// Parses the form written by `Display`, or a short form for linear
+/// PCM like `f32le@48000x2`: `f`, `s` or `u` for float, signed or
+/// unsigned samples, the bits, an optional `le` or `be`, then the sample
+/// rate and channels.
+impl str::FromStr for AudioStreamBasicDescription {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.contains(',') {
+            parse_long(s)
+        } else {
+            parse_short(s)
+        }
+    }
+}
+
+/// The most channels a parsed description can have: as many as a
+/// channel layout tag can count.
+const MAX_CHANNELS: u32 = 0xFFFF;
+
+/// The most bits a parsed sample can have.
+const MAX_BITS: u32 = 64;
+
+fn parse_channels(s: &str) -> Result<u32, ParseFormatError> {
+    match s.parse() {
+        Ok(channels) if channels <= MAX_CHANNELS => Ok(channels),
+        _ => Err(ParseFormatError::Channels),
+    }
+}
+
+fn parse_long(s: &str) -> Result<AudioStreamBasicDescription, ParseFormatError> {
+    let mut fields = s.split(',').map(str::trim);
+    let channels = fields
+        .next()
+        .and_then(|f| f.strip_suffix(" ch"))
+        .ok_or(ParseFormatError::Channels)
+        .and_then(|f| parse_channels(f.trim()))?;
+    let sample_rate = fields
+        .next()
+        .and_then(|f| f.strip_suffix(" Hz"))
+        .and_then(|f| f.trim().parse().ok())
+        .ok_or(ParseFormatError::SampleRate)?;
+    let sample = fields.next().ok_or(ParseFormatError::SampleFormat)?;
+
+    if sample.starts_with('\'') || sample.starts_with("0x") {
+        let format = AudioFormat::from(
+            parse_four_char_code(sample).ok_or(ParseFormatError::FourCharCode)?,
+        );
+        let (mut frames, mut bytes, mut flags) = (0, 0, 0);
+        for field in fields {
+            if let Some(n) = field.strip_suffix(" frames/packet") {
+                frames = n.parse().map_err(|_| ParseFormatError::UnexpectedField)?;
+            } else if let Some(n) = field.strip_suffix(" bytes/packet") {
+                bytes = n.parse().map_err(|_| ParseFormatError::UnexpectedField)?;
+            } else if let Some(n) = field.strip_prefix("flags 0x") {
+                flags = u32::from_str_radix(n, 16).map_err(|_| ParseFormatError::UnexpectedField)?;
+            } else {
+                return Err(ParseFormatError::UnexpectedField);
+            }
+        }
+        return Ok(AudioStreamBasicDescription::builder(format, sample_rate, channels)
+            .frames_per_packet(frames)
+            .bytes_per_packet(bytes)
+            .format_flags(flags)
+            .build());
+    }
+
+    let (kind, bits, word_bits, fraction) =
+        parse_sample(sample).ok_or(ParseFormatError::SampleFormat)?;
+    let mut builder = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, sample_rate, channels)
+        .sample_kind(kind)
+        .bits_per_channel(bits)
+        .fraction_bits(fraction);
+    if let Some(word_bits) = word_bits {
+        builder = builder.word_bits(word_bits);
+    }
+    for field in fields {
+        builder = match field {
+            "aligned low" => builder.aligned_low(),
+            "big-endian" => builder.big_endian(true),
+            "little-endian" => builder.big_endian(false),
+            "interleaved" => builder,
+            "non-interleaved" => builder.non_interleaved(),
+            _ => return Err(ParseFormatError::UnexpectedField),
+        };
+    }
+    Ok(builder.build())
+}
+
+/// Parses a sample type like `Int24/32` into its kind, bits, word bits
+/// and fraction bits. Samples and words are at most `MAX_BITS`, and
+/// fixed point samples have fewer fraction bits than that.
+fn parse_sample(s: &str) -> Option<(SampleKind, u32, Option<u32>, u32)> {
+    let (kind, bits, word_bits, fraction) = parse_sample_bits(s)?;
+    if bits > MAX_BITS || word_bits.map_or(false, |w| w > MAX_BITS) || fraction >= MAX_BITS {
+        return None;
+    }
+    Some((kind, bits, word_bits, fraction))
+}
+
+fn parse_sample_bits(s: &str) -> Option<(SampleKind, u32, Option<u32>, u32)> {
+    let (s, word_bits) = match s.find('/') {
+        Some(i) => (&s[..i], Some(s[i + 1..].parse().ok()?)),
+        None => (s, None),
+    };
+    let (unsigned, s) = match s.strip_prefix('U') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let integer = if unsigned {
+        SampleKind::UnsignedInteger
+    } else {
+        SampleKind::SignedInteger
+    };
+    if let Some(bits) = s.strip_prefix("Float") {
+        if unsigned {
+            return None;
+        }
+        Some((SampleKind::Float, bits.parse().ok()?, word_bits, 0))
+    } else if let Some(bits) = s.strip_prefix("Int") {
+        Some((integer, bits.parse().ok()?, word_bits, 0))
+    } else if let Some(bits) = s.strip_prefix("Fixed") {
+        let dot = bits.find('.')?;
+        let whole: u32 = bits[..dot].parse().ok()?;
+        let fraction: u32 = bits[dot + 1..].parse().ok()?;
+        Some((integer, whole.checked_add(fraction)?, word_bits, fraction))
+    } else {
+        None
+    }
+}
+
+fn parse_short(s: &str) -> Result<AudioStreamBasicDescription, ParseFormatError> {
+    let at = s.find('@').ok_or(ParseFormatError::SampleRate)?;
+    let (sample, rest) = (&s[..at], &s[at + 1..]);
+    let x = rest.rfind('x').ok_or(ParseFormatError::Channels)?;
+    let sample_rate = rest[..x].parse().map_err(|_| ParseFormatError::SampleRate)?;
+    let channels = parse_channels(&rest[x + 1..])?;
+
+    let kind = match sample.chars().next() {
+        Some('f') => SampleKind::Float,
+        Some('s') | Some('i') => SampleKind::SignedInteger,
+        Some('u') => SampleKind::UnsignedInteger,
+        _ => return Err(ParseFormatError::SampleFormat),
+    };
+    let (bits, big_endian) = if let Some(bits) = sample[1..].strip_suffix("le") {
+        (bits, false)
+    } else if let Some(bits) = sample[1..].strip_suffix("be") {
+        (bits, true)
+    } else {
+        (&sample[1..], cfg!(target_endian = "big"))
+    };
+    let bits = match bits.parse() {
+        Ok(bits) if bits <= MAX_BITS => bits,
+        _ => return Err(ParseFormatError::SampleFormat),
+    };
+    Ok(AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, sample_rate, channels)
+        .sample_kind(kind)
+        .bits_per_channel(bits)
+        .big_endian(big_endian)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s16_stereo() -> AudioStreamBasicDescription {
+        "s16le@48000x2".parse().unwrap()
+    }
+
+    fn issues(format: &AudioStreamBasicDescription) -> Vec<AsbdIssue> {
+        format.validate().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn consistent_formats_validate() {
+        assert_eq!(s16_stereo().validate(), Ok(()));
+        let aac: AudioStreamBasicDescription = "2 ch, 48000 Hz, 'aac ', 1024 frames/packet"
+            .parse()
+            .unwrap();
+        assert_eq!(aac.validate(), Ok(()));
+    }
+
+    #[test]
+    fn any_rate_is_allowed() {
+        let format = s16_stereo();
+        unsafe { (*format.as_ptr()).mSampleRate = AudioStreamBasicDescription::ANY_RATE };
+        assert_eq!(format.validate(), Ok(()));
+    }
+
+    #[test]
+    fn negative_and_non_finite_rates_are_rejected() {
+        for &rate in &[-44100., f64::INFINITY, f64::NEG_INFINITY] {
+            let format = s16_stereo();
+            unsafe { (*format.as_ptr()).mSampleRate = rate };
+            assert_eq!(issues(&format), vec![AsbdIssue::InvalidSampleRate(rate)]);
+        }
+        let format = s16_stereo();
+        unsafe { (*format.as_ptr()).mSampleRate = f64::NAN };
+        match issues(&format)[..] {
+            [AsbdIssue::InvalidSampleRate(rate)] => assert!(rate.is_nan()),
+            ref other => panic!("unexpected issues {:?}", other),
+        }
+    }
+
+    #[test]
+    fn every_issue_is_reported() {
+        let format = s16_stereo();
+        unsafe {
+            let raw = &mut *format.as_ptr();
+            raw.mChannelsPerFrame = 0;
+            raw.mFramesPerPacket = 2;
+        }
+        assert_eq!(
+            issues(&format),
+            vec![
+                AsbdIssue::NoChannels,
+                AsbdIssue::FramesPerPacket(2),
+                AsbdIssue::BytesPerPacket {
+                    bytes_per_packet: 4,
+                    expected: 8,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn lpcm_layout_issues() {
+        let format = s16_stereo();
+        unsafe { (*format.as_ptr()).mBytesPerFrame = 0 };
+        assert!(issues(&format).contains(&AsbdIssue::NoBytesPerFrame));
+
+        let format = s16_stereo();
+        unsafe {
+            let raw = &mut *format.as_ptr();
+            raw.mBytesPerFrame = 5;
+            raw.mBytesPerPacket = 5;
+        }
+        assert_eq!(
+            issues(&format),
+            vec![AsbdIssue::BytesPerFrame {
+                bytes_per_frame: 5,
+                channels: 2,
+            }]
+        );
+
+        let format = s16_stereo();
+        unsafe { (*format.as_ptr()).mBitsPerChannel = 24 };
+        assert_eq!(
+            issues(&format),
+            vec![AsbdIssue::BitsExceedWord {
+                bits_per_channel: 24,
+                word_bits: 16,
+            }]
+        );
+
+        let format = s16_stereo();
+        unsafe { (*format.as_ptr()).mBitsPerChannel = 12 };
+        assert_eq!(
+            issues(&format),
+            vec![AsbdIssue::PackedBits {
+                bits_per_channel: 12,
+                word_bits: 16,
+            }]
+        );
+
+        let format = s16_stereo();
+        unsafe { (*format.as_ptr()).mBitsPerChannel = 0 };
+        assert_eq!(issues(&format), vec![AsbdIssue::NoBitsPerChannel]);
+    }
+
+    #[test]
+    fn lpcm_flag_issues() {
+        let format = s16_stereo();
+        unsafe { (*format.as_ptr()).mFormatFlags |= ffi::kAudioFormatFlagIsAlignedHigh };
+        assert_eq!(issues(&format), vec![AsbdIssue::PackedAndAlignedHigh]);
+
+        let format = s16_stereo();
+        unsafe { (*format.as_ptr()).mFormatFlags |= ffi::kAudioFormatFlagIsFloat };
+        assert_eq!(
+            issues(&format),
+            vec![AsbdIssue::FloatAndSignedInteger, AsbdIssue::FloatBits(16)]
+        );
+
+        let format: AudioStreamBasicDescription = "f32@48000x1".parse().unwrap();
+        unsafe {
+            (*format.as_ptr()).mFormatFlags |=
+                8 << ffi::kLinearPCMFormatFlagsSampleFractionShift
+        };
+        assert_eq!(issues(&format), vec![AsbdIssue::FloatFractionBits(8)]);
+
+        let format: AudioStreamBasicDescription = "u8@48000x1".parse().unwrap();
+        unsafe {
+            (*format.as_ptr()).mFormatFlags |=
+                12 << ffi::kLinearPCMFormatFlagsSampleFractionShift
+        };
+        assert_eq!(
+            issues(&format),
+            vec![AsbdIssue::FractionBits {
+                fraction_bits: 12,
+                bits_per_channel: 8,
+            }]
+        );
+    }
+
+    #[test]
+    fn builder_lpcm_defaults() {
+        let format = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, 48000., 2)
+            .sample_kind(SampleKind::SignedInteger)
+            .bits_per_channel(20)
+            .build();
+        assert_eq!(format.bits_per_channel(), 20);
+        assert_eq!(format.bytes_per_frame(), 6);
+        assert_eq!(format.bytes_per_packet(), 6);
+        assert!(format.format_flags().contains(AudioFormatFlags::IS_ALIGNED_HIGH));
+        assert_eq!(format.validate(), Ok(()));
+    }
+
+    #[test]
+    fn builder_sizes_saturate() {
+        let format = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, 48000., 2)
+            .sample_kind(SampleKind::SignedInteger)
+            .bits_per_channel(u32::MAX)
+            .build();
+        assert_eq!(format.bytes_per_frame(), u32::MAX / 8 * 2);
+        assert_eq!(format.bytes_per_packet(), u32::MAX / 8 * 2);
+        assert!(format.validate().is_err());
+
+        let format = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, 48000., u32::MAX)
+            .word_bits(64)
+            .build();
+        assert_eq!(format.bytes_per_frame(), u32::MAX);
+
+        let format = AudioStreamBasicDescription::builder(AudioFormat::AppleIma4, 48000., u32::MAX)
+            .build();
+        assert_eq!(format.bytes_per_packet(), u32::MAX);
+    }
+
+    #[test]
+    fn builder_fixed_point() {
+        let format = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, 48000., 1)
+            .sample_kind(SampleKind::SignedInteger)
+            .bits_per_channel(32)
+            .fraction_bits(24)
+            .build();
+        assert_eq!(format.fraction_bits(), 24);
+        assert_eq!(format.validate(), Ok(()));
+    }
+
+    #[test]
+    fn builder_fraction_bits_saturate() {
+        let format = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, 48000., 1)
+            .sample_kind(SampleKind::SignedInteger)
+            .bits_per_channel(32)
+            .fraction_bits(100)
+            .build();
+        assert_eq!(format.fraction_bits(), 63);
+        assert_eq!(
+            issues(&format),
+            vec![AsbdIssue::FractionBits {
+                fraction_bits: 63,
+                bits_per_channel: 32,
+            }]
+        );
+    }
+
+    #[test]
+    fn builder_lossless_source_depths() {
+        for &(bits, flags) in &[
+            (16, ffi::kAppleLosslessFormatFlag_16BitSourceData),
+            (20, ffi::kAppleLosslessFormatFlag_20BitSourceData),
+            (24, ffi::kAppleLosslessFormatFlag_24BitSourceData),
+            (32, ffi::kAppleLosslessFormatFlag_32BitSourceData),
+        ] {
+            for &format in &[AudioFormat::AppleLossless, AudioFormat::Flac] {
+                let format = AudioStreamBasicDescription::builder(format, 44100., 2)
+                    .bits_per_channel(bits)
+                    .build();
+                assert_eq!(unsafe { (*format.as_ptr()).mFormatFlags }, flags);
+                assert_eq!(format.frames_per_packet(), 4096);
+            }
+        }
+    }
+
+    #[test]
+    fn builder_rounds_lossless_depths_up() {
+        for &(bits, flags) in &[
+            (8, ffi::kAppleLosslessFormatFlag_16BitSourceData),
+            (18, ffi::kAppleLosslessFormatFlag_20BitSourceData),
+            (21, ffi::kAppleLosslessFormatFlag_24BitSourceData),
+            (25, ffi::kAppleLosslessFormatFlag_32BitSourceData),
+            (64, ffi::kAppleLosslessFormatFlag_32BitSourceData),
+        ] {
+            let format = AudioStreamBasicDescription::builder(AudioFormat::Flac, 44100., 2)
+                .bits_per_channel(bits)
+                .build();
+            assert_eq!(unsafe { (*format.as_ptr()).mFormatFlags }, flags);
+        }
+    }
+
+    fn raw(format: &AudioStreamBasicDescription) -> String {
+        format!("{:?}", unsafe { *format.as_ptr() })
+    }
+
+    const LONG_FORMS: &[&str] = &[
+        "2 ch, 48000 Hz, Float32, interleaved",
+        "1 ch, 44100 Hz, Float64, non-interleaved",
+        "2 ch, 48000 Hz, Int16, interleaved",
+        "1 ch, 96000 Hz, Int24/32, interleaved",
+        "2 ch, 48000 Hz, Int20/32, aligned low, interleaved",
+        "1 ch, 8000 Hz, UInt8, interleaved",
+        "1 ch, 48000 Hz, Fixed8.24, interleaved",
+        "6 ch, 22050.5 Hz, Int16, non-interleaved",
+        "2 ch, 48000 Hz, 'aac ', 1024 frames/packet",
+        "1 ch, 44100 Hz, 'ima4', 64 frames/packet, 34 bytes/packet",
+        "2 ch, 44100 Hz, 'alac', 4096 frames/packet, flags 0x2",
+    ];
+
+    #[test]
+    fn long_forms_round_trip() {
+        for &s in LONG_FORMS {
+            let format: AudioStreamBasicDescription = s.parse().unwrap();
+            assert_eq!(format.to_string(), s);
+            let again: AudioStreamBasicDescription = format.to_string().parse().unwrap();
+            assert_eq!(raw(&again), raw(&format));
+        }
+    }
+
+    #[test]
+    fn endianness_round_trips() {
+        for &s in &[
+            "2 ch, 48000 Hz, Int16, big-endian, interleaved",
+            "2 ch, 48000 Hz, Int16, little-endian, interleaved",
+        ] {
+            let format: AudioStreamBasicDescription = s.parse().unwrap();
+            let again: AudioStreamBasicDescription = format.to_string().parse().unwrap();
+            assert_eq!(raw(&again), raw(&format));
+            assert_eq!(
+                format.format_flags().contains(AudioFormatFlags::IS_BIG_ENDIAN),
+                s.contains("big")
+            );
+        }
+    }
+
+    #[test]
+    fn short_forms_round_trip() {
+        for &(short, long) in &[
+            ("f32@48000x2", "2 ch, 48000 Hz, Float32, interleaved"),
+            ("f64@44100x1", "1 ch, 44100 Hz, Float64, interleaved"),
+            ("i16@48000x2", "2 ch, 48000 Hz, Int16, interleaved"),
+            ("u8@8000x1", "1 ch, 8000 Hz, UInt8, interleaved"),
+            ("s24@96000x6", "6 ch, 96000 Hz, Int24, interleaved"),
+            ("s16be@48000x2", "2 ch, 48000 Hz, Int16, big-endian, interleaved"),
+            ("s16le@48000x2", "2 ch, 48000 Hz, Int16, little-endian, interleaved"),
+        ] {
+            let format: AudioStreamBasicDescription = short.parse().unwrap();
+            let expected: AudioStreamBasicDescription = long.parse().unwrap();
+            assert_eq!(raw(&format), raw(&expected), "{}", short);
+            let again: AudioStreamBasicDescription = format.to_string().parse().unwrap();
+            assert_eq!(raw(&again), raw(&format), "{}", short);
+        }
+    }
+
+    #[test]
+    fn malformed_descriptions_are_rejected() {
+        use self::ParseFormatError::*;
+        for &(s, error) in &[
+            ("", SampleRate),
+            ("s16", SampleRate),
+            ("s16@48000", Channels),
+            ("s16@fast x2", SampleRate),
+            ("s16@48000x", Channels),
+            ("s16@48000x65536", Channels),
+            ("s16@48000x99999999999", Channels),
+            ("@48000x2", SampleFormat),
+            ("q16@48000x2", SampleFormat),
+            ("\u{e9}16@48000x2", SampleFormat),
+            ("s128@48000x2", SampleFormat),
+            ("s65@48000x2", SampleFormat),
+            ("s16xe@48000x2", SampleFormat),
+            ("2, 48000 Hz, Int16", Channels),
+            ("65536 ch, 48000 Hz, Int16", Channels),
+            ("2 ch, 48000, Int16", SampleRate),
+            ("2 ch, 48000 Hz", SampleFormat),
+            ("2 ch, 48000 Hz, Int65", SampleFormat),
+            ("2 ch, 48000 Hz, Int16/128", SampleFormat),
+            ("2 ch, 48000 Hz, UFloat32", SampleFormat),
+            ("2 ch, 48000 Hz, Fixed8", SampleFormat),
+            ("2 ch, 48000 Hz, Fixed0.64", SampleFormat),
+            ("2 ch, 48000 Hz, Fixed4294967295.1", SampleFormat),
+            ("2 ch, 48000 Hz, Fixed4294967295.4294967295", SampleFormat),
+            ("2 ch, 48000 Hz, Int16, sideways", UnexpectedField),
+            ("2 ch, 48000 Hz, 'aac', 1024 frames/packet", FourCharCode),
+            ("2 ch, 48000 Hz, 'aac ', many frames/packet", UnexpectedField),
+            ("2 ch, 48000 Hz, 'aac ', flags 0xzz", UnexpectedField),
+        ] {
+            assert_eq!(
+                s.parse::<AudioStreamBasicDescription>().err(),
+                Some(error),
+                "{:?}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn parsing_never_panics() {
+        // Every prefix and suffix of valid descriptions, which covers
+        // most of the ways a field can be cut short.
+        let short = ["s16le@48000x2", "f64be@44100x1", "u8@8000x1"];
+        for s in LONG_FORMS.iter().chain(short.iter()) {
+            for (i, _) in s.char_indices() {
+                let _ = s[..i].parse::<AudioStreamBasicDescription>();
+                let _ = s[i..].parse::<AudioStreamBasicDescription>();
+            }
+        }
+        for s in &[
+            "s4294967295@1x1",
+            "u64@-1x4294967295",
+            "f32@NaNx1",
+            "f32@infx1",
+            "4294967295 ch, 1e400 Hz, Float4294967295/4294967295",
+            "1 ch, 48000 Hz, UFixed63.1",
+            "1 ch, 48000 Hz, 'aac ', 4294967295 frames/packet, 4294967295 bytes/packet",
+            "65535 ch, 48000 Hz, 'ima4'",
+            "65535 ch, 48000 Hz, Float64/64",
+            ",,,,",
+            "x@x",
+        ] {
+            let _ = s.parse::<AudioStreamBasicDescription>();
+        }
+    }
+}
@@ -0,0 +1,823 @@
+// Copyright © 2017-2018 Daniel Glastonbury <dan.glastonbury@gmail.com>
+
+//! Streaming reading and writing of WAV files, including
+//! `WAVE_FORMAT_EXTENSIBLE` and RF64 files larger than 4 GB.
+
+use {AudioBufferListRef, AudioChannelLayout, AudioChannelLayoutRef, AudioFormat, AudioFormatFlags,
+     AudioStreamBasicDescription, AudioStreamBasicDescriptionRef, SampleKind,
+     StandardChannelLayoutTag};
+use audio_file::{buffer_frames, check_sample_rate, gather, scatter, skip, AudioFileError,
+                 AudioFileResult, ReadBytes};
+use interleave::frame_count;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_ALAW: u16 = 6;
+const WAVE_FORMAT_MULAW: u16 = 7;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// The bytes of the `KSDATAFORMAT_SUBTYPE` GUIDs after the format tag
+/// in their first two bytes.
+const SUBTYPE_GUID_TAIL: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+];
+
+/// Chunk sizes of this value are in the `ds64` chunk of an RF64 file.
+const RF64_SIZE: u32 = 0xFFFF_FFFF;
+
+/// The size of a `ds64` chunk without a table, and of the `JUNK` chunk
+/// a writer reserves for one.
+const DS64_SIZE: u32 = 28;
+
+/// The contents of a `fmt ` chunk.
+struct FormatChunk {
+    tag: u16,
+    channels: u16,
+    sample_rate: u32,
+    block_align: u16,
+    bits: u16,
+    valid_bits: u16,
+    channel_mask: u32,
+}
+
+impl FormatChunk {
+    fn read<R: Read>(reader: &mut R, size: u32) -> AudioFileResult<FormatChunk> {
+        if size < 16 {
+            return Err(AudioFileError::Malformed("fmt chunk is too short"));
+        }
+        let mut chunk = FormatChunk {
+            tag: reader.u16_le()?,
+            channels: reader.u16_le()?,
+            sample_rate: reader.u32_le()?,
+            block_align: {
+                let _byte_rate = reader.u32_le()?;
+                reader.u16_le()?
+            },
+            bits: reader.u16_le()?,
+            valid_bits: 0,
+            channel_mask: 0,
+        };
+        chunk.valid_bits = chunk.bits;
+        let mut read = 16;
+        if chunk.tag == WAVE_FORMAT_EXTENSIBLE {
+            if size < 40 || reader.u16_le()? < 22 {
+                return Err(AudioFileError::Malformed("fmt chunk is too short"));
+            }
+            let valid_bits = reader.u16_le()?;
+            if valid_bits != 0 {
+                chunk.valid_bits = valid_bits;
+            }
+            chunk.channel_mask = reader.u32_le()?;
+            let guid: [u8; 16] = reader.bytes_array()?;
+            if guid[2..] != SUBTYPE_GUID_TAIL {
+                return Err(AudioFileError::Unsupported("WAVE_FORMAT_EXTENSIBLE sub format"));
+            }
+            chunk.tag = u16::from_le_bytes([guid[0], guid[1]]);
+            read = 40;
+        }
+        skip(reader, u64::from(size - read) + u64::from(size & 1))?;
+        Ok(chunk)
+    }
+
+    fn to_format(&self) -> AudioFileResult<AudioStreamBasicDescription> {
+        let channels = u32::from(self.channels);
+        let block_align = u32::from(self.block_align);
+        if channels == 0 || block_align == 0 || block_align % channels != 0 {
+            return Err(AudioFileError::Malformed("fmt chunk has an invalid block size"));
+        }
+        let word_bits = block_align / channels * 8;
+        let bits = u32::from(self.valid_bits);
+        let rate = f64::from(self.sample_rate);
+        let lpcm = AudioStreamBasicDescription::builder(AudioFormat::LinearPcm, rate, channels);
+        let format = match self.tag {
+            WAVE_FORMAT_PCM if bits > 0 && bits <= word_bits => {
+                // 8-bit samples are unsigned, and wider ones signed.
+                let kind = if word_bits == 8 {
+                    SampleKind::UnsignedInteger
+                } else {
+                    SampleKind::SignedInteger
+                };
+                lpcm.sample_kind(kind)
+                    .bits_per_channel(bits)
+                    .word_bits(word_bits)
+                    .big_endian(false)
+                    .build()
+            }
+            WAVE_FORMAT_IEEE_FLOAT if (bits == 32 || bits == 64) && bits == word_bits => lpcm
+                .sample_kind(SampleKind::Float)
+                .bits_per_channel(bits)
+                .big_endian(false)
+                .build(),
+            WAVE_FORMAT_ALAW | WAVE_FORMAT_MULAW if word_bits == 8 => {
+                let id = if self.tag == WAVE_FORMAT_ALAW {
+                    AudioFormat::ALaw
+                } else {
+                    AudioFormat::ULaw
+                };
+                AudioStreamBasicDescription::builder(id, rate, channels).build()
+            }
+            WAVE_FORMAT_PCM | WAVE_FORMAT_IEEE_FLOAT | WAVE_FORMAT_ALAW | WAVE_FORMAT_MULAW => {
+                return Err(AudioFileError::Unsupported("WAV sample size"))
+            }
+            _ => return Err(AudioFileError::Unsupported("WAV format tag")),
+        };
+        Ok(format)
+    }
+}
+
+/// Reads the frames of a WAV file, or of an RF64 file larger than
+/// 4 GB.
+///
+/// Files with PCM, IEEE float, µ-law or A-law samples can be read, in
+/// either the original or the `WAVE_FORMAT_EXTENSIBLE` format chunk.
+/// If the data chunk's size is missing, as it is when a recording
+/// stopped before its header was finished, the data is taken to run to
+/// the end of the file. The size is missing when it's `0xFFFFFFFF`
+/// without a `ds64` chunk, or when it's 0 and the RIFF size ends before
+/// the data.
+pub struct WavReader<R> {
+    reader: R,
+    format: AudioStreamBasicDescription,
+    channel_mask: u32,
+    data_start: u64,
+    frames: u64,
+    position: u64,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read + Seek> WavReader<R> {
+    /// Reads the header of the file, leaving `reader` at the first
+    /// frame.
+    pub fn new(mut reader: R) -> AudioFileResult<WavReader<R>> {
+        let riff_start = reader.stream_position()?;
+        let rf64 = match &reader.four_cc()? {
+            b"RIFF" => false,
+            b"RF64" | b"BW64" => true,
+            _ => return Err(AudioFileError::Malformed("not a RIFF file")),
+        };
+        let mut riff_size = u64::from(reader.u32_le()?);
+        if &reader.four_cc()? != b"WAVE" {
+            return Err(AudioFileError::Malformed("not a WAVE file"));
+        }
+
+        let mut ds64_data_size = None;
+        let mut fmt = None;
+        let data_size = loop {
+            let id = reader.four_cc().map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => AudioFileError::Malformed("no data chunk"),
+                _ => e.into(),
+            })?;
+            let size = reader.u32_le()?;
+            match &id {
+                b"ds64" if rf64 => {
+                    if size < DS64_SIZE {
+                        return Err(AudioFileError::Malformed("ds64 chunk is too short"));
+                    }
+                    riff_size = reader.u64_le()?;
+                    ds64_data_size = Some(reader.u64_le()?);
+                    skip(&mut reader, u64::from(size - 16) + u64::from(size & 1))?;
+                }
+                b"fmt " => fmt = Some(FormatChunk::read(&mut reader, size)?),
+                b"data" => match ds64_data_size {
+                    Some(size64) if size == RF64_SIZE => break Some(size64),
+                    _ if size == RF64_SIZE => break None,
+                    _ => break Some(u64::from(size)),
+                },
+                _ => skip(&mut reader, u64::from(size) + u64::from(size & 1))?,
+            }
+        };
+        let fmt = fmt.ok_or(AudioFileError::Malformed("no fmt chunk before the data"))?;
+        let format = fmt.to_format()?;
+
+        let data_start = reader.stream_position()?;
+        // An empty data chunk is only real if the RIFF chunk says there's
+        // more to the file.
+        let riff_end = (riff_start + 8).saturating_add(riff_size);
+        let data_size = data_size.filter(|&size| size != 0 || riff_end > data_start);
+        let available = reader.seek(SeekFrom::End(0))?.saturating_sub(data_start);
+        reader.seek(SeekFrom::Start(data_start))?;
+        let data_size = data_size.map_or(available, |size| size.min(available));
+        Ok(WavReader {
+            reader,
+            frames: data_size / u64::from(fmt.block_align),
+            format,
+            channel_mask: fmt.channel_mask,
+            data_start,
+            position: 0,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+impl<R> WavReader<R> {
+    /// The format of the file's frames. It's always interleaved.
+    pub fn format(&self) -> &AudioStreamBasicDescriptionRef {
+        &self.format
+    }
+
+    /// The layout of the file's channels: the channel mask of a
+    /// `WAVE_FORMAT_EXTENSIBLE` file, or mono or stereo for files with
+    /// one or two channels and no mask.
+    pub fn channel_layout(&self) -> Option<AudioChannelLayout> {
+        let channels = self.format.channels_per_frame();
+        if self.channel_mask != 0 && self.channel_mask.count_ones() == channels {
+            let bitmap = ::AudioChannelBitmap::from_bits(self.channel_mask)?;
+            return Some(AudioChannelLayout::with_bitmap(bitmap));
+        }
+        match channels {
+            1 => Some(AudioChannelLayout::with_tag(StandardChannelLayoutTag::MONO)),
+            2 => Some(AudioChannelLayout::with_tag(StandardChannelLayoutTag::STEREO)),
+            _ => None,
+        }
+    }
+
+    /// The number of frames in the file.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// The index of the next frame to be read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R> fmt::Debug for WavReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WavReader")
+            .field("format", &self.format.to_string())
+            .field("frames", &self.frames)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl<R: Read + Seek> WavReader<R> {
+    /// Moves to `frame`, or to the end of the file if it's past the
+    /// end.
+    pub fn seek(&mut self, frame: u64) -> AudioFileResult<()> {
+        let frame = frame.min(self.frames);
+        let frame_bytes = u64::from(self.format.bytes_per_frame());
+        self.reader
+            .seek(SeekFrom::Start(self.data_start + frame * frame_bytes))?;
+        self.position = frame;
+        Ok(())
+    }
+
+    /// Reads as many frames as fit in `abl`, and returns the number
+    /// read. The list can be interleaved or not, but must have a channel
+    /// for each of the file's channels, with samples of the file's
+    /// size. Returns 0 at the end of the file.
+    pub fn read(&mut self, abl: &mut AudioBufferListRef) -> AudioFileResult<usize> {
+        let channels = self.format.channels_per_frame() as usize;
+        let sample_bytes = self.format.sample_word_size() as usize;
+        let frames = buffer_frames(abl, channels, sample_bytes)?;
+        let frames = (frames as u64).min(self.frames - self.position) as usize;
+        self.buffer.resize(frames * channels * sample_bytes, 0);
+        self.reader.read_exact(&mut self.buffer)?;
+        scatter(&self.buffer, abl, sample_bytes);
+        self.position += frames as u64;
+        Ok(frames)
+    }
+}
+
+/// Writes the frames of a WAV file.
+///
+/// The header is written first with empty sizes, which `finalize`
+/// fills in. A file whose data grows past 4 GB becomes an RF64 file,
+/// using the space the header reserves for its `ds64` chunk. The file
+/// is finalized when the writer is dropped, ignoring errors, so call
+/// `finalize` or `into_inner` to see them.
+pub struct WavWriter<W: Write + Seek> {
+    writer: Option<W>,
+    format: AudioStreamBasicDescription,
+    riff_start: u64,
+    data_start: u64,
+    data_size: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Starts a file of frames in `format`. Mono and stereo files are
+    /// marked as such, and files with more channels have no channel
+    /// layout.
+    pub fn new(
+        writer: W,
+        format: &AudioStreamBasicDescriptionRef,
+    ) -> AudioFileResult<WavWriter<W>> {
+        let mask = match format.channels_per_frame() {
+            1 => ::AudioChannelBitmap::CENTER.bits(),
+            2 => (::AudioChannelBitmap::LEFT | ::AudioChannelBitmap::RIGHT).bits(),
+            _ => 0,
+        };
+        WavWriter::start(writer, format, mask, false)
+    }
+
+    /// Starts a file of frames in `format`, with the channel mask of
+    /// `layout`. Only layouts that can be channel bitmaps can be
+    /// written.
+    pub fn with_channel_layout(
+        writer: W,
+        format: &AudioStreamBasicDescriptionRef,
+        layout: &AudioChannelLayoutRef,
+    ) -> AudioFileResult<WavWriter<W>> {
+        let bitmap = layout
+            .to_bitmap()
+            .ok_or(AudioFileError::Unsupported("channel layout has no WAV channel mask"))?;
+        if bitmap.bits().count_ones() != format.channels_per_frame() {
+            return Err(AudioFileError::BufferLayout);
+        }
+        WavWriter::start(writer, format, bitmap.bits(), true)
+    }
+
+    fn start(
+        mut writer: W,
+        format: &AudioStreamBasicDescriptionRef,
+        channel_mask: u32,
+        extensible: bool,
+    ) -> AudioFileResult<WavWriter<W>> {
+        check_sample_rate(format)?;
+        if format.validate().is_err() {
+            return Err(AudioFileError::Unsupported("inconsistent stream description"));
+        }
+        let channels = format.channels_per_frame();
+        let word_bits = format.sample_word_size().saturating_mul(8);
+        let bits = format.bits_per_channel();
+        let flags = format.format_flags();
+        let tag = match format.format_id() {
+            AudioFormat::LinearPcm => {
+                if flags.contains(AudioFormatFlags::IS_BIG_ENDIAN) {
+                    return Err(AudioFileError::Unsupported("big endian WAV samples"));
+                }
+                let raw_flags = unsafe { (*format.as_ptr()).mFormatFlags };
+                if raw_flags & ::ffi::kLinearPCMFormatFlagsSampleFractionMask != 0 {
+                    return Err(AudioFileError::Unsupported("fixed point WAV samples"));
+                }
+                if bits < word_bits && !flags.contains(AudioFormatFlags::IS_ALIGNED_HIGH) {
+                    return Err(AudioFileError::Unsupported("low aligned WAV samples"));
+                }
+                let signed = flags.contains(AudioFormatFlags::IS_SIGNED_INTEGER);
+                if format.is_float() {
+                    WAVE_FORMAT_IEEE_FLOAT
+                } else if signed == (word_bits > 8) {
+                    WAVE_FORMAT_PCM
+                } else {
+                    return Err(AudioFileError::Unsupported(
+                        "WAV samples are unsigned at 8 bits and signed above",
+                    ));
+                }
+            }
+            AudioFormat::ULaw => WAVE_FORMAT_MULAW,
+            AudioFormat::ALaw => WAVE_FORMAT_ALAW,
+            _ => return Err(AudioFileError::Unsupported("WAV can't hold the format")),
+        };
+        let extensible = extensible || channels > 2 || bits != word_bits
+            || (tag == WAVE_FORMAT_PCM && word_bits > 16);
+
+        let block_align = channels * word_bits / 8;
+        let rate = format.sample_rate().round() as u32;
+        let mut header = Vec::with_capacity(80);
+        header.extend_from_slice(b"RIFF\0\0\0\0WAVE");
+        // Room for a ds64 chunk, should the file grow past 4 GB.
+        header.extend_from_slice(b"JUNK");
+        header.extend_from_slice(&DS64_SIZE.to_le_bytes());
+        header.extend_from_slice(&[0; DS64_SIZE as usize]);
+        header.extend_from_slice(b"fmt ");
+        let fmt_size: u32 = if extensible {
+            40
+        } else if tag == WAVE_FORMAT_PCM {
+            16
+        } else {
+            18
+        };
+        header.extend_from_slice(&fmt_size.to_le_bytes());
+        let fmt_tag = if extensible { WAVE_FORMAT_EXTENSIBLE } else { tag };
+        header.extend_from_slice(&fmt_tag.to_le_bytes());
+        header.extend_from_slice(&(channels as u16).to_le_bytes());
+        header.extend_from_slice(&rate.to_le_bytes());
+        header.extend_from_slice(&(rate * block_align).to_le_bytes());
+        header.extend_from_slice(&(block_align as u16).to_le_bytes());
+        header.extend_from_slice(&(word_bits as u16).to_le_bytes());
+        if extensible {
+            header.extend_from_slice(&22u16.to_le_bytes());
+            header.extend_from_slice(&(bits as u16).to_le_bytes());
+            header.extend_from_slice(&channel_mask.to_le_bytes());
+            header.extend_from_slice(&tag.to_le_bytes());
+            header.extend_from_slice(&SUBTYPE_GUID_TAIL);
+        } else if fmt_size == 18 {
+            header.extend_from_slice(&0u16.to_le_bytes());
+        }
+        header.extend_from_slice(b"data\0\0\0\0");
+
+        let riff_start = writer.stream_position()?;
+        writer.write_all(&header)?;
+        let file_format = format.to_owned();
+        unsafe {
+            let asbd = &mut *file_format.as_ptr();
+            asbd.mFormatFlags &= !AudioFormatFlags::IS_NON_INTERLEAVED.bits();
+            asbd.mBytesPerFrame = block_align;
+            asbd.mBytesPerPacket = block_align;
+        }
+        Ok(WavWriter {
+            writer: Some(writer),
+            format: file_format,
+            riff_start,
+            data_start: riff_start + header.len() as u64,
+            data_size: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// The format of the file's frames. It's the format the writer was
+    /// created with, but interleaved.
+    pub fn format(&self) -> &AudioStreamBasicDescriptionRef {
+        &self.format
+    }
+
+    /// The number of frames written so far.
+    pub fn frames(&self) -> u64 {
+        self.data_size / u64::from(self.format.bytes_per_frame())
+    }
+
+    /// Appends every frame in `abl`, and returns the number written. The
+    /// list can be interleaved or not, but must have a channel for each
+    /// of the file's channels, with samples of the file's size.
+    pub fn write(&mut self, abl: &AudioBufferListRef) -> AudioFileResult<usize> {
+        let channels = self.format.channels_per_frame() as usize;
+        let sample_bytes = self.format.sample_word_size() as usize;
+        buffer_frames(abl, channels, sample_bytes)?;
+        let frames = frame_count(abl, sample_bytes);
+        self.buffer.clear();
+        gather(abl, frames, sample_bytes, &mut self.buffer);
+        let writer = self.writer.as_mut().unwrap();
+        writer.seek(SeekFrom::Start(self.data_start + self.data_size))?;
+        writer.write_all(&self.buffer)?;
+        self.data_size += self.buffer.len() as u64;
+        Ok(frames)
+    }
+
+    /// Fills in the sizes in the header, and pads the data to an even
+    /// length. More frames can be written afterwards.
+    pub fn finalize(&mut self) -> AudioFileResult<()> {
+        let frames = self.frames();
+        let writer = self.writer.as_mut().unwrap();
+        let data_end = self.data_start + self.data_size;
+        writer.seek(SeekFrom::Start(data_end))?;
+        let pad = self.data_size & 1;
+        if pad != 0 {
+            writer.write_all(&[0])?;
+        }
+        let riff_size = data_end + pad - (self.riff_start + 8);
+        let data_size_at = self.data_start - 4;
+        if riff_size > u64::from(RF64_SIZE - 1) {
+            let mut ds64 = Vec::with_capacity(DS64_SIZE as usize + 8);
+            ds64.extend_from_slice(b"ds64");
+            ds64.extend_from_slice(&DS64_SIZE.to_le_bytes());
+            ds64.extend_from_slice(&riff_size.to_le_bytes());
+            ds64.extend_from_slice(&self.data_size.to_le_bytes());
+            ds64.extend_from_slice(&frames.to_le_bytes());
+            ds64.extend_from_slice(&0u32.to_le_bytes());
+            writer.seek(SeekFrom::Start(self.riff_start))?;
+            writer.write_all(b"RF64")?;
+            writer.write_all(&RF64_SIZE.to_le_bytes())?;
+            writer.seek(SeekFrom::Start(self.riff_start + 12))?;
+            writer.write_all(&ds64)?;
+            writer.seek(SeekFrom::Start(data_size_at))?;
+            writer.write_all(&RF64_SIZE.to_le_bytes())?;
+        } else {
+            writer.seek(SeekFrom::Start(self.riff_start + 4))?;
+            writer.write_all(&(riff_size as u32).to_le_bytes())?;
+            writer.seek(SeekFrom::Start(data_size_at))?;
+            writer.write_all(&(self.data_size as u32).to_le_bytes())?;
+        }
+        writer.seek(SeekFrom::Start(data_end))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Finalizes the file and returns the underlying writer.
+    pub fn into_inner(mut self) -> AudioFileResult<W> {
+        self.finalize()?;
+        Ok(self.writer.take().unwrap())
+    }
+}
+
+impl<W: Write + Seek> fmt::Debug for WavWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WavWriter")
+            .field("format", &self.format.to_string())
+            .field("frames", &self.frames())
+            .finish()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.finalize();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use AudioBufferList;
+
+    fn format(s: &str) -> AudioStreamBasicDescription {
+        s.parse().unwrap()
+    }
+
+    /// A buffer list of `format` holding `frames` of counting bytes.
+    fn frames(format: &AudioStreamBasicDescriptionRef, frames: usize) -> AudioBufferList {
+        let mut abl = AudioBufferList::allocate(format, frames);
+        for buffer in abl.iter_mut() {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+        }
+        abl
+    }
+
+    fn write(format: &AudioStreamBasicDescriptionRef, abl: &AudioBufferList) -> Vec<u8> {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), format).unwrap();
+        writer.write(abl).unwrap();
+        writer.into_inner().unwrap().into_inner()
+    }
+
+    fn round_trip(s: &str) {
+        let format = format(s);
+        let written = frames(&format, 5);
+        let file = write(&format, &written);
+        let mut reader = WavReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.format().to_string(), format.to_string(), "{}", s);
+        assert_eq!(reader.frames(), 5);
+        let mut read = AudioBufferList::allocate(&format, 8);
+        assert_eq!(reader.read(&mut read).unwrap(), 5);
+        assert_eq!(&read[0][..written[0].len()], &written[0][..], "{}", s);
+        assert_eq!(reader.read(&mut read).unwrap(), 0);
+    }
+
+    #[test]
+    fn round_trips() {
+        for s in &[
+            "u8@8000x1",
+            "s16le@44100x2",
+            "s24le@48000x2",
+            "s32le@96000x1",
+            "f32le@48000x2",
+            "f64le@48000x1",
+            "s16le@48000x6",
+            "1 ch, 48000 Hz, Int20/24, little-endian, interleaved",
+            "2 ch, 8000 Hz, 'ulaw', 1 frames/packet, 2 bytes/packet",
+            "1 ch, 8000 Hz, 'alaw', 1 frames/packet, 1 bytes/packet",
+        ] {
+            round_trip(s);
+        }
+    }
+
+    #[test]
+    fn channel_layouts_round_trip() {
+        let surround = format("s16le@48000x6");
+        let bitmap = ::AudioChannelBitmap::LEFT
+            | ::AudioChannelBitmap::RIGHT
+            | ::AudioChannelBitmap::CENTER
+            | ::AudioChannelBitmap::LFE_SCREEN
+            | ::AudioChannelBitmap::LEFT_SURROUND
+            | ::AudioChannelBitmap::RIGHT_SURROUND;
+        let layout = AudioChannelLayout::with_bitmap(bitmap);
+        let mut writer =
+            WavWriter::with_channel_layout(Cursor::new(Vec::new()), &surround, &layout).unwrap();
+        writer.write(&frames(&surround, 3)).unwrap();
+        let file = writer.into_inner().unwrap().into_inner();
+        let reader = WavReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.frames(), 3);
+        assert_eq!(reader.channel_layout().and_then(|l| l.to_bitmap()), Some(bitmap));
+
+        let stereo = format("s16le@48000x2");
+        let file = write(&stereo, &frames(&stereo, 1));
+        let reader = WavReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(
+            reader.channel_layout().and_then(|l| l.to_bitmap()),
+            Some(::AudioChannelBitmap::LEFT | ::AudioChannelBitmap::RIGHT)
+        );
+    }
+
+    #[test]
+    fn non_interleaved_buffers_round_trip() {
+        let interleaved = format("s16le@48000x2");
+        let non_interleaved =
+            AudioStreamBasicDescription::with_lpcm(48000., 2, 16, 16, false, false, true);
+        let written = frames(&non_interleaved, 4);
+        let file = write(&non_interleaved, &written);
+        let mut reader = WavReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.format().to_string(), interleaved.to_string());
+        let mut read = AudioBufferList::allocate(&non_interleaved, 4);
+        assert_eq!(reader.read(&mut read).unwrap(), 4);
+        assert_eq!(&read[0][..], &written[0][..]);
+        assert_eq!(&read[1][..], &written[1][..]);
+    }
+
+    #[test]
+    fn seeking() {
+        let format = format("s16le@48000x1");
+        let file = write(&format, &frames(&format, 8));
+        let mut reader = WavReader::new(Cursor::new(file)).unwrap();
+        let mut read = AudioBufferList::allocate(&format, 2);
+        reader.seek(5).unwrap();
+        assert_eq!(reader.position(), 5);
+        assert_eq!(reader.read(&mut read).unwrap(), 2);
+        assert_eq!(&read[0][..], &[10, 11, 12, 13][..]);
+        reader.seek(100).unwrap();
+        assert_eq!(reader.position(), 8);
+        assert_eq!(reader.read(&mut read).unwrap(), 0);
+    }
+
+    #[test]
+    fn writers_reject_what_wav_cant_hold() {
+        let unsupported = |s: &str| {
+            WavWriter::new(Cursor::new(Vec::new()), &format(s)).err().map(|e| e.to_string())
+        };
+        assert!(unsupported("s16be@48000x2").is_some());
+        assert!(unsupported("u16le@48000x2").is_some());
+        assert!(unsupported("s8@48000x2").is_some());
+        assert_eq!(
+            unsupported("s16le@0x2").as_deref(),
+            Some("unsupported: unspecified sample rate")
+        );
+        assert!(unsupported("2 ch, 48000 Hz, 'aac ', 1024 frames/packet").is_some());
+    }
+
+    fn chunk_size_at(file: &[u8], id: &[u8; 4]) -> usize {
+        file.windows(4).position(|w| w == id).unwrap() + 4
+    }
+
+    #[test]
+    fn missing_data_sizes_run_to_the_end() {
+        let format = format("s16le@48000x2");
+        let file = write(&format, &frames(&format, 5));
+        let data_size_at = chunk_size_at(&file, b"data");
+
+        // A recording that stopped before its header was finished.
+        let mut unfinished = file.clone();
+        unfinished[4..8].copy_from_slice(&[0; 4]);
+        unfinished[data_size_at..data_size_at + 4].copy_from_slice(&[0; 4]);
+        assert_eq!(WavReader::new(Cursor::new(unfinished)).unwrap().frames(), 5);
+
+        let mut unknown = file.clone();
+        unknown[data_size_at..data_size_at + 4].copy_from_slice(&RF64_SIZE.to_le_bytes());
+        assert_eq!(WavReader::new(Cursor::new(unknown)).unwrap().frames(), 5);
+
+        // A size past the end of the file is cut short.
+        let mut long = file.clone();
+        long[data_size_at..data_size_at + 4].copy_from_slice(&1000u32.to_le_bytes());
+        assert_eq!(WavReader::new(Cursor::new(long)).unwrap().frames(), 5);
+    }
+
+    #[test]
+    fn empty_data_chunks_are_empty() {
+        let format = format("s16le@48000x2");
+        let mut file = write(&format, &frames(&format, 0));
+        assert_eq!(WavReader::new(Cursor::new(file.clone())).unwrap().frames(), 0);
+
+        // With a chunk after the data, its size of 0 is real.
+        file.extend_from_slice(b"LIST");
+        file.extend_from_slice(&4u32.to_le_bytes());
+        file.extend_from_slice(b"INFO");
+        let riff_size = file.len() as u32 - 8;
+        file[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        assert_eq!(WavReader::new(Cursor::new(file)).unwrap().frames(), 0);
+    }
+
+    /// A stream that keeps the first bytes written to it and pretends to
+    /// keep the rest, reading them back as zeros. It stands in for files
+    /// too large to make.
+    struct Sparse {
+        head: Vec<u8>,
+        tail: Vec<(u64, Vec<u8>)>,
+        len: u64,
+        position: u64,
+    }
+
+    const SPARSE_HEAD: u64 = 4096;
+
+    impl Sparse {
+        fn new() -> Sparse {
+            Sparse {
+                head: Vec::new(),
+                tail: Vec::new(),
+                len: 0,
+                position: 0,
+            }
+        }
+    }
+
+    impl Write for Sparse {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let end = self.position + buf.len() as u64;
+            if end <= SPARSE_HEAD {
+                let (start, end) = (self.position as usize, end as usize);
+                if self.head.len() < end {
+                    self.head.resize(end, 0);
+                }
+                self.head[start..end].copy_from_slice(buf);
+            } else {
+                assert!(self.position >= SPARSE_HEAD, "write across the head");
+                self.tail.push((self.position, buf.to_vec()));
+            }
+            self.position = end;
+            self.len = self.len.max(end);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Sparse {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = (buf.len() as u64).min(self.len.saturating_sub(self.position)) as usize;
+            for (i, byte) in buf[..n].iter_mut().enumerate() {
+                let at = self.position + i as u64;
+                *byte = if at < self.head.len() as u64 {
+                    self.head[at as usize]
+                } else {
+                    self.tail
+                        .iter()
+                        .rev()
+                        .find(|&&(start, ref data)| at >= start && at < start + data.len() as u64)
+                        .map_or(0, |&(start, ref data)| data[(at - start) as usize])
+                };
+            }
+            self.position += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl Seek for Sparse {
+        fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+            self.position = match from {
+                SeekFrom::Start(n) => n,
+                SeekFrom::End(n) => (self.len as i64 + n) as u64,
+                SeekFrom::Current(n) => (self.position as i64 + n) as u64,
+            };
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn large_files_become_rf64() {
+        let format = format("s16le@48000x2");
+        let mut writer = WavWriter::new(Sparse::new(), &format).unwrap();
+        let first = frames(&format, 4);
+        writer.write(&first).unwrap();
+        // Skip ahead past 4 GB of data, as though it had been written.
+        let skipped = 5 << 30;
+        writer.data_size = skipped;
+        let last = frames(&format, 3);
+        writer.write(&last).unwrap();
+        let data_size = skipped + 12;
+        let mut file = writer.into_inner().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        assert_eq!(&file.head[..4], b"RF64");
+        assert_eq!(&file.head[4..8], &RF64_SIZE.to_le_bytes());
+        assert_eq!(&file.head[12..20], b"ds64\x1c\0\0\0");
+        assert_eq!(&file.head[20..28], &(file.len - 8).to_le_bytes());
+        assert_eq!(&file.head[28..36], &data_size.to_le_bytes());
+        assert_eq!(&file.head[36..44], &(data_size / 4).to_le_bytes());
+        let data_size_at = chunk_size_at(&file.head, b"data");
+        assert_eq!(&file.head[data_size_at..data_size_at + 4], &RF64_SIZE.to_le_bytes());
+
+        let mut reader = WavReader::new(file).unwrap();
+        assert_eq!(reader.frames(), data_size / 4);
+        let mut read = AudioBufferList::allocate(&format, 4);
+        assert_eq!(reader.read(&mut read).unwrap(), 4);
+        assert_eq!(&read[0][..], &first[0][..]);
+        reader.seek(reader.frames() - 3).unwrap();
+        assert_eq!(reader.read(&mut read).unwrap(), 3);
+        assert_eq!(&read[0][..12], &last[0][..]);
+    }
+
+    #[test]
+    fn small_files_stay_riff() {
+        let format = format("s16le@48000x2");
+        let mut writer = WavWriter::new(Sparse::new(), &format).unwrap();
+        writer.data_size = u64::from(RF64_SIZE) - 1000;
+        writer.write(&frames(&format, 1)).unwrap();
+        let mut file = writer.into_inner().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(&file.head[..4], b"RIFF");
+        assert_eq!(&file.head[12..16], b"JUNK");
+        let reader = WavReader::new(file).unwrap();
+        assert_eq!(reader.frames(), (u64::from(RF64_SIZE) - 996) / 4);
+    }
+}